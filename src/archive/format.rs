@@ -0,0 +1,937 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io::{copy, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dedup,
+    encryption::keys::{KeySlot, MetadataKey},
+    error::ArchiveError,
+    internal,
+    pipeline::stage::{StageDescriptor, StageKind},
+};
+
+use super::{
+    recovery::{self, ParityEncoder},
+    volume::Volumes,
+    EntryMetadata,
+};
+
+/*
+    An archive is written as:
+    [ header ][ object 0 ] ... [ object n ][ index ][ trailer ]
+
+    Header:
+    [ magic ][ version ][ metadata length ][ metadata (JSON) ]
+    [ 8     ][ 2       ][ 4               ][ ...             ] (Bytes, LE)
+
+    The metadata of archives with key slots is followed by spaces, so
+    that it can be rewritten with more slots in the same room.
+
+    Object:
+    [ object magic ][ name length ][ name ][ data ][ data length ][ crc32 ]
+    [ 4            ][ 2           ][ ...  ][ ...  ][ 8           ][ 4     ] (Bytes, LE)
+
+    Objects are the files `compress_directory` writes, stored as they
+    are. Their name and descriptor repeat what the index says, so they
+    can still be found by scanning the archive.
+
+    The index is JSON, listing every object and the metadata of the
+    entries they hold.
+
+    Appending to an archive writes the new objects and a new index
+    after the old trailer, leaving everything before it untouched.
+    Objects that were superseded are kept, only marked as replaced in
+    the new index. If an append is cut short, the archive is read up
+    to the last trailer whose index is intact.
+
+    Archives with encrypted metadata keep only their key slots readable
+    in the header, the rest of it and the index are encrypted with a
+    `MetadataKey`. Their objects are stored without name and descriptor,
+    so they can't be scanned for.
+
+    A split archive is the same bytes cut into volumes, see `volume`.
+    An archive can be followed by a recovery record, see `recovery`.
+
+    Trailer:
+    [ index offset ][ index length ][ index crc32 ][ magic ]
+    [ 8            ][ 8            ][ 4           ][ 8     ] (Bytes, LE)
+*/
+
+pub(crate) const VERSION: u16 = 1;
+
+const MAGIC: &[u8; 8] = b"ZAPARCH1";
+const OBJECT_MAGIC: &[u8; 4] = b"ZAPO";
+const TRAILER_MAGIC: &[u8; 8] = b"ZAPINDX1";
+/// How every index starts, the first field being `objects`.
+const INDEX_START: &[u8; 11] = b"{\"objects\":";
+const HEADER_SIZE: usize = 14;
+const DESCRIPTOR_SIZE: usize = 12;
+const TRAILER_SIZE: usize = 28;
+/// Room left in the header of archives with key slots, so that slots
+/// can be added without moving anything else.
+const KEY_SLOT_ROOM: usize = 4096;
+
+/// Archive wide settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Header {
+    /// Stages every object went through, in the order data goes
+    /// through them when encoding.
+    pub stages: Vec<StageDescriptor>,
+    /// Random, identifies the archive in a chain of incremental ones.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Id of the archive this one holds the changes since.
+    #[serde(default)]
+    pub since: Option<String>,
+    /// Size of the volumes a split archive is written as.
+    #[serde(default)]
+    pub volume_size: Option<u64>,
+    /// Copies of the key entries are encrypted with.
+    #[serde(default)]
+    pub key_slots: Vec<KeySlot>,
+    /// Every other field, encrypted, for archives with encrypted
+    /// metadata. They are left empty in the header as stored.
+    #[serde(default)]
+    pub sealed: Option<String>,
+}
+
+impl Header {
+    /// Header of a new archive, with a fresh id.
+    pub fn new(stages: Vec<StageDescriptor>) -> Header {
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut id);
+
+        Header {
+            stages,
+            id: Some(dedup::to_hex(&id)),
+            since: None,
+            volume_size: None,
+            key_slots: vec![],
+            sealed: None,
+        }
+    }
+
+    /// True when entries need an encryption secret to be read.
+    pub fn is_encrypted(&self) -> bool {
+        self.sealed.is_some()
+            || self
+                .stages
+                .iter()
+                .any(|s| s.kind == StageKind::Encryption && s.name != "passthrough")
+    }
+
+    /// The header as stored when metadata is encrypted with `key`, only
+    /// the key slots are left readable.
+    pub fn seal(&self, key: &MetadataKey) -> Result<Header, Error> {
+        let inner = Header {
+            key_slots: vec![],
+            sealed: None,
+            ..self.clone()
+        };
+
+        Ok(Header {
+            key_slots: self.key_slots.clone(),
+            sealed: Some(dedup::to_hex(&key.encrypt(&serde_json::to_vec(&inner)?)?)),
+            ..Header::default()
+        })
+    }
+
+    /// The header with the fields `seal` encrypted, as it was before.
+    pub fn unseal(self, key: &MetadataKey) -> Result<Header, ArchiveError> {
+        let sealed = match &self.sealed {
+            Some(sealed) => sealed,
+            None => return Ok(self),
+        };
+
+        let sealed = match dedup::decode_hex(sealed) {
+            Some(sealed) => sealed,
+            None => return Err(ArchiveError::Corrupt("Invalid encrypted header".into())),
+        };
+
+        let sealed = key.decrypt(&sealed).map_err(|e| ArchiveError::Corrupt(e.to_string()))?;
+
+        let mut header: Header = serde_json::from_slice(&sealed)?;
+        header.key_slots = self.key_slots;
+
+        Ok(header)
+    }
+}
+
+/// Where a stored object lives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ObjectRecord {
+    pub name: String,
+    /// Offset of the object data.
+    pub offset: u64,
+    pub length: u64,
+    pub crc32: u32,
+    /// Superseded by a later object or no longer needed, the data
+    /// stays in the archive until it is compacted.
+    #[serde(default)]
+    pub replaced: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Index {
+    pub objects: Vec<ObjectRecord>,
+    /// Keyed by entry path. For incremental archives this also covers
+    /// unchanged files, which are only stored in earlier archives.
+    #[serde(default)]
+    pub entries: BTreeMap<String, EntryMetadata>,
+    /// Paths of the entries deleted since the archive this one was made
+    /// against.
+    #[serde(default)]
+    pub tombstones: BTreeSet<String>,
+    /// Parity of the recovery record, so that it can be added back if
+    /// an append was cut short after dropping it.
+    #[serde(default)]
+    pub parity: Option<u8>,
+}
+
+impl Index {
+    /// Objects that haven't been replaced.
+    pub fn live_objects(&self) -> impl Iterator<Item = &ObjectRecord> {
+        self.objects.iter().filter(|o| !o.replaced)
+    }
+}
+
+/// Writes the container, objects are appended as they come in.
+pub(crate) struct ContainerWriter<W> {
+    io: ParityWriter<W>,
+    position: u64,
+    index: Index,
+    volume_size: Option<u64>,
+    /// Encrypts the header and index when metadata is encrypted.
+    metadata_key: Option<MetadataKey>,
+    /// False for padded entries in an index that isn't encrypted, whose
+    /// sizes would give away what the padding hides.
+    keep_sizes: bool,
+}
+
+impl<W> ContainerWriter<W>
+where
+    W: Write,
+{
+    /// Writes the header to `io`. With `parity` set, a recovery record
+    /// of that many percent of the archive size is written by `finish`.
+    /// With `metadata_key` set, the header and index are encrypted and
+    /// objects are stored without their name and descriptor.
+    pub fn new(io: W, header: &Header, parity: Option<u8>, metadata_key: Option<MetadataKey>) -> Result<Self, Error> {
+        let mut metadata = match &metadata_key {
+            Some(key) => serde_json::to_vec(&header.seal(key)?)?,
+            None => serde_json::to_vec(header)?,
+        };
+
+        // Left as whitespace after the JSON
+        if !header.key_slots.is_empty() {
+            metadata.resize(metadata.len() + KEY_SLOT_ROOM, b' ');
+        }
+
+        let mut io = ParityWriter {
+            inner: io,
+            encoder: parity.map(ParityEncoder::new).transpose()?,
+        };
+
+        io.write_all(MAGIC)?;
+        io.write_all(&VERSION.to_le_bytes())?;
+        io.write_all(&(metadata.len() as u32).to_le_bytes())?;
+        io.write_all(&metadata)?;
+
+        Ok(ContainerWriter {
+            io,
+            position: (HEADER_SIZE + metadata.len()) as u64,
+            index: Index {
+                parity,
+                ..Index::default()
+            },
+            volume_size: header.volume_size,
+            keep_sizes: keep_sizes(header, &metadata_key),
+            metadata_key,
+        })
+    }
+
+    /// Continues an archive with `header` of `position` bytes whose
+    /// index was `index`, `io` has to be positioned at its end.
+    pub fn resume(io: W, header: &Header, index: Index, position: u64, metadata_key: Option<MetadataKey>) -> Self {
+        ContainerWriter {
+            io: ParityWriter { inner: io, encoder: None },
+            position,
+            index,
+            volume_size: None,
+            keep_sizes: keep_sizes(header, &metadata_key),
+            metadata_key,
+        }
+    }
+
+    /// Stores everything read from `reader` as `name`, returning the
+    /// number of bytes stored.
+    pub fn add_object<R>(&mut self, name: &str, reader: &mut R) -> Result<u64, Error>
+    where
+        R: Read,
+    {
+        self.add_object_with(name, |writer| copy(reader, writer).map(|_| ()))
+    }
+
+    /// Stores everything `write` writes as `name`, returning the number
+    /// of bytes stored. An earlier object with the same name is marked
+    /// as replaced. If `write` fails the object is left out of the
+    /// index, but whatever it wrote so far stays in the archive.
+    pub fn add_object_with<F, E>(&mut self, name: &str, write: F) -> Result<u64, E>
+    where
+        F: FnOnce(&mut ObjectWriter<'_, W>) -> Result<(), E>,
+        E: From<Error>,
+    {
+        if !is_valid_name(name) || name.len() > u16::MAX as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid object name: {}", name),
+            )
+            .into());
+        }
+
+        // Names are only kept in the encrypted index
+        let framed = self.metadata_key.is_none();
+
+        if framed {
+            self.io.write_all(OBJECT_MAGIC)?;
+            self.io.write_all(&(name.len() as u16).to_le_bytes())?;
+            self.io.write_all(name.as_bytes())?;
+            self.position += (OBJECT_MAGIC.len() + 2 + name.len()) as u64;
+        }
+
+        let offset = self.position;
+
+        let mut writer = ObjectWriter {
+            io: &mut self.io,
+            hasher: crc32fast::Hasher::new(),
+            length: 0,
+        };
+
+        let result = write(&mut writer);
+
+        let length = writer.length;
+        let crc32 = writer.hasher.finalize();
+
+        self.position += length;
+        result?;
+
+        if framed {
+            self.io.write_all(&length.to_le_bytes())?;
+            self.io.write_all(&crc32.to_le_bytes())?;
+            self.position += DESCRIPTOR_SIZE as u64;
+        }
+
+        self.mark_replaced(name);
+
+        self.index.objects.push(ObjectRecord {
+            name: name.to_string(),
+            offset,
+            length,
+            crc32,
+            replaced: false,
+        });
+
+        Ok(length)
+    }
+
+    pub fn mark_replaced(&mut self, name: &str) {
+        self.index
+            .objects
+            .iter_mut()
+            .filter(|o| o.name == name)
+            .for_each(|o| o.replaced = true);
+    }
+
+    pub fn add_tombstone(&mut self, path: String) {
+        self.index.tombstones.insert(path);
+    }
+
+    pub fn set_metadata(&mut self, path: String, mut metadata: EntryMetadata) {
+        if !self.keep_sizes {
+            metadata.size = None;
+        }
+
+        self.index.entries.insert(path, metadata);
+    }
+
+    pub fn remove_metadata(&mut self, path: &str) {
+        self.index.entries.remove(path);
+    }
+
+    /// Writes the index and trailer. For split archives they are moved
+    /// to the start of a new volume if they wouldn't fit in the current
+    /// one, unless they don't fit in a volume at all.
+    pub fn finish(mut self) -> Result<W, Error> {
+        let index = match &self.metadata_key {
+            Some(key) => key.encrypt(&serde_json::to_vec(&self.index)?)?,
+            None => serde_json::to_vec(&self.index)?,
+        };
+
+        if let Some(size) = self.volume_size {
+            let used = self.position % size;
+            let needed = (index.len() + TRAILER_SIZE) as u64;
+
+            if used > 0 && used + needed > size && needed <= size {
+                let padding = size - used;
+
+                copy(&mut std::io::repeat(0).take(padding), &mut self.io)?;
+                self.position += padding;
+            }
+        }
+
+        self.io.write_all(&index)?;
+        self.io.write_all(&self.position.to_le_bytes())?;
+        self.io.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.io.write_all(&crc32fast::hash(&index).to_le_bytes())?;
+        self.io.write_all(TRAILER_MAGIC)?;
+
+        let ParityWriter { mut inner, encoder } = self.io;
+
+        if let Some(encoder) = encoder {
+            encoder.finish(&mut inner)?;
+        }
+
+        inner.flush()?;
+
+        Ok(inner)
+    }
+}
+
+/// Feeds everything written to the parity encoder, if there is one.
+pub(crate) struct ParityWriter<W> {
+    inner: W,
+    encoder: Option<ParityEncoder>,
+}
+
+impl<W> Write for ParityWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.write_all(&buf[..len])?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Data of the object being added, checksummed as it is written.
+pub(crate) struct ObjectWriter<'a, W> {
+    io: &'a mut ParityWriter<W>,
+    hasher: crc32fast::Hasher,
+    length: u64,
+}
+
+impl<W> Write for ObjectWriter<'_, W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.io.write(buf)?;
+
+        self.hasher.update(&buf[..len]);
+        self.length += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.io.flush()
+    }
+}
+
+/// Sizes are left out of the metadata of padded entries, unless the
+/// index they are kept in is encrypted.
+fn keep_sizes(header: &Header, metadata_key: &Option<MetadataKey>) -> bool {
+    metadata_key.is_some() || !header.stages.iter().any(StageDescriptor::is_padded)
+}
+
+/// The header of the archive in `volumes` as it is stored, with the
+/// room left after its metadata.
+pub(crate) fn raw_header(volumes: &Volumes) -> Result<Vec<u8>, ArchiveError> {
+    let mut io = volumes.reader();
+    read_header(&mut io)?;
+
+    let len = io.stream_position()?;
+    let mut buf = Vec::with_capacity(len as usize);
+
+    io.seek(SeekFrom::Start(0))?;
+    io.take(len).read_to_end(&mut buf)?;
+
+    Ok(buf)
+}
+
+/// Replaces the header of the archive in `volumes` with `header`, in
+/// the room the current one takes. The recovery record is kept up to
+/// date, see `recovery::write_at`.
+pub(crate) fn rewrite_header(volumes: &Volumes, header: &Header) -> Result<(), ArchiveError> {
+    let mut buf = [0u8; HEADER_SIZE];
+    volumes.reader().read_exact(&mut buf)?;
+
+    let room = u32::from_le_bytes(buf[10..14].try_into().unwrap()) as usize;
+    let mut metadata = serde_json::to_vec(header)?;
+
+    if metadata.len() > room {
+        return Err(ArchiveError::HeaderFull(format!("{} bytes needed, {} available", metadata.len(), room)));
+    }
+
+    metadata.resize(room, b' ');
+
+    recovery::write_at(volumes, HEADER_SIZE as u64, &metadata)
+}
+
+pub(crate) fn read_header<R>(io: &mut R) -> Result<Header, ArchiveError>
+where
+    R: Read,
+{
+    let mut buf = [0u8; HEADER_SIZE];
+
+    match io.read_exact(&mut buf) {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(ArchiveError::NotAnArchive),
+        Err(e) => return Err(e.into()),
+    }
+
+    if &buf[0..8] != MAGIC {
+        return Err(ArchiveError::NotAnArchive);
+    }
+
+    let version = u16::from_le_bytes(buf[8..10].try_into().unwrap());
+
+    if version != VERSION {
+        return Err(ArchiveError::UnsupportedVersion(version));
+    }
+
+    let len = u32::from_le_bytes(buf[10..14].try_into().unwrap()) as u64;
+
+    let mut metadata = vec![];
+    io.take(len).read_to_end(&mut metadata)?;
+
+    if metadata.len() as u64 != len {
+        return Err(ArchiveError::Corrupt("Truncated header".into()));
+    }
+
+    Ok(serde_json::from_slice(&metadata)?)
+}
+
+/// True when `io` holds a whole archive, from its header up to its
+/// trailer, rather than a volume of a split one.
+pub(crate) fn is_whole_archive<R>(io: &mut R) -> Result<bool, Error>
+where
+    R: Read + Seek,
+{
+    let len = recovery::archive_len(io)?;
+
+    if len < (HEADER_SIZE + TRAILER_SIZE) as u64 {
+        return Ok(false);
+    }
+
+    let mut magic = [0u8; 8];
+
+    io.seek(SeekFrom::Start(0))?;
+    io.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Ok(false);
+    }
+
+    io.seek(SeekFrom::Start(len - magic.len() as u64))?;
+    io.read_exact(&mut magic)?;
+
+    Ok(&magic == TRAILER_MAGIC)
+}
+
+/// True when the first `len` bytes of `io` end with an index trailer.
+pub(crate) fn ends_with_trailer<R>(io: &mut R, len: u64) -> Result<bool, Error>
+where
+    R: Read + Seek,
+{
+    Ok(read_trailer(io, len)?.is_some())
+}
+
+/// Reads the index, decrypting it with `metadata_key` for archives with
+/// encrypted metadata. Returns it with where the archive ends.
+///
+/// An append that was cut short leaves part of its objects after the
+/// trailer it was going to replace, so when the archive doesn't end in
+/// a trailer the last one whose index is intact is used.
+pub(crate) fn read_index<R>(io: &mut R, metadata_key: Option<&MetadataKey>) -> Result<(Index, u64), ArchiveError>
+where
+    R: Read + Seek,
+{
+    let len = recovery::archive_len(io)?;
+
+    let (len, mut index) = match read_trailer(io, len)? {
+        Some(trailer) => (len, read_raw_index(io, len, &trailer)?),
+        None => match find_last_index(io, len)? {
+            Some(found) => found,
+            None => return Err(ArchiveError::Corrupt("Missing index".into())),
+        },
+    };
+
+    let index_offset = len - (TRAILER_SIZE + index.len()) as u64;
+
+    if let Some(key) = metadata_key {
+        index = key.decrypt(&index).map_err(|e| ArchiveError::Corrupt(e.to_string()))?;
+    }
+
+    let index: Index = serde_json::from_slice(&index)?;
+    let mut names = HashSet::new();
+
+    for object in index.objects.iter() {
+        // Names end up as paths when unpacking, anything but a plain
+        // relative path would let a crafted archive write outside of
+        // the output folder.
+        if !is_valid_name(&object.name) {
+            return Err(ArchiveError::Corrupt(format!("Invalid object name: {}", object.name)));
+        }
+
+        match object.offset.checked_add(object.length) {
+            Some(end) if end <= index_offset => (),
+            _ => return Err(ArchiveError::Corrupt(format!("Object out of bounds: {}", object.name))),
+        }
+
+        if !object.replaced && !names.insert(&object.name) {
+            return Err(ArchiveError::Corrupt(format!("Duplicate object: {}", object.name)));
+        }
+    }
+
+    Ok((index, len))
+}
+
+struct Trailer {
+    index_offset: u64,
+    index_len: u64,
+    index_crc32: u32,
+}
+
+/// Reads the trailer ending at `end`, if there is one.
+fn read_trailer<R>(io: &mut R, end: u64) -> Result<Option<Trailer>, Error>
+where
+    R: Read + Seek,
+{
+    if end < TRAILER_SIZE as u64 {
+        return Ok(None);
+    }
+
+    let mut buf = [0u8; TRAILER_SIZE];
+    io.seek(SeekFrom::Start(end - TRAILER_SIZE as u64))?;
+    io.read_exact(&mut buf)?;
+
+    if &buf[20..28] != TRAILER_MAGIC {
+        return Ok(None);
+    }
+
+    Ok(Some(Trailer {
+        index_offset: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+        index_len: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        index_crc32: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+    }))
+}
+
+/// Reads the index `trailer` points to, as it is stored.
+fn read_raw_index<R>(io: &mut R, end: u64, trailer: &Trailer) -> Result<Vec<u8>, ArchiveError>
+where
+    R: Read + Seek,
+{
+    if trailer.index_offset.checked_add(trailer.index_len) != Some(end - TRAILER_SIZE as u64) {
+        return Err(ArchiveError::Corrupt("Index out of bounds".into()));
+    }
+
+    let mut index = Vec::with_capacity(trailer.index_len as usize);
+    io.seek(SeekFrom::Start(trailer.index_offset))?;
+    io.take(trailer.index_len).read_to_end(&mut index)?;
+
+    if crc32fast::hash(&index) != trailer.index_crc32 {
+        return Err(ArchiveError::Corrupt("Index failed its checksum".into()));
+    }
+
+    Ok(index)
+}
+
+/// The last index before `len` that is intact, with where its trailer
+/// ends, looking backwards from `len`.
+fn find_last_index<R>(io: &mut R, len: u64) -> Result<Option<(u64, Vec<u8>)>, ArchiveError>
+where
+    R: Read + Seek,
+{
+    rfind_magic(io, len, TRAILER_MAGIC, |io, end| {
+        Ok(match read_trailer(io, end)? {
+            Some(trailer) => read_raw_index(io, end, &trailer).ok().map(|index| (end, index)),
+            None => None,
+        })
+    })
+}
+
+/// Calls `found` with where each `magic` in the first `len` bytes of
+/// `io` ends, the last one first, until it returns something.
+pub(crate) fn rfind_magic<R, T, E, F>(io: &mut R, len: u64, magic: &[u8], mut found: F) -> Result<Option<T>, E>
+where
+    R: Read + Seek,
+    E: From<Error>,
+    F: FnMut(&mut R, u64) -> Result<Option<T>, E>,
+{
+    let mut buf = vec![0u8; 1 << 20];
+    // Where the bytes already searched start, a magic cut between two
+    // reads is found by reading a little past it
+    let mut searched = len;
+
+    while searched > 0 {
+        let start = searched.saturating_sub(buf.len() as u64);
+        let end = (searched + magic.len() as u64 - 1).min(len);
+        let window = &mut buf[..(end - start) as usize];
+
+        io.seek(SeekFrom::Start(start))?;
+        io.read_exact(window)?;
+
+        let ends: Vec<u64> = window
+            .windows(magic.len())
+            .enumerate()
+            .rev()
+            .filter(|(_, w)| *w == magic)
+            .map(|(i, _)| start + (i + magic.len()) as u64)
+            .collect();
+
+        for end in ends {
+            if let Some(value) = found(io, end)? {
+                return Ok(Some(value));
+            }
+        }
+
+        searched = start;
+    }
+
+    Ok(None)
+}
+
+/// Objects found by `scan_objects`.
+pub(crate) struct Scan {
+    /// In the order they were written.
+    pub objects: Vec<ObjectRecord>,
+    /// Names of the objects that couldn't be read, and why.
+    pub damaged: Vec<(String, String)>,
+}
+
+/// Finds the objects of the first `len` bytes of `io` without the
+/// index, for archives that were cut short or damaged. Objects are
+/// found by their header and only kept if the descriptor after their
+/// data matches.
+pub(crate) fn scan_objects<R>(io: &mut R, len: u64) -> Result<Scan, Error>
+where
+    R: Read + Seek,
+{
+    // Where objects start, and where an object can end, which is
+    // before the next one or an index
+    let mut starts = vec![];
+    let mut ends = BTreeSet::from([len]);
+
+    let mut buf = vec![0u8; 1 << 20];
+    let mut window = vec![];
+    let mut offset = 0;
+
+    io.seek(SeekFrom::Start(0))?;
+    let mut reader = Read::by_ref(io).take(len);
+
+    loop {
+        let read = reader.read(&mut buf)?;
+        window.extend_from_slice(&buf[..read]);
+
+        // Keep enough to match a pattern cut between two reads
+        let end = match read {
+            0 => window.len(),
+            _ => window.len().saturating_sub(INDEX_START.len() - 1),
+        };
+
+        for i in 0..end {
+            let position = offset + i as u64;
+
+            if window[i..].starts_with(OBJECT_MAGIC) {
+                starts.push(position);
+                ends.insert(position);
+            } else if window[i..].starts_with(INDEX_START) {
+                ends.insert(position);
+            }
+        }
+
+        if read == 0 {
+            break;
+        }
+
+        window.drain(..end);
+        offset += end as u64;
+    }
+
+    let mut objects = vec![];
+    let mut damaged = vec![];
+    let mut covered = 0;
+
+    for start in starts {
+        if start < covered {
+            continue;
+        }
+
+        let mut buf = [0u8; 6];
+        io.seek(SeekFrom::Start(start))?;
+
+        if io.read_exact(&mut buf).is_err() {
+            continue;
+        }
+
+        let mut name = vec![0u8; u16::from_le_bytes(buf[4..6].try_into().unwrap()) as usize];
+
+        if io.read_exact(&mut name).is_err() {
+            continue;
+        }
+
+        // Anything else is a magic that happened to be in some data
+        let name = match String::from_utf8(name) {
+            Ok(name) if is_valid_name(&name) => name,
+            _ => continue,
+        };
+
+        let offset = start + buf.len() as u64 + name.len() as u64;
+        let mut found = None;
+
+        for end in ends.range(offset + DESCRIPTOR_SIZE as u64..) {
+            let mut descriptor = [0u8; DESCRIPTOR_SIZE];
+            io.seek(SeekFrom::Start(end - DESCRIPTOR_SIZE as u64))?;
+            io.read_exact(&mut descriptor)?;
+
+            let length = u64::from_le_bytes(descriptor[0..8].try_into().unwrap());
+            let crc32 = u32::from_le_bytes(descriptor[8..12].try_into().unwrap());
+
+            if offset + length + DESCRIPTOR_SIZE as u64 == *end {
+                found = Some((length, crc32));
+                break;
+            }
+        }
+
+        let (length, crc32) = match found {
+            Some(found) => found,
+            None => {
+                damaged.push((name, "cut short or damaged".to_string()));
+                continue;
+            }
+        };
+
+        let mut hasher = crc32fast::Hasher::new();
+        io.seek(SeekFrom::Start(offset))?;
+        copy(&mut Read::by_ref(io).take(length), &mut HashWriter(&mut hasher))?;
+
+        covered = offset + length + DESCRIPTOR_SIZE as u64;
+
+        if hasher.finalize() != crc32 {
+            damaged.push((name, "failed its checksum".to_string()));
+            continue;
+        }
+
+        objects.push(ObjectRecord {
+            name,
+            offset,
+            length,
+            crc32,
+            replaced: false,
+        });
+    }
+
+    Ok(Scan { objects, damaged })
+}
+
+struct HashWriter<'a>(&'a mut crc32fast::Hasher);
+
+impl Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.contains('\\') && internal::is_relative_path(Path::new(name))
+}
+
+/// Name of the object or entry stored at `path`, relative to the
+/// archived folder.
+pub(crate) fn name_of(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Read access to a single object of the archive.
+pub(crate) struct Section<R> {
+    io: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R> Section<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(mut io: R, start: u64, len: u64) -> Result<Self, Error> {
+        io.seek(SeekFrom::Start(start))?;
+
+        Ok(Section {
+            io,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl<R> Read for Section<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = buf.len().min(self.len.saturating_sub(self.pos) as usize);
+        let len = self.io.read(&mut buf[..max])?;
+
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<R> Seek for Section<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        let pos = match pos {
+            Some(pos) => pos,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Seek before start of object")),
+        };
+
+        self.io.seek(SeekFrom::Start(self.start + pos))?;
+        self.pos = pos;
+
+        Ok(pos)
+    }
+}
@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use crate::{decompress_directory, error::DecompressionError, options::ArchiveOptions, report::DecompressionReport};
+
+/*
+    Archives written before the container format are zapf packs of the
+    folder `compress_directory` wrote. Nothing in them records the
+    stages their files went through, so those have to be given again.
+
+    They are read by unpacking them into a temporary folder, which is
+    then decompressed like any other such folder.
+*/
+
+/// Extracts the zapf archive at `path` below `output`. `options` have
+/// to pick the encryption and compression the archive was made with.
+pub fn extract<P, Q>(path: P, output: Q, options: ArchiveOptions) -> Result<DecompressionReport, DecompressionError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    // Removed again however extracting ends
+    let folder = TempDir::new()?;
+
+    zapf::unpack_files(path.as_ref(), folder.path())?;
+
+    decompress_directory(
+        &folder.path().to_string_lossy(),
+        &output.as_ref().to_string_lossy(),
+        options,
+    )
+}
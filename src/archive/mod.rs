@@ -0,0 +1,1722 @@
+mod format;
+pub mod legacy;
+pub mod recovery;
+pub mod rekey;
+pub mod salvage;
+#[cfg(test)]
+mod test_util;
+pub mod volume;
+pub mod writer;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs::{self, File},
+    io::{self, copy, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Take, Write},
+    path::{Path, PathBuf},
+    slice,
+    sync::{Arc, OnceLock},
+    time::SystemTime,
+};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::{
+    dedup::{self, CHUNK_DIR, MANIFEST_EXT, REFERENCE_EXT},
+    encryption::{
+        keys::{self, MetadataKey},
+        EncryptionSecret,
+    },
+    error::{ArchiveError, PipelineDecompressionError},
+    internal::executor::Executor,
+    options::ArchiveOptions,
+    pipeline::{
+        self,
+        seekable::{SeekableReader, SEEKABLE_EXT},
+        stage::{BoxedReadStage, StageDescriptor},
+        ProcessingPipeline,
+    },
+    report::{CompressionReport, EntryReport, ReportCollector, SkipReason},
+    solid::{self, SolidEntry, SOLID_DIR},
+};
+
+use self::{
+    format::{ContainerWriter, Header, Index, ObjectRecord, Section},
+    volume::{VolumeReader, Volumes},
+};
+
+/// Metadata of an archived file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntryMetadata {
+    /// Size of the file before it was archived.
+    pub size: Option<u64>,
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+    /// Hex encoded SHA-256 of the content, or its HMAC-SHA256 under a
+    /// key derived from the file key when the entry is encrypted.
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+impl EntryMetadata {
+    pub fn from_file(metadata: &fs::Metadata) -> EntryMetadata {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode())
+        };
+
+        #[cfg(not(unix))]
+        let mode = None;
+
+        EntryMetadata {
+            size: Some(metadata.len()),
+            modified: metadata.modified().ok(),
+            mode,
+            hash: None,
+        }
+    }
+}
+
+/// A zap archive opened for reading.
+///
+/// Entries are decoded on demand, so single files can be listed and
+/// extracted without touching the rest of the archive.
+pub struct Archive {
+    path: PathBuf,
+    volumes: Volumes,
+    /// Length up to the end of the index, without the recovery record
+    /// or what an append that was cut short left after it.
+    len: u64,
+    header: Header,
+    index: Index,
+    objects: HashMap<String, usize>,
+    /// Secret the entries are encrypted with.
+    secret: EncryptionSecret,
+    /// Key the header and index are encrypted with, if they are.
+    metadata_key: Option<MetadataKey>,
+    pipeline: ProcessingPipeline,
+    entries: OnceLock<Vec<EntryRecord>>,
+}
+
+struct EntryRecord {
+    path: PathBuf,
+    source: EntrySource,
+    metadata: EntryMetadata,
+}
+
+enum EntrySource {
+    /// An object holding the encoded file, as a plain stream or with
+    /// the seekable framing.
+    Stream(String),
+    /// An object holding a reference to another stream.
+    Reference(String),
+    /// An object holding the chunk list of a deduplicated file.
+    Chunked(String),
+    Solid(SolidEntry),
+}
+
+impl Archive {
+    /// Opens the archive at `path`, for archives that aren't encrypted.
+    /// Archives whose entries are encrypted can still be listed, unless
+    /// their metadata is encrypted too.
+    pub fn open<P>(path: P) -> Result<Archive, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        Archive::open_with_options(path, ArchiveOptions::default())
+    }
+
+    /// Opens the archive at `path`. Only the encryption secret and
+    /// stage registry are taken from `options`, the stages themselves
+    /// are read from the archive.
+    ///
+    /// If a rekey of the archive was cut short, its header is restored
+    /// first, see `Rekey::save`.
+    ///
+    /// Split archives are opened from any of their volumes, or from
+    /// their name without the volume number.
+    pub fn open_with_options<P>(path: P, mut options: ArchiveOptions) -> Result<Archive, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let volumes = Volumes::open(&path)?;
+        rekey::recover(&volumes)?;
+
+        let mut io = volumes.reader();
+
+        let (header, secret, metadata_key) = open_header(&mut io, &path, options.encryption_secret)?;
+        options.encryption_secret = secret;
+
+        if let Some(size) = header.volume_size {
+            volumes.check_size(size)?;
+        }
+
+        let (index, len) = match format::read_index(&mut io, metadata_key.as_ref()) {
+            Err(ArchiveError::Corrupt(e)) => match header.volume_size.and_then(|size| volumes.next_missing(size)) {
+                Some(next) => return Err(ArchiveError::MissingVolume(next.display().to_string())),
+                None => return Err(ArchiveError::Corrupt(e)),
+            },
+            index => index?,
+        };
+
+        drop(io);
+
+        Ok(Archive::new(path, volumes, len, header, index, metadata_key, options))
+    }
+
+    /// True when the archive at `path` has to be opened with an
+    /// encryption secret to read its entries, or to be opened at all
+    /// when its metadata is encrypted. Only the header is read, so
+    /// volumes of a split archive can be missing.
+    pub fn needs_secret<P>(path: P) -> Result<bool, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let volumes = Volumes::open_present(path.as_ref())?;
+        rekey::recover(&volumes)?;
+
+        Ok(format::read_header(&mut volumes.reader())?.is_encrypted())
+    }
+
+    /// `options` have to hold the secret the entries are encrypted with,
+    /// as `open_header` returns it.
+    fn new(
+        path: PathBuf,
+        volumes: Volumes,
+        len: u64,
+        header: Header,
+        index: Index,
+        metadata_key: Option<MetadataKey>,
+        options: ArchiveOptions,
+    ) -> Archive {
+        let objects = index
+            .objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| !object.replaced)
+            .map(|(i, object)| (object.name.clone(), i))
+            .collect();
+
+        Archive {
+            path,
+            volumes,
+            len,
+            secret: options.encryption_secret.clone(),
+            metadata_key,
+            pipeline: reader_pipeline(&header, options),
+            header,
+            index,
+            objects,
+            entries: OnceLock::new(),
+        }
+    }
+
+    /// Stages the entries went through, in the order data goes through
+    /// them when encoding.
+    pub fn stages(&self) -> &[StageDescriptor] {
+        &self.header.stages
+    }
+
+    /// Identifies the archive, for incremental archives made since it.
+    /// Archives written before ids were added have none.
+    pub fn id(&self) -> Option<&str> {
+        self.header.id.as_deref()
+    }
+
+    /// Id of the archive this one holds the changes since, `None` for
+    /// full archives.
+    pub fn since(&self) -> Option<&str> {
+        self.header.since.as_deref()
+    }
+
+    /// True when the archive is stored as numbered volumes.
+    pub fn is_split(&self) -> bool {
+        self.volumes.is_split()
+    }
+
+    /// Parity size of the recovery record, in percent of the archive
+    /// size, or `None` if it has none.
+    pub fn parity(&self) -> Result<Option<u8>, ArchiveError> {
+        recovery::parity(&mut self.volumes.reader())
+    }
+
+    /// True when entries need an encryption secret to be read.
+    pub fn is_encrypted(&self) -> bool {
+        self.header.is_encrypted()
+    }
+
+    /// Key the content hashes in the metadata of encrypted entries are
+    /// made with. `None` when the entries aren't encrypted, or when the
+    /// archive was opened without their secret.
+    pub(crate) fn content_key(&self) -> Option<[u8; 32]> {
+        match self.is_encrypted() {
+            true => keys::content_key(&self.secret),
+            false => None,
+        }
+    }
+
+    /// True when the header and index are encrypted as well.
+    pub fn has_encrypted_metadata(&self) -> bool {
+        self.metadata_key.is_some()
+    }
+
+    /// True when some files are packed in solid blocks, so listing them
+    /// decodes the solid index.
+    pub fn has_solid_blocks(&self) -> bool {
+        self.objects.contains_key(&format::name_of(&solid::index_name()))
+    }
+
+    /// The secret `decompress_directory` needs for the entries of this
+    /// archive, the key its key slots opened with the secret it was
+    /// opened with.
+    pub fn entry_secret(&self) -> &EncryptionSecret {
+        &self.secret
+    }
+
+    /// Every file in the archive, sorted by path.
+    ///
+    /// Listing files packed in solid blocks decodes the solid index,
+    /// which needs the encryption secret for encrypted archives.
+    pub fn entries(&self) -> Result<Entries<'_>, ArchiveError> {
+        Ok(Entries {
+            archive: self,
+            inner: self.entry_records()?.iter(),
+        })
+    }
+
+    /// The file stored as `path`, relative to the archived folder.
+    pub fn entry<P>(&self, path: P) -> Result<ArchiveEntry<'_>, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        self.entry_records()?
+            .iter()
+            .find(|record| record.path == path)
+            .map(|record| ArchiveEntry {
+                archive: self,
+                record,
+            })
+            .ok_or_else(|| ArchiveError::EntryNotFound(path.display().to_string()))
+    }
+
+    /// Writes every stored object below `folder` as it is, still
+    /// encoded, which is the layout `decompress_directory` reads.
+    pub fn unpack_to<P>(&self, folder: P) -> Result<(), ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        for object in self.index.live_objects() {
+            let output_path = folder.as_ref().join(&object.name);
+
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut reader = CrcReader::new(self.object_section(object)?);
+            copy(&mut reader, &mut File::create(&output_path)?)?;
+
+            if reader.crc32() != object.crc32 {
+                return Err(ArchiveError::Corrupt(format!("{} failed its checksum", object.name)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes every file below `output`, each through its own entry
+    /// reader. Only the parallelism, error policy, progress and
+    /// cancellation are taken from `options`. Files that fail partway
+    /// are removed again.
+    pub fn extract_to<P>(&self, output: P, options: ArchiveOptions) -> Result<EntryReport<ArchiveError>, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let output = output.as_ref();
+        let entries: Vec<_> = self.entries()?.collect();
+        let collector = ReportCollector::new(options.error_policy);
+        let progress = options.progress.as_deref();
+        let cancellation = options.cancellation.as_ref();
+        let is_cancelled = || cancellation.is_some_and(|c| c.is_cancelled());
+
+        let extract = |entry: &ArchiveEntry<'_>| {
+            let path = entry.path().to_path_buf();
+
+            if is_cancelled() {
+                return;
+            }
+
+            if collector.is_aborted() {
+                collector.skipped(vec![path], SkipReason::Aborted);
+                return;
+            }
+
+            // Entry paths were checked when the archive was opened, none
+            // of them can point outside of the folder.
+            let output_path = output.join(&path);
+
+            if let Some(progress) = progress {
+                progress.file_started(&path);
+            }
+
+            let result = crate::catch_panic(
+                || {
+                    if let Some(parent) = output_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+
+                    let mut reader = entry.reader()?;
+                    let mut file = File::create(&output_path)?;
+
+                    pipeline::copy_chunks(&mut reader, &mut file, progress, cancellation, ArchiveError::Cancelled)
+                },
+                |message| PipelineDecompressionError::Panicked(message).into(),
+            );
+
+            if let Some(progress) = progress {
+                progress.file_finished(&path);
+            }
+
+            match result {
+                Ok(_) => collector.succeeded(vec![path]),
+                Err(e) => {
+                    // What was written before failing hasn't been
+                    // authenticated yet
+                    let _ = fs::remove_file(&output_path);
+
+                    if !is_cancelled() {
+                        collector.failed(path, e, vec![]);
+                    }
+                }
+            }
+        };
+
+        match Executor::new(&options.parallelism).map_err(Error::other)? {
+            Executor::Pool(pool) => pool.install(|| entries.par_iter().for_each(extract)),
+            Executor::Inline => entries.iter().for_each(extract),
+        }
+
+        if is_cancelled() {
+            return Err(ArchiveError::Cancelled);
+        }
+
+        Ok(collector.take())
+    }
+
+    /// Writes a copy of the archive to `writer`, leaving out replaced
+    /// objects, old indexes, chunks no file uses anymore and solid
+    /// blocks with no files left.
+    ///
+    /// Objects are copied as they are stored, so nothing is encoded
+    /// again, other than a solid index whose blocks were renumbered.
+    /// A recovery record is computed again for the copy.
+    /// Needs the encryption secret for archives with deduplicated
+    /// files or solid blocks, to find out what is still in use.
+    pub fn compact_to<W>(&self, writer: W) -> Result<W, ArchiveError>
+    where
+        W: Write + Send,
+    {
+        let records = self.entry_records()?;
+
+        let mut chunks = HashSet::new();
+        let mut blocks = BTreeSet::new();
+
+        for record in records {
+            match &record.source {
+                EntrySource::Chunked(name) => {
+                    for (hash, _) in dedup::parse_chunk_list(self.decode_object(name)?)? {
+                        chunks.insert(format::name_of(&dedup::chunk_name(&hash)));
+                    }
+                }
+                EntrySource::Solid(entry) => {
+                    blocks.insert(entry.block);
+                }
+                _ => (),
+            }
+        }
+
+        // Blocks left are numbered from 0 again, as extracting expects
+        let renumbered: HashMap<String, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(new, old)| (format::name_of(&solid::block_name(*old)), new))
+            .collect();
+
+        let solid_index = format::name_of(&solid::index_name());
+
+        let mut container = ContainerWriter::new(writer, &self.header, self.parity()?, self.metadata_key.clone())?;
+
+        for object in self.index.live_objects() {
+            let path = Path::new(&object.name);
+
+            if object.name == solid_index {
+                if blocks.is_empty() {
+                    continue;
+                }
+
+                if blocks.iter().enumerate().all(|(new, old)| new == *old) {
+                    self.copy_object(object, &object.name, &mut container)?;
+                    continue;
+                }
+
+                let entries: Vec<SolidEntry> = records
+                    .iter()
+                    .filter_map(|record| match &record.source {
+                        EntrySource::Solid(entry) => Some(SolidEntry {
+                            block: renumbered[&format::name_of(&solid::block_name(entry.block))],
+                            ..entry.clone()
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+
+                let index = solid::format_index(&entries);
+
+                container.add_object_with(&object.name, |writer| {
+                    self.pipeline.compress_stream(&index[..], writer)
+                })?;
+            } else if path.starts_with(SOLID_DIR) {
+                if let Some(block) = renumbered.get(&object.name) {
+                    self.copy_object(object, &format::name_of(&solid::block_name(*block)), &mut container)?;
+                }
+            } else if !path.starts_with(CHUNK_DIR) || chunks.contains(&object.name) {
+                self.copy_object(object, &object.name, &mut container)?;
+            }
+        }
+
+        // Unchanged files of an incremental archive only have metadata,
+        // and deleted ones a tombstone, restoring the chain needs both
+        for (path, metadata) in &self.index.entries {
+            container.set_metadata(path.clone(), metadata.clone());
+        }
+
+        for path in &self.index.tombstones {
+            container.add_tombstone(path.clone());
+        }
+
+        Ok(container.finish()?)
+    }
+
+    fn copy_object<W>(
+        &self,
+        object: &ObjectRecord,
+        name: &str,
+        container: &mut ContainerWriter<W>,
+    ) -> Result<(), ArchiveError>
+    where
+        W: Write,
+    {
+        let mut reader = CrcReader::new(self.object_section(object)?);
+        container.add_object(name, &mut reader)?;
+
+        if reader.crc32() != object.crc32 {
+            return Err(ArchiveError::Corrupt(format!("{} failed its checksum", object.name)));
+        }
+
+        Ok(())
+    }
+
+    /// Fails unless the encryption secret decodes the archive, checked
+    /// on its smallest object.
+    pub(crate) fn check_secret(&self) -> Result<(), ArchiveError> {
+        let smallest = match self.index.live_objects().min_by_key(|o| o.length) {
+            Some(object) => object.name.clone(),
+            None => return Ok(()),
+        };
+
+        copy(&mut self.stream_reader(&smallest)?, &mut io::sink())?;
+
+        Ok(())
+    }
+
+    fn entry_records(&self) -> Result<&[EntryRecord], ArchiveError> {
+        if let Some(entries) = self.entries.get() {
+            return Ok(entries);
+        }
+
+        let entries = self.read_entries()?;
+
+        Ok(self.entries.get_or_init(|| entries))
+    }
+
+    fn read_entries(&self) -> Result<Vec<EntryRecord>, ArchiveError> {
+        let solid_index = format::name_of(&solid::index_name());
+        let mut entries = vec![];
+
+        for object in self.index.live_objects() {
+            let name = object.name.clone();
+            let path = Path::new(&name);
+
+            if path.starts_with(CHUNK_DIR) {
+                continue;
+            }
+
+            if path.starts_with(SOLID_DIR) {
+                if name == solid_index {
+                    let index = self.decode_object(&name)?;
+
+                    for entry in solid::parse_index(index)? {
+                        let mut metadata = self.metadata_of(&entry.path);
+                        metadata.size.get_or_insert(entry.length);
+
+                        entries.push(EntryRecord {
+                            path: entry.path.clone(),
+                            source: EntrySource::Solid(entry),
+                            metadata,
+                        });
+                    }
+                }
+
+                continue;
+            }
+
+            let (path, source) = match entry_of(&name) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            entries.push(EntryRecord {
+                metadata: self.metadata_of(&path),
+                path,
+                source,
+            });
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(entries)
+    }
+
+    fn metadata_of(&self, path: &Path) -> EntryMetadata {
+        self.index
+            .entries
+            .get(&format::name_of(path))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn object(&self, name: &str) -> Result<&ObjectRecord, ArchiveError> {
+        match self.objects.get(name) {
+            Some(i) => Ok(&self.index.objects[*i]),
+            None => Err(ArchiveError::Corrupt(format!("Missing object: {}", name))),
+        }
+    }
+
+    fn object_section(&self, object: &ObjectRecord) -> Result<Section<VolumeReader<'_>>, ArchiveError> {
+        Ok(Section::new(self.volumes.reader(), object.offset, object.length)?)
+    }
+
+    fn section(&self, name: &str) -> Result<Section<VolumeReader<'_>>, ArchiveError> {
+        self.object_section(self.object(name)?)
+    }
+
+    /// Section of an object read from start to end, checked against
+    /// its checksum once the end is reached.
+    fn verified_section(&self, name: &str) -> Result<CrcReader<Section<VolumeReader<'_>>>, ArchiveError> {
+        let object = self.object(name)?;
+
+        Ok(CrcReader::verifying(self.object_section(object)?, object))
+    }
+
+    /// Decodes a whole object into memory, for the small ones
+    /// describing other entries.
+    fn decode_object(&self, name: &str) -> Result<Vec<u8>, ArchiveError> {
+        let mut buf = vec![];
+        self.pipeline.decompress_stream(self.verified_section(name)?, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn stream_reader(&self, name: &str) -> Result<Box<dyn Read + Send + '_>, ArchiveError> {
+        if Path::new(name).extension().unwrap_or_default() == SEEKABLE_EXT {
+            return Ok(Box::new(SeekableReader::new(self.pipeline.clone(), self.section(name)?)?));
+        }
+
+        Ok(Box::new(StageReader::new(self.pipeline.build_reader(self.verified_section(name)?)?)))
+    }
+}
+
+/// Iterator over the entries of an archive.
+pub struct Entries<'a> {
+    archive: &'a Archive,
+    inner: slice::Iter<'a, EntryRecord>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = ArchiveEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|record| ArchiveEntry {
+            archive: self.archive,
+            record,
+        })
+    }
+}
+
+pub struct ArchiveEntry<'a> {
+    archive: &'a Archive,
+    record: &'a EntryRecord,
+}
+
+impl<'a> ArchiveEntry<'a> {
+    /// Path of the file, relative to the archived folder.
+    pub fn path(&self) -> &'a Path {
+        &self.record.path
+    }
+
+    pub fn metadata(&self) -> &'a EntryMetadata {
+        &self.record.metadata
+    }
+
+    /// Opens the decoded content of the file. Nothing is decoded ahead
+    /// of what is read.
+    pub fn reader(&self) -> Result<EntryReader<'a>, ArchiveError> {
+        let archive = self.archive;
+
+        let inner: Box<dyn Read + Send + 'a> = match &self.record.source {
+            EntrySource::Stream(name) => archive.stream_reader(name)?,
+            EntrySource::Reference(name) => {
+                let target = dedup::parse_reference(archive.decode_object(name)?)?;
+                archive.stream_reader(&format::name_of(&target))?
+            }
+            EntrySource::Chunked(name) => {
+                let chunks = dedup::parse_chunk_list(archive.decode_object(name)?)?;
+                Box::new(ChunkReader::new(archive, chunks))
+            }
+            EntrySource::Solid(entry) => {
+                let block = archive.section(&format::name_of(&solid::block_name(entry.block)))?;
+
+                let mut reader = SeekableReader::new(archive.pipeline.clone(), block)?;
+                reader.seek(SeekFrom::Start(entry.offset))?;
+
+                Box::new(ExactReader(reader.take(entry.length)))
+            }
+        };
+
+        Ok(EntryReader { inner })
+    }
+
+    /// Like `reader`, with random access. Only files stored with the
+    /// seekable framing or packed into a solid block can be opened
+    /// this way, only the blocks holding what is read are decoded.
+    pub fn seekable_reader(&self) -> Result<SeekableEntryReader<'a>, ArchiveError> {
+        let archive = self.archive;
+
+        let (name, start, len) = match &self.record.source {
+            EntrySource::Stream(name) => (name.clone(), 0, None),
+            EntrySource::Reference(name) => {
+                let target = dedup::parse_reference(archive.decode_object(name)?)?;
+                (format::name_of(&target), 0, None)
+            }
+            EntrySource::Solid(entry) => (
+                format::name_of(&solid::block_name(entry.block)),
+                entry.offset,
+                Some(entry.length),
+            ),
+            EntrySource::Chunked(_) => return Err(ArchiveError::NotSeekable(self.path().display().to_string())),
+        };
+
+        if len.is_none() && Path::new(&name).extension().unwrap_or_default() != SEEKABLE_EXT {
+            return Err(ArchiveError::NotSeekable(self.path().display().to_string()));
+        }
+
+        let mut inner = SeekableReader::new(archive.pipeline.clone(), archive.section(&name)?)?;
+        inner.seek(SeekFrom::Start(start))?;
+
+        Ok(SeekableEntryReader {
+            len: len.unwrap_or(inner.len()),
+            inner,
+            start,
+            position: 0,
+        })
+    }
+}
+
+/// Decoded content of an archived file.
+pub struct EntryReader<'a> {
+    inner: Box<dyn Read + Send + 'a>,
+}
+
+impl Read for EntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Decoded content of an archived file, with random access. See
+/// `ArchiveEntry::seekable_reader`.
+pub struct SeekableEntryReader<'a> {
+    inner: SeekableReader<Section<VolumeReader<'a>>>,
+    /// Where the file starts in `inner`, which is a whole solid block
+    /// for files packed into one.
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl SeekableEntryReader<'_> {
+    /// Length of the decoded file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for SeekableEntryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let left = self.len.saturating_sub(self.position);
+        let limit = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+
+        if limit == 0 {
+            return Ok(0);
+        }
+
+        let len = self.inner.read(&mut buf[..limit])?;
+
+        if len == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Solid block is shorter than its index"));
+        }
+
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for SeekableEntryReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        let position = match position {
+            Some(position) => position,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position")),
+        };
+
+        // Past the end, reads return nothing until seeking back
+        self.inner.seek(SeekFrom::Start(self.start + position.min(self.len)))?;
+        self.position = position;
+
+        Ok(position)
+    }
+}
+
+/// Stores the encoded files `compress_directory` wrote to `folder` as
+/// an archive in `writer`, along with the metadata `report` holds for
+/// the files it stored. Files that failed or were skipped get none, so
+/// an incremental archive made since this one stores them.
+///
+/// Only the stages, volume size and parity are taken from `options`,
+/// the stages have to be the ones `compress_directory` used.
+pub fn pack_directory<W>(
+    folder: &Path,
+    report: &CompressionReport,
+    writer: W,
+    options: ArchiveOptions,
+) -> Result<W, ArchiveError>
+where
+    W: Write,
+{
+    let mut header = Header::new(options.stages());
+    header.volume_size = options.volume_size;
+    header.key_slots = options.key_slots();
+
+    let mut container = ContainerWriter::new(writer, &header, options.parity, options.metadata_key()?)?;
+
+    for entry in WalkDir::new(folder).sort_by_file_name() {
+        let entry = entry.map_err(Error::from)?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let name = match entry.path().strip_prefix(folder) {
+            Ok(p) => format::name_of(p),
+            Err(_) => continue,
+        };
+
+        container.add_object(&name, &mut File::open(entry.path())?)?;
+    }
+
+    for path in report.entries.succeeded.iter() {
+        if let Some(metadata) = report.metadata.get(path) {
+            container.set_metadata(format::name_of(path), metadata.clone());
+        }
+    }
+
+    Ok(container.finish()?)
+}
+
+/// Restores the files of the last archive of `chain` below `output`.
+/// The chain starts with a full archive, each following one holding
+/// the changes since the one before it. Returns the number of files
+/// restored.
+pub fn restore_chain<P>(chain: &[Archive], output: P) -> Result<usize, ArchiveError>
+where
+    P: AsRef<Path>,
+{
+    for (i, archive) in chain.iter().enumerate() {
+        let expected = match i {
+            0 => None,
+            _ => chain[i - 1].id(),
+        };
+
+        if archive.since() != expected || (i > 0 && expected.is_none()) {
+            return Err(ArchiveError::BrokenChain(match i {
+                0 => format!("{} is incremental, the chain has to start with a full archive", archive.path.display()),
+                _ => format!("{} wasn't made since {}", archive.path.display(), chain[i - 1].path.display()),
+            }));
+        }
+    }
+
+    // Replay the chain, keeping the latest archive holding each file
+    let mut latest: BTreeMap<&Path, ArchiveEntry<'_>> = BTreeMap::new();
+
+    for archive in chain.iter() {
+        for path in archive.index.tombstones.iter() {
+            latest.remove(Path::new(path));
+        }
+
+        for record in archive.entry_records()? {
+            latest.insert(&record.path, ArchiveEntry { archive, record });
+        }
+    }
+
+    for (path, entry) in latest.iter() {
+        let output_path = output.as_ref().join(path);
+
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        copy(&mut entry.reader()?, &mut File::create(&output_path)?)?;
+    }
+
+    Ok(latest.len())
+}
+
+/// Path of the file the object `name` holds and how, unless it is a
+/// chunk, part of a solid block or not an entry at all.
+fn entry_of(name: &str) -> Option<(PathBuf, EntrySource)> {
+    let path = Path::new(name);
+
+    if path.starts_with(CHUNK_DIR) || path.starts_with(SOLID_DIR) {
+        return None;
+    }
+
+    let source = match path.extension().and_then(|e| e.to_str()) {
+        Some("lz4") | Some(SEEKABLE_EXT) => EntrySource::Stream(name.to_string()),
+        Some(MANIFEST_EXT) => EntrySource::Chunked(name.to_string()),
+        Some(REFERENCE_EXT) => EntrySource::Reference(name.to_string()),
+        _ => return None,
+    };
+
+    Some((path.with_extension(""), source))
+}
+
+/// Pipeline decoding the objects of an archive with `header`, whose
+/// entries are encrypted with the secret in `options`.
+fn reader_pipeline(header: &Header, options: ArchiveOptions) -> ProcessingPipeline {
+    ProcessingPipeline::new()
+        .with_encryption_secret(Arc::new(options.encryption_secret))
+        .with_registry(options.registry)
+        .with_stages(Some(Arc::new(header.stages.clone())))
+}
+
+/// Reads the header of the archive at `path` from `io`, along with the
+/// secret its entries are encrypted with and, when its metadata is
+/// encrypted, the key to decrypt it. Key slots are opened with `secret`.
+fn open_header<R>(
+    io: &mut R,
+    path: &Path,
+    secret: EncryptionSecret,
+) -> Result<(Header, EncryptionSecret, Option<MetadataKey>), ArchiveError>
+where
+    R: Read,
+{
+    let header = format::read_header(io)?;
+    let secret = entry_secret(&header, secret)?;
+
+    if header.sealed.is_none() {
+        return Ok((header, secret, None));
+    }
+
+    let metadata_key = match MetadataKey::new(&secret) {
+        Some(key) => key,
+        None => return Err(ArchiveError::Sealed(path.display().to_string())),
+    };
+
+    Ok((header.unseal(&metadata_key)?, secret, Some(metadata_key)))
+}
+
+/// The secret the entries of an archive with `header` were encrypted
+/// with, opening its key slots with a key file.
+fn entry_secret(header: &Header, secret: EncryptionSecret) -> Result<EncryptionSecret, ArchiveError> {
+    match header.is_encrypted() {
+        true => Ok(keys::file_secret(&header.key_slots, secret)?),
+        false => Ok(secret),
+    }
+}
+
+/// Hex encoded hash of everything read from `reader`, keyed with `key`
+/// as `HashingReader` does.
+pub(crate) fn content_hash<R>(reader: &mut R, key: Option<&[u8; 32]>) -> Result<String, Error>
+where
+    R: Read,
+{
+    let mut hasher = ContentHasher::new(key);
+    copy(reader, &mut hasher)?;
+
+    Ok(hasher.finish())
+}
+
+/// The SHA-256 of the content, or its HMAC-SHA256 when the entry is
+/// encrypted, so that the index doesn't tell whether a known file is
+/// stored.
+enum ContentHasher {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl ContentHasher {
+    fn new(key: Option<&[u8; 32]>) -> Self {
+        match key {
+            Some(key) => ContentHasher::Keyed(Hmac::new_from_slice(key).unwrap()),
+            None => ContentHasher::Plain(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Plain(hasher) => Digest::update(hasher, data),
+            ContentHasher::Keyed(mac) => mac.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            ContentHasher::Plain(hasher) => dedup::to_hex(&hasher.finalize()),
+            ContentHasher::Keyed(mac) => dedup::to_hex(&mac.finalize().into_bytes()),
+        }
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes and counts what is read through it, so the metadata of an
+/// entry comes from the same read that stored it. Hashes are keyed
+/// with `key`, the content key of the archive when its entries are
+/// encrypted.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    key: Option<[u8; 32]>,
+    hasher: ContentHasher,
+    count: u64,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R, key: Option<&[u8; 32]>) -> Self {
+        HashingReader {
+            inner,
+            key: key.copied(),
+            hasher: ContentHasher::new(key),
+            count: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Starts over, after `inner` was rewound to be read again.
+    pub fn restart(&mut self) {
+        self.hasher = ContentHasher::new(self.key.as_ref());
+        self.count = 0;
+    }
+
+    /// Sets the size and hash in `metadata` from what was read.
+    pub fn finish(self, metadata: &mut EntryMetadata) {
+        metadata.size = Some(self.count);
+        metadata.hash = Some(self.hasher.finish());
+    }
+}
+
+impl<R> Read for HashingReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+
+        self.hasher.update(&buf[..len]);
+        self.count += len as u64;
+
+        Ok(len)
+    }
+}
+
+/// Calls `finish` on the stages once they are read to the end, so the
+/// last checks they make aren't skipped.
+struct StageReader<'a> {
+    stage: Option<BoxedReadStage<'a>>,
+}
+
+impl<'a> StageReader<'a> {
+    fn new(stage: BoxedReadStage<'a>) -> Self {
+        StageReader { stage: Some(stage) }
+    }
+}
+
+impl Read for StageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let stage = match self.stage.as_mut() {
+            Some(stage) => stage,
+            None => return Ok(0),
+        };
+
+        let len = stage.read(buf)?;
+
+        if len == 0 && !buf.is_empty() {
+            if let Some(stage) = self.stage.take() {
+                stage.finish()?;
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+/// Reassembles a deduplicated file, decoding one chunk at a time.
+struct ChunkReader<'a> {
+    archive: &'a Archive,
+    key: Option<[u8; 32]>,
+    chunks: VecDeque<([u8; 32], usize)>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn new(archive: &'a Archive, chunks: Vec<([u8; 32], usize)>) -> Self {
+        // Chunks of encrypted entries are named with a keyed hash
+        let key = match archive.is_encrypted() {
+            true => keys::chunk_key(&archive.secret),
+            false => None,
+        };
+
+        ChunkReader {
+            archive,
+            key,
+            chunks: chunks.into(),
+            current: Cursor::new(vec![]),
+        }
+    }
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let len = self.current.read(buf)?;
+
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+
+            let (hash, len) = match self.chunks.pop_front() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+
+            let chunk = self
+                .archive
+                .decode_object(&format::name_of(&dedup::chunk_name(&hash)))
+                .map_err(Error::other)?;
+
+            dedup::verify_chunk(self.key.as_ref(), &hash, len, &chunk)?;
+
+            self.current = Cursor::new(chunk);
+        }
+    }
+}
+
+/// Fails instead of ending early when the inner reader is shorter
+/// than its limit.
+struct ExactReader<R>(Take<R>);
+
+impl<R> Read for ExactReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.0.read(buf)?;
+
+        if len == 0 && !buf.is_empty() && self.0.limit() > 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Solid block is shorter than its index"));
+        }
+
+        Ok(len)
+    }
+}
+
+struct CrcReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    expected: Option<(u32, String)>,
+}
+
+impl<R> CrcReader<R> {
+    fn new(inner: R) -> Self {
+        CrcReader {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            expected: None,
+        }
+    }
+
+    /// Fails the read reaching the end of `inner` unless everything
+    /// read matches the checksum of `object`.
+    fn verifying(inner: R, object: &ObjectRecord) -> Self {
+        CrcReader {
+            expected: Some((object.crc32, object.name.clone())),
+            ..CrcReader::new(inner)
+        }
+    }
+
+    fn crc32(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+impl<R> Read for CrcReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+
+        if len == 0 && !buf.is_empty() {
+            if let Some((crc32, name)) = self.expected.take() {
+                if self.crc32() != crc32 {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("{} failed its checksum", name)));
+                }
+            }
+        }
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use super::test_util::{create, data, read};
+    use crate::{
+        archive::writer::ArchiveWriter,
+        cancel::CancellationToken,
+        encryption::{keys::FileKey, padding::Padding, EncryptionType},
+    };
+
+    fn paths(archive: &Archive) -> Vec<PathBuf> {
+        archive.entries().unwrap().map(|e| e.path().to_path_buf()).collect()
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &[u8])]) {
+        for (name, content) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap().write_all(content).unwrap();
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &data(100_000, 1), EntryMetadata::default()).unwrap();
+            w.add_bytes("dir/b", b"bee", EntryMetadata::default()).unwrap();
+            w.add_reader("empty", &b""[..]).unwrap();
+        });
+
+        let archive = Archive::open(&path).unwrap();
+
+        assert_eq!(paths(&archive), [Path::new("a"), Path::new("dir/b"), Path::new("empty")]);
+        assert_eq!(read(&archive, "a").unwrap(), data(100_000, 1));
+        assert_eq!(read(&archive, "dir/b").unwrap(), b"bee");
+        assert_eq!(read(&archive, "empty").unwrap(), b"");
+        assert_eq!(archive.entry("a").unwrap().metadata().size, Some(100_000));
+        assert!(matches!(archive.entry("missing"), Err(ArchiveError::EntryNotFound(_))));
+    }
+
+    #[test]
+    fn extract_to_folder() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+        let output = dir.path().join("output");
+
+        create(&path, ArchiveOptions::new().with_block_size(Some(16 * 1024)), |w| {
+            w.add_bytes("a", &data(100_000, 1), EntryMetadata::default()).unwrap();
+            w.add_bytes("dir/b", b"bee", EntryMetadata::default()).unwrap();
+        });
+
+        let archive = Archive::open(&path).unwrap();
+        let report = archive.extract_to(&output, ArchiveOptions::new()).unwrap();
+
+        assert_eq!(report.succeeded.len(), 2);
+        assert!(report.failed.is_empty());
+        assert_eq!(fs::read(output.join("a")).unwrap(), data(100_000, 1));
+        assert_eq!(fs::read(output.join("dir/b")).unwrap(), b"bee");
+    }
+
+    #[test]
+    fn seek_within_entries() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+        let content = data(100_000, 1);
+
+        create(&path, ArchiveOptions::new().with_block_size(Some(16 * 1024)), |w| {
+            w.add_bytes("a", &content, EntryMetadata::default()).unwrap();
+        });
+
+        let archive = Archive::open(&path).unwrap();
+        let mut reader = archive.entry("a").unwrap().seekable_reader().unwrap();
+        assert_eq!(reader.len(), 100_000);
+
+        let mut range = vec![0; 1000];
+        reader.seek(SeekFrom::Start(50_000)).unwrap();
+        reader.read_exact(&mut range).unwrap();
+        assert_eq!(range, content[50_000..51_000]);
+
+        let mut tail = vec![];
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, content[99_990..]);
+
+        assert!(reader.seek(SeekFrom::Current(-200_000)).is_err());
+
+        // Entries without the seekable framing can't be opened this way
+        let plain = dir.path().join("plain.zap");
+        create(&plain, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &content, EntryMetadata::default()).unwrap();
+        });
+
+        let archive = Archive::open(&plain).unwrap();
+        assert!(matches!(
+            archive.entry("a").unwrap().seekable_reader(),
+            Err(ArchiveError::NotSeekable(_))
+        ));
+    }
+
+    #[test]
+    fn seek_within_solid_entries() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source");
+        let folder = dir.path().join("folder");
+        let path = dir.path().join("a.zap");
+
+        write_files(&source, &[("a", &data(3000, 1)), ("b", &data(5000, 2)), ("c", &data(4000, 3))]);
+
+        let options = ArchiveOptions::new().with_solid_block_size(Some(64 * 1024));
+        let report = crate::compress_directory(source.to_str().unwrap(), folder.to_str().unwrap(), options).unwrap();
+        pack_directory(&folder, &report, File::create(&path).unwrap(), ArchiveOptions::new()).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert!(archive.has_solid_blocks());
+
+        let mut reader = archive.entry("b").unwrap().seekable_reader().unwrap();
+        assert_eq!(reader.len(), 5000);
+
+        let mut range = vec![0; 1000];
+        reader.seek(SeekFrom::Start(2500)).unwrap();
+        reader.read_exact(&mut range).unwrap();
+        assert_eq!(range, data(5000, 2)[2500..3500]);
+
+        // Reads stop at the end of the file, not of the block
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data(5000, 2)[3500..]);
+    }
+
+    #[test]
+    fn corrupted_object_fails_to_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &data(50_000, 1), EntryMetadata::default()).unwrap();
+        });
+
+        let mut bytes = fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert!(read(&archive, "a").is_err());
+    }
+
+    #[test]
+    fn extract_to_removes_failed_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+        let output = dir.path().join("output");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &data(50_000, 1), EntryMetadata::default()).unwrap();
+        });
+
+        // Only found once the whole entry was read
+        let mut bytes = fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        let report = archive.extract_to(&output, ArchiveOptions::new()).unwrap();
+
+        assert_eq!(report.failed.len(), 1);
+        assert!(!output.join("a").exists());
+    }
+
+    #[test]
+    fn cancelled_extract_to() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+        let output = dir.path().join("output");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &data(50_000, 1), EntryMetadata::default()).unwrap();
+        });
+
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let archive = Archive::open(&path).unwrap();
+        let result = archive.extract_to(&output, ArchiveOptions::new().with_cancellation(cancellation));
+
+        assert!(matches!(result, Err(ArchiveError::Cancelled)));
+        assert!(!output.join("a").exists());
+    }
+
+    #[test]
+    fn not_an_archive() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+        fs::write(&path, b"not an archive at all").unwrap();
+
+        assert!(matches!(Archive::open(&path), Err(ArchiveError::NotAnArchive)));
+    }
+
+    #[test]
+    fn sealed_archive_hides_names_and_lengths() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+        let content = data(70_001, 1);
+
+        let mut file_key = FileKey::generate();
+        file_key.add_password(b"password").unwrap();
+
+        let options = || {
+            ArchiveOptions::new()
+                .with_encryption(EncryptionType::XChaCha)
+                .with_file_key(file_key.clone())
+                .with_metadata_encryption(true)
+                .with_padding(Padding::Padme)
+                .with_block_size(Some(16 * 1024))
+        };
+
+        create(&path, options(), |w| {
+            w.add_bytes("secret-name", &content, EntryMetadata::default()).unwrap();
+        });
+
+        let raw = fs::read(&path).unwrap();
+        let contains = |needle: &[u8]| raw.windows(needle.len()).any(|w| w == needle);
+
+        assert!(!contains(b"secret-name"));
+        assert!(!contains(SEEKABLE_EXT.as_bytes()));
+        assert!(!contains(&(content.len() as u64).to_le_bytes()));
+        assert!(!contains(&(16 * 1024u32).to_le_bytes()));
+
+        assert!(matches!(Archive::open(&path), Err(ArchiveError::Sealed(_))));
+
+        let options = ArchiveOptions::new().with_encryption_secret(EncryptionSecret::Password(b"password".to_vec()));
+        let archive = Archive::open_with_options(&path, options).unwrap();
+        assert_eq!(read(&archive, "secret-name").unwrap(), content);
+    }
+
+    #[test]
+    fn encrypted_hashes_are_keyed() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source");
+
+        write_files(&source, &[("a", b"one"), ("b", b"two")]);
+
+        let options = || {
+            let mut file_key = FileKey::generate();
+            file_key.add_password(b"password").unwrap();
+
+            ArchiveOptions::new()
+                .with_encryption(EncryptionType::XChaCha)
+                .with_file_key(file_key)
+        };
+        let open = |path: &Path| {
+            let options = ArchiveOptions::new().with_encryption_secret(EncryptionSecret::Password(b"password".to_vec()));
+            Archive::open_with_options(path, options).unwrap()
+        };
+
+        let full = dir.path().join("full.zap");
+        create(&full, options(), |w| w.add_path("", &source).unwrap());
+
+        let plain = content_hash(&mut &b"one"[..], None).unwrap();
+        assert_ne!(Archive::open(&full).unwrap().index.entries["a"].hash, Some(plain));
+
+        // Without the secret, nothing can be found unchanged
+        let since = |previous: &Archive, path: &Path| {
+            let mut writer = ArchiveWriter::create_since(File::create(path).unwrap(), options(), previous).unwrap();
+            let changed = writer.update_path("", &source).unwrap();
+            writer.finish().unwrap();
+            changed
+        };
+
+        assert_eq!(since(&Archive::open(&full).unwrap(), &dir.path().join("locked.zap")), 2);
+
+        // Each archive has its own file key, hashes are carried over
+        // under the key of the new one
+        let incremental = dir.path().join("incremental.zap");
+        assert_eq!(since(&open(&full), &incremental), 0);
+        assert_eq!(since(&open(&incremental), &dir.path().join("next.zap")), 0);
+    }
+
+    #[test]
+    fn padded_sizes_are_only_kept_in_a_sealed_index() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source");
+
+        write_files(&source, &[("a", b"one")]);
+
+        let options = |sealed: bool| {
+            let mut file_key = FileKey::generate();
+            file_key.add_password(b"password").unwrap();
+
+            ArchiveOptions::new()
+                .with_encryption(EncryptionType::XChaCha)
+                .with_file_key(file_key)
+                .with_padding(Padding::Padme)
+                .with_metadata_encryption(sealed)
+        };
+        let open = |path: &Path| {
+            let options = ArchiveOptions::new().with_encryption_secret(EncryptionSecret::Password(b"password".to_vec()));
+            Archive::open_with_options(path, options).unwrap()
+        };
+
+        let plain = dir.path().join("plain.zap");
+        create(&plain, options(false), |w| w.add_path("", &source).unwrap());
+        assert_eq!(open(&plain).index.entries["a"].size, None);
+
+        let sealed = dir.path().join("sealed.zap");
+        create(&sealed, options(true), |w| w.add_path("", &source).unwrap());
+        assert_eq!(open(&sealed).index.entries["a"].size, Some(3));
+
+        // Unchanged files are still found by their hash
+        let incremental = dir.path().join("incremental.zap");
+        let mut writer = ArchiveWriter::create_since(File::create(&incremental).unwrap(), options(false), &open(&plain)).unwrap();
+        assert_eq!(writer.update_path("", &source).unwrap(), 0);
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn append_and_compact() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &data(50_000, 1), EntryMetadata::default()).unwrap();
+            w.add_bytes("b", &data(50_000, 2), EntryMetadata::default()).unwrap();
+        });
+
+        let mut writer = ArchiveWriter::append(&path, ArchiveOptions::new()).unwrap();
+        writer.add_bytes("a", &data(60_000, 3), EntryMetadata::default()).unwrap();
+        writer.add_bytes("c", b"sea", EntryMetadata::default()).unwrap();
+        assert_eq!(writer.remove("b").unwrap(), 1);
+        writer.finish().unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(paths(&archive), [Path::new("a"), Path::new("c")]);
+        assert_eq!(read(&archive, "a").unwrap(), data(60_000, 3));
+
+        let compacted = dir.path().join("compacted.zap");
+        archive.compact_to(File::create(&compacted).unwrap()).unwrap();
+
+        assert!(fs::metadata(&compacted).unwrap().len() < fs::metadata(&path).unwrap().len());
+
+        let compacted = Archive::open(&compacted).unwrap();
+        assert_eq!(paths(&compacted), [Path::new("a"), Path::new("c")]);
+        assert_eq!(read(&compacted, "a").unwrap(), data(60_000, 3));
+        assert_eq!(read(&compacted, "c").unwrap(), b"sea");
+    }
+
+    #[test]
+    fn dropped_append_is_rolled_back() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", b"aye", EntryMetadata::default()).unwrap();
+        });
+
+        let before = fs::read(&path).unwrap();
+
+        let mut writer = ArchiveWriter::append(&path, ArchiveOptions::new()).unwrap();
+        writer.add_bytes("b", &data(50_000, 1), EntryMetadata::default()).unwrap();
+        drop(writer);
+
+        assert_eq!(fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn interrupted_append_leaves_the_archive_readable() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new().with_parity(Some(10)), |w| {
+            w.add_bytes("a", b"aye", EntryMetadata::default()).unwrap();
+        });
+
+        // Killed while writing: nothing is rolled back
+        let mut writer = ArchiveWriter::append(&path, ArchiveOptions::new()).unwrap();
+        writer.add_bytes("b", &data(100_000, 1), EntryMetadata::default()).unwrap();
+        std::mem::forget(writer);
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(paths(&archive), [Path::new("a")]);
+        assert_eq!(read(&archive, "a").unwrap(), b"aye");
+
+        let mut writer = ArchiveWriter::append(&path, ArchiveOptions::new()).unwrap();
+        writer.add_bytes("c", b"sea", EntryMetadata::default()).unwrap();
+        writer.finish().unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(paths(&archive), [Path::new("a"), Path::new("c")]);
+        assert_eq!(archive.parity().unwrap(), Some(10));
+
+        // Killed while writing the recovery record
+        let len = archive.len;
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(fs::metadata(&path).unwrap().len() - 10).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!(archive.len, len);
+        assert_eq!(read(&archive, "c").unwrap(), b"sea");
+        assert_eq!(archive.parity().unwrap(), None);
+
+        // and the next append adds it back
+        ArchiveWriter::append(&path, ArchiveOptions::new()).unwrap().finish().unwrap();
+        assert_eq!(Archive::open(&path).unwrap().parity().unwrap(), Some(10));
+    }
+
+    #[test]
+    fn restore_chain_with_compacted_incremental() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source");
+        let output = dir.path().join("output");
+
+        write_files(&source, &[("a", b"one"), ("b", b"two"), ("sub/c", &data(20_000, 1))]);
+
+        let full = dir.path().join("full.zap");
+        create(&full, ArchiveOptions::new(), |w| w.add_path("", &source).unwrap());
+        let full = Archive::open(&full).unwrap();
+
+        write_files(&source, &[("a", b"one, changed"), ("d", b"four")]);
+        fs::remove_file(source.join("b")).unwrap();
+
+        let incremental = dir.path().join("incremental.zap");
+        let mut writer = ArchiveWriter::create_since(File::create(&incremental).unwrap(), ArchiveOptions::new(), &full).unwrap();
+        assert_eq!(writer.update_path("", &source).unwrap(), 2);
+        writer.finish().unwrap();
+
+        let compacted = dir.path().join("compacted.zap");
+        Archive::open(&incremental).unwrap().compact_to(File::create(&compacted).unwrap()).unwrap();
+        let compacted = Archive::open(&compacted).unwrap();
+
+        let chain = [full, compacted];
+        assert_eq!(restore_chain(&chain, &output).unwrap(), 3);
+
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"one, changed");
+        assert_eq!(fs::read(output.join("d")).unwrap(), b"four");
+        assert_eq!(fs::read(output.join("sub/c")).unwrap(), data(20_000, 1));
+        assert!(!output.join("b").exists());
+
+        // Unchanged files are still known to the compacted archive
+        let next = dir.path().join("next.zap");
+        let mut writer = ArchiveWriter::create_since(File::create(&next).unwrap(), ArchiveOptions::new(), &chain[1]).unwrap();
+        assert_eq!(writer.update_path("", &source).unwrap(), 0);
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn files_that_failed_are_stored_by_the_next_incremental() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("source");
+        let folder = dir.path().join("folder");
+        let output = dir.path().join("output");
+
+        write_files(&source, &[("a", b"one"), ("b", b"two")]);
+
+        // Nothing can be written where `b` would be compressed to
+        fs::create_dir_all(folder.join("b.lz4")).unwrap();
+
+        let report = crate::compress_directory(
+            source.to_str().unwrap(),
+            folder.to_str().unwrap(),
+            ArchiveOptions::new(),
+        )
+        .unwrap();
+
+        assert_eq!(report.entries.succeeded, [Path::new("a")]);
+        assert_eq!(report.entries.failed.len(), 1);
+
+        let full = dir.path().join("full.zap");
+        pack_directory(&folder, &report, File::create(&full).unwrap(), ArchiveOptions::new()).unwrap();
+        let full = Archive::open(&full).unwrap();
+
+        assert_eq!(paths(&full), [Path::new("a")]);
+        assert_eq!(full.index.entries.keys().collect::<Vec<_>>(), ["a"]);
+
+        let incremental = dir.path().join("incremental.zap");
+        let mut writer = ArchiveWriter::create_since(File::create(&incremental).unwrap(), ArchiveOptions::new(), &full).unwrap();
+        assert_eq!(writer.update_path("", &source).unwrap(), 1);
+        writer.finish().unwrap();
+
+        let chain = [full, Archive::open(&incremental).unwrap()];
+        assert_eq!(restore_chain(&chain, &output).unwrap(), 2);
+
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"one");
+        assert_eq!(fs::read(output.join("b")).unwrap(), b"two");
+    }
+}
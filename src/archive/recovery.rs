@@ -0,0 +1,817 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{copy, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ArchiveError;
+
+use super::{format, rekey, volume::Volumes};
+
+/*
+    A recovery record is written after the archive trailer:
+    [ archive ][ parity shards ][ recovery trailer ][ descriptor ][ descriptor ][ recovery trailer ]
+
+    The archive is cut into shards of `SHARD_SIZE` bytes, grouped in
+    stripes of `DATA_SHARDS` shards. Each stripe gets the same number of
+    Reed-Solomon parity shards, enough to rebuild that many damaged
+    shards of the stripe. The last stripe uses smaller shards so short
+    archives don't get a full size parity shard.
+
+    The descriptor is JSON, holding the crc32 of every shard so the
+    damaged ones can be told apart. It is written twice, as nothing can
+    be repaired without it, and so is the trailer. The first copy of
+    the trailer is only looked for when the last one is damaged.
+
+    Each copy of the descriptor is followed by spaces, leaving room for
+    the checksums of the first stripe to grow when the header is
+    rewritten, see `write_at`.
+
+    Recovery trailer:
+    [ archive length ][ descriptor length ][ descriptor crc32 ][ magic ]
+    [ 8              ][ 8                 ][ 4                ][ 8     ] (Bytes, LE)
+*/
+
+const MAGIC: &[u8; 8] = b"ZAPRCVR1";
+const TRAILER_SIZE: u64 = 28;
+const SHARD_SIZE: u64 = 64 * 1024;
+const DATA_SHARDS: usize = 128;
+/// Most digits a crc32 gains in JSON when it changes.
+const CRC32_GROWTH: usize = 9;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Descriptor {
+    /// Parity size, in percent of the archive size.
+    parity: u8,
+    shard_size: u64,
+    data_shards: usize,
+    parity_shards: usize,
+    /// Shard size of the last stripe.
+    last_shard_size: u64,
+    data_crc32: Vec<u32>,
+    parity_crc32: Vec<u32>,
+}
+
+/// Where a stripe of shards is, the last one being shorter.
+struct Stripe {
+    offset: u64,
+    len: u64,
+    shard_size: u64,
+    /// Index of the first shard of the stripe, in `data_crc32`.
+    first: usize,
+    parity_offset: u64,
+}
+
+impl Stripe {
+    fn data_shards(&self) -> usize {
+        self.len.div_ceil(self.shard_size) as usize
+    }
+}
+
+/// What `repair` found and fixed.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// Shards that failed their checksum, parity shards included.
+    pub damaged: usize,
+    pub repaired: usize,
+    /// Copies of the recovery trailer that were damaged and rewritten.
+    pub repaired_trailers: usize,
+    /// Bytes of the archive still damaged, in stripes with more damaged
+    /// shards than parity shards.
+    pub unrepaired_bytes: u64,
+}
+
+/// Computes the parity of everything written to it, stripe by stripe.
+/// Parity shards are kept in a temporary file until `finish` copies
+/// them after the archive.
+pub(crate) struct ParityEncoder {
+    parity: u8,
+    parity_shards: usize,
+    stripe: Vec<u8>,
+    len: u64,
+    data_crc32: Vec<u32>,
+    parity_crc32: Vec<u32>,
+    temp: File,
+}
+
+impl ParityEncoder {
+    pub fn new(parity: u8) -> Result<ParityEncoder, Error> {
+        if !(1..=100).contains(&parity) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Parity has to be between 1 and 100 percent, not {}", parity),
+            ));
+        }
+
+        Ok(ParityEncoder {
+            parity,
+            parity_shards: (DATA_SHARDS * parity as usize).div_ceil(100),
+            stripe: Vec::with_capacity(DATA_SHARDS * SHARD_SIZE as usize),
+            len: 0,
+            data_crc32: vec![],
+            parity_crc32: vec![],
+            temp: tempfile::tempfile()?,
+        })
+    }
+
+    /// Writes the recovery record to `io`, which has to be positioned
+    /// right after the archive.
+    pub fn finish<W>(mut self, io: &mut W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let last_shard_size = match self.stripe.len() as u64 {
+            0 => SHARD_SIZE,
+            len => len.div_ceil(DATA_SHARDS as u64),
+        };
+
+        if !self.stripe.is_empty() {
+            self.encode_stripe(last_shard_size)?;
+        }
+
+        let first_stripe = self.data_crc32.len().min(DATA_SHARDS) + self.parity_shards;
+
+        self.temp.seek(SeekFrom::Start(0))?;
+        copy(&mut self.temp, io)?;
+
+        let mut descriptor = serde_json::to_vec(&Descriptor {
+            parity: self.parity,
+            shard_size: SHARD_SIZE,
+            data_shards: DATA_SHARDS,
+            parity_shards: self.parity_shards,
+            last_shard_size,
+            data_crc32: std::mem::take(&mut self.data_crc32),
+            parity_crc32: std::mem::take(&mut self.parity_crc32),
+        })?;
+
+        descriptor.resize(descriptor.len() + first_stripe * CRC32_GROWTH, b' ');
+
+        let trailer = trailer((self.len, descriptor.len() as u64, crc32fast::hash(&descriptor)));
+
+        io.write_all(&trailer)?;
+        io.write_all(&descriptor)?;
+        io.write_all(&descriptor)?;
+        io.write_all(&trailer)?;
+        io.flush()
+    }
+
+    fn encode_stripe(&mut self, shard_size: u64) -> Result<(), Error> {
+        let (data_crc32, parity) = encode_stripe(&self.stripe, shard_size, self.parity_shards)?;
+
+        self.data_crc32.extend(data_crc32);
+
+        for shard in parity.iter() {
+            self.temp.write_all(shard)?;
+            self.parity_crc32.push(crc32fast::hash(shard));
+        }
+
+        self.stripe.clear();
+
+        Ok(())
+    }
+}
+
+/// The checksums of the data shards of `stripe`, and its parity shards.
+fn encode_stripe(stripe: &[u8], shard_size: u64, parity_shards: usize) -> Result<(Vec<u32>, Vec<Vec<u8>>), Error> {
+    let data: Vec<Vec<u8>> = stripe
+        .chunks(shard_size as usize)
+        .map(|chunk| padded(chunk, shard_size))
+        .collect();
+
+    let mut parity = vec![vec![0u8; shard_size as usize]; parity_shards];
+
+    ReedSolomon::new(data.len(), parity_shards)
+        .and_then(|codec| codec.encode_sep(&data, &mut parity))
+        .map_err(Error::other)?;
+
+    Ok((data.iter().map(|shard| crc32fast::hash(shard)).collect(), parity))
+}
+
+impl Write for ParityEncoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let capacity = DATA_SHARDS * SHARD_SIZE as usize;
+        let len = buf.len().min(capacity - self.stripe.len());
+
+        self.stripe.extend_from_slice(&buf[..len]);
+        self.len += len as u64;
+
+        if self.stripe.len() == capacity {
+            self.encode_stripe(SHARD_SIZE)?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Adds a recovery record with `parity` percent of parity data to the
+/// archive in `file`, which must not have one. If that fails, the file
+/// is truncated back to the archive alone.
+pub(crate) fn add_record(file: &mut File, parity: u8) -> Result<(), Error> {
+    let len = file.seek(SeekFrom::End(0))?;
+
+    let result = ParityEncoder::new(parity).and_then(|mut encoder| {
+        file.seek(SeekFrom::Start(0))?;
+        copy(&mut Read::by_ref(file).take(len), &mut encoder)?;
+
+        file.seek(SeekFrom::Start(len))?;
+        encoder.finish(file)
+    });
+
+    if result.is_err() {
+        let _ = file.set_len(len);
+    }
+
+    result
+}
+
+/// Writes `data` at `offset` of the archive in `volumes`, which has to
+/// be within the archive. Only the stripes of the recovery record that
+/// cover it are encoded again, then both copies of the descriptor and
+/// trailer are rewritten, one after the other so that either is left
+/// whole when this is cut short.
+///
+/// Records written without room for their descriptor to grow are
+/// computed again whole when it doesn't fit, which split archives can't
+/// be.
+pub(crate) fn write_at(volumes: &Volumes, offset: u64, data: &[u8]) -> Result<(), ArchiveError> {
+    let mut io = volumes.reader();
+
+    let (mut descriptor, (archive_len, descriptor_len, _)) = match read_descriptor(&mut io)? {
+        Some(found) => found,
+        None => return Ok(volumes.write_at(offset, data)?),
+    };
+
+    let end = match offset.checked_add(data.len() as u64) {
+        Some(end) if end <= archive_len => end,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "Write past the end of the archive").into()),
+    };
+
+    let stripes = stripes(&descriptor, archive_len)?;
+    let mut parity = vec![];
+
+    for (n, stripe) in stripes.iter().enumerate() {
+        let (from, to) = (offset.max(stripe.offset), end.min(stripe.offset + stripe.len));
+
+        if from >= to {
+            continue;
+        }
+
+        let mut stripe_data = vec![0u8; stripe.len as usize];
+        io.seek(SeekFrom::Start(stripe.offset))?;
+        io.read_exact(&mut stripe_data)?;
+
+        stripe_data[(from - stripe.offset) as usize..(to - stripe.offset) as usize]
+            .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+
+        let (data_crc32, shards) = encode_stripe(&stripe_data, stripe.shard_size, descriptor.parity_shards)?;
+        let first_parity = n * descriptor.parity_shards;
+
+        descriptor.data_crc32[stripe.first..stripe.first + data_crc32.len()].copy_from_slice(&data_crc32);
+
+        for (i, shard) in shards.iter().enumerate() {
+            descriptor.parity_crc32[first_parity + i] = crc32fast::hash(shard);
+        }
+
+        parity.push((stripe.parity_offset, shards.concat()));
+    }
+
+    drop(io);
+
+    let mut encoded = serde_json::to_vec(&descriptor)?;
+
+    if encoded.len() as u64 > descriptor_len {
+        if volumes.is_split() {
+            return Err(ArchiveError::SplitArchive(volumes.first().display().to_string()));
+        }
+
+        // Dropped first, so the record never repairs the data back
+        let mut file = OpenOptions::new().read(true).write(true).open(volumes.first())?;
+        file.set_len(archive_len)?;
+
+        volumes.write_at(offset, data)?;
+
+        return Ok(add_record(&mut file, descriptor.parity)?);
+    }
+
+    encoded.resize(descriptor_len as usize, b' ');
+
+    let copy_end = parity_end(&descriptor, &stripes, archive_len) + TRAILER_SIZE;
+    let trailer = trailer((archive_len, descriptor_len, crc32fast::hash(&encoded)));
+
+    volumes.write_at(offset, data)?;
+
+    for (offset, shards) in parity {
+        volumes.write_at(offset, &shards)?;
+    }
+
+    volumes.write_at(copy_end - TRAILER_SIZE, &[&trailer[..], &encoded].concat())?;
+    volumes.write_at(copy_end + descriptor_len, &[&encoded[..], &trailer[..]].concat())?;
+
+    Ok(())
+}
+
+/// Length of the archive in `io`, without the recovery record. Unless
+/// both copies of the recovery trailer agree, the whole of `io` is taken
+/// for the archive.
+pub(crate) fn archive_len<R>(io: &mut R) -> Result<u64, Error>
+where
+    R: Read + Seek,
+{
+    let len = io.seek(SeekFrom::End(0))?;
+
+    let trailer = match read_trailer(io, len)? {
+        Some(trailer) => trailer,
+        None => return Ok(len),
+    };
+
+    Ok(match copy_end(len, trailer) {
+        Some(end) if trailer.0 < end && read_trailer(io, end)? == Some(trailer) => trailer.0,
+        _ => len,
+    })
+}
+
+/// Parity size of the recovery record in `io`, in percent.
+pub(crate) fn parity<R>(io: &mut R) -> Result<Option<u8>, ArchiveError>
+where
+    R: Read + Seek,
+{
+    Ok(read_descriptor(io)?.map(|(descriptor, _)| descriptor.parity))
+}
+
+/// Rebuilds the damaged parts of the archive at `path` in place from
+/// its recovery record. Split archives are repaired volume by volume.
+///
+/// Every shard is checked, so this reads the whole archive even when
+/// nothing is damaged. Stripes with more damaged shards than parity
+/// shards are left as they are.
+pub fn repair<P>(path: P) -> Result<RepairReport, ArchiveError>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let volumes = Volumes::open(path)?;
+
+    // Otherwise the record of a header rewrite that was cut short would
+    // put the old header back
+    rekey::recover(&volumes)?;
+
+    let mut io = volumes.reader();
+
+    let (descriptor, trailer) = match read_descriptor(&mut io)? {
+        Some(found) => found,
+        None => return Err(ArchiveError::NoRecoveryRecord(path.display().to_string())),
+    };
+
+    let archive_len = trailer.0;
+    let stripes = stripes(&descriptor, archive_len)?;
+    let copy_end = parity_end(&descriptor, &stripes, archive_len) + TRAILER_SIZE;
+
+    let mut report = RepairReport {
+        repaired_trailers: repair_trailers(&volumes, &mut io, trailer, copy_end)?,
+        ..RepairReport::default()
+    };
+
+    for (n, stripe) in stripes.iter().enumerate() {
+        let data_shards = stripe.data_shards();
+
+        let mut data = vec![0u8; stripe.len as usize];
+        io.seek(SeekFrom::Start(stripe.offset))?;
+        io.read_exact(&mut data)?;
+
+        let mut shards: Vec<(Vec<u8>, bool)> = data
+            .chunks(stripe.shard_size as usize)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let shard = padded(chunk, stripe.shard_size);
+                let valid = crc32fast::hash(&shard) == descriptor.data_crc32[stripe.first + i];
+
+                (shard, valid)
+            })
+            .collect();
+
+        let first_parity = n * descriptor.parity_shards;
+
+        io.seek(SeekFrom::Start(stripe.parity_offset))?;
+
+        for i in 0..descriptor.parity_shards {
+            let mut shard = vec![0u8; stripe.shard_size as usize];
+            io.read_exact(&mut shard)?;
+
+            let valid = crc32fast::hash(&shard) == descriptor.parity_crc32[first_parity + i];
+            shards.push((shard, valid));
+        }
+
+        let damaged: Vec<usize> = (0..shards.len()).filter(|i| !shards[*i].1).collect();
+
+        if damaged.is_empty() {
+            continue;
+        }
+
+        report.damaged += damaged.len();
+
+        if damaged.len() > descriptor.parity_shards {
+            report.unrepaired_bytes += damaged
+                .iter()
+                .filter(|i| **i < data_shards)
+                .map(|i| stripe.shard_size.min(stripe.len - *i as u64 * stripe.shard_size))
+                .sum::<u64>();
+
+            continue;
+        }
+
+        ReedSolomon::new(data_shards, descriptor.parity_shards)
+            .and_then(|codec| codec.reconstruct(&mut shards))
+            .map_err(|e| ArchiveError::Corrupt(format!("Failed to rebuild damaged shards: {}", e)))?;
+
+        for i in damaged {
+            let (offset, len) = match i < data_shards {
+                true => {
+                    let start = i as u64 * stripe.shard_size;
+                    (stripe.offset + start, stripe.shard_size.min(stripe.len - start))
+                }
+                false => (
+                    stripe.parity_offset + (i - data_shards) as u64 * stripe.shard_size,
+                    stripe.shard_size,
+                ),
+            };
+
+            volumes.write_at(offset, &shards[i].0[..len as usize])?;
+            report.repaired += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// What a recovery trailer holds: the archive length, descriptor length
+/// and descriptor crc32.
+type Trailer = (u64, u64, u32);
+
+fn trailer((archive_len, descriptor_len, descriptor_crc32): Trailer) -> [u8; TRAILER_SIZE as usize] {
+    let mut buf = [0u8; TRAILER_SIZE as usize];
+
+    buf[0..8].copy_from_slice(&archive_len.to_le_bytes());
+    buf[8..16].copy_from_slice(&descriptor_len.to_le_bytes());
+    buf[16..20].copy_from_slice(&descriptor_crc32.to_le_bytes());
+    buf[20..28].copy_from_slice(MAGIC);
+
+    buf
+}
+
+/// The trailer ending at `end`, if there is one.
+fn read_trailer<R>(io: &mut R, end: u64) -> Result<Option<Trailer>, Error>
+where
+    R: Read + Seek,
+{
+    if end < TRAILER_SIZE {
+        return Ok(None);
+    }
+
+    let mut buf = [0u8; TRAILER_SIZE as usize];
+    io.seek(SeekFrom::Start(end - TRAILER_SIZE))?;
+    io.read_exact(&mut buf)?;
+
+    if &buf[20..28] != MAGIC {
+        return Ok(None);
+    }
+
+    Ok(Some((
+        u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+        u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+        u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+    )))
+}
+
+/// Where the first copy of `trailer` ends, for a file of `len` bytes
+/// ending with the other one.
+fn copy_end(len: u64, (_, descriptor_len, _): Trailer) -> Option<u64> {
+    descriptor_len
+        .checked_mul(2)
+        .and_then(|descriptors| descriptors.checked_add(TRAILER_SIZE))
+        .and_then(|after| len.checked_sub(after))
+}
+
+/// The descriptor of the recovery record in `io`, and the trailer it was
+/// found with. When the trailer at the end is damaged, the copy in front
+/// of the descriptors is looked for instead.
+fn read_descriptor<R>(io: &mut R) -> Result<Option<(Descriptor, Trailer)>, ArchiveError>
+where
+    R: Read + Seek,
+{
+    let len = io.seek(SeekFrom::End(0))?;
+    let last = read_trailer(io, len)?;
+
+    if let Some(trailer) = last {
+        let descriptor = match copy_end(len, trailer) {
+            Some(end) => check_record(io, trailer, end)?,
+            None => None,
+        };
+
+        if let Some(descriptor) = descriptor {
+            return Ok(Some((descriptor, trailer)));
+        }
+    } else if format::ends_with_trailer(io, len)? {
+        // Nothing follows the archive
+        return Ok(None);
+    }
+
+    let found = format::rfind_magic(io, len, MAGIC, |io, end| -> Result<_, ArchiveError> {
+        match read_trailer(io, end)? {
+            Some(trailer) if copy_end(len, trailer) == Some(end) => {
+                Ok(check_record(io, trailer, end)?.map(|descriptor| (descriptor, trailer)))
+            }
+            _ => Ok(None),
+        }
+    })?;
+
+    match (found, last) {
+        (Some(found), _) => Ok(Some(found)),
+        (None, Some(_)) => Err(ArchiveError::Corrupt("Recovery record failed its checksum".into())),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The descriptor `trailer` points to, the first copy of the trailer
+/// ending at `copy_end`, if it is intact and its parity shards fill the
+/// room between the archive and that copy.
+fn check_record<R>(io: &mut R, trailer: Trailer, copy_end: u64) -> Result<Option<Descriptor>, ArchiveError>
+where
+    R: Read + Seek,
+{
+    let (archive_len, descriptor_len, descriptor_crc32) = trailer;
+
+    if archive_len >= copy_end {
+        return Ok(None);
+    }
+
+    for copy in 0..2 {
+        let mut descriptor = Vec::with_capacity(descriptor_len as usize);
+        io.seek(SeekFrom::Start(copy_end + copy * descriptor_len))?;
+        io.take(descriptor_len).read_to_end(&mut descriptor)?;
+
+        if crc32fast::hash(&descriptor) != descriptor_crc32 {
+            continue;
+        }
+
+        let descriptor: Descriptor = serde_json::from_slice(&descriptor)?;
+
+        return Ok(match stripes(&descriptor, archive_len) {
+            Ok(stripes) if parity_end(&descriptor, &stripes, archive_len) + TRAILER_SIZE == copy_end => Some(descriptor),
+            _ => None,
+        });
+    }
+
+    Ok(None)
+}
+
+/// Where the parity shards of `stripes` end.
+fn parity_end(descriptor: &Descriptor, stripes: &[Stripe], archive_len: u64) -> u64 {
+    match stripes.last() {
+        Some(stripe) => stripe.parity_offset + stripe.shard_size * descriptor.parity_shards as u64,
+        None => archive_len,
+    }
+}
+
+/// Rewrites the copies of `trailer` that don't match it, the first one
+/// ending at `copy_end`. Returns how many were.
+fn repair_trailers<R>(volumes: &Volumes, io: &mut R, trailer: Trailer, copy_end: u64) -> Result<usize, Error>
+where
+    R: Read + Seek,
+{
+    let len = io.seek(SeekFrom::End(0))?;
+    let expected = self::trailer(trailer);
+    let mut repaired = 0;
+
+    for end in [copy_end, len] {
+        let mut buf = [0u8; TRAILER_SIZE as usize];
+        io.seek(SeekFrom::Start(end - TRAILER_SIZE))?;
+        io.read_exact(&mut buf)?;
+
+        if buf != expected {
+            volumes.write_at(end - TRAILER_SIZE, &expected)?;
+            repaired += 1;
+        }
+    }
+
+    Ok(repaired)
+}
+
+fn stripes(descriptor: &Descriptor, archive_len: u64) -> Result<Vec<Stripe>, ArchiveError> {
+    let invalid = || ArchiveError::Corrupt("Recovery record doesn't match the archive".into());
+
+    // Anything past what `ParityEncoder` writes is rejected before
+    // it is used to size a buffer
+    if !(1..=SHARD_SIZE).contains(&descriptor.shard_size)
+        || !(1..=SHARD_SIZE).contains(&descriptor.last_shard_size)
+        || !(1..=DATA_SHARDS).contains(&descriptor.data_shards)
+        || descriptor.parity_shards > DATA_SHARDS
+    {
+        return Err(invalid());
+    }
+
+    let stripe_len = descriptor
+        .shard_size
+        .checked_mul(descriptor.data_shards as u64)
+        .ok_or_else(invalid)?;
+    let mut stripes = vec![];
+    let (mut offset, mut first, mut parity_offset) = (0, 0, archive_len);
+
+    while offset < archive_len {
+        let len = stripe_len.min(archive_len - offset);
+
+        let shard_size = match len == stripe_len {
+            true => descriptor.shard_size,
+            false => descriptor.last_shard_size,
+        };
+
+        let stripe = Stripe {
+            offset,
+            len,
+            shard_size,
+            first,
+            parity_offset,
+        };
+
+        offset += len;
+        first += stripe.data_shards();
+        parity_offset = shard_size
+            .checked_mul(descriptor.parity_shards as u64)
+            .and_then(|len| parity_offset.checked_add(len))
+            .ok_or_else(invalid)?;
+
+        // Every data shard has a checksum, so a record can't describe
+        // more stripes than that
+        if first > descriptor.data_crc32.len() {
+            return Err(invalid());
+        }
+
+        stripes.push(stripe);
+    }
+
+    if parity_offset.checked_add(TRAILER_SIZE).is_none() {
+        return Err(invalid());
+    }
+
+    let parity_count = stripes.len() * descriptor.parity_shards;
+
+    if first != descriptor.data_crc32.len()
+        || parity_count != descriptor.parity_crc32.len()
+        || stripes.iter().any(|s| s.data_shards() > descriptor.data_shards)
+    {
+        return Err(invalid());
+    }
+
+    Ok(stripes)
+}
+
+/// `chunk` padded with zeroes to `size` bytes.
+fn padded(chunk: &[u8], size: u64) -> Vec<u8> {
+    let mut shard = chunk.to_vec();
+    shard.resize(size as usize, 0);
+
+    shard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        archive::{
+            test_util::{create, data, flip, read_from},
+            Archive, EntryMetadata,
+        },
+        encryption::EncryptionSecret,
+        options::ArchiveOptions,
+    };
+
+    /// An archive of a single entry with 20% parity.
+    fn with_parity(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new().with_parity(Some(20)), |w| {
+            w.add_bytes("a", &data(300_000, 0), EntryMetadata::default()).unwrap();
+        });
+
+        path
+    }
+
+    #[test]
+    fn repairs_damaged_shards() {
+        let dir = TempDir::new().unwrap();
+        let path = with_parity(&dir);
+        let original = fs::read(&path).unwrap();
+
+        for offset in [100, 100_000, 200_000] {
+            flip(&path, offset);
+        }
+
+        let report = repair(&path).unwrap();
+
+        assert_eq!((report.damaged, report.repaired, report.unrepaired_bytes), (3, 3, 0));
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert_eq!(read_from(&path, EncryptionSecret::None, "a").unwrap(), data(300_000, 0));
+    }
+
+    #[test]
+    fn too_much_damage_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let path = with_parity(&dir);
+
+        // More damaged shards than a stripe has parity shards
+        for offset in (0..300_000).step_by(4096) {
+            flip(&path, offset);
+        }
+
+        let report = repair(&path).unwrap();
+
+        assert!(report.unrepaired_bytes > 0);
+        assert!(report.repaired < report.damaged);
+    }
+
+    #[test]
+    fn damaged_trailer_falls_back_to_its_copy() {
+        let dir = TempDir::new().unwrap();
+        let path = with_parity(&dir);
+        let original = fs::read(&path).unwrap();
+        let len = original.len() as u64;
+
+        let trailer = read_trailer(&mut File::open(&path).unwrap(), len).unwrap().unwrap();
+        let copy_end = copy_end(len, trailer).unwrap();
+
+        // Either copy, in the magic or in the archive length
+        for offset in [len - 1, len - TRAILER_SIZE, copy_end - TRAILER_SIZE] {
+            flip(&path, offset);
+            flip(&path, 1000);
+
+            let report = repair(&path).unwrap();
+
+            assert_eq!((report.repaired_trailers, report.repaired), (1, 1));
+            assert_eq!(fs::read(&path).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn archive_is_read_past_a_damaged_trailer() {
+        let dir = TempDir::new().unwrap();
+        let path = with_parity(&dir);
+        let len = fs::metadata(&path).unwrap().len();
+
+        flip(&path, len - TRAILER_SIZE);
+
+        assert_eq!(read_from(&path, EncryptionSecret::None, "a").unwrap(), data(300_000, 0));
+        assert_eq!(Archive::open(&path).unwrap().parity().unwrap(), Some(20));
+    }
+
+    #[test]
+    fn oversized_descriptors_are_rejected() {
+        let valid = || Descriptor {
+            parity: 20,
+            shard_size: SHARD_SIZE,
+            data_shards: DATA_SHARDS,
+            parity_shards: 26,
+            last_shard_size: SHARD_SIZE,
+            data_crc32: vec![0; DATA_SHARDS],
+            parity_crc32: vec![0; 26],
+        };
+        let archive_len = SHARD_SIZE * DATA_SHARDS as u64;
+
+        assert_eq!(stripes(&valid(), archive_len).unwrap().len(), 1);
+
+        for descriptor in [
+            Descriptor { shard_size: u64::MAX, ..valid() },
+            Descriptor { shard_size: SHARD_SIZE + 1, ..valid() },
+            Descriptor { last_shard_size: u64::MAX, ..valid() },
+            Descriptor { data_shards: usize::MAX, ..valid() },
+            Descriptor { parity_shards: usize::MAX, ..valid() },
+        ] {
+            assert!(matches!(stripes(&descriptor, archive_len), Err(ArchiveError::Corrupt(_))));
+        }
+
+        // An archive with more shards than the record has checksums for
+        assert!(matches!(stripes(&valid(), u64::MAX - 1), Err(ArchiveError::Corrupt(_))));
+    }
+
+    #[test]
+    fn no_recovery_record() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", b"aye", EntryMetadata::default()).unwrap();
+        });
+
+        assert!(matches!(repair(&path), Err(ArchiveError::NoRecoveryRecord(_))));
+        assert_eq!(Archive::open(&path).unwrap().parity().unwrap(), None);
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    encryption::{
+        keys::{FileKey, Recipient},
+        EncryptionSecret,
+    },
+    error::{ArchiveError, EncryptionKeyError},
+};
+
+use super::{
+    format::{self, Header},
+    recovery,
+    volume::Volumes,
+};
+
+/// The key slots of an encrypted archive, opened with one of them so
+/// that passwords and public keys can be added or removed. Saving only
+/// rewrites the header, the entries stay encrypted with the same key.
+pub struct Rekey {
+    volumes: Volumes,
+    header: Header,
+    file_key: FileKey,
+    /// Slot the archive was opened with, until it is removed.
+    opened: Option<usize>,
+}
+
+impl Rekey {
+    /// Opens the key slots of the archive at `path` with `secret`, a
+    /// password or a key file.
+    pub fn open<P>(path: P, secret: EncryptionSecret) -> Result<Rekey, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let volumes = Volumes::open(path.as_ref())?;
+        recover(&volumes)?;
+
+        let header = format::read_header(&mut volumes.reader())?;
+
+        if header.key_slots.is_empty() {
+            return Err(EncryptionKeyError::NoKeySlots.into());
+        }
+
+        let (file_key, opened) = FileKey::open(header.key_slots.clone(), &secret)?;
+
+        Ok(Rekey {
+            volumes,
+            header,
+            file_key,
+            opened: Some(opened),
+        })
+    }
+
+    /// Number of key slots the archive has.
+    pub fn len(&self) -> usize {
+        self.file_key.slots().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.file_key.slots().is_empty()
+    }
+
+    pub fn add_password(&mut self, password: &[u8]) -> Result<(), ArchiveError> {
+        Ok(self.file_key.add_password(password)?)
+    }
+
+    pub fn add_recipient(&mut self, recipient: &Recipient) -> Result<(), ArchiveError> {
+        Ok(self.file_key.add_recipient(recipient)?)
+    }
+
+    /// Removes the slot the archive was opened with, unless it is the
+    /// last one left.
+    pub fn remove_opened(&mut self) -> Result<(), ArchiveError> {
+        if let Some(opened) = self.opened {
+            self.file_key.remove_slot(opened)?;
+            self.opened = None;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the key slots to the header, along with the parts of the
+    /// recovery record that cover it.
+    ///
+    /// The header is first copied to a backup file next to the archive,
+    /// which is put back when the archive is next opened if the header
+    /// was left half written.
+    pub fn save(mut self) -> Result<(), ArchiveError> {
+        let backup = backup_path(&self.volumes);
+        let mut backup_file = File::create(&backup)?;
+        backup_file.write_all(&format::raw_header(&self.volumes)?)?;
+        backup_file.sync_all()?;
+        drop(backup_file);
+
+        self.header.key_slots = self.file_key.slots().to_vec();
+
+        format::rewrite_header(&self.volumes, &self.header)?;
+        fs::remove_file(backup)?;
+
+        Ok(())
+    }
+}
+
+/// Where `Rekey::save` keeps the header while rewriting it.
+fn backup_path(volumes: &Volumes) -> PathBuf {
+    let mut path = OsString::from(volumes.first().as_os_str());
+    path.push(".rekey");
+
+    PathBuf::from(path)
+}
+
+/// Finishes a `Rekey::save` that was cut short: the header is put back
+/// from its backup, unless the new one was written whole.
+pub(crate) fn recover(volumes: &Volumes) -> Result<(), ArchiveError> {
+    let path = backup_path(volumes);
+
+    let backup = match fs::read(&path) {
+        Ok(backup) => backup,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // A backup that was cut short itself was made before the header was
+    // touched
+    let header = match format::read_header(&mut volumes.reader()).is_err() && format::read_header(&mut &backup[..]).is_ok() {
+        true => backup,
+        false => format::raw_header(volumes)?,
+    };
+
+    // The recovery record may not have been brought up to date yet
+    recovery::write_at(volumes, 0, &header)?;
+
+    fs::remove_file(path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        archive::{
+            test_util::{create, flip, read_from},
+            volume::VolumeWriter,
+            writer::ArchiveWriter,
+            EntryMetadata,
+        },
+        encryption::{keys::Identity, EncryptionType},
+        options::ArchiveOptions,
+    };
+
+    fn password(password: &str) -> EncryptionSecret {
+        EncryptionSecret::Password(password.as_bytes().to_vec())
+    }
+
+    /// An archive holding "a", opened with the password "one".
+    fn encrypted(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("a.zap");
+
+        let mut file_key = FileKey::generate();
+        file_key.add_password(b"one").unwrap();
+
+        let options = ArchiveOptions::new()
+            .with_encryption(EncryptionType::XChaCha)
+            .with_file_key(file_key)
+            .with_parity(Some(10));
+
+        create(&path, options, |w| {
+            w.add_bytes("a", b"aye", EntryMetadata::default()).unwrap();
+        });
+
+        path
+    }
+
+    #[test]
+    fn slots_are_added_and_removed() {
+        let dir = TempDir::new().unwrap();
+        let path = encrypted(&dir);
+
+        let identity = Identity::generate();
+        let key_file = dir.path().join("b.key");
+        fs::write(&key_file, identity.to_string()).unwrap();
+        let key = EncryptionSecret::Key(key_file.display().to_string());
+
+        let mut rekey = Rekey::open(&path, password("one")).unwrap();
+        rekey.add_password(b"two").unwrap();
+        rekey.add_recipient(&identity.recipient()).unwrap();
+        assert_eq!(rekey.len(), 3);
+        rekey.save().unwrap();
+
+        for secret in [password("one"), password("two"), key.clone()] {
+            assert_eq!(read_from(&path, secret, "a").unwrap(), b"aye");
+        }
+
+        let mut rekey = Rekey::open(&path, password("one")).unwrap();
+        rekey.remove_opened().unwrap();
+        assert_eq!(rekey.len(), 2);
+        rekey.save().unwrap();
+
+        assert!(matches!(
+            read_from(&path, password("one"), "a"),
+            Err(ArchiveError::KeyError(EncryptionKeyError::NoMatchingSlot))
+        ));
+        assert_eq!(read_from(&path, password("two"), "a").unwrap(), b"aye");
+        assert_eq!(read_from(&path, key, "a").unwrap(), b"aye");
+
+        // The recovery record is made again for the new header
+        assert_eq!(recovery::repair(&path).unwrap().damaged, 0);
+    }
+
+    #[test]
+    fn last_slot_is_kept() {
+        let dir = TempDir::new().unwrap();
+        let path = encrypted(&dir);
+
+        let mut rekey = Rekey::open(&path, password("one")).unwrap();
+
+        assert!(matches!(
+            rekey.remove_opened(),
+            Err(ArchiveError::KeyError(EncryptionKeyError::LastKeySlot))
+        ));
+        assert!(matches!(
+            Rekey::open(&path, password("two")),
+            Err(ArchiveError::KeyError(EncryptionKeyError::NoMatchingSlot))
+        ));
+    }
+
+    #[test]
+    fn split_archive_with_parity_is_rekeyed() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("split.zap");
+
+        let mut file_key = FileKey::generate();
+        file_key.add_password(b"one").unwrap();
+
+        let options = ArchiveOptions::new()
+            .with_encryption(EncryptionType::XChaCha)
+            .with_file_key(file_key)
+            .with_parity(Some(10))
+            .with_volume_size(Some(4096));
+
+        let mut writer = ArchiveWriter::create(VolumeWriter::create(&base, 4096).unwrap(), options).unwrap();
+        writer.add_bytes("a", &[7; 20_000], EntryMetadata::default()).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+
+        let mut rekey = Rekey::open(&base, password("one")).unwrap();
+        rekey.add_password(b"two").unwrap();
+        rekey.save().unwrap();
+
+        assert!(!backup_path(&Volumes::open(&base).unwrap()).exists());
+        assert_eq!(read_from(&base, password("two"), "a").unwrap(), [7; 20_000]);
+        assert_eq!(recovery::repair(&base).unwrap().damaged, 0);
+    }
+
+    #[test]
+    fn damaged_header_is_repaired_to_the_new_one() {
+        let dir = TempDir::new().unwrap();
+        let path = encrypted(&dir);
+
+        let mut rekey = Rekey::open(&path, password("one")).unwrap();
+        rekey.add_password(b"two").unwrap();
+        rekey.remove_opened().unwrap();
+        rekey.save().unwrap();
+
+        let header = format::raw_header(&Volumes::open(&path).unwrap()).unwrap();
+        flip(&path, header.len() as u64 / 2);
+
+        let report = recovery::repair(&path).unwrap();
+        assert_eq!((report.damaged, report.repaired), (1, 1));
+
+        assert!(read_from(&path, password("one"), "a").is_err());
+        assert_eq!(read_from(&path, password("two"), "a").unwrap(), b"aye");
+    }
+
+    #[test]
+    fn record_is_updated_after_a_save_cut_short() {
+        let dir = TempDir::new().unwrap();
+        let path = encrypted(&dir);
+
+        let volumes = Volumes::open(&path).unwrap();
+        let before = fs::read(&path).unwrap();
+        let header_len = format::raw_header(&volumes).unwrap().len();
+
+        let mut rekey = Rekey::open(&path, password("one")).unwrap();
+        rekey.add_password(b"two").unwrap();
+        rekey.save().unwrap();
+
+        // The header was written, the recovery record wasn't
+        let mut cut_short = fs::read(&path).unwrap()[..header_len].to_vec();
+        cut_short.extend_from_slice(&before[header_len..]);
+        fs::write(&path, cut_short).unwrap();
+        fs::write(backup_path(&volumes), &before[..header_len]).unwrap();
+
+        assert_eq!(read_from(&path, password("two"), "a").unwrap(), b"aye");
+        assert!(!backup_path(&volumes).exists());
+        assert_eq!(recovery::repair(&path).unwrap().damaged, 0);
+    }
+
+    #[test]
+    fn half_written_header_is_restored() {
+        let dir = TempDir::new().unwrap();
+        let path = encrypted(&dir);
+
+        let volumes = Volumes::open(&path).unwrap();
+        let header = format::raw_header(&volumes).unwrap();
+        fs::write(backup_path(&volumes), &header).unwrap();
+
+        // Cut short halfway through the metadata
+        volumes.write_at(header.len() as u64 / 2, &[b'x'; 100]).unwrap();
+        assert!(format::read_header(&mut volumes.reader()).is_err());
+
+        assert_eq!(read_from(&path, password("one"), "a").unwrap(), b"aye");
+        assert_eq!(format::raw_header(&volumes).unwrap(), header);
+        assert!(!backup_path(&volumes).exists());
+    }
+
+    #[test]
+    fn whole_header_is_kept_over_its_backup() {
+        let dir = TempDir::new().unwrap();
+        let path = encrypted(&dir);
+
+        let volumes = Volumes::open(&path).unwrap();
+        fs::write(backup_path(&volumes), format::raw_header(&volumes).unwrap()).unwrap();
+
+        let mut rekey = Rekey::open(&path, password("one")).unwrap();
+        rekey.add_password(b"two").unwrap();
+        rekey.save().unwrap();
+
+        // The save finished, but its backup was left behind
+        fs::write(backup_path(&volumes), b"stale").unwrap();
+
+        assert_eq!(read_from(&path, password("two"), "a").unwrap(), b"aye");
+        assert!(!backup_path(&volumes).exists());
+    }
+}
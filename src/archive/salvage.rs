@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, copy},
+    path::Path,
+};
+
+use crate::{error::ArchiveError, options::ArchiveOptions, solid};
+
+use super::{
+    format::{self, Index, Section},
+    open_header, recovery,
+    volume::Volumes,
+    entry_of, Archive, CrcReader,
+};
+
+/// What could be read back from a damaged archive.
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    /// Number of files written to the output folder.
+    pub recovered: usize,
+    /// Files that couldn't be recovered, and why.
+    pub lost: Vec<(String, String)>,
+    /// Stored objects that were cut short or damaged, and why.
+    pub damaged: Vec<(String, String)>,
+    /// False when the index couldn't be read and the archive was
+    /// scanned instead. The metadata of the files is lost then, and
+    /// files removed from the archive can come back.
+    pub index_found: bool,
+}
+
+impl fmt::Display for SalvageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Recovered {} files, lost {}", self.recovered, self.lost.len())?;
+
+        if !self.index_found {
+            writeln!(
+                f,
+                "The index couldn't be read, the archive was scanned for files instead. \
+                 Files stored past the damage are not known, and removed files may have come back."
+            )?;
+        }
+
+        if !self.lost.is_empty() {
+            writeln!(f, "\nLost files:")?;
+
+            for (path, reason) in self.lost.iter() {
+                writeln!(f, "  {}: {}", path, reason)?;
+            }
+        }
+
+        if !self.damaged.is_empty() {
+            writeln!(f, "\nDamaged data:")?;
+
+            for (name, reason) in self.damaged.iter() {
+                writeln!(f, "  {}: {}", name, reason)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An archive that was cut short or damaged, opened to extract
+/// whatever can still be read.
+pub struct Salvage {
+    archive: Archive,
+    damaged: Vec<(String, String)>,
+    index_found: bool,
+}
+
+impl Salvage {
+    /// Finds what is left of the archive at `path`. Its index is used
+    /// if it can be read, otherwise the archive is scanned for the
+    /// objects it holds. Either way, every object is checked against
+    /// its checksum and left out if it fails.
+    ///
+    /// A split archive is read from whichever of its volumes are there.
+    /// Only the encryption secret and stage registry are taken from
+    /// `options`. Archives with encrypted metadata can't be scanned, so
+    /// they can only be salvaged while their index can be read.
+    pub fn scan<P>(path: P, mut options: ArchiveOptions) -> Result<Salvage, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let volumes = Volumes::open_present(&path)?;
+        let mut io = volumes.reader();
+
+        let (header, secret, metadata_key) = open_header(&mut io, &path, options.encryption_secret)?;
+        options.encryption_secret = secret;
+
+        let len = recovery::archive_len(&mut io)?;
+
+        let (index, damaged, index_found) = match format::read_index(&mut io, metadata_key.as_ref()) {
+            Ok((mut index, _)) => {
+                let mut damaged = vec![];
+
+                for object in index.objects.iter_mut().filter(|o| !o.replaced) {
+                    let valid = Section::new(volumes.reader(), object.offset, object.length)
+                        .map(CrcReader::new)
+                        .and_then(|mut reader| copy(&mut reader, &mut io::sink()).map(|_| reader.crc32()));
+
+                    let reason = match valid {
+                        Ok(crc32) if crc32 == object.crc32 => continue,
+                        Ok(_) => "failed its checksum".to_string(),
+                        Err(e) => e.to_string(),
+                    };
+
+                    damaged.push((object.name.clone(), reason));
+                    object.replaced = true;
+                }
+
+                (index, damaged, true)
+            }
+            Err(e) if metadata_key.is_some() => {
+                return Err(ArchiveError::Corrupt(format!(
+                    "{}, archives with encrypted metadata can't be scanned instead",
+                    e
+                )))
+            }
+            Err(_) => {
+                let scan = format::scan_objects(&mut io, len)?;
+
+                // Appending writes the new version of an object after
+                // the old one
+                let mut latest = HashMap::new();
+                let mut index = Index::default();
+
+                for object in scan.objects {
+                    if let Some(i) = latest.insert(object.name.clone(), index.objects.len()) {
+                        index.objects[i].replaced = true;
+                    }
+
+                    index.objects.push(object);
+                }
+
+                (index, scan.damaged, false)
+            }
+        };
+
+        drop(io);
+
+        Ok(Salvage {
+            archive: Archive::new(path, volumes, len, header, index, metadata_key, options),
+            damaged,
+            index_found,
+        })
+    }
+
+    /// Writes every file that decodes and passes its checks below
+    /// `output`. Files that fail are removed again and listed in the
+    /// report.
+    pub fn extract_to<P>(mut self, output: P) -> Result<SalvageReport, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let archive = &mut self.archive;
+
+        let mut report = SalvageReport {
+            damaged: self.damaged,
+            index_found: self.index_found,
+            ..SalvageReport::default()
+        };
+
+        // Without their index, files packed in solid blocks can't be
+        // told apart
+        let solid_index = format::name_of(&solid::index_name());
+
+        if let Some(i) = archive.objects.get(&solid_index).copied() {
+            if let Err(e) = archive.decode_object(&solid_index) {
+                report.lost.push(("Files packed in solid blocks".to_string(), e.to_string()));
+
+                archive.index.objects[i].replaced = true;
+                archive.objects.remove(&solid_index);
+            }
+        }
+
+        // Objects that held a file on their own, the others are
+        // reported through the files that needed them
+        for (name, reason) in report.damaged.iter() {
+            if let Some((path, _)) = entry_of(name) {
+                if archive.entry(&path).is_err() {
+                    report.lost.push((path.display().to_string(), reason.clone()));
+                }
+            }
+        }
+
+        for entry in archive.entries()? {
+            let output_path = output.as_ref().join(entry.path());
+
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let result = entry
+                .reader()
+                .and_then(|mut reader| Ok(copy(&mut reader, &mut File::create(&output_path)?)?));
+
+            match result {
+                Ok(_) => report.recovered += 1,
+                Err(e) => {
+                    let _ = fs::remove_file(&output_path);
+                    report.lost.push((entry.path().display().to_string(), e.to_string()));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::archive::{
+        test_util::{create, data, flip},
+        EntryMetadata,
+    };
+
+    /// An archive of three entries, with the offset and length of the
+    /// object holding "b".
+    fn three_entries(dir: &TempDir) -> (PathBuf, u64, u64) {
+        let path = dir.path().join("a.zap");
+
+        create(&path, ArchiveOptions::new(), |w| {
+            w.add_bytes("a", &data(50_000, 1), EntryMetadata::default()).unwrap();
+            w.add_bytes("b", &data(50_000, 2), EntryMetadata::default()).unwrap();
+            w.add_bytes("c", &data(50_000, 3), EntryMetadata::default()).unwrap();
+        });
+
+        let archive = Archive::open(&path).unwrap();
+        let object = archive.object("b.lz4").unwrap();
+
+        (path, object.offset, object.length)
+    }
+
+    #[test]
+    fn damaged_object_with_index() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("output");
+        let (path, offset, length) = three_entries(&dir);
+
+        flip(&path, offset + length / 2);
+
+        let report = Salvage::scan(&path, ArchiveOptions::new()).unwrap().extract_to(&output).unwrap();
+
+        assert!(report.index_found);
+        assert_eq!(report.recovered, 2);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.lost.iter().map(|(path, _)| path.as_str()).collect::<Vec<_>>(), ["b"]);
+        assert_eq!(fs::read(output.join("a")).unwrap(), data(50_000, 1));
+        assert_eq!(fs::read(output.join("c")).unwrap(), data(50_000, 3));
+        assert!(!output.join("b").exists());
+    }
+
+    #[test]
+    fn truncated_archive_is_scanned() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("output");
+        let (path, offset, length) = three_entries(&dir);
+
+        // Cut short in the middle of "b", the index is gone
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(offset + length / 2).unwrap();
+
+        assert!(Archive::open(&path).is_err());
+
+        let report = Salvage::scan(&path, ArchiveOptions::new()).unwrap().extract_to(&output).unwrap();
+
+        assert!(!report.index_found);
+        assert_eq!(report.recovered, 1);
+        assert_eq!(fs::read(output.join("a")).unwrap(), data(50_000, 1));
+        assert!(!output.join("b").exists());
+        assert!(!output.join("c").exists());
+    }
+}
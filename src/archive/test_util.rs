@@ -0,0 +1,45 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use super::{writer::ArchiveWriter, Archive};
+use crate::{encryption::EncryptionSecret, error::ArchiveError, options::ArchiveOptions};
+
+/// `len` bytes that differ with `seed`.
+pub(crate) fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 % 251) as u8 ^ seed).collect()
+}
+
+/// Writes an archive to `path` holding what `add` adds.
+pub(crate) fn create(path: &Path, options: ArchiveOptions, add: impl FnOnce(&mut ArchiveWriter<File>)) {
+    let mut writer = ArchiveWriter::create(File::create(path).unwrap(), options).unwrap();
+    add(&mut writer);
+    writer.finish().unwrap();
+}
+
+pub(crate) fn read(archive: &Archive, name: &str) -> Result<Vec<u8>, ArchiveError> {
+    let mut content = vec![];
+    archive.entry(name)?.reader()?.read_to_end(&mut content)?;
+
+    Ok(content)
+}
+
+/// Opens the archive at `path` with `secret` to read the entry `name`.
+pub(crate) fn read_from(path: &Path, secret: EncryptionSecret, name: &str) -> Result<Vec<u8>, ArchiveError> {
+    let archive = Archive::open_with_options(path, ArchiveOptions::new().with_encryption_secret(secret))?;
+
+    read(&archive, name)
+}
+
+/// Inverts the byte at `offset` of the file at `path`.
+pub(crate) fn flip(path: &Path, offset: u64) {
+    let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+    let mut byte = [0u8];
+
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[byte[0] ^ 0xff]).unwrap();
+}
@@ -0,0 +1,466 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::error::ArchiveError;
+
+use super::format;
+
+/*
+    A split archive is the bytes of a single archive cut into volumes
+    of a fixed size, written as `<path>.001`, `<path>.002` and so on.
+    Only the last volume can be shorter. Nothing is added to the
+    volumes themselves, the header of the first one records the
+    volume size and the index is kept in the last one.
+*/
+
+/// Writes an archive as volumes of `size` bytes, for media or uploads
+/// with a size limit. The same size has to be given to the archive
+/// options with `with_volume_size`, so the index isn't split between
+/// two volumes.
+pub struct VolumeWriter {
+    base: PathBuf,
+    size: u64,
+    count: usize,
+    current: Option<BufWriter<File>>,
+    /// Bytes written to the current volume.
+    written: u64,
+}
+
+impl VolumeWriter {
+    /// Volumes are created as they are needed, next to `base`.
+    pub fn create<P>(base: P, size: u64) -> Result<VolumeWriter, Error>
+    where
+        P: AsRef<Path>,
+    {
+        if size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Volume size can't be 0"));
+        }
+
+        Ok(VolumeWriter {
+            base: base.as_ref().to_path_buf(),
+            size,
+            count: 0,
+            current: None,
+            written: 0,
+        })
+    }
+
+    /// Flushes the last volume and removes the volumes left over from
+    /// an earlier, longer archive with the same name, which would
+    /// otherwise be read as part of this one. Returns the number of
+    /// volumes written.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        if let Some(mut current) = self.current.take() {
+            current.flush()?;
+        }
+
+        let mut stale = self.count + 1;
+
+        while volume_path(&self.base, stale).exists() {
+            fs::remove_file(volume_path(&self.base, stale))?;
+            stale += 1;
+        }
+
+        Ok(self.count)
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let current = match self.current.as_mut() {
+            Some(current) if self.written < self.size => current,
+            _ => {
+                if let Some(mut full) = self.current.take() {
+                    full.flush()?;
+                }
+
+                self.count += 1;
+                self.written = 0;
+                self.current.insert(BufWriter::new(File::create(volume_path(&self.base, self.count))?))
+            }
+        };
+
+        let max = buf.len().min((self.size - self.written) as usize);
+        let len = current.write(&buf[..max])?;
+
+        self.written += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.current.as_mut() {
+            Some(current) => current.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The files an archive is stored in, a single one unless it is split.
+pub(crate) struct Volumes {
+    /// Path and length of each volume.
+    volumes: Vec<(PathBuf, u64)>,
+    split: bool,
+}
+
+impl Volumes {
+    /// Finds the volumes of the archive at `path`, which is either a
+    /// single file, a split archive's name without the volume number
+    /// or any of its volumes.
+    pub fn open(path: &Path) -> Result<Volumes, ArchiveError> {
+        let base = match split_base(path) {
+            // A single archive whose name happens to end like a volume's
+            Some(_) if path.is_file() && format::is_whole_archive(&mut File::open(path)?)? => {
+                return Volumes::single(path)
+            }
+            Some(base) => base,
+            None if path.exists() || !volume_path(path, 1).exists() => return Volumes::single(path),
+            None => path.to_path_buf(),
+        };
+
+        let mut volumes = vec![];
+
+        loop {
+            let path = volume_path(&base, volumes.len() + 1);
+
+            match fs::metadata(&path) {
+                Ok(metadata) => volumes.push((path, metadata.len())),
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // A gap means everything after it would be read at the wrong
+        // offset
+        let last = volume_numbers(&base)?.last().copied().unwrap_or(0);
+
+        if volumes.is_empty() || last > volumes.len() {
+            return Err(ArchiveError::MissingVolume(
+                volume_path(&base, volumes.len() + 1).display().to_string(),
+            ));
+        }
+
+        Ok(Volumes { volumes, split: true })
+    }
+
+    fn single(path: &Path) -> Result<Volumes, ArchiveError> {
+        let len = fs::metadata(path)?.len();
+
+        Ok(Volumes {
+            volumes: vec![(path.to_path_buf(), len)],
+            split: false,
+        })
+    }
+
+    /// Like `open`, but a split archive is made of whichever of its
+    /// volumes are there, for salvaging what they hold.
+    pub fn open_present(path: &Path) -> Result<Volumes, ArchiveError> {
+        let base = match Volumes::open(path) {
+            Err(ArchiveError::MissingVolume(_)) => split_base(path).unwrap_or_else(|| path.to_path_buf()),
+            volumes => return volumes,
+        };
+
+        let mut volumes = vec![];
+
+        for number in volume_numbers(&base)? {
+            let path = volume_path(&base, number);
+            let len = fs::metadata(&path)?.len();
+
+            volumes.push((path, len));
+        }
+
+        if volumes.is_empty() {
+            return Err(ArchiveError::MissingVolume(volume_path(&base, 1).display().to_string()));
+        }
+
+        Ok(Volumes { volumes, split: true })
+    }
+
+    pub fn is_split(&self) -> bool {
+        self.split
+    }
+
+    /// Path of the first volume, the one holding the header.
+    pub fn first(&self) -> &Path {
+        &self.volumes[0].0
+    }
+
+    /// Fails unless every volume but the last is `size` bytes long.
+    pub fn check_size(&self, size: u64) -> Result<(), ArchiveError> {
+        let (_, init) = self.volumes.split_last().unwrap();
+
+        for (path, len) in init.iter() {
+            if *len != size {
+                return Err(ArchiveError::Corrupt(format!(
+                    "{} is {} bytes, volumes are {} bytes",
+                    path.display(),
+                    len,
+                    size
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Path of the volume that would follow the last one found, if the
+    /// last one is full and so might not really be the last.
+    pub fn next_missing(&self, size: u64) -> Option<PathBuf> {
+        let (path, len) = self.volumes.last().unwrap();
+
+        match self.split && *len == size {
+            true => Some(next_volume_path(path)),
+            false => None,
+        }
+    }
+
+    /// Overwrites the bytes at `offset` with `data`, across volumes. The
+    /// data is on disk once this returns.
+    pub fn write_at(&self, mut offset: u64, mut data: &[u8]) -> Result<(), Error> {
+        let mut start = 0;
+
+        for (path, len) in self.volumes.iter() {
+            if !data.is_empty() && offset < start + len {
+                let max = data.len().min((start + len - offset) as usize);
+
+                let mut file = OpenOptions::new().write(true).open(path)?;
+                file.seek(SeekFrom::Start(offset - start))?;
+                file.write_all(&data[..max])?;
+                file.sync_data()?;
+
+                data = &data[max..];
+                offset += max as u64;
+            }
+
+            start += len;
+        }
+
+        match data.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(ErrorKind::UnexpectedEof, "Write past the end of the archive")),
+        }
+    }
+
+    pub fn reader(&self) -> VolumeReader<'_> {
+        VolumeReader {
+            volumes: self,
+            current: None,
+            pos: 0,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.volumes.iter().map(|(_, len)| len).sum()
+    }
+}
+
+/// Reads the volumes of an archive as if they were one file, opening
+/// them as they are reached.
+pub(crate) struct VolumeReader<'a> {
+    volumes: &'a Volumes,
+    /// Index and open file of the volume `pos` was last in.
+    current: Option<(usize, File)>,
+    pos: u64,
+}
+
+impl Read for VolumeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut start = 0;
+
+        for (i, (path, len)) in self.volumes.volumes.iter().enumerate() {
+            if self.pos >= start + len {
+                start += len;
+                continue;
+            }
+
+            let offset = self.pos - start;
+
+            let file = match &mut self.current {
+                Some((current, file)) if *current == i => file,
+                _ => {
+                    let mut file = File::open(path)?;
+                    file.seek(SeekFrom::Start(offset))?;
+
+                    &mut self.current.insert((i, file)).1
+                }
+            };
+
+            let max = buf.len().min((len - offset) as usize);
+            let read = file.read(&mut buf[..max])?;
+
+            self.pos += read as u64;
+
+            return Ok(read);
+        }
+
+        Ok(0)
+    }
+}
+
+impl Seek for VolumeReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.volumes.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        let pos = match pos {
+            Some(pos) => pos,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Seek before start of archive")),
+        };
+
+        // Keep the open volume positioned where reading continues
+        if pos != self.pos {
+            if let Some((i, file)) = &mut self.current {
+                let start: u64 = self.volumes.volumes[..*i].iter().map(|(_, len)| len).sum();
+
+                match pos.checked_sub(start) {
+                    Some(offset) if offset < self.volumes.volumes[*i].1 => {
+                        file.seek(SeekFrom::Start(offset))?;
+                    }
+                    _ => self.current = None,
+                }
+            }
+        }
+
+        self.pos = pos;
+
+        Ok(pos)
+    }
+}
+
+/// `<base>.<number>`, with the number padded to three digits.
+pub(crate) fn volume_path(base: &Path, number: usize) -> PathBuf {
+    let mut path = OsString::from(base.as_os_str());
+    path.push(format!(".{:03}", number));
+
+    PathBuf::from(path)
+}
+
+fn next_volume_path(path: &Path) -> PathBuf {
+    let base = path.with_extension("");
+    let number = volume_number(path).unwrap_or(0);
+
+    volume_path(&base, number + 1)
+}
+
+/// The archive name without the volume number, if `path` is a volume.
+fn split_base(path: &Path) -> Option<PathBuf> {
+    volume_number(path)?;
+
+    Some(path.with_extension(""))
+}
+
+fn volume_number(path: &Path) -> Option<usize> {
+    let extension = path.extension()?.to_str()?;
+
+    match extension.len() >= 3 && extension.bytes().all(|b| b.is_ascii_digit()) {
+        true => extension.parse().ok().filter(|n| *n > 0),
+        false => None,
+    }
+}
+
+/// Numbers of the volumes found next to `base`, sorted.
+fn volume_numbers(base: &Path) -> Result<Vec<usize>, Error> {
+    let parent = match base.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let name = base.file_name().unwrap_or_default();
+    let mut numbers = vec![];
+
+    for entry in fs::read_dir(parent)? {
+        let path = entry?.path();
+
+        if path.with_extension("").file_name() == Some(name) {
+            numbers.extend(volume_number(&path));
+        }
+    }
+
+    numbers.sort_unstable();
+
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::{
+        archive::{writer::ArchiveWriter, Archive, EntryMetadata},
+        options::ArchiveOptions,
+    };
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 253) as u8).collect()
+    }
+
+    fn write<W: Write + Send>(writer: W, options: ArchiveOptions) -> W {
+        let mut archive = ArchiveWriter::create(writer, options).unwrap();
+        archive.add_bytes("a", &data(20_000), EntryMetadata::default()).unwrap();
+        archive.add_bytes("b", b"bee", EntryMetadata::default()).unwrap();
+
+        archive.finish().unwrap()
+    }
+
+    fn read(archive: &Archive, name: &str) -> Vec<u8> {
+        let mut content = vec![];
+        archive.entry(name).unwrap().reader().unwrap().read_to_end(&mut content).unwrap();
+
+        content
+    }
+
+    #[test]
+    fn split_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("split.zap");
+
+        let options = ArchiveOptions::new().with_volume_size(Some(4096));
+        let volumes = write(VolumeWriter::create(&base, 4096).unwrap(), options);
+        assert!(volumes.finish().unwrap() > 1);
+
+        for path in [base.clone(), volume_path(&base, 1), volume_path(&base, 2)] {
+            let archive = Archive::open(&path).unwrap();
+
+            assert!(archive.is_split());
+            assert_eq!(read(&archive, "a"), data(20_000));
+            assert_eq!(read(&archive, "b"), b"bee");
+        }
+    }
+
+    #[test]
+    fn missing_volume_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("split.zap");
+
+        let options = ArchiveOptions::new().with_volume_size(Some(4096));
+        write(VolumeWriter::create(&base, 4096).unwrap(), options).finish().unwrap();
+        fs::remove_file(volume_path(&base, 2)).unwrap();
+
+        assert!(matches!(Archive::open(&base), Err(ArchiveError::MissingVolume(_))));
+    }
+
+    #[test]
+    fn single_archive_named_like_a_volume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backup.001");
+
+        write(File::create(&path).unwrap(), ArchiveOptions::new());
+
+        let archive = Archive::open(&path).unwrap();
+
+        assert!(!archive.is_split());
+        assert_eq!(read(&archive, "a"), data(20_000));
+    }
+}
@@ -0,0 +1,614 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Error, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use walkdir::WalkDir;
+
+use crate::{
+    dedup::{self, CHUNK_DIR},
+    error::ArchiveError,
+    options::ArchiveOptions,
+    pipeline::ProcessingPipeline,
+    solid::{self, SOLID_DIR},
+};
+
+use super::{
+    format::{self, ContainerWriter, Header},
+    recovery, content_hash, Archive, ArchiveEntry, EntryMetadata, EntryRecord, EntrySource, HashingReader,
+};
+
+/// Builds an archive entry by entry, without going through a folder
+/// first. The result is read with `Archive` like any other archive.
+///
+/// Entries go through the stages set in the options, with the seekable
+/// framing when a block size is set. Deduplication and solid blocks
+/// are only done by `compress_directory`.
+pub struct ArchiveWriter<W> {
+    container: ContainerWriter<W>,
+    pipeline: ProcessingPipeline,
+    names: HashSet<String>,
+    /// The archive being appended to.
+    existing: Option<Archive>,
+    /// Objects of `existing` that were replaced so far.
+    replaced: HashSet<String>,
+    /// Entries of `existing` packed in solid blocks that were replaced
+    /// so far.
+    replaced_solid: HashSet<PathBuf>,
+    /// Files of the archive an incremental one is made since.
+    base: Option<BTreeMap<String, EntryMetadata>>,
+    /// Key the hashes in `base` are made with.
+    base_key: Option<[u8; 32]>,
+    /// Files of `base` found unchanged so far.
+    unchanged: HashSet<String>,
+    // Declared last so the container is flushed before truncating
+    rollback: Option<Rollback>,
+}
+
+impl<W> ArchiveWriter<W>
+where
+    W: Write + Send,
+{
+    /// Writes the archive header to `writer`.
+    pub fn create(writer: W, options: ArchiveOptions) -> Result<ArchiveWriter<W>, ArchiveError> {
+        let mut header = Header::new(options.stages());
+        header.volume_size = options.volume_size;
+        header.key_slots = options.key_slots();
+
+        ArchiveWriter::with_header(writer, options, header)
+    }
+
+    /// Like `create`, for an incremental archive holding the changes
+    /// since `previous`. Files are added with `update_path`, which
+    /// only stores the ones that changed. Files of `previous` that
+    /// weren't found again are recorded as deleted by `finish`.
+    pub fn create_since(
+        writer: W,
+        options: ArchiveOptions,
+        previous: &Archive,
+    ) -> Result<ArchiveWriter<W>, ArchiveError> {
+        let since = match previous.id() {
+            Some(id) => id.to_string(),
+            None => {
+                return Err(ArchiveError::BrokenChain(format!(
+                    "{} has no id, it was written by an older version",
+                    previous.path.display()
+                )))
+            }
+        };
+
+        let mut header = Header::new(options.stages());
+        header.since = Some(since);
+        header.volume_size = options.volume_size;
+        header.key_slots = options.key_slots();
+
+        let mut base = previous.index.entries.clone();
+
+        // Files listed without being stored anywhere can't be restored,
+        // so they count as changed. Those an incremental archive lists
+        // without storing them were stored before it in the chain.
+        if previous.since().is_none() {
+            let stored: HashSet<String> = previous
+                .entry_records()?
+                .iter()
+                .map(|record| format::name_of(&record.path))
+                .collect();
+
+            base.retain(|key, _| stored.contains(key));
+        }
+
+        // Hashes of encrypted entries can't be checked without the key
+        // they were made with, so those files count as changed
+        let base_key = previous.content_key();
+
+        if previous.is_encrypted() && base_key.is_none() {
+            base.values_mut().for_each(|metadata| metadata.hash = None);
+        }
+
+        let mut writer = ArchiveWriter::with_header(writer, options, header)?;
+        writer.base = Some(base);
+        writer.base_key = base_key;
+
+        Ok(writer)
+    }
+
+    fn with_header(writer: W, options: ArchiveOptions, header: Header) -> Result<ArchiveWriter<W>, ArchiveError> {
+        let container = ContainerWriter::new(writer, &header, options.parity, options.metadata_key()?)?;
+        let content_key = options.content_key();
+
+        let pipeline = ProcessingPipeline::new()
+            .with_compression(Arc::new(options.compression))
+            .with_compression_level(Arc::new(options.compression_level))
+            .with_encryption(Arc::new(options.encryption))
+            .with_encryption_secret(Arc::new(options.encryption_secret))
+            .with_padding(options.padding)
+            .with_signing(Arc::new(options.signing))
+            .with_registry(options.registry)
+            .with_stages(options.stages)
+            .with_block_size(options.block_size)
+            .with_content_key(content_key)
+            .with_progress(options.progress)
+            .with_cancellation(options.cancellation);
+
+        Ok(ArchiveWriter {
+            container,
+            pipeline,
+            names: HashSet::new(),
+            existing: None,
+            replaced: HashSet::new(),
+            replaced_solid: HashSet::new(),
+            base: None,
+            base_key: None,
+            unchanged: HashSet::new(),
+            rollback: None,
+        })
+    }
+
+    /// Adds the file at `path` as `name`. A directory is added with
+    /// every file below it, stored under `name`.
+    pub fn add_path<N, P>(&mut self, name: N, path: P) -> Result<(), ArchiveError>
+    where
+        N: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        self.add_tree(name.as_ref(), path.as_ref(), false).map(|_| ())
+    }
+
+    /// Like `add_path`, but skips files whose size, modification time
+    /// and content hash match the entry they would replace. Returns the
+    /// number of files added.
+    pub fn update_path<N, P>(&mut self, name: N, path: P) -> Result<usize, ArchiveError>
+    where
+        N: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        self.add_tree(name.as_ref(), path.as_ref(), true)
+    }
+
+    /// Adds `data` as `name`. The size and hash in `metadata` are always
+    /// set from `data`.
+    pub fn add_bytes<N>(&mut self, name: N, data: &[u8], metadata: EntryMetadata) -> Result<(), ArchiveError>
+    where
+        N: AsRef<Path>,
+    {
+        self.add_entry(name.as_ref(), data, metadata)
+    }
+
+    /// Adds everything read from `reader` as `name`.
+    pub fn add_reader<N, R>(&mut self, name: N, reader: R) -> Result<(), ArchiveError>
+    where
+        N: AsRef<Path>,
+        R: Read,
+    {
+        self.add_entry(name.as_ref(), reader, EntryMetadata::default())
+    }
+
+    /// Removes the entry stored as `name`, or every entry below it when
+    /// `name` is a folder. Returns the number of entries removed.
+    pub fn remove<N>(&mut self, name: N) -> Result<usize, ArchiveError>
+    where
+        N: AsRef<Path>,
+    {
+        let path = PathBuf::from(format::name_of(name.as_ref()));
+
+        let existing: Vec<PathBuf> = match &self.existing {
+            Some(existing) => existing
+                .entry_records()?
+                .iter()
+                .filter(|r| r.path.starts_with(&path) && !self.names.contains(&format::name_of(&r.path)))
+                .map(|r| r.path.clone())
+                .collect(),
+            None => vec![],
+        };
+
+        let added: Vec<String> = self
+            .names
+            .iter()
+            .filter(|key| Path::new(key).starts_with(&path))
+            .cloned()
+            .collect();
+
+        for key in added.iter() {
+            self.container.mark_replaced(&format!("{}.{}", key, self.pipeline.stream_ext()));
+            self.container.remove_metadata(key);
+            self.names.remove(key);
+        }
+
+        for path in existing.iter() {
+            self.retire_entry(path, None)?;
+            self.container.remove_metadata(&format::name_of(path));
+        }
+
+        match added.len() + existing.len() {
+            0 => Err(ArchiveError::EntryNotFound(name.as_ref().display().to_string())),
+            removed => Ok(removed),
+        }
+    }
+
+    /// Writes the index, the archive is complete once this returns.
+    pub fn finish(mut self) -> Result<W, ArchiveError> {
+        self.detach_references()?;
+        self.rewrite_solid_index()?;
+
+        if let Some(base) = &self.base {
+            for path in base.keys() {
+                if !self.names.contains(path) && !self.unchanged.contains(path) {
+                    self.container.add_tombstone(path.clone());
+                }
+            }
+        }
+
+        let ArchiveWriter {
+            container, rollback, ..
+        } = self;
+
+        let io = container.finish()?;
+
+        if let Some(rollback) = rollback {
+            rollback.commit()?;
+        }
+
+        Ok(io)
+    }
+
+    fn add_tree(&mut self, name: &Path, path: &Path, only_changed: bool) -> Result<usize, ArchiveError> {
+        let mut added = 0;
+
+        for entry in WalkDir::new(path).sort_by_file_name() {
+            let entry = entry.map_err(Error::from)?;
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let name = match entry.path().strip_prefix(path) {
+                Ok(relative) => name.join(relative),
+                Err(_) => continue,
+            };
+
+            let mut metadata = EntryMetadata::from_file(&entry.metadata().map_err(Error::from)?);
+
+            if only_changed && self.is_unchanged(&name, &mut metadata, entry.path())? {
+                // Incremental archives list every file, stored or not
+                if self.base.is_some() {
+                    let key = format::name_of(&name);
+
+                    self.container.set_metadata(key.clone(), metadata);
+                    self.unchanged.insert(key);
+                }
+
+                continue;
+            }
+
+            self.add_entry(&name, File::open(entry.path())?, metadata)?;
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    fn add_entry<R>(&mut self, name: &Path, reader: R, mut metadata: EntryMetadata) -> Result<(), ArchiveError>
+    where
+        R: Read,
+    {
+        let key = format::name_of(name);
+
+        // Names in these folders are reserved for `compress_directory`
+        if !format::is_valid_name(&key) || name.starts_with(CHUNK_DIR) || name.starts_with(SOLID_DIR) {
+            return Err(ArchiveError::InvalidName(name.display().to_string()));
+        }
+
+        if self.names.contains(&key) {
+            return Err(ArchiveError::DuplicateEntry(key));
+        }
+
+        let mut reader = HashingReader::new(reader, self.pipeline.content_key());
+        let pipeline = &self.pipeline;
+
+        pipeline.file_started(name);
+
+        // Same layout as `compress_directory`, the extension is dropped
+        // again when reading.
+        let object = format!("{}.{}", key, pipeline.stream_ext());
+
+        let result = self
+            .container
+            .add_object_with(&object, |writer| pipeline.encode_entry(&mut reader, writer));
+
+        pipeline.file_finished(name);
+
+        result?;
+
+        self.retire_entry(Path::new(&key), Some(&object))?;
+
+        reader.finish(&mut metadata);
+
+        self.container.set_metadata(key.clone(), metadata);
+        self.names.insert(key);
+
+        Ok(())
+    }
+
+    /// Marks whatever held the existing entry at `path` as replaced,
+    /// other than `object` if it now holds it.
+    fn retire_entry(&mut self, path: &Path, object: Option<&str>) -> Result<(), ArchiveError> {
+        let record = match existing_record(&self.existing, path)? {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+
+        match &record.source {
+            EntrySource::Stream(name) | EntrySource::Reference(name) | EntrySource::Chunked(name) => {
+                if Some(name.as_str()) != object {
+                    self.container.mark_replaced(name);
+                }
+
+                self.replaced.insert(name.clone());
+            }
+            EntrySource::Solid(entry) => {
+                self.replaced_solid.insert(entry.path.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compares the file at `file` against the entry stored as `path`.
+    /// The hash is only computed, and set in `metadata`, when the size
+    /// and modification time match.
+    fn is_unchanged(&self, path: &Path, metadata: &mut EntryMetadata, file: &Path) -> Result<bool, ArchiveError> {
+        let key = format::name_of(path);
+
+        let (previous, previous_key) = match &self.base {
+            Some(base) => (base.get(&key), self.base_key),
+            None => (
+                existing_record(&self.existing, Path::new(&key))?.map(|r| &r.metadata),
+                self.pipeline.content_key().copied(),
+            ),
+        };
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return Ok(false),
+        };
+
+        // Padded entries in an index that isn't encrypted have no size,
+        // the hash tells those apart
+        let resized = previous.size.is_some() && previous.size != metadata.size;
+
+        if resized || previous.modified.is_none() || previous.modified != metadata.modified {
+            return Ok(false);
+        }
+
+        // Without a hash there is nothing to tell a rewrite that kept
+        // the size and time apart from the same content
+        let hash = match &previous.hash {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+
+        // The hash to compare is made with the key of the archive it
+        // came from, the one kept with the key of this one
+        let mut reader = HashingReader::new(File::open(file)?, self.pipeline.content_key());
+        let unchanged = *hash == content_hash(&mut reader, previous_key.as_ref())?;
+
+        reader.finish(metadata);
+
+        Ok(unchanged)
+    }
+
+    /// References of the existing archive whose target was replaced
+    /// get their own copy of the old content, as they would point to
+    /// the new one otherwise.
+    fn detach_references(&mut self) -> Result<(), ArchiveError> {
+        let existing = match &self.existing {
+            Some(existing) if !self.replaced.is_empty() => existing,
+            _ => return Ok(()),
+        };
+
+        for record in existing.entry_records()? {
+            let name = match &record.source {
+                EntrySource::Reference(name) if !self.replaced.contains(name) => name,
+                _ => continue,
+            };
+
+            let target = format::name_of(&dedup::parse_reference(existing.decode_object(name)?)?);
+
+            if !self.replaced.contains(&target) {
+                continue;
+            }
+
+            let reader = ArchiveEntry {
+                archive: existing,
+                record,
+            }
+            .reader()?;
+
+            let pipeline = &self.pipeline;
+            let object = format!("{}.{}", format::name_of(&record.path), pipeline.stream_ext());
+
+            self.container
+                .add_object_with(&object, |writer| pipeline.encode_entry(reader, writer))?;
+
+            self.container.mark_replaced(name);
+        }
+
+        Ok(())
+    }
+
+    /// Writes a solid index without the replaced entries, their data
+    /// stays in the blocks until the archive is compacted.
+    fn rewrite_solid_index(&mut self) -> Result<(), ArchiveError> {
+        let existing = match &self.existing {
+            Some(existing) if !self.replaced_solid.is_empty() => existing,
+            _ => return Ok(()),
+        };
+
+        let entries: Vec<_> = existing
+            .entry_records()?
+            .iter()
+            .filter_map(|record| match &record.source {
+                EntrySource::Solid(entry) if !self.replaced_solid.contains(&entry.path) => Some(entry.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let index = solid::format_index(&entries);
+
+        let pipeline = self.pipeline.without_progress();
+
+        self.container
+            .add_object_with(&format::name_of(&solid::index_name()), |writer| {
+                pipeline.compress_stream(&index[..], writer)
+            })?;
+
+        Ok(())
+    }
+}
+
+impl ArchiveWriter<BufWriter<File>> {
+    /// Opens the archive at `path` to add, replace or remove entries.
+    /// Nothing already in the archive is rewritten, the data left
+    /// unused is dropped by `Archive::compact_to`.
+    ///
+    /// New entries go through the stages the archive was written with,
+    /// so only the encryption secret, stage registry, block size,
+    /// progress and cancellation are taken from `options`. If the
+    /// writer is dropped before `finish`, the archive is truncated back
+    /// to how it was.
+    ///
+    /// A recovery record is dropped as soon as the archive is opened,
+    /// and computed again once it is finished or rolled back. If the
+    /// process dies before either, the archive opens as it was before
+    /// but without parity until the next append that finishes, which
+    /// adds the record back with the parity kept in the index.
+    pub fn append<P>(path: P, options: ArchiveOptions) -> Result<ArchiveWriter<BufWriter<File>>, ArchiveError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let existing = Archive::open_with_options(
+            path,
+            ArchiveOptions {
+                encryption_secret: options.encryption_secret.clone(),
+                registry: options.registry.clone(),
+                ..ArchiveOptions::default()
+            },
+        )?;
+
+        if existing.is_split() {
+            return Err(ArchiveError::SplitArchive(path.display().to_string()));
+        }
+
+        // New entries would be unreadable with a different secret
+        if existing.is_encrypted() {
+            existing.check_secret()?;
+        }
+
+        // The record may be missing after an append that was cut short
+        let parity = existing.parity()?.or(existing.index.parity);
+
+        let mut io = OpenOptions::new().read(true).write(true).open(path)?;
+        let position = existing.len;
+
+        let rollback = Rollback {
+            io: io.try_clone()?,
+            len: position,
+            parity,
+            committed: false,
+        };
+
+        // New objects go where the recovery record was. The archive is
+        // synced first, so that its index is on disk before anything is
+        // written after it.
+        io.sync_all()?;
+        io.set_len(position)?;
+        io.seek(SeekFrom::Start(position))?;
+
+        let pipeline = ProcessingPipeline::new()
+            .with_compression_level(Arc::new(options.compression_level))
+            .with_encryption_secret(Arc::new(existing.entry_secret().clone()))
+            .with_registry(options.registry)
+            .with_stages(Some(Arc::new(existing.stages().to_vec())))
+            .with_block_size(options.block_size)
+            .with_content_key(existing.content_key())
+            .with_progress(options.progress)
+            .with_cancellation(options.cancellation);
+
+        Ok(ArchiveWriter {
+            container: ContainerWriter::resume(
+                BufWriter::new(io),
+                &existing.header,
+                existing.index.clone(),
+                position,
+                existing.metadata_key.clone(),
+            ),
+            pipeline,
+            names: HashSet::new(),
+            existing: Some(existing),
+            replaced: HashSet::new(),
+            replaced_solid: HashSet::new(),
+            base: None,
+            base_key: None,
+            unchanged: HashSet::new(),
+            rollback: Some(rollback),
+        })
+    }
+}
+
+fn existing_record<'a>(existing: &'a Option<Archive>, path: &Path) -> Result<Option<&'a EntryRecord>, ArchiveError> {
+    let records = match existing {
+        Some(existing) => existing.entry_records()?,
+        None => return Ok(None),
+    };
+
+    // Records are sorted by path
+    Ok(records
+        .binary_search_by(|record| record.path.as_path().cmp(path))
+        .ok()
+        .map(|i| &records[i]))
+}
+
+/// Truncates an archive that was being appended to back to its
+/// previous length, unless the new index was written. Either way, the
+/// archive gets its recovery record back.
+struct Rollback {
+    io: File,
+    len: u64,
+    /// Parity of the recovery record the archive had.
+    parity: Option<u8>,
+    committed: bool,
+}
+
+impl Rollback {
+    fn commit(mut self) -> Result<(), Error> {
+        self.committed = true;
+
+        // The new index has to be on disk before a recovery record
+        // covers it
+        self.io.sync_all()?;
+
+        match self.parity {
+            Some(parity) => recovery::add_record(&mut self.io, parity),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        let _ = self.io.set_len(self.len);
+
+        if let Some(parity) = self.parity {
+            let _ = recovery::add_record(&mut self.io, parity);
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use clap::ValueEnum;
 use simple_logger::SimpleLogger;
 
-pub fn init_logger(level: Verbosity) -> Result<(), log::SetLoggerError> {
+pub fn init_logger(level: &Verbosity) -> Result<(), log::SetLoggerError> {
     let level = match level {
         Verbosity::Quiet => log::LevelFilter::Off,
         Verbosity::Normal => log::LevelFilter::Error,
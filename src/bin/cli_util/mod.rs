@@ -2,26 +2,52 @@ mod compression;
 mod encryption;
 mod logging;
 mod password;
+mod progress;
 
 use std::{
-    fs::{self, File},
-    io::BufWriter,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    num::NonZeroUsize,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use clap::{Parser, Subcommand};
 
 use log::info;
-use zap::{encryption::EncryptionSecret, error::ZapError};
+use zap::{
+    archive::{
+        legacy, pack_directory, recovery, rekey::Rekey, restore_chain, salvage::Salvage, volume::VolumeWriter, writer::ArchiveWriter,
+        Archive,
+    },
+    dedup::DedupMode,
+    compression::CompressionType,
+    encryption::{
+        keys::{FileKey, Identity, Recipient},
+        padding::Padding,
+        EncryptionSecret, EncryptionType,
+    },
+    error::{ArchiveError, EncryptionKeyError, RepositoryError, ZapError},
+    options::{ArchiveOptions, ErrorPolicy, Parallelism},
+    pipeline::seekable::DEFAULT_BLOCK_SIZE,
+    repository::Repository,
+};
 
-use zapf::{pack_files, unpack_files};
+use tempfile::TempDir;
+use walkdir::WalkDir;
 
-use crate::cli_util::{logging::init_logger, password::get_password_confirm};
+use crate::cli_util::{
+    logging::init_logger,
+    password::{get_new_password, get_password_confirm},
+};
 
 use self::{
     compression::{BinCompressionType, CompressionLevel},
     encryption::BinEncryptionType,
     logging::Verbosity,
     password::get_password_noconf,
+    progress::ProgressBarObserver,
 };
 
 #[derive(Debug, Parser)]
@@ -56,7 +82,7 @@ enum Command {
         /// Compress using default algorithm (Lz4)
         #[arg(short, long)]
         compress: bool,
-        /// Path to private key file (not currently supported)
+        /// Path to a private key file from [zap keygen], to encrypt for its public key
         #[arg(short, long)]
         keypath: Option<String>,
         /// Output verbosity
@@ -71,6 +97,48 @@ enum Command {
         /// Compression level when using [--compression_algorithm gzip]
         #[arg(long, default_value = "fastest")]
         compression_level: CompressionLevel,
+        /// Store files as independent blocks so they can be read with random access
+        #[arg(long)]
+        seekable: bool,
+        /// Block size in bytes when using [--seekable]
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+        seekable_block_size: usize,
+        /// Store duplicated content once using content-defined chunking
+        #[arg(long)]
+        dedup: bool,
+        /// Store identical files once, a lighter alternative to [--dedup]
+        #[arg(long, conflicts_with = "dedup")]
+        dedup_files: bool,
+        /// Pack small files together into shared blocks before compressing
+        #[arg(long)]
+        solid: bool,
+        /// Solid block size in bytes when using [--solid]
+        #[arg(long, default_value_t = 4 * 1024 * 1024)]
+        solid_block_size: usize,
+        /// Stop at the first file that fails instead of archiving the rest
+        #[arg(long)]
+        fail_fast: bool,
+        /// Number of worker threads (defaults to one per core)
+        #[arg(long)]
+        threads: Option<NonZeroUsize>,
+        /// Only store what changed since this archive, for use with [zap restore]
+        #[arg(long, conflicts_with_all = ["dedup", "dedup_files", "solid"])]
+        since: Option<String>,
+        /// Split the archive into volumes of this size (eg. 700M, 4G), written as OUTPUT.001, OUTPUT.002...
+        #[arg(long, value_parser = parse_size)]
+        volume_size: Option<u64>,
+        /// Add parity data of this many percent of the archive size, to fix damage with [zap repair]
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+        parity: Option<u8>,
+        /// Encrypt for this public key from [zap keygen] instead of a password, can be repeated
+        #[arg(short, long, value_parser = parse_recipient)]
+        recipient: Vec<Recipient>,
+        /// Also encrypt file names, sizes and other metadata, so listing the archive needs the password or key
+        #[arg(long)]
+        encrypt_metadata: bool,
+        /// Pad encrypted files to hide their exact size, adding at most 12% to it. Implies --encrypt-metadata
+        #[arg(long)]
+        pad: bool,
     },
     /// Extract an archive
     Extract {
@@ -78,28 +146,198 @@ enum Command {
         input: String,
         /// Output folder
         output: String,
-        #[arg(short, long)]
-        /// Decrypt using default algorithm (XChaChaPoly1305)
+        // The algorithms are read from the archive, these are only
+        // needed for zapf archives from before the container format.
+        #[arg(short, long, hide = true)]
         encrypt: bool,
-        #[arg(short, long)]
-        /// Decompress using default algorithm (Lz4)
+        #[arg(short, long, hide = true)]
         compress: bool,
-        /// Path to private key file (not currently supported)
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
         #[arg(short, long)]
         keypath: Option<String>,
         /// Output verbosity
         #[arg(short, long, default_value = "normal")]
         verbosity: Verbosity,
-        /// Override encryption algorithm used
-        #[arg(long, default_value = "passthrough")]
-        encryption_algorithm: BinEncryptionType,
-        /// Override compression algorithm used
-        #[arg(long, default_value = "passthrough")]
-        compression_algorithm: BinCompressionType,
+        #[arg(long, hide = true)]
+        encryption_algorithm: Option<BinEncryptionType>,
+        #[arg(long, hide = true)]
+        compression_algorithm: Option<BinCompressionType>,
+        /// Stop at the first file that fails instead of extracting the rest
+        #[arg(long)]
+        fail_fast: bool,
+        /// Number of worker threads (defaults to one per core)
+        #[arg(long)]
+        threads: Option<NonZeroUsize>,
+        /// Extract whatever can still be read from a damaged or cut short archive,
+        /// and write a report of what was lost next to the output folder
+        #[arg(long, conflicts_with_all = ["fail_fast", "threads"])]
+        salvage: bool,
+    },
+    /// Restore a full archive followed by the incremental archives made since it,
+    /// or a snapshot with [--repo]
+    Restore {
+        /// Archives of the chain, oldest first, or the snapshot id with [--repo]
+        #[arg(required = true)]
+        archives: Vec<String>,
+        /// Output folder
+        #[arg(short, long)]
+        output: String,
+        /// Repository to restore the snapshot from, repositories are only encrypted with a password
+        #[arg(long, conflicts_with = "keypath")]
+        repo: Option<String>,
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
+        #[arg(short, long)]
+        keypath: Option<String>,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Take a snapshot of a folder into a repository, creating it if needed
+    Snapshot {
+        /// Input folder
+        input: String,
+        /// Repository folder
+        repo: String,
+        /// Encrypt using default algorithm (XChaChaPoly1305), when creating the repository
+        #[arg(short, long)]
+        encrypt: bool,
+        /// Compress using default algorithm (Lz4), when creating the repository
+        #[arg(short, long)]
+        compress: bool,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// List the snapshots of a repository
+    Snapshots {
+        repo: String,
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Remove snapshots from a repository, their data is only freed by [zap prune]
+    Forget {
+        repo: String,
+        /// Keep the latest snapshot of each of the last N days with snapshots
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        keep_daily: u64,
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Remove the data no snapshot of a repository uses anymore
+    Prune {
+        repo: String,
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
     },
     /// List contents of an archive
     List {
         archive: String,
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
+        #[arg(short, long)]
+        keypath: Option<String>,
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Add files to an archive, replacing entries with the same path
+    Add {
+        archive: String,
+        /// Files or folders to add, stored under the relative path given
+        /// (or their name, for absolute paths)
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
+        #[arg(short, long)]
+        keypath: Option<String>,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+        /// Store files as independent blocks so they can be read with random access
+        #[arg(long)]
+        seekable: bool,
+        /// Block size in bytes when using [--seekable]
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+        seekable_block_size: usize,
+    },
+    /// Remove files or folders from an archive
+    Remove {
+        archive: String,
+        /// Paths of the entries to remove
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
+        #[arg(short, long)]
+        keypath: Option<String>,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Rewrite an archive without the data of replaced or removed entries
+    Compact {
+        archive: String,
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
+        #[arg(short, long)]
+        keypath: Option<String>,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Add new and changed files of the folder an archive was created from
+    Update {
+        archive: String,
+        /// Input folder
+        input: String,
+        /// Path to a private key file from [zap keygen], for archives encrypted for its public key
+        #[arg(short, long)]
+        keypath: Option<String>,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+        /// Store files as independent blocks so they can be read with random access
+        #[arg(long)]
+        seekable: bool,
+        /// Block size in bytes when using [--seekable]
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+        seekable_block_size: usize,
+    },
+    /// Fix damaged parts of an archive from the parity data added with [--parity]
+    Repair {
+        archive: String,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Add, change or remove the passwords and public keys an archive can be opened with,
+    /// by rewriting only its header
+    Rekey {
+        archive: String,
+        /// Path to a private key file from [zap keygen] to open the archive with,
+        /// instead of asking for its password
+        #[arg(short, long)]
+        keypath: Option<String>,
+        /// Add a password, which is asked for
+        #[arg(long)]
+        add_password: bool,
+        /// Replace the password the archive is opened with by a new one
+        #[arg(long, conflicts_with_all = ["keypath", "add_password", "remove"])]
+        change_password: bool,
+        /// Add a public key from [zap keygen], can be repeated
+        #[arg(long, value_parser = parse_recipient)]
+        add_recipient: Vec<Recipient>,
+        /// Remove the password or key file the archive is opened with
+        #[arg(long)]
+        remove: bool,
+        /// Output verbosity
+        #[arg(short, long, default_value = "normal")]
+        verbosity: Verbosity,
+    },
+    /// Write a new private key file and print its public key, for use with [--recipient]
+    Keygen {
+        /// Key file to write, or to read with [--public]
+        keyfile: String,
+        /// Print the public key of an existing key file instead
+        #[arg(long)]
+        public: bool,
+        /// Output verbosity
         #[arg(short, long, default_value = "normal")]
         verbosity: Verbosity,
     },
@@ -118,7 +356,25 @@ impl Command {
                 mut encryption_algorithm,
                 mut compression_algorithm,
                 compression_level,
+                seekable,
+                seekable_block_size,
+                dedup,
+                dedup_files,
+                solid,
+                solid_block_size,
+                fail_fast,
+                threads,
+                since,
+                volume_size,
+                parity,
+                recipient,
+                encrypt_metadata,
+                pad,
             } => {
+                // Sizes in a plain index would give away what padding hides
+                let encrypt_metadata = encrypt_metadata || pad;
+                let encryption = encryption || !recipient.is_empty() || encrypt_metadata;
+
                 if let (true, BinEncryptionType::Passthrough) = (encryption, &encryption_algorithm) {
                     encryption_algorithm = BinEncryptionType::XChaCha;
                 }
@@ -127,43 +383,185 @@ impl Command {
                     compression_algorithm = BinCompressionType::Lz4;
                 }
 
+                let options = ArchiveOptions::new()
+                    .with_compression_level(compression_level.into())
+                    .with_block_size(seekable.then_some(seekable_block_size))
+                    .with_dedup(match (dedup, dedup_files) {
+                        (true, _) => DedupMode::Chunks,
+                        (_, true) => DedupMode::Files,
+                        _ => DedupMode::Off,
+                    })
+                    .with_solid_block_size(solid.then_some(solid_block_size))
+                    .with_error_policy(error_policy(fail_fast))
+                    .with_parallelism(parallelism(threads))
+                    .with_volume_size(volume_size)
+                    .with_parity(parity)
+                    .with_metadata_encryption(encrypt_metadata)
+                    .with_padding(match pad {
+                        true => Padding::Padme,
+                        false => Padding::None,
+                    });
+
                 Self::archive(
                     input,
                     output,
                     keypath,
+                    recipient,
                     verbosity,
                     encryption_algorithm,
                     compression_algorithm,
-                    compression_level,
+                    since,
+                    options,
                 )
             },
             Command::Extract {
                 input,
                 output,
-                encrypt: encryption,
-                compress: compression,
                 keypath,
                 verbosity,
-                mut encryption_algorithm,
-                mut compression_algorithm,
+                salvage: true,
+                ..
+            } => Self::salvage(input, output, keypath, verbosity),
+            Command::Extract {
+                input,
+                output,
+                encrypt,
+                compress,
+                keypath,
+                verbosity,
+                encryption_algorithm,
+                compression_algorithm,
+                fail_fast,
+                threads,
+                ..
             } => {
-                if let (true, BinEncryptionType::Passthrough) = (encryption, &encryption_algorithm) {
-                    encryption_algorithm = BinEncryptionType::XChaCha;
+                let options = ArchiveOptions::new()
+                    .with_error_policy(error_policy(fail_fast))
+                    .with_parallelism(parallelism(threads));
+
+                // Archives from before the container format are zapf packs
+                if let Err(ArchiveError::NotAnArchive) = Archive::needs_secret(&input) {
+                    let encryption_algorithm = match (encrypt, encryption_algorithm) {
+                        (_, Some(algorithm)) => algorithm,
+                        (true, None) => BinEncryptionType::XChaCha,
+                        (false, None) => BinEncryptionType::Passthrough,
+                    };
+
+                    let compression_algorithm = match (compress, compression_algorithm) {
+                        (_, Some(algorithm)) => algorithm,
+                        (true, None) => BinCompressionType::Lz4,
+                        (false, None) => BinCompressionType::Passthrough,
+                    };
+
+                    return Self::extract_legacy(input, output, verbosity, encryption_algorithm, compression_algorithm, options);
                 }
 
-                if let (true, BinCompressionType::Passthrough) = (compression, &compression_algorithm) {
-                    compression_algorithm = BinCompressionType::Lz4;
+                Self::extract(input, output, keypath, verbosity, options)
+            },
+            Command::Restore {
+                archives,
+                output,
+                repo,
+                keypath,
+                verbosity,
+            } => match repo {
+                Some(repo) => Self::restore_snapshot(repo, archives, output, verbosity),
+                None => Self::restore(archives, output, keypath, verbosity),
+            },
+            Command::Snapshot {
+                input,
+                repo,
+                encrypt,
+                compress,
+                verbosity,
+            } => Self::snapshot(input, repo, encrypt, compress, verbosity),
+            Command::Snapshots { repo, verbosity } => Self::snapshots(repo, verbosity),
+            Command::Forget {
+                repo,
+                keep_daily,
+                verbosity,
+            } => Self::forget(repo, keep_daily as usize, verbosity),
+            Command::Prune { repo, verbosity } => Self::prune(repo, verbosity),
+            Command::List {
+                archive,
+                keypath,
+                verbosity,
+            } => Self::list(archive, keypath, verbosity),
+            Command::Repair { archive, verbosity } => Self::repair(archive, verbosity),
+            Command::Rekey {
+                archive,
+                keypath,
+                add_password,
+                change_password,
+                add_recipient,
+                remove,
+                verbosity,
+            } => Self::rekey(
+                archive,
+                keypath,
+                add_password || change_password,
+                add_recipient,
+                remove || change_password,
+                verbosity,
+            ),
+            Command::Keygen {
+                keyfile,
+                public,
+                verbosity,
+            } => Self::keygen(keyfile, public, verbosity),
+            Command::Add {
+                archive,
+                paths,
+                keypath,
+                verbosity,
+                seekable,
+                seekable_block_size,
+            } => {
+                let options = ArchiveOptions::new().with_block_size(seekable.then_some(seekable_block_size));
+
+                Self::append(archive, keypath, verbosity, options, |writer| {
+                    for path in paths.iter() {
+                        writer.add_path(entry_name(Path::new(path)), path)?;
+                    }
+
+                    Ok(())
+                })
+            },
+            Command::Remove {
+                archive,
+                paths,
+                keypath,
+                verbosity,
+            } => Self::append(archive, keypath, verbosity, ArchiveOptions::new(), |writer| {
+                for path in paths.iter() {
+                    let removed = writer.remove(Path::new(path))?;
+                    info!("Removed {} entries for {}", removed, path);
                 }
-                Self::extract(
-                    input,
-                    output,
-                    keypath,
-                    verbosity,
-                    encryption_algorithm,
-                    compression_algorithm,
-                )
+
+                Ok(())
+            }),
+            Command::Compact {
+                archive,
+                keypath,
+                verbosity,
+            } => Self::compact(archive, keypath, verbosity),
+            Command::Update {
+                archive,
+                input,
+                keypath,
+                verbosity,
+                seekable,
+                seekable_block_size,
+            } => {
+                let options = ArchiveOptions::new().with_block_size(seekable.then_some(seekable_block_size));
+
+                Self::append(archive, keypath, verbosity, options, |writer| {
+                    let updated = writer.update_path("", &input)?;
+                    info!("Updated {} files", updated);
+
+                    Ok(())
+                })
             },
-            Command::List { archive, verbosity } => Self::list(archive, verbosity),
         }
     }
 
@@ -171,42 +569,163 @@ impl Command {
         input: String,
         output: String,
         keypath: Option<String>,
+        mut recipients: Vec<Recipient>,
         verbosity: Verbosity,
         encryption_algorithm: BinEncryptionType,
         compression_algorithm: BinCompressionType,
-        compression_level: CompressionLevel,
+        since: Option<String>,
+        options: ArchiveOptions,
     ) -> Result<(), ZapError> {
-        preamble(verbosity)?;
+        preamble(&verbosity)?;
 
-        let encryption_secret: EncryptionSecret = match (&encryption_algorithm, keypath) {
-            (BinEncryptionType::Passthrough, _) => EncryptionSecret::None,
-            (_, Some(path)) => EncryptionSecret::Key(path),
-            (_, None) => EncryptionSecret::Password(match get_password_confirm(256) {
-                Ok(pass) => pass,
-                Err(e) => return Err(e.into()),
-            }),
+        if let Some(path) = &keypath {
+            recipients.push(Identity::read(path)?.recipient());
+        }
+
+        // Entries are encrypted with a random key, stored in the header
+        // for the password or each of the public keys
+        let file_key = match &encryption_algorithm {
+            BinEncryptionType::Passthrough => None,
+            _ => {
+                let mut file_key = FileKey::generate();
+
+                match recipients.is_empty() {
+                    true => file_key.add_password(&get_password_confirm(256)?)?,
+                    false => {
+                        for recipient in recipients.iter() {
+                            file_key.add_recipient(recipient)?;
+                        }
+                    }
+                }
+
+                Some(file_key)
+            }
         };
 
         info!("Encryption: {:?}", encryption_algorithm);
         info!("Compression: {:?}", compression_algorithm);
 
-        zap::compress_directory(
-            &input,
-            "/tmp/unpacked",
-            encryption_algorithm.into(),
-            encryption_secret,
-            compression_algorithm.into(),
-            compression_level.into(),
-            zap::signing::SigningType::default(),
-        )?;
+        let total = WalkDir::new(&input)
+            .into_iter()
+            .filter_map(|e| e.ok()?.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum();
 
-        let out_file = File::create(output).expect("Could not create file");
+        // Unchanged files aren't read when archiving incrementally
+        let total = since.is_none().then_some(total);
 
-        let mut out_writer = BufWriter::new(out_file);
+        let volume_size = options.volume_size();
 
-        pack_files("/tmp/unpacked", &mut out_writer)?;
+        let progress = Arc::new(ProgressBarObserver::new(total, &verbosity));
 
-        Ok(fs::remove_dir_all("/tmp/unpacked")?)
+        let options = options
+            .with_encryption(encryption_algorithm.into())
+            .with_compression(compression_algorithm.into())
+            .with_signing(zap::signing::SigningType::default())
+            .with_progress(progress.clone());
+
+        let options = match file_key.clone() {
+            Some(file_key) => options.with_file_key(file_key),
+            None => options,
+        };
+
+        if let Some(since) = since {
+            // The content hashes of encrypted entries are keyed with the
+            // file key, unchanged files can't be told apart without it
+            let previous = Self::open_with_secret(&since, keypath)?;
+
+            let result = match volume_size {
+                Some(size) => {
+                    let out_writer = VolumeWriter::create(&output, size)?;
+
+                    Self::archive_since(out_writer, &input, options, &previous)
+                        .and_then(|writer| Ok(writer.finish()?))
+                        .map(|volumes| info!("Wrote {} volumes", volumes))
+                }
+                None => {
+                    let out_writer = BufWriter::new(File::create(&output)?);
+
+                    Self::archive_since(out_writer, &input, options, &previous).map(|_| ())
+                }
+            };
+
+            progress.finish();
+
+            result?;
+
+            return Ok(());
+        }
+
+        // Stored in the archive, so extracting doesn't need to be told
+        let pack_options = ArchiveOptions::new()
+            .with_stages(options.stages())
+            .with_volume_size(volume_size)
+            .with_parity(options.parity())
+            .with_metadata_encryption(options.metadata_encryption());
+
+        let pack_options = match file_key {
+            Some(file_key) => pack_options.with_file_key(file_key),
+            None => pack_options,
+        };
+
+        // Removed again however archiving ends
+        let folder = TempDir::new()?;
+
+        let report = zap::compress_directory(&input, &folder.path().to_string_lossy(), options);
+
+        progress.finish();
+
+        let report = report?;
+
+        if let Some(stats) = report.dedup {
+            println!(
+                "Deduplication: stored {} of {} bytes (ratio {:.2})",
+                stats.stored_bytes,
+                stats.total_bytes,
+                stats.ratio()
+            );
+        }
+
+        let folder = folder.path();
+
+        match volume_size {
+            Some(size) => {
+                let out_writer = VolumeWriter::create(&output, size)?;
+                let volumes = pack_directory(folder, &report, out_writer, pack_options)?.finish()?;
+
+                info!("Wrote {} volumes", volumes);
+            }
+            None => {
+                let out_file = File::create(output).expect("Could not create file");
+
+                let out_writer = BufWriter::new(out_file);
+
+                pack_directory(folder, &report, out_writer, pack_options)?;
+            }
+        }
+
+        match report.entries.failed.len() {
+            0 => Ok(()),
+            failed => Err(ZapError::EntriesFailed(failed)),
+        }
+    }
+
+    fn archive_since<W>(
+        out_writer: W,
+        input: &str,
+        options: ArchiveOptions,
+        previous: &Archive,
+    ) -> Result<W, ArchiveError>
+    where
+        W: Write + Send,
+    {
+        let mut writer = ArchiveWriter::create_since(out_writer, options, previous)?;
+
+        let changed = writer.update_path("", input)?;
+        info!("Stored {} changed files", changed);
+
+        writer.finish()
     }
 
     fn extract(
@@ -214,47 +733,538 @@ impl Command {
         output: String,
         keypath: Option<String>,
         verbosity: Verbosity,
+        options: ArchiveOptions,
+    ) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        let archive = Self::open_with_secret(&input, keypath)?;
+
+        let total = archive.entries()?.filter_map(|e| e.metadata().size).sum();
+        let progress = Arc::new(ProgressBarObserver::new(Some(total), &verbosity));
+
+        let report = archive.extract_to(&output, options.with_progress(progress.clone()));
+
+        progress.finish();
+
+        match report?.failed.len() {
+            0 => Ok(()),
+            failed => Err(ZapError::EntriesFailed(failed)),
+        }
+    }
+
+    fn extract_legacy(
+        input: String,
+        output: String,
+        verbosity: Verbosity,
         encryption_algorithm: BinEncryptionType,
         compression_algorithm: BinCompressionType,
+        options: ArchiveOptions,
     ) -> Result<(), ZapError> {
-        preamble(verbosity)?;
+        preamble(&verbosity)?;
 
-        let encryption_secret: EncryptionSecret = match (&encryption_algorithm, keypath) {
-            (BinEncryptionType::Passthrough, _) => EncryptionSecret::None,
-            (_, None) => EncryptionSecret::Password(match get_password_noconf(256) {
-                Ok(pass) => pass,
-                Err(e) => return Err(e.into()),
-            }),
-            (_, Some(path)) => EncryptionSecret::Key(path),
+        info!("Reading {} as a zapf archive", input);
+
+        let encryption_secret = match encryption_algorithm {
+            BinEncryptionType::Passthrough => EncryptionSecret::None,
+            _ => EncryptionSecret::Password(get_password_noconf(256)?),
+        };
+
+        // The extracted size is only known once everything is decoded
+        let progress = Arc::new(ProgressBarObserver::new(None, &verbosity));
+
+        let options = options
+            .with_encryption(encryption_algorithm.into())
+            .with_compression(compression_algorithm.into())
+            .with_encryption_secret(encryption_secret)
+            .with_progress(progress.clone());
+
+        let report = legacy::extract(&input, &output, options);
+
+        progress.finish();
+
+        match report?.entries.failed.len() {
+            0 => Ok(()),
+            failed => Err(ZapError::EntriesFailed(failed)),
+        }
+    }
+
+    fn salvage(input: String, output: String, keypath: Option<String>, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        info!("Salvaging archive: {}", input);
+
+        let encryption_secret = Self::secret_for(&input, keypath)?;
+        let salvage = Salvage::scan(&input, ArchiveOptions::new().with_encryption_secret(encryption_secret))?;
+
+        let report = salvage.extract_to(&output)?;
+
+        let output = Path::new(&output);
+        let report_path = output.with_file_name(format!(
+            "{}-salvage-report.txt",
+            output.file_name().unwrap_or_default().to_string_lossy()
+        ));
+
+        fs::write(&report_path, report.to_string())?;
+
+        println!(
+            "Recovered {} files, lost {}, see {}",
+            report.recovered,
+            report.lost.len(),
+            report_path.display()
+        );
+
+        // Damage can also hide files whose paths were never found
+        match (report.lost.len(), report.damaged.len()) {
+            (0, 0) => Ok(()),
+            (0, damaged) => Err(ArchiveError::Corrupt(format!("{} damaged objects", damaged)).into()),
+            (failed, _) => Err(ZapError::EntriesFailed(failed)),
+        }
+    }
+
+    fn restore(
+        archives: Vec<String>,
+        output: String,
+        keypath: Option<String>,
+        verbosity: Verbosity,
+    ) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        // The chain is expected to share one secret
+        let mut encryption_secret = EncryptionSecret::None;
+
+        for path in archives.iter() {
+            if Archive::needs_secret(path)? {
+                encryption_secret = Self::secret_for(path, keypath)?;
+                break;
+            }
+        }
+
+        let chain = archives
+            .iter()
+            .map(|path| {
+                let options = ArchiveOptions::new().with_encryption_secret(encryption_secret.clone());
+                Archive::open_with_options(path, options)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let restored = restore_chain(&chain, &output)?;
+        info!("Restored {} files", restored);
+
+        Ok(())
+    }
+
+    fn restore_snapshot(
+        repo: String,
+        ids: Vec<String>,
+        output: String,
+        verbosity: Verbosity,
+    ) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        let id = match ids.as_slice() {
+            [id] => id,
+            _ => return Err(ZapError::Generic("Exactly one snapshot id is expected with --repo".into())),
+        };
+
+        let progress = Arc::new(ProgressBarObserver::new(None, &verbosity));
+        let repository = Self::open_repository(&repo, ArchiveOptions::new().with_progress(progress.clone()))?;
+
+        let restored = repository.restore(id, &output);
+
+        progress.finish();
+
+        let restored = restored?;
+        info!("Restored {} files", restored);
+
+        Ok(())
+    }
+
+    fn snapshot(
+        input: String,
+        repo: String,
+        encrypt: bool,
+        compress: bool,
+        verbosity: Verbosity,
+    ) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        let progress = Arc::new(ProgressBarObserver::new(None, &verbosity));
+        let options = ArchiveOptions::new().with_progress(progress.clone());
+
+        let repository = match Repository::open(&repo, ArchiveOptions::new()) {
+            Ok(_) => Self::open_repository(&repo, options)?,
+            Err(RepositoryError::NotARepository(_)) => {
+                let encryption_secret = match encrypt {
+                    false => EncryptionSecret::None,
+                    true => EncryptionSecret::Password(get_password_confirm(256)?),
+                };
+
+                let options = options
+                    .with_encryption(match encrypt {
+                        true => EncryptionType::XChaCha,
+                        false => EncryptionType::Passthrough,
+                    })
+                    .with_encryption_secret(encryption_secret)
+                    .with_compression(match compress {
+                        true => CompressionType::Lz4,
+                        false => CompressionType::Passthrough,
+                    });
+
+                Repository::init(&repo, options)?
+            }
+            Err(e) => return Err(e.into()),
         };
 
-        // Need to check if this function validates path names
-        // to prevent directory traversal.
-        unpack_files(input, "/tmp/unpacked")?;
-
-        zap::decompress_directory(
-            "/tmp/unpacked",
-            &output,
-            encryption_algorithm.into(),
-            encryption_secret,
-            compression_algorithm.into(),
-            zap::signing::SigningType::default(),
-        )?;
-
-        Ok(fs::remove_dir_all("/tmp/unpacked")?)
-        //Ok(())
+        let snapshot = repository.snapshot(&input);
+
+        progress.finish();
+
+        let snapshot = snapshot?;
+
+        println!("Snapshot {} of {} files saved", snapshot.id, snapshot.files);
+
+        Ok(())
     }
 
-    fn list(archive: String, verbosity: Verbosity) -> Result<(), ZapError> {
-        preamble(verbosity)?;
+    fn snapshots(repo: String, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        let repository = Self::open_repository(&repo, ArchiveOptions::new())?;
+
+        for snapshot in repository.snapshots()? {
+            println!(
+                "{}  {}  {:>8} files  {:>12} bytes  {}",
+                snapshot.id,
+                format_time(snapshot.time),
+                snapshot.files,
+                snapshot.size,
+                snapshot.source.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn forget(repo: String, keep_daily: usize, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        let repository = Self::open_repository(&repo, ArchiveOptions::new())?;
+
+        for snapshot in repository.forget(keep_daily)? {
+            println!("Removed snapshot {} from {}", snapshot.id, format_time(snapshot.time));
+        }
+
+        Ok(())
+    }
+
+    fn prune(repo: String, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        let repository = Self::open_repository(&repo, ArchiveOptions::new())?;
+        let stats = repository.prune()?;
+
+        println!("Removed {} chunks, freeing {} bytes", stats.removed_chunks, stats.freed_bytes);
+
+        Ok(())
+    }
+
+    fn list(archive: String, keypath: Option<String>, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
 
         info!("Listing archive: {}", archive);
 
-        Err(ZapError::NotImplemented("Archive listing not yet implemented.".into()))
+        // The secret is only needed for an encrypted index, or to decode
+        // the index of files packed in solid blocks
+        let archive = match Archive::open(&archive) {
+            Ok(opened) if !(opened.is_encrypted() && opened.has_solid_blocks()) => opened,
+            Ok(_) | Err(ArchiveError::Sealed(_)) => Self::open_with_secret(&archive, keypath)?,
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in archive.entries()? {
+            match (&verbosity, entry.metadata().size) {
+                (Verbosity::Verbose | Verbosity::Debug, Some(size)) => {
+                    println!("{:>12}  {}", size, entry.path().display())
+                }
+                (Verbosity::Verbose | Verbosity::Debug, None) => {
+                    println!("{:>12}  {}", "-", entry.path().display())
+                }
+                _ => println!("{}", entry.path().display()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn repair(archive: String, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        info!("Repairing archive: {}", archive);
+
+        let report = recovery::repair(&archive)?;
+
+        println!(
+            "{} damaged parts found, {} repaired",
+            report.damaged + report.repaired_trailers,
+            report.repaired + report.repaired_trailers
+        );
+
+        match report.unrepaired_bytes {
+            0 => Ok(()),
+            lost => Err(ArchiveError::Corrupt(format!("{} bytes are too damaged to be repaired", lost)).into()),
+        }
+    }
+
+    fn rekey(
+        archive: String,
+        keypath: Option<String>,
+        add_password: bool,
+        recipients: Vec<Recipient>,
+        remove: bool,
+        verbosity: Verbosity,
+    ) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        info!("Changing the keys of archive: {}", archive);
+
+        let encryption_secret = match keypath {
+            Some(path) => EncryptionSecret::Key(path),
+            None => EncryptionSecret::Password(get_password_noconf(256)?),
+        };
+
+        let mut rekey = Rekey::open(&archive, encryption_secret)?;
+
+        if add_password {
+            rekey.add_password(&get_new_password(256)?)?;
+        }
+
+        for recipient in recipients.iter() {
+            rekey.add_recipient(recipient)?;
+        }
+
+        if remove {
+            rekey.remove_opened()?;
+        }
+
+        let slots = rekey.len();
+        rekey.save()?;
+
+        println!("The archive has {} key slots", slots);
+
+        Ok(())
+    }
+
+    fn keygen(keyfile: String, public: bool, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        if public {
+            println!("{}", Identity::read(&keyfile)?.recipient());
+
+            return Ok(());
+        }
+
+        let identity = Identity::generate();
+
+        // Never replace a key archives may already be encrypted for
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        writeln!(options.open(&keyfile)?, "{}", identity)?;
+
+        info!("Wrote private key to {}", keyfile);
+        println!("{}", identity.recipient());
+
+        Ok(())
+    }
+
+    fn compact(archive: String, keypath: Option<String>, verbosity: Verbosity) -> Result<(), ZapError> {
+        preamble(&verbosity)?;
+
+        info!("Compacting archive: {}", archive);
+
+        let path = archive;
+        let archive = Self::open_with_secret(&path, keypath)?;
+
+        if archive.is_split() {
+            return Err(ArchiveError::SplitArchive(path).into());
+        }
+
+        // Only replaces the archive once the copy is complete
+        let compacted = format!("{}.compact", path);
+
+        let result = File::create(&compacted)
+            .map_err(ArchiveError::from)
+            .and_then(|file| archive.compact_to(BufWriter::new(file)));
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&compacted);
+            return Err(e.into());
+        }
+
+        let before = fs::metadata(&path)?.len();
+        fs::rename(&compacted, &path)?;
+
+        info!("Compacted {} bytes to {}", before, fs::metadata(&path)?.len());
+
+        Ok(())
+    }
+
+    fn append<F>(
+        archive: String,
+        keypath: Option<String>,
+        verbosity: Verbosity,
+        options: ArchiveOptions,
+        add: F,
+    ) -> Result<(), ZapError>
+    where
+        F: FnOnce(&mut ArchiveWriter<BufWriter<File>>) -> Result<(), ArchiveError>,
+    {
+        preamble(&verbosity)?;
+
+        info!("Appending to archive: {}", archive);
+
+        let encryption_secret = Self::secret_for(&archive, keypath)?;
+
+        let progress = Arc::new(ProgressBarObserver::new(None, &verbosity));
+
+        let options = options
+            .with_encryption_secret(encryption_secret)
+            .with_progress(progress.clone());
+
+        // Dropping the writer on error leaves the archive as it was
+        let result = ArchiveWriter::append(&archive, options).and_then(|mut writer| {
+            add(&mut writer)?;
+            writer.finish()
+        });
+
+        progress.finish();
+
+        result?;
+
+        Ok(())
+    }
+
+    /// Opens the archive at `path`, asking for the secret if it is
+    /// encrypted.
+    fn open_with_secret(path: &str, keypath: Option<String>) -> Result<Archive, ZapError> {
+        let options = ArchiveOptions::new().with_encryption_secret(Self::secret_for(path, keypath)?);
+
+        Ok(Archive::open_with_options(path, options)?)
+    }
+
+    /// Opens the repository at `path`, asking for the password if it is
+    /// encrypted.
+    fn open_repository(path: &str, options: ArchiveOptions) -> Result<Repository, ZapError> {
+        if !Repository::open(path, ArchiveOptions::new())?.is_encrypted() {
+            return Ok(Repository::open(path, options)?);
+        }
+
+        let encryption_secret = EncryptionSecret::Password(get_password_noconf(256)?);
+
+        Ok(Repository::open(path, options.with_encryption_secret(encryption_secret))?)
+    }
+
+    /// Asks for the secret of the archive at `path`, if it is encrypted.
+    fn secret_for(path: &str, keypath: Option<String>) -> Result<EncryptionSecret, ZapError> {
+        Ok(match (Archive::needs_secret(path)?, keypath) {
+            (false, _) => EncryptionSecret::None,
+            (_, None) => EncryptionSecret::Password(get_password_noconf(256)?),
+            (_, Some(path)) => EncryptionSecret::Key(path),
+        })
+    }
+}
+
+/// A public key, given as printed by [zap keygen] or as a file holding it.
+fn parse_recipient(s: &str) -> Result<Recipient, String> {
+    match s.parse() {
+        Ok(recipient) => Ok(recipient),
+        Err(e) => match fs::read_to_string(s) {
+            Ok(text) => text.trim().parse().map_err(|e: EncryptionKeyError| e.to_string()),
+            Err(_) => Err(e.to_string()),
+        },
+    }
+}
+
+/// Name a path given on the command line is stored as.
+fn entry_name(path: &Path) -> PathBuf {
+    match path.is_absolute() {
+        true => path.file_name().map(PathBuf::from).unwrap_or_default(),
+        false => path.components().filter(|c| *c != Component::CurDir).collect(),
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS`, in UTC.
+fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from the days since 1970-01-01, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Size in bytes, with an optional K, M, G or T suffix (powers of 1024).
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("Unknown size unit: {}", unit)),
+    };
+
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(1 << shift)) {
+        Some(0) => Err("Size can't be 0".into()),
+        Some(size) => Ok(size),
+        None => Err(format!("Invalid size: {}", size)),
+    }
+}
+
+fn error_policy(fail_fast: bool) -> ErrorPolicy {
+    match fail_fast {
+        true => ErrorPolicy::FailFast,
+        false => ErrorPolicy::Continue,
+    }
+}
+
+fn parallelism(threads: Option<NonZeroUsize>) -> Parallelism {
+    match threads {
+        Some(threads) => Parallelism::Threads(threads),
+        None => Parallelism::Available,
     }
 }
 
-fn preamble(verbosity: Verbosity) -> Result<(), ZapError> {
+fn preamble(verbosity: &Verbosity) -> Result<(), ZapError> {
     init_logger(verbosity)?;
 
     log::debug!("pid: {}", std::process::id());
@@ -4,12 +4,21 @@ use sha2::{Digest, Sha256, Sha512};
 use zap::error::{HashingError, InputError, PasswordError};
 
 pub fn get_password_confirm(key_len: usize) -> Result<Vec<u8>, PasswordError> {
-    let pass = match prompt_password("Enter a password for encryption: ") {
+    confirm_password("Enter a password for encryption: ", "Repeat encryption password: ", key_len)
+}
+
+/// Asks for a password to add to an archive that is already encrypted.
+pub fn get_new_password(key_len: usize) -> Result<Vec<u8>, PasswordError> {
+    confirm_password("Enter the new password: ", "Repeat the new password: ", key_len)
+}
+
+fn confirm_password(prompt: &str, repeat: &str, key_len: usize) -> Result<Vec<u8>, PasswordError> {
+    let pass = match prompt_password(prompt) {
         Ok(val) => val,
         Err(e) => return Err(InputError::from(e).into()),
     };
 
-    let confirm_pass = match prompt_password("Repeat encryption password: ") {
+    let confirm_pass = match prompt_password(repeat) {
         Ok(val) => val,
         Err(e) => return Err(InputError::from(e).into()),
     };
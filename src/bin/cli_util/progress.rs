@@ -0,0 +1,48 @@
+use std::{path::Path, time::Duration};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use zap::progress::ProgressObserver;
+
+use super::logging::Verbosity;
+
+/// Progress bar over the bytes going through the pipeline.
+pub struct ProgressBarObserver {
+    bar: ProgressBar,
+}
+
+impl ProgressBarObserver {
+    /// With a known `total` the bar shows an ETA, otherwise only the
+    /// throughput. Nothing is drawn in quiet mode.
+    pub fn new(total: Option<u64>, verbosity: &Verbosity) -> Self {
+        let bar = match (verbosity, total) {
+            (Verbosity::Quiet, _) => ProgressBar::hidden(),
+            (_, Some(total)) => ProgressBar::new(total).with_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {wide_msg}",
+                )
+                .unwrap(),
+            ),
+            (_, None) => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {bytes} {binary_bytes_per_sec} {wide_msg}").unwrap(),
+            ),
+        };
+
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        ProgressBarObserver { bar }
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+impl ProgressObserver for ProgressBarObserver {
+    fn file_started(&self, path: &Path) {
+        self.bar.set_message(path.display().to_string());
+    }
+
+    fn bytes_processed(&self, bytes: u64) {
+        self.bar.inc(bytes);
+    }
+}
@@ -6,7 +6,12 @@ pub mod snappy;
 use crate::error::CompressorInitError;
 
 // External
-use std::io::{Error, Read, Write};
+use std::{
+    future::Future,
+    io::{Error, Read, Write},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
 
 pub struct CompressionMode;
 pub struct DecompressionMode;
@@ -18,6 +23,16 @@ pub trait Decompress: Read {
     fn finalise(self) -> Result<(), Error>;
 }
 
+/// Async counterpart of [`Compress`].
+pub trait AsyncCompress: AsyncWrite + Unpin + Send {
+    fn finalise(self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Async counterpart of [`Decompress`].
+pub trait AsyncDecompress: AsyncRead + Unpin + Send {
+    fn finalise(self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
 pub trait CompressionAlgorithm<T>
 where
     T: Write,
@@ -0,0 +1,741 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use fastcdc::v2020::StreamCDC;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{PipelineCompressionError, PipelineDecompressionError},
+    internal,
+    pipeline::ProcessingPipeline,
+};
+
+/// Directory (relative to the output folder) holding the unique chunks.
+pub const CHUNK_DIR: &str = ".zap-chunks";
+/// Extension used for the chunk lists that replace deduplicated files.
+pub const MANIFEST_EXT: &str = "zapcdc";
+/// Extension used for entries pointing at an identical, stored file.
+pub const REFERENCE_EXT: &str = "zapref";
+
+// FastCDC cut point bounds, in bytes.
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupMode {
+    #[default]
+    Off,
+    /// Hash whole files and store identical files once.
+    Files,
+    /// Split files with content-defined chunking and store every
+    /// unique chunk once.
+    Chunks,
+}
+
+/// For whole-file deduplication every file counts as a single chunk.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct DedupStats {
+    /// Bytes of file content that went through the chunker.
+    pub total_bytes: u64,
+    /// Bytes of unique chunks actually stored (before compression).
+    pub stored_bytes: u64,
+    pub total_chunks: u64,
+    pub unique_chunks: u64,
+}
+
+impl DedupStats {
+    /// How many bytes of input each stored byte stands for.
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+
+        self.total_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+/// Content addressed store of chunks shared by every file in a run.
+/// Each chunk is written through the processing pipeline on its own,
+/// so chunks are compressed and encrypted like regular entries.
+pub struct ChunkStore {
+    dir: PathBuf,
+    /// Key chunks are named with, see `chunk_id`.
+    key: Option<[u8; 32]>,
+    seen: Mutex<HashMap<[u8; 32], ChunkState>>,
+    /// Notified whenever a claimed chunk was written, or failed to be.
+    written: Condvar,
+    total_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+    total_chunks: AtomicU64,
+    unique_chunks: AtomicU64,
+}
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> ChunkStore {
+        ChunkStore {
+            dir,
+            key: None,
+            seen: Mutex::new(HashMap::new()),
+            written: Condvar::new(),
+            total_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+            total_chunks: AtomicU64::new(0),
+            unique_chunks: AtomicU64::new(0),
+        }
+    }
+
+    /// Names chunks by their HMAC-SHA256 under `key` instead of their
+    /// SHA-256, which encrypted chunks have to be so that their names
+    /// don't give their content away.
+    pub fn with_key(mut self, key: Option<[u8; 32]>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Opens the chunks already stored in `dir`, which are not stored
+    /// again.
+    pub fn open(dir: PathBuf) -> Result<ChunkStore, Error> {
+        let store = ChunkStore::new(dir);
+
+        if store.dir.is_dir() {
+            let mut seen = store.seen.lock().unwrap();
+
+            for entry in fs::read_dir(&store.dir)? {
+                if let Some(hash) = parse_chunk_file_name(&entry?.file_name().to_string_lossy()) {
+                    seen.insert(hash, ChunkState::Written);
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        DedupStats {
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+            total_chunks: self.total_chunks.load(Ordering::Relaxed),
+            unique_chunks: self.unique_chunks.load(Ordering::Relaxed),
+        }
+    }
+
+    fn chunk_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.dir.join(chunk_file_name(hash))
+    }
+
+    /// Chunks `source`, stores any chunk not seen before and writes
+    /// the encoded chunk list to `manifest`.
+    pub fn store<R>(
+        &self,
+        pipeline: &ProcessingPipeline,
+        source: R,
+        manifest: &Path,
+    ) -> Result<(), PipelineCompressionError>
+    where
+        R: Read,
+    {
+        /*
+            The manifest is a list of chunks in file order:
+            [ hex encoded chunk id ] [ chunk length ]\n
+        */
+        let mut chunk_list = String::new();
+
+        for (hash, length) in self.store_chunks(pipeline, source)? {
+            writeln!(chunk_list, "{} {}", to_hex(&hash), length).unwrap();
+        }
+
+        let io = File::create(manifest)?;
+        pipeline
+            .without_progress()
+            .build_encryptor(io, &mut chunk_list.as_bytes())
+    }
+
+    /// Chunks `source` and stores any chunk not seen before, returning
+    /// the id and length of every chunk in file order.
+    pub fn store_chunks<R>(
+        &self,
+        pipeline: &ProcessingPipeline,
+        source: R,
+    ) -> Result<Vec<([u8; 32], usize)>, PipelineCompressionError>
+    where
+        R: Read,
+    {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut chunks = vec![];
+
+        for chunk in StreamCDC::new(source, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(fastcdc::v2020::Error::IoError(e)) => return Err(e.into()),
+                Err(e) => return Err(Error::other(e.to_string()).into()),
+            };
+
+            let hash = chunk_id(self.key.as_ref(), &chunk.data);
+
+            self.total_bytes.fetch_add(chunk.length as u64, Ordering::Relaxed);
+            self.total_chunks.fetch_add(1, Ordering::Relaxed);
+
+            if let Some(claim) = self.claim_chunk(&hash) {
+                let chunk_path = self.chunk_path(&hash);
+
+                // Written aside first, so a chunk is never there partially
+                // for later files or runs to point at
+                let partial_path = chunk_path.with_extension("partial");
+
+                let result = File::create(&partial_path)
+                    .map_err(|e| e.into())
+                    .and_then(|io| pipeline.build_encryptor(io, &mut &chunk.data[..]))
+                    .and_then(|_| fs::rename(&partial_path, &chunk_path).map_err(|e| e.into()));
+
+                if let Err(e) = result {
+                    let _ = fs::remove_file(&partial_path);
+                    return Err(e);
+                }
+
+                claim.written();
+
+                self.stored_bytes.fetch_add(chunk.length as u64, Ordering::Relaxed);
+                self.unique_chunks.fetch_add(1, Ordering::Relaxed);
+            } else {
+                pipeline.report_bytes(chunk.length as u64);
+            }
+
+            chunks.push((hash, chunk.length));
+        }
+
+        Ok(chunks)
+    }
+
+    /// Claims the chunk `hash` if it still has to be written, `None`
+    /// if it already was.
+    ///
+    /// Files only point at chunks that were written: while another
+    /// file is still writing the same chunk this waits for it, and
+    /// takes over the claim if that file fails.
+    fn claim_chunk(&self, hash: &[u8; 32]) -> Option<ChunkClaim<'_>> {
+        let mut seen = self.seen.lock().unwrap();
+
+        loop {
+            match seen.get(hash) {
+                Some(ChunkState::Written) => return None,
+                Some(ChunkState::Writing) => seen = self.written.wait(seen).unwrap(),
+                None => break,
+            }
+        }
+
+        seen.insert(*hash, ChunkState::Writing);
+
+        Some(ChunkClaim {
+            store: self,
+            hash: *hash,
+            written: false,
+        })
+    }
+
+    /// Rebuilds a file from the chunk list in `manifest`, verifying
+    /// every chunk against its id.
+    pub fn restore<W>(
+        &self,
+        pipeline: &ProcessingPipeline,
+        manifest: &Path,
+        destination: &mut W,
+    ) -> Result<(), PipelineDecompressionError>
+    where
+        W: Write,
+    {
+        let mut chunk_list = vec![];
+        pipeline
+            .without_progress()
+            .build_dencryptor(File::open(manifest)?, &mut chunk_list)?;
+
+        for (hash, len) in parse_chunk_list(chunk_list)? {
+            destination.write_all(&self.read_chunk(pipeline, &hash, len)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decodes a single chunk, verifying it against its id.
+    pub fn read_chunk(
+        &self,
+        pipeline: &ProcessingPipeline,
+        hash: &[u8; 32],
+        len: usize,
+    ) -> Result<Vec<u8>, PipelineDecompressionError> {
+        let mut chunk = Vec::with_capacity(len);
+        pipeline.build_dencryptor(File::open(self.chunk_path(hash))?, &mut chunk)?;
+
+        verify_chunk(self.key.as_ref(), hash, len, &chunk)?;
+
+        Ok(chunk)
+    }
+}
+
+/// Keeps track of file contents already stored in a run, so that later
+/// files with the same content only need a reference to the first one.
+pub struct FileIndex {
+    root: PathBuf,
+    seen: Mutex<HashMap<[u8; 32], Seen>>,
+    /// Notified whenever a claimed entry was written, or failed to be.
+    written: Condvar,
+    total_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+    total_files: AtomicU64,
+    unique_files: AtomicU64,
+}
+
+impl FileIndex {
+    /// `root` is the folder entries are stored in, references are
+    /// kept relative to it.
+    pub fn new(root: PathBuf) -> FileIndex {
+        FileIndex {
+            root,
+            seen: Mutex::new(HashMap::new()),
+            written: Condvar::new(),
+            total_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+            total_files: AtomicU64::new(0),
+            unique_files: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        DedupStats {
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+            total_chunks: self.total_files.load(Ordering::Relaxed),
+            unique_chunks: self.unique_files.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Hashes `source` and claims `destination` as the entry holding
+    /// its content. If an entry with the same content was already
+    /// written, its path is returned instead.
+    ///
+    /// References are only handed out to entries that were written:
+    /// while the entry claiming the same content is still being
+    /// written this waits for it, and takes over the claim if that
+    /// entry fails.
+    pub fn claim<R>(&self, source: &mut R, destination: &Path) -> Result<Claimed<'_>, Error>
+    where
+        R: Read,
+    {
+        let mut hasher = Sha256::new();
+        let len = std::io::copy(source, &mut hasher)?;
+        let hash: [u8; 32] = hasher.finalize().into();
+
+        let relative = match destination.strip_prefix(&self.root) {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} is outside of {}", destination.display(), self.root.display()),
+                ))
+            }
+        };
+
+        self.total_bytes.fetch_add(len, Ordering::Relaxed);
+        self.total_files.fetch_add(1, Ordering::Relaxed);
+
+        let mut seen = self.seen.lock().unwrap();
+
+        loop {
+            match seen.get(&hash) {
+                Some(Seen::Written(existing)) => return Ok(Claimed::Duplicate(existing.clone())),
+                Some(Seen::Writing) => seen = self.written.wait(seen).unwrap(),
+                None => break,
+            }
+        }
+
+        seen.insert(hash, Seen::Writing);
+
+        self.stored_bytes.fetch_add(len, Ordering::Relaxed);
+        self.unique_files.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Claimed::First(Claim {
+            index: self,
+            hash,
+            len,
+            relative: Some(relative),
+        }))
+    }
+
+    /// Writes an encoded reference to the stored entry `target`.
+    pub fn store_reference(
+        &self,
+        pipeline: &ProcessingPipeline,
+        target: &Path,
+        reference: &Path,
+    ) -> Result<(), PipelineCompressionError> {
+        let io = File::create(reference)?;
+        let target = target.to_string_lossy();
+
+        pipeline
+            .without_progress()
+            .build_encryptor(io, &mut target.as_bytes())
+    }
+
+    /// Decodes a reference entry and returns the path of the entry
+    /// holding the actual content.
+    pub fn resolve(
+        &self,
+        pipeline: &ProcessingPipeline,
+        reference: &Path,
+    ) -> Result<PathBuf, PipelineDecompressionError> {
+        let mut target = vec![];
+        pipeline
+            .without_progress()
+            .build_dencryptor(File::open(reference)?, &mut target)?;
+
+        Ok(self.root.join(parse_reference(target)?))
+    }
+}
+
+enum ChunkState {
+    /// Claimed by a file that is still writing it.
+    Writing,
+    Written,
+}
+
+/// Claim on a chunk, until it is written. Dropping the claim without
+/// calling `written` gives it up, so that another file with the same
+/// chunk writes it instead.
+struct ChunkClaim<'a> {
+    store: &'a ChunkStore,
+    hash: [u8; 32],
+    written: bool,
+}
+
+impl ChunkClaim<'_> {
+    fn written(mut self) {
+        self.written = true;
+
+        self.store.seen.lock().unwrap().insert(self.hash, ChunkState::Written);
+        self.store.written.notify_all();
+    }
+}
+
+impl Drop for ChunkClaim<'_> {
+    fn drop(&mut self) {
+        if self.written {
+            return;
+        }
+
+        self.store.seen.lock().unwrap().remove(&self.hash);
+        self.store.written.notify_all();
+    }
+}
+
+enum Seen {
+    /// Claimed by an entry that is still being written.
+    Writing,
+    /// Written to this path, relative to the root of the index.
+    Written(PathBuf),
+}
+
+/// Outcome of `FileIndex::claim`.
+pub enum Claimed<'a> {
+    /// No entry with the same content was written yet. The entry has
+    /// to be written, then the claim marked as written.
+    First(Claim<'a>),
+    /// The content is already stored in the entry at this path.
+    Duplicate(PathBuf),
+}
+
+/// Claim on a content, until the entry holding it is written. Dropping
+/// the claim without calling `written` gives it up, so that another
+/// entry with the same content is written instead.
+pub struct Claim<'a> {
+    index: &'a FileIndex,
+    hash: [u8; 32],
+    len: u64,
+    relative: Option<PathBuf>,
+}
+
+impl Claim<'_> {
+    /// Marks the entry as written, later entries with the same content
+    /// then reference it.
+    pub fn written(mut self) {
+        let relative = self.relative.take().unwrap();
+
+        self.index.seen.lock().unwrap().insert(self.hash, Seen::Written(relative));
+        self.index.written.notify_all();
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if self.relative.is_none() {
+            return;
+        }
+
+        self.index.seen.lock().unwrap().remove(&self.hash);
+        self.index.stored_bytes.fetch_sub(self.len, Ordering::Relaxed);
+        self.index.unique_files.fetch_sub(1, Ordering::Relaxed);
+        self.index.written.notify_all();
+    }
+}
+
+/// Path of a chunk relative to the folder holding the chunk directory.
+pub fn chunk_name(hash: &[u8; 32]) -> PathBuf {
+    Path::new(CHUNK_DIR).join(chunk_file_name(hash))
+}
+
+fn chunk_file_name(hash: &[u8; 32]) -> String {
+    format!("{}.lz4", to_hex(hash))
+}
+
+/// Hash of the chunk stored in the file `name`, `None` for anything
+/// else.
+pub(crate) fn parse_chunk_file_name(name: &str) -> Option<[u8; 32]> {
+    from_hex(name.strip_suffix(".lz4")?)
+}
+
+/// Parses a decoded chunk list into the hash and length of every chunk.
+pub(crate) fn parse_chunk_list(chunk_list: Vec<u8>) -> Result<Vec<([u8; 32], usize)>, Error> {
+    let chunk_list = match String::from_utf8(chunk_list) {
+        Ok(l) => l,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Corrupt chunk list")),
+    };
+
+    chunk_list
+        .lines()
+        .map(|line| {
+            parse_manifest_line(line).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Corrupt chunk list entry: {}", line),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Id of a chunk: its HMAC-SHA256 under `key` when chunks are keyed,
+/// its SHA-256 otherwise.
+pub(crate) fn chunk_id(key: Option<&[u8; 32]>, data: &[u8]) -> [u8; 32] {
+    match key {
+        Some(key) => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data);
+            mac.finalize().into_bytes().into()
+        }
+        None => Sha256::digest(data).into(),
+    }
+}
+
+/// Checks a decoded chunk against its entry in the chunk list.
+pub(crate) fn verify_chunk(
+    key: Option<&[u8; 32]>,
+    hash: &[u8; 32],
+    len: usize,
+    chunk: &[u8],
+) -> Result<(), Error> {
+    if chunk.len() != len || chunk_id(key, chunk) != *hash {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Chunk {} failed verification", to_hex(hash)),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parses a decoded reference into the path of its target, relative
+/// to the folder entries are stored in.
+pub(crate) fn parse_reference(target: Vec<u8>) -> Result<PathBuf, Error> {
+    let target = match String::from_utf8(target) {
+        Ok(t) => PathBuf::from(t),
+        Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Corrupt file reference")),
+    };
+
+    if !internal::is_relative_path(&target) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid file reference: {}", target.display()),
+        ));
+    }
+
+    Ok(target)
+}
+
+fn parse_manifest_line(line: &str) -> Option<([u8; 32], usize)> {
+    let (hash, len) = line.split_once(' ')?;
+
+    Some((from_hex(hash)?, len.parse().ok()?))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        write!(s, "{:02x}", b).unwrap();
+        s
+    })
+}
+
+pub(crate) fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+
+    let mut out = [0u8; N];
+
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(out)
+}
+
+/// Like `from_hex`, for any length.
+pub(crate) fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len().div_ceil(2))
+        .map(|i| u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::*;
+
+    fn claim<'a>(index: &'a FileIndex, content: &[u8], name: &str) -> Claimed<'a> {
+        index.claim(&mut &content[..], &Path::new("/root").join(name)).unwrap()
+    }
+
+    #[test]
+    fn duplicates_reference_the_written_entry() {
+        let index = FileIndex::new(PathBuf::from("/root"));
+
+        match claim(&index, b"same", "a") {
+            Claimed::First(claim) => claim.written(),
+            Claimed::Duplicate(_) => panic!("nothing was written yet"),
+        }
+
+        match claim(&index, b"same", "b") {
+            Claimed::Duplicate(target) => assert_eq!(target, Path::new("a")),
+            Claimed::First(_) => panic!("a was written"),
+        }
+
+        match claim(&index, b"other", "c") {
+            Claimed::First(claim) => claim.written(),
+            Claimed::Duplicate(_) => panic!("the content differs"),
+        }
+
+        let stats = index.stats();
+        assert_eq!((stats.total_chunks, stats.unique_chunks), (3, 2));
+    }
+
+    #[test]
+    fn failed_entry_gives_up_its_claim() {
+        let index = FileIndex::new(PathBuf::from("/root"));
+
+        drop(claim(&index, b"same", "a"));
+
+        match claim(&index, b"same", "b") {
+            Claimed::First(claim) => claim.written(),
+            Claimed::Duplicate(_) => panic!("a was never written"),
+        }
+
+        match claim(&index, b"same", "c") {
+            Claimed::Duplicate(target) => assert_eq!(target, Path::new("b")),
+            Claimed::First(_) => panic!("b was written"),
+        }
+
+        assert_eq!(index.stats().unique_chunks, 1);
+    }
+
+    #[test]
+    fn duplicates_wait_for_the_entry_being_written() {
+        let index = FileIndex::new(PathBuf::from("/root"));
+        let (sender, receiver) = mpsc::channel();
+
+        thread::scope(|scope| {
+            let first = claim(&index, b"same", "a");
+
+            scope.spawn(|| {
+                let claimed = claim(&index, b"same", "b");
+                sender.send(matches!(claimed, Claimed::First(_))).unwrap();
+            });
+
+            // Nothing is handed out while `a` is being written
+            assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+            // and `b` is written instead once `a` fails
+            drop(first);
+            assert!(receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+        });
+    }
+
+    #[test]
+    fn chunks_wait_for_the_file_writing_them() {
+        let store = ChunkStore::new(PathBuf::from("/chunks"));
+        let (sender, receiver) = mpsc::channel();
+
+        thread::scope(|scope| {
+            let first = store.claim_chunk(&[1; 32]).unwrap();
+
+            scope.spawn(|| {
+                let claim = store.claim_chunk(&[1; 32]);
+                sender.send(claim.is_some()).unwrap();
+
+                claim.unwrap().written();
+                sender.send(store.claim_chunk(&[1; 32]).is_some()).unwrap();
+            });
+
+            // The chunk isn't pointed at while it is being written
+            assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+            // and is written by the other file once the first one fails
+            drop(first);
+            assert!(receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+            assert!(!receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+        });
+    }
+
+    #[test]
+    fn destination_must_be_below_root() {
+        let index = FileIndex::new(PathBuf::from("/root"));
+
+        assert!(index.claim(&mut &b"x"[..], Path::new("/elsewhere/a")).is_err());
+    }
+
+    #[test]
+    fn keyed_chunks_are_not_named_by_their_content() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let pipeline = ProcessingPipeline::new();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        let store = ChunkStore::new(dir.path().join(CHUNK_DIR)).with_key(Some([3; 32]));
+        let chunks = store.store_chunks(&pipeline, &data[..]).unwrap();
+
+        let mut offset = 0;
+
+        for (hash, len) in &chunks {
+            let chunk = &data[offset..offset + len];
+            offset += len;
+
+            assert_ne!(*hash, <[u8; 32]>::from(Sha256::digest(chunk)));
+            assert_eq!(store.read_chunk(&pipeline, hash, *len).unwrap(), chunk);
+        }
+
+        // Another key names the same chunks differently
+        let other = ChunkStore::new(dir.path().join(CHUNK_DIR)).with_key(Some([4; 32]));
+        let (hash, len) = chunks[0];
+
+        assert_ne!(other.store_chunks(&pipeline, &data[..len]).unwrap()[0].0, hash);
+        assert!(other.read_chunk(&pipeline, &hash, len).is_err());
+    }
+}
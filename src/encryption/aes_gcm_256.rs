@@ -5,13 +5,16 @@ use std::{
 
 // External
 use aes_gcm::{
-    aead::{Aead, OsRng, Nonce},
+    aead::{self, Aead, OsRng, Nonce, Payload},
     AeadCore, KeyInit, Aes256Gcm,
 };
 
 use crate::error::EncryptorInitError;
 
-use super::{DecryptionModule, DecryptorMode, EncryptionModule, EncryptorMode, EncryptionAlgorithm, DecryptionAlgorithm};
+use super::{
+    padding::{Padder, Padding, DATA_FRAME},
+    read_frame, DecryptionModule, DecryptorMode, EncryptionModule, EncryptorMode, EncryptionAlgorithm, DecryptionAlgorithm,
+};
 
 const NONCE_SIZE: usize = 12;
 
@@ -19,7 +22,8 @@ pub struct AesGcmAlgorithm<T, V> {
     key: T,
     // Temporarily stored as Vec<u8> until it is decided how
     // How the nonce will be stored as in zap metadata
-    tag: V
+    tag: V,
+    padding: Padding,
 }
 
 impl AesGcmAlgorithm<(), ()> {
@@ -27,6 +31,7 @@ impl AesGcmAlgorithm<(), ()> {
         AesGcmAlgorithm {
             key: (),
             tag: (),
+            padding: Padding::None,
         }
     }
 }
@@ -42,6 +47,7 @@ impl <T, V> AesGcmAlgorithm<T, V> {
         AesGcmAlgorithm {
             key,
             tag: self.tag,
+            padding: self.padding,
         }
     }
 
@@ -49,8 +55,16 @@ impl <T, V> AesGcmAlgorithm<T, V> {
         AesGcmAlgorithm {
             key: self.key,
             tag,
+            padding: self.padding,
         }
     }
+
+    /// Pads the plaintext with `padding` before its last frame is sealed,
+    /// decrypting then expects it to be padded the same way.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
 }
 
 impl <T> EncryptionAlgorithm<T> for AesGcmAlgorithm<Vec<u8>, ()>
@@ -69,6 +83,7 @@ where T: Write
                 },
                 nonce,
                 internal_buffer: vec![],
+                padder: Padder::new(self.padding),
                 io,
                 mode: PhantomData
             }
@@ -90,6 +105,7 @@ where T: Read
                 },
                 nonce: Nonce::<Aes256Gcm>::default(),
                 internal_buffer: vec![],
+                padder: Padder::new(self.padding),
                 io,
                 mode: PhantomData
             }
@@ -101,6 +117,7 @@ pub struct AesGcmEncryptor<T, M> {
     cipher: Aes256Gcm,
     nonce: Nonce<Aes256Gcm>,
     internal_buffer: Vec<u8>,
+    padder: Padder,
     io: T,
     mode: PhantomData<M>,
 }
@@ -137,23 +154,18 @@ where
 {
     fn finalise(mut self) -> Result<(), std::io::Error> {
 
+        if self.padder.is_padded() {
+            for (frame, kind) in self.padder.end(std::mem::take(&mut self.internal_buffer)) {
+                self.seal(&frame, kind)?;
+            }
+        }
+
         while !self.internal_buffer.is_empty() {
             let drain_len = std::cmp::min(self.internal_buffer.len(), 8192);
 
-            let buf = self.internal_buffer.drain(..drain_len);
-
-            self.nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-            
-            match self.cipher.encrypt(&self.nonce, buf.as_slice()) {
-                Ok(n) => {
-                    self.io.write_all(self.nonce.as_slice())?;
-                    self.io.write_all(&n)?;
-                }
-                Err(e) => return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to encrypt: {}", e),
-                )),
-            }
+            let frame: Vec<u8> = self.internal_buffer.drain(..drain_len).collect();
+
+            self.seal(&frame, DATA_FRAME)?;
         }
 
         self.io.flush()?;
@@ -162,6 +174,28 @@ where
     }
 }
 
+impl<T> AesGcmEncryptor<T, EncryptorMode>
+where
+    T: Write,
+{
+    fn seal(&mut self, msg: &[u8], kind: u8) -> Result<(), Error> {
+        self.padder.write_id(&mut self.io)?;
+        let aad = &self.padder.next_aad(kind);
+
+        self.nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        match self.cipher.encrypt(&self.nonce, Payload { msg, aad }) {
+            Ok(n) => {
+                self.io.write_all(self.nonce.as_slice())?;
+                self.io.write_all(&n)?;
+            }
+            Err(e) => return Err(Error::other(format!("Failed to encrypt: {}", e))),
+        }
+
+        Ok(())
+    }
+}
+
 impl<T> Write for AesGcmEncryptor<T, EncryptorMode>
 where
     T: Write,
@@ -175,22 +209,12 @@ where
         */
 
         self.internal_buffer.extend_from_slice(buf);
+        self.padder.wrote(buf.len());
 
         while self.internal_buffer.len() > 8192 {
-            let buf = self.internal_buffer.drain(..8192);
-
-            self.nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-
-            match self.cipher.encrypt(&self.nonce, buf.as_slice()) {
-                Ok(n) => {
-                    self.io.write_all(self.nonce.as_slice())?;
-                    self.io.write_all(&n)?;
-                }
-                Err(e) => return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to encrypt: {}", e),
-                )),
-            }
+            let frame: Vec<u8> = self.internal_buffer.drain(..8192).collect();
+
+            self.seal(&frame, DATA_FRAME)?;
         }
 
         Ok(buf.len())
@@ -205,47 +229,71 @@ impl<T> DecryptionModule for AesGcmEncryptor<T, DecryptorMode>
 where
     T: Read,
 {
-    fn finalise(self) -> Result<(), Error> {
-        Ok(())
+    fn finalise(mut self) -> Result<(), Error> {
+        // Frames left are padding, they are opened to be checked too
+        while self.padder.is_padded() && self.open_frame()? {}
+
+        self.padder.finish()
     }
 }
 
-impl<T> Read for AesGcmEncryptor<T, DecryptorMode>
+impl<T> AesGcmEncryptor<T, DecryptorMode>
 where
     T: Read,
 {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    /// Decrypts the next frame into the internal buffer, false once
+    /// there are none left.
+    fn open_frame(&mut self) -> Result<bool, Error> {
+        self.padder.read_id(&mut self.io)?;
 
         let mut raw_buf = vec![0u8; 8192+16+NONCE_SIZE];
 
-        let read_len = self.io.read(&mut raw_buf)?;
+        let read_len = read_frame(&mut self.io, &mut raw_buf)?;
 
-        if read_len > 0 {
-            let raw_nonce= raw_buf.drain(..12).collect::<Vec<u8>>();
-            let nonce = Nonce::<Aes256Gcm>::from_slice(&raw_nonce);
+        if read_len == 0 {
+            return Ok(false);
+        }
 
-            match self.cipher.decrypt(
-                nonce,
-                &raw_buf[..(read_len-12)], /* Payload {
-                                          msg: &raw_buf[..read_len],
-                                          aad: &self.key
-                                      }*/
-            ) {
-                Ok(plaintext) => {
-                    // May consider changing this so that cipher.update writes
-                    // directly to self.internal_buffer. For now though we can
-                    // extend self.internal_buffer from dec_buf.
-                    self.internal_buffer.extend_from_slice(&plaintext);
-                },
-                Err(e) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to decrypt: {}", e),
-                    ))
-                }
-            }
+        if self.padder.kinds().is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Data after the last padded frame"));
         }
 
+        if read_len < NONCE_SIZE + 16 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated encrypted frame"));
+        }
+
+        let raw_nonce= raw_buf.drain(..12).collect::<Vec<u8>>();
+        let nonce = Nonce::<Aes256Gcm>::from_slice(&raw_nonce);
+        let msg = &raw_buf[..(read_len-12)];
+
+        let opened = self.padder.kinds().iter().find_map(|&kind| {
+            let aad = &self.padder.aad(kind);
+
+            self.cipher
+                .decrypt(nonce, Payload { msg, aad })
+                .ok()
+                .map(|plaintext| (plaintext, kind))
+        });
+
+        match opened {
+            Some((plaintext, kind)) => {
+                let data = self.padder.strip(&plaintext, kind)?;
+                self.internal_buffer.extend_from_slice(data);
+            },
+            None => return Err(Error::other(format!("Failed to decrypt: {}", aead::Error))),
+        }
+
+        Ok(true)
+    }
+}
+
+impl<T> Read for AesGcmEncryptor<T, DecryptorMode>
+where
+    T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.open_frame()?;
+
         // Copy n bytes where n is the lesser of buf and internal_buf
         // The copy is super jank but that will hopefully change when
         // slice.take() take comes out of nightly.
@@ -3,7 +3,7 @@ use crate::error::EncryptorInitError;
 
 // External
 use aes_gcm::{
-    aead::{Aead, OsRng},
+    aead::{self, Aead, OsRng, Payload},
     KeyInit, AeadCore,
 };
 use chacha20poly1305::{ChaCha20Poly1305, Nonce};
@@ -12,18 +12,23 @@ use std::{
     vec, marker::PhantomData,
 };
 
-use super::{EncryptionAlgorithm, EncryptorMode, DecryptionAlgorithm, DecryptorMode, EncryptionModule, DecryptionModule};
+use super::{
+    padding::{Padder, Padding, DATA_FRAME},
+    read_frame, EncryptionAlgorithm, EncryptorMode, DecryptionAlgorithm, DecryptorMode, EncryptionModule, DecryptionModule,
+};
 
 const NONCE_SIZE: usize = 12;
 
 pub struct ChaChaPolyAlgorithm<T> {
     key: T,
+    padding: Padding,
 }
 
 impl ChaChaPolyAlgorithm<()> {
     pub fn new() -> ChaChaPolyAlgorithm<()> {
         ChaChaPolyAlgorithm {
             key: (),
+            padding: Padding::None,
         }
     }
 }
@@ -39,8 +44,16 @@ impl <T> ChaChaPolyAlgorithm<T> {
     pub fn with_key(self, key: Vec<u8>) -> ChaChaPolyAlgorithm<Vec<u8>> {
         ChaChaPolyAlgorithm {
             key,
+            padding: self.padding,
         }
     }
+
+    /// Pads the plaintext with `padding` before its last frame is sealed,
+    /// decrypting then expects it to be padded the same way.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
 }
 
 impl <T> EncryptionAlgorithm<T> for ChaChaPolyAlgorithm<Vec<u8>>
@@ -55,6 +68,7 @@ where T: Write
                 Err(e) => return Err(EncryptorInitError::AlgorithmError(format!("ChaChaPoly: {}", e))),
             },
             internal_buffer: vec![],
+            padder: Padder::new(self.padding),
             io: writer,
             mode: PhantomData
         })
@@ -73,6 +87,7 @@ where T: Read
                 Err(e) => return Err(EncryptorInitError::AlgorithmError(format!("ChaChaPoly: {}", e))),
             },
             internal_buffer: vec![],
+            padder: Padder::new(self.padding),
             io: reader,
             mode: PhantomData
         })
@@ -84,6 +99,7 @@ pub struct ChaChaPoly<T, M> {
     // Temporarily stored as Vec<u8> until it is decided how
     // How the nonce will be stored as in zap metadata
     internal_buffer: Vec<u8>,
+    padder: Padder,
     io: T,
     mode: PhantomData<M>,
 } 
@@ -92,23 +108,18 @@ impl <T> EncryptionModule for ChaChaPoly<T, EncryptorMode>
 where T: Write
 {
     fn finalise(mut self) -> Result<(), Error> {
+        if self.padder.is_padded() {
+            for (frame, kind) in self.padder.end(std::mem::take(&mut self.internal_buffer)) {
+                self.seal(&frame, kind)?;
+            }
+        }
+
         while !self.internal_buffer.is_empty() {
             let drain_len = std::cmp::min(self.internal_buffer.len(), 8192);
 
-            let buf = self.internal_buffer.drain(..drain_len);
-
-            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-            
-            match self.cipher.encrypt(&nonce, buf.as_slice()) {
-                Ok(n) => {
-                    self.io.write_all(&nonce)?;
-                    self.io.write_all(&n)?;
-                }
-                Err(e) => return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to encrypt: {}", e),
-                )),
-            }
+            let frame: Vec<u8> = self.internal_buffer.drain(..drain_len).collect();
+
+            self.seal(&frame, DATA_FRAME)?;
         }
 
         self.io.flush()?;
@@ -117,6 +128,28 @@ where T: Write
     }
 }
 
+impl<T> ChaChaPoly<T, EncryptorMode>
+where
+    T: Write,
+{
+    fn seal(&mut self, msg: &[u8], kind: u8) -> Result<(), Error> {
+        self.padder.write_id(&mut self.io)?;
+        let aad = &self.padder.next_aad(kind);
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        match self.cipher.encrypt(&nonce, Payload { msg, aad }) {
+            Ok(n) => {
+                self.io.write_all(&nonce)?;
+                self.io.write_all(&n)?;
+            }
+            Err(e) => return Err(Error::other(format!("Failed to encrypt: {}", e))),
+        }
+
+        Ok(())
+    }
+}
+
 impl<T> Write for ChaChaPoly<T, EncryptorMode>
 where
     T: Write,
@@ -134,22 +167,12 @@ where
         */
 
         self.internal_buffer.extend_from_slice(buf);
+        self.padder.wrote(buf.len());
 
         while self.internal_buffer.len() > 8192 {
-            let buf = self.internal_buffer.drain(..8192);
-
-            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-
-            match self.cipher.encrypt(&nonce, buf.as_slice()) {
-                Ok(n) => {
-                    self.io.write_all(&nonce)?;
-                    self.io.write_all(&n)?;
-                }
-                Err(e) => return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Failed to encrypt: {}", e),
-                )),
-            }
+            let frame: Vec<u8> = self.internal_buffer.drain(..8192).collect();
+
+            self.seal(&frame, DATA_FRAME)?;
         }
 
         Ok(buf.len())
@@ -159,47 +182,71 @@ where
 impl <T> DecryptionModule for ChaChaPoly<T, DecryptorMode>
 where T: Read
 {
-    fn finalise(self) -> Result<(), Error> {
-        Ok(())
+    fn finalise(mut self) -> Result<(), Error> {
+        // Frames left are padding, they are opened to be checked too
+        while self.padder.is_padded() && self.open_frame()? {}
+
+        self.padder.finish()
     }
 }
 
-impl<T> Read for ChaChaPoly<T, DecryptorMode>
+impl<T> ChaChaPoly<T, DecryptorMode>
 where
     T: Read,
 {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        
+    /// Decrypts the next frame into the internal buffer, false once
+    /// there are none left.
+    fn open_frame(&mut self) -> Result<bool, Error> {
+        self.padder.read_id(&mut self.io)?;
+
         let mut raw_buf = vec![0u8; 8192+16+NONCE_SIZE];
 
-        let read_len = self.io.read(&mut raw_buf)?;
-
-        if read_len > 0 {
-            let raw_nonce= raw_buf.drain(..NONCE_SIZE).collect::<Vec<u8>>();
-            let nonce = Nonce::from_slice(&raw_nonce);
-
-            match self.cipher.decrypt(
-                nonce,
-                &raw_buf[..(read_len-NONCE_SIZE)], /* Payload {
-                                          msg: &raw_buf[..read_len],
-                                          aad: &self.key
-                                      }*/
-            ) {
-                Ok(plaintext) => {
-                    // May consider changing this so that cipher.update writes
-                    // directly to self.internal_buffer. For now though we can
-                    // extend self.internal_buffer from dec_buf.
-                    self.internal_buffer.extend_from_slice(&plaintext);
-                },
-                Err(e) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        format!("Failed to decrypt: {}", e),
-                    ))
-                }
-            }
+        let read_len = read_frame(&mut self.io, &mut raw_buf)?;
+
+        if read_len == 0 {
+            return Ok(false);
+        }
+
+        if self.padder.kinds().is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Data after the last padded frame"));
+        }
+
+        if read_len < NONCE_SIZE + 16 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated encrypted frame"));
+        }
+
+        let raw_nonce= raw_buf.drain(..NONCE_SIZE).collect::<Vec<u8>>();
+        let nonce = Nonce::from_slice(&raw_nonce);
+        let msg = &raw_buf[..(read_len-NONCE_SIZE)];
+
+        let opened = self.padder.kinds().iter().find_map(|&kind| {
+            let aad = &self.padder.aad(kind);
+
+            self.cipher
+                .decrypt(nonce, Payload { msg, aad })
+                .ok()
+                .map(|plaintext| (plaintext, kind))
+        });
+
+        match opened {
+            Some((plaintext, kind)) => {
+                let data = self.padder.strip(&plaintext, kind)?;
+                self.internal_buffer.extend_from_slice(data);
+            },
+            None => return Err(Error::other(format!("Failed to decrypt: {}", aead::Error))),
         }
 
+        Ok(true)
+    }
+}
+
+impl<T> Read for ChaChaPoly<T, DecryptorMode>
+where
+    T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.open_frame()?;
+
         // Copy n bytes where n is the lesser of buf and internal_buf
         // The copy is super jank but that will hopefully change when
         // slice.take() take comes out of nightly.
@@ -0,0 +1,592 @@
+use std::{
+    fmt, fs,
+    io::{Error, ErrorKind},
+    path::Path,
+    str::FromStr,
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, rand_core::RngCore},
+    ChaCha20Poly1305, Nonce, XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::{dedup, error::EncryptionKeyError};
+
+use super::EncryptionSecret;
+
+/*
+    Entries are encrypted with a random file key, which the header
+    stores once for every password or public key that can open the
+    archive, in a key slot. Changing these only rewrites the header.
+
+    A key slot holds the file key encrypted with ChaCha20Poly1305
+    under a key made for that slot alone, so the nonce is left at
+    zero.
+
+    For a password, that key is derived with Argon2id from the
+    password and a random salt, the parameters are kept in the slot.
+
+    For an X25519 recipient, a new ephemeral key pair is made for each
+    slot. The shared secret it agrees on with the recipient key goes
+    through HKDF-SHA256, salted with both public keys. A key file is
+    stored as a slot for its public key.
+
+    Archives written before key slots existed use the password as the
+    file key.
+
+    When metadata is encrypted too, it is encrypted with XChaCha20Poly1305
+    under a key derived from the file key with HKDF-SHA256, whichever
+    algorithm the entries use.
+
+    Deduplicated chunks of encrypted entries are named by their
+    HMAC-SHA256 under another key derived the same way, rather than by
+    their plain SHA-256, so that a name doesn't tell whether a known
+    file is stored. The content hashes kept in the metadata of their
+    entries are made the same way, under a third key.
+*/
+
+const SECRET_PREFIX: &str = "zap-secret-";
+const PUBLIC_PREFIX: &str = "zap-public-";
+const WRAP_INFO: &[u8] = b"zap x25519 file key";
+const METADATA_INFO: &[u8] = b"zap metadata";
+const CHUNK_INFO: &[u8] = b"zap chunk names";
+const CONTENT_INFO: &[u8] = b"zap content hashes";
+/// How many times the default Argon2 costs a password slot can ask for,
+/// so that a crafted header can't make opening an archive take all of
+/// the memory or hours of work.
+const MAX_COST_FACTOR: u32 = 16;
+
+/// A private key, archives encrypted for its `Recipient` are opened
+/// with it.
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Reads a key file written by `zap keygen`.
+    pub fn read<P>(path: P) -> Result<Identity, EncryptionKeyError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        match fs::read_to_string(path) {
+            Ok(text) => text.trim().parse(),
+            Err(_) => Err(EncryptionKeyError::FailedToFindKeyfile(path.display().to_string())),
+        }
+    }
+
+    /// The public key of this identity.
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = EncryptionKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(SECRET_PREFIX).and_then(dedup::from_hex::<32>) {
+            Some(bytes) => Ok(Identity(StaticSecret::from(bytes))),
+            None => Err(EncryptionKeyError::InvalidKey("not a zap private key".into())),
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", SECRET_PREFIX, dedup::to_hex(self.0.as_bytes()))
+    }
+}
+
+/// A public key archives can be encrypted for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl FromStr for Recipient {
+    type Err = EncryptionKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(PUBLIC_PREFIX).and_then(dedup::from_hex::<32>) {
+            Some(bytes) => Ok(Recipient(PublicKey::from(bytes))),
+            None => Err(EncryptionKeyError::InvalidKey(format!("{} is not a zap public key", s))),
+        }
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PUBLIC_PREFIX, dedup::to_hex(self.0.as_bytes()))
+    }
+}
+
+/// A copy of the file key of an archive, stored in its header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub(crate) enum KeySlot {
+    /// Encrypted with a key derived from a password.
+    Password {
+        salt: String,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        key: String,
+    },
+    /// Encrypted for an X25519 public key, `ephemeral` being the public
+    /// half of the key pair made for the slot.
+    X25519 { ephemeral: String, key: String },
+}
+
+/// The random key the entries of an archive are encrypted with, along
+/// with its key slots.
+#[derive(Clone)]
+pub struct FileKey {
+    key: [u8; 32],
+    slots: Vec<KeySlot>,
+}
+
+impl FileKey {
+    /// A new key, without any slot yet.
+    pub fn generate() -> FileKey {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+
+        FileKey { key, slots: vec![] }
+    }
+
+    /// Opens the first of `slots` that `secret` fits, returning the key
+    /// along with the index of that slot.
+    pub(crate) fn open(slots: Vec<KeySlot>, secret: &EncryptionSecret) -> Result<(FileKey, usize), EncryptionKeyError> {
+        let (index, key) = match secret {
+            EncryptionSecret::Password(password) => open_password_slot(&slots, password)?,
+            EncryptionSecret::Key(path) => open_x25519_slot(&slots, &Identity::read(path)?)?,
+            EncryptionSecret::None => return Err(EncryptionKeyError::KeyfileNotProvided),
+        };
+
+        Ok((FileKey { key, slots }, index))
+    }
+
+    /// Adds a slot opened with `password`.
+    pub fn add_password(&mut self, password: &[u8]) -> Result<(), EncryptionKeyError> {
+        let mut salt = [0u8; 32];
+        OsRng.fill_bytes(&mut salt);
+
+        let params = Params::default();
+        let wrapped = encrypt_key(&password_key(password, &salt, &params)?, &self.key)?;
+
+        self.slots.push(KeySlot::Password {
+            salt: dedup::to_hex(&salt),
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            key: dedup::to_hex(&wrapped),
+        });
+
+        Ok(())
+    }
+
+    /// Adds a slot opened with the private key of `recipient`.
+    pub fn add_recipient(&mut self, recipient: &Recipient) -> Result<(), EncryptionKeyError> {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.0);
+
+        // Low order points would make the shared secret predictable
+        if !shared.was_contributory() {
+            return Err(EncryptionKeyError::InvalidKey(format!("{} can't be encrypted for", recipient)));
+        }
+
+        let wrapped = encrypt_key(&x25519_key(shared.as_bytes(), &ephemeral_public, &recipient.0), &self.key)?;
+
+        self.slots.push(KeySlot::X25519 {
+            ephemeral: dedup::to_hex(ephemeral_public.as_bytes()),
+            key: dedup::to_hex(&wrapped),
+        });
+
+        Ok(())
+    }
+
+    /// Removes the slot at `index`, the key can't be left without any.
+    pub(crate) fn remove_slot(&mut self, index: usize) -> Result<(), EncryptionKeyError> {
+        if self.slots.len() <= 1 {
+            return Err(EncryptionKeyError::LastKeySlot);
+        }
+
+        self.slots.remove(index);
+
+        Ok(())
+    }
+
+    /// The key as the secret the encryption stages are given.
+    pub fn secret(&self) -> EncryptionSecret {
+        EncryptionSecret::Password(self.key.to_vec())
+    }
+
+    pub(crate) fn slots(&self) -> &[KeySlot] {
+        &self.slots
+    }
+}
+
+/// Encrypts the header and index of archives with encrypted metadata.
+#[derive(Clone)]
+pub(crate) struct MetadataKey(XChaCha20Poly1305);
+
+impl MetadataKey {
+    /// Derived from `secret`, which has to be the file key the entries
+    /// are encrypted with.
+    pub fn new(secret: &EncryptionSecret) -> Option<MetadataKey> {
+        let file_key = match secret {
+            EncryptionSecret::Password(file_key) => file_key,
+            _ => return None,
+        };
+
+        let mut key = [0u8; 32];
+
+        Hkdf::<Sha256>::new(None, file_key)
+            .expand(METADATA_INFO, &mut key)
+            .unwrap();
+
+        Some(MetadataKey(XChaCha20Poly1305::new(&key.into())))
+    }
+
+    /// `data` encrypted, after the random nonce it was encrypted with.
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        match self.0.encrypt(&nonce, data) {
+            Ok(encrypted) => Ok([nonce.as_slice(), &encrypted].concat()),
+            Err(e) => Err(Error::other(format!("Failed to encrypt metadata: {}", e))),
+        }
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce_size = XNonce::default().len();
+
+        if data.len() < nonce_size {
+            return Err(Error::new(ErrorKind::InvalidData, "Encrypted metadata is too short"));
+        }
+
+        let (nonce, data) = data.split_at(nonce_size);
+
+        self.0
+            .decrypt(XNonce::from_slice(nonce), data)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Failed to decrypt metadata"))
+    }
+}
+
+/// Key chunks of entries encrypted with `secret` are named with, see
+/// `dedup::chunk_id`.
+pub(crate) fn chunk_key(secret: &EncryptionSecret) -> Option<[u8; 32]> {
+    derive_key(secret, CHUNK_INFO)
+}
+
+/// Key the content hashes of entries encrypted with `secret` are made
+/// with, see `archive::HashingReader`.
+pub(crate) fn content_key(secret: &EncryptionSecret) -> Option<[u8; 32]> {
+    derive_key(secret, CONTENT_INFO)
+}
+
+fn derive_key(secret: &EncryptionSecret, info: &[u8]) -> Option<[u8; 32]> {
+    let file_key = match secret {
+        EncryptionSecret::Password(file_key) => file_key,
+        _ => return None,
+    };
+
+    let mut key = [0u8; 32];
+
+    Hkdf::<Sha256>::new(None, file_key)
+        .expand(info, &mut key)
+        .unwrap();
+
+    Some(key)
+}
+
+/// The secret to decrypt entries with, for an archive with `slots`.
+pub(crate) fn file_secret(slots: &[KeySlot], secret: EncryptionSecret) -> Result<EncryptionSecret, EncryptionKeyError> {
+    match (slots.is_empty(), secret) {
+        (_, EncryptionSecret::None) => Ok(EncryptionSecret::None),
+        (false, secret) => Ok(FileKey::open(slots.to_vec(), &secret)?.0.secret()),
+        (true, EncryptionSecret::Key(_)) => Err(EncryptionKeyError::PasswordRequired),
+        (true, secret) => Ok(secret),
+    }
+}
+
+fn open_password_slot(slots: &[KeySlot], password: &[u8]) -> Result<(usize, [u8; 32]), EncryptionKeyError> {
+    let mut found = false;
+    let mut rejected = None;
+
+    for (index, slot) in slots.iter().enumerate() {
+        let (salt, params, wrapped) = match slot {
+            KeySlot::Password {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+                key,
+            } => (salt, slot_params(*m_cost, *t_cost, *p_cost), key),
+            _ => continue,
+        };
+
+        found = true;
+
+        let (salt, params) = match (dedup::from_hex::<32>(salt), params) {
+            (Some(salt), Ok(params)) => (salt, params),
+            (_, Err(e)) => {
+                rejected = Some(e);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if let Some(key) = decrypt_key(&password_key(password, &salt, &params)?, wrapped) {
+            return Ok((index, key));
+        }
+    }
+
+    match (found, rejected) {
+        (_, Some(e)) => Err(e),
+        (true, None) => Err(EncryptionKeyError::NoMatchingSlot),
+        (false, None) => Err(EncryptionKeyError::KeyRequired),
+    }
+}
+
+/// The Argon2 parameters of a password slot, unless they cost more than
+/// `MAX_COST_FACTOR` times the default ones.
+fn slot_params(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Params, EncryptionKeyError> {
+    let default = Params::default();
+
+    if m_cost > default.m_cost() * MAX_COST_FACTOR
+        || t_cost > default.t_cost() * MAX_COST_FACTOR
+        || p_cost > default.p_cost() * MAX_COST_FACTOR
+    {
+        return Err(EncryptionKeyError::InvalidKey(format!(
+            "a key slot asks for {} KiB of memory, {} passes and {} lanes, more than is allowed",
+            m_cost, t_cost, p_cost
+        )));
+    }
+
+    Params::new(m_cost, t_cost, p_cost, None).map_err(|e| EncryptionKeyError::InvalidKey(e.to_string()))
+}
+
+fn open_x25519_slot(slots: &[KeySlot], identity: &Identity) -> Result<(usize, [u8; 32]), EncryptionKeyError> {
+    let recipient = identity.recipient();
+    let mut found = false;
+
+    for (index, slot) in slots.iter().enumerate() {
+        let (ephemeral, wrapped) = match slot {
+            KeySlot::X25519 { ephemeral, key } => (ephemeral, key),
+            _ => continue,
+        };
+
+        found = true;
+
+        let ephemeral = match dedup::from_hex::<32>(ephemeral) {
+            Some(ephemeral) => PublicKey::from(ephemeral),
+            None => continue,
+        };
+
+        let shared = identity.0.diffie_hellman(&ephemeral);
+
+        if let Some(key) = decrypt_key(&x25519_key(shared.as_bytes(), &ephemeral, &recipient.0), wrapped) {
+            return Ok((index, key));
+        }
+    }
+
+    match found {
+        true => Err(EncryptionKeyError::NoMatchingSlot),
+        false => Err(EncryptionKeyError::PasswordRequired),
+    }
+}
+
+fn password_key(password: &[u8], salt: &[u8], params: &Params) -> Result<[u8; 32], EncryptionKeyError> {
+    let mut key = [0u8; 32];
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password_into(password, salt, &mut key)
+        .map_err(|e| EncryptionKeyError::InvalidKey(e.to_string()))?;
+
+    Ok(key)
+}
+
+fn x25519_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+
+    // Only fails for outputs longer than 255 hashes
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .unwrap();
+
+    key
+}
+
+fn encrypt_key(slot_key: &[u8; 32], key: &[u8; 32]) -> Result<Vec<u8>, EncryptionKeyError> {
+    ChaCha20Poly1305::new(slot_key.into())
+        .encrypt(&Nonce::default(), key.as_slice())
+        .map_err(|e| EncryptionKeyError::InvalidKey(e.to_string()))
+}
+
+/// The file key, unless `slot_key` isn't the key of the slot.
+fn decrypt_key(slot_key: &[u8; 32], wrapped: &str) -> Option<[u8; 32]> {
+    let wrapped = dedup::from_hex::<48>(wrapped)?;
+
+    ChaCha20Poly1305::new(slot_key.into())
+        .decrypt(&Nonce::default(), wrapped.as_slice())
+        .ok()?
+        .try_into()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// `identity` written as a key file, as the secret to open slots with.
+    fn key_file(dir: &TempDir, name: &str, identity: &Identity) -> EncryptionSecret {
+        let path = dir.path().join(name);
+        fs::write(&path, identity.to_string()).unwrap();
+
+        EncryptionSecret::Key(path.display().to_string())
+    }
+
+    #[test]
+    fn every_recipient_opens_the_key() {
+        let dir = TempDir::new().unwrap();
+        let identities = [Identity::generate(), Identity::generate(), Identity::generate()];
+
+        let mut file_key = FileKey::generate();
+
+        for identity in identities.iter() {
+            file_key.add_recipient(&identity.recipient()).unwrap();
+        }
+
+        for (index, identity) in identities.iter().enumerate() {
+            let secret = key_file(&dir, &format!("{}.key", index), identity);
+            let (opened, slot) = FileKey::open(file_key.slots().to_vec(), &secret).unwrap();
+
+            assert_eq!(opened.key, file_key.key);
+            assert_eq!(slot, index);
+        }
+    }
+
+    #[test]
+    fn wrong_identity_matches_no_slot() {
+        let dir = TempDir::new().unwrap();
+
+        let mut file_key = FileKey::generate();
+        file_key.add_recipient(&Identity::generate().recipient()).unwrap();
+
+        let secret = key_file(&dir, "other.key", &Identity::generate());
+
+        assert!(matches!(
+            FileKey::open(file_key.slots().to_vec(), &secret),
+            Err(EncryptionKeyError::NoMatchingSlot)
+        ));
+
+        // Nor does a password open a slot for a public key
+        assert!(matches!(
+            FileKey::open(file_key.slots().to_vec(), &EncryptionSecret::Password(b"password".to_vec())),
+            Err(EncryptionKeyError::KeyRequired)
+        ));
+    }
+
+    #[test]
+    fn low_order_recipient_is_rejected() {
+        let mut file_key = FileKey::generate();
+
+        // Zero and one are points of small order
+        let mut one = [0u8; 32];
+        one[0] = 1;
+
+        for point in [[0u8; 32], one] {
+            let recipient = Recipient(PublicKey::from(point));
+
+            assert!(matches!(
+                file_key.add_recipient(&recipient),
+                Err(EncryptionKeyError::InvalidKey(_))
+            ));
+        }
+
+        assert!(file_key.slots().is_empty());
+    }
+
+    #[test]
+    fn every_password_opens_the_key() {
+        let mut file_key = FileKey::generate();
+        file_key.add_password(b"one").unwrap();
+        file_key.add_password(b"two").unwrap();
+
+        for (index, password) in [&b"one"[..], b"two"].into_iter().enumerate() {
+            let secret = EncryptionSecret::Password(password.to_vec());
+            let (opened, slot) = FileKey::open(file_key.slots().to_vec(), &secret).unwrap();
+
+            assert_eq!(opened.key, file_key.key);
+            assert_eq!(slot, index);
+        }
+
+        assert!(matches!(
+            FileKey::open(file_key.slots().to_vec(), &EncryptionSecret::Password(b"three".to_vec())),
+            Err(EncryptionKeyError::NoMatchingSlot)
+        ));
+    }
+
+    #[test]
+    fn costly_password_slot_is_rejected() {
+        let mut file_key = FileKey::generate();
+        file_key.add_password(b"one").unwrap();
+
+        let default = Params::default();
+
+        for (m_cost, t_cost, p_cost) in [
+            (u32::MAX, default.t_cost(), default.p_cost()),
+            (default.m_cost(), 1 << 20, default.p_cost()),
+            (default.m_cost(), default.t_cost(), 1 << 20),
+        ] {
+            let slots: Vec<KeySlot> = file_key
+                .slots()
+                .iter()
+                .map(|slot| match slot.clone() {
+                    KeySlot::Password { salt, key, .. } => KeySlot::Password {
+                        salt,
+                        m_cost,
+                        t_cost,
+                        p_cost,
+                        key,
+                    },
+                    slot => slot,
+                })
+                .collect();
+
+            assert!(matches!(
+                FileKey::open(slots, &EncryptionSecret::Password(b"one".to_vec())),
+                Err(EncryptionKeyError::InvalidKey(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn keys_round_trip_as_text() {
+        let identity = Identity::generate();
+
+        let parsed: Identity = identity.to_string().parse().unwrap();
+        assert_eq!(parsed.recipient(), identity.recipient());
+
+        let recipient: Recipient = identity.recipient().to_string().parse().unwrap();
+        assert_eq!(recipient, identity.recipient());
+
+        assert!("zap-public-00".parse::<Recipient>().is_err());
+        assert!(identity.recipient().to_string().parse::<Identity>().is_err());
+    }
+}
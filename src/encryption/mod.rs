@@ -1,5 +1,7 @@
 pub mod aes_gcm_256;
 pub mod chachapoly;
+pub mod keys;
+pub mod padding;
 pub mod passthrough;
 pub mod xchachapoly;
 
@@ -8,7 +10,12 @@ use crate::error::EncryptorInitError;
 
 // External
 
-use std::io::{Error, Read, Write};
+use std::{
+    future::Future,
+    io::{Error, Read, Write},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite};
 
 pub struct EncryptorMode;
 pub struct DecryptorMode;
@@ -20,6 +27,39 @@ pub trait DecryptionModule: Read {
     fn finalise(self) -> Result<(), Error>;
 }
 
+/// Async counterpart of [`EncryptionModule`]. Writing goes through
+/// `AsyncWrite`, finalise pads and flushes the last block.
+pub trait AsyncEncryptionModule: AsyncWrite + Unpin + Send {
+    fn finalise(self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Async counterpart of [`DecryptionModule`]. Finalise checks whatever
+/// is left after the data, like padding.
+pub trait AsyncDecryptionModule: AsyncRead + Unpin + Send {
+    fn finalise(self) -> impl Future<Output = Result<(), Error>> + Send;
+}
+
+/// Reads one encrypted frame, only stopping short of `buf.len()` at the
+/// end of the stream. A single `read` is not enough for sources such as
+/// sockets, which may hand out a frame in several pieces.
+pub(crate) fn read_frame<R>(io: &mut R, buf: &mut [u8]) -> Result<usize, Error>
+where
+    R: Read,
+{
+    let mut read_len = 0;
+
+    while read_len < buf.len() {
+        match io.read(&mut buf[read_len..]) {
+            Ok(0) => break,
+            Ok(n) => read_len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(read_len)
+}
+
 pub trait EncryptionAlgorithm<T>
 where
     T: Write,
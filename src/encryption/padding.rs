@@ -0,0 +1,429 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+/*
+    A padded stream starts with a random id, followed by its frames:
+    [ id ][ frame ][ frame ]...
+    [ 16 ][ ...   ][ ...   ]
+
+    The plaintext of the frames is framed like any other, as:
+    [ data ][ length ][ data ][ zeros ]
+    [ ...  ][ 8      ][ ...  ][ ...   ] (Bytes, LE)
+
+    Frames sealed before the stream is finished only hold data. The
+    length is that of all the data and starts the first frame sealed
+    once it is finished. The zeros make the plaintext
+    `Padding::padded_len` of the data and the length field.
+
+    Every frame is sealed with the id, its index and its kind as
+    associated data, so that frames can't be reordered, dropped or
+    taken from another stream without opening failing:
+    [ id ][ index ][ kind ]
+    [ 16 ][ 8     ][ 1    ] (Bytes, LE)
+*/
+
+/// Kind of a frame only holding data.
+pub(crate) const DATA_FRAME: u8 = 0;
+/// Kind of the frame starting with the length of the data.
+pub(crate) const LENGTH_FRAME: u8 = 1;
+/// Kind of the frame ending the stream, which may be the length frame too.
+pub(crate) const LAST_FRAME: u8 = 2;
+
+/// Plaintext of every frame but the last, as the encryption modules
+/// seal them.
+const FRAME_SIZE: usize = 8192;
+
+const LENGTH_SIZE: usize = 8;
+
+const ID_SIZE: usize = 16;
+
+/// Padding added to the plaintext of encrypted entries, so that their
+/// stored size gives less of their true size away.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// Padmé, which rounds lengths to a multiple of a power of two so
+    /// that at most `O(log log n)` bits of the length are left. The
+    /// overhead is at most 12%, and less for longer lengths.
+    Padme,
+}
+
+impl Padding {
+    /// Length the plaintext of `len` bytes is padded to.
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match self {
+            Padding::None => len,
+            Padding::Padme => padme(len),
+        }
+    }
+}
+
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+
+    let exponent = len.ilog2();
+    let last_bits = exponent - (exponent.ilog2() + 1);
+    let mask = (1u64 << last_bits) - 1;
+
+    (len + mask) & !mask
+}
+
+/// Pads the plaintext of an encryption module, or strips the padding
+/// again when decrypting.
+#[derive(Default)]
+pub(crate) struct Padder {
+    padding: Padding,
+    /// Id of the stream, once it was written or read.
+    id: Option<[u8; ID_SIZE]>,
+    /// Index of the next frame.
+    index: u64,
+    /// Plaintext written or read so far, padding included.
+    len: u64,
+    /// Data left to read, once the length frame was read.
+    data_left: Option<u64>,
+    /// Plaintext the stream is padded to, once the length frame was read.
+    padded_len: u64,
+    /// True once the last frame was read.
+    ended: bool,
+}
+
+impl Padder {
+    pub fn new(padding: Padding) -> Padder {
+        Padder {
+            padding,
+            ..Padder::default()
+        }
+    }
+
+    pub fn is_padded(&self) -> bool {
+        self.padding != Padding::None
+    }
+
+    /// Writes the id of the stream, before its first frame is sealed.
+    pub fn write_id<W: Write>(&mut self, io: &mut W) -> Result<(), Error> {
+        if self.is_padded() && self.id.is_none() {
+            let mut id = [0u8; ID_SIZE];
+            OsRng.fill_bytes(&mut id);
+
+            io.write_all(&id)?;
+            self.id = Some(id);
+        }
+
+        Ok(())
+    }
+
+    /// Reads the id of the stream, before its first frame is opened.
+    pub fn read_id<R: Read>(&mut self, io: &mut R) -> Result<(), Error> {
+        if self.is_padded() && self.id.is_none() {
+            let mut id = [0u8; ID_SIZE];
+
+            match io.read_exact(&mut id) {
+                Ok(()) => self.id = Some(id),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Err(Error::new(ErrorKind::InvalidData, "Padded stream is truncated"))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Associated data to seal or open the next frame with, if it is of
+    /// `kind`. Frames of streams that aren't padded have none.
+    pub fn aad(&self, kind: u8) -> Vec<u8> {
+        match self.id {
+            Some(id) if self.is_padded() => [&id[..], &self.index.to_le_bytes(), &[kind]].concat(),
+            _ => vec![],
+        }
+    }
+
+    /// Associated data to seal the next frame with, counting it as sealed.
+    pub fn next_aad(&mut self, kind: u8) -> Vec<u8> {
+        let aad = self.aad(kind);
+        self.index += 1;
+
+        aad
+    }
+
+    /// Kinds the next frame read may be of, in the order to try them.
+    pub fn kinds(&self) -> &'static [u8] {
+        match (self.is_padded(), self.data_left, self.ended) {
+            (false, _, _) => &[DATA_FRAME],
+            (true, _, true) => &[],
+            (true, None, false) => &[DATA_FRAME, LENGTH_FRAME, LENGTH_FRAME | LAST_FRAME],
+            (true, Some(_), false) => &[DATA_FRAME, LAST_FRAME],
+        }
+    }
+
+    /// Counts `n` bytes of data written.
+    pub fn wrote(&mut self, n: usize) {
+        self.len += n as u64;
+    }
+
+    /// Frames ending the stream, `tail` being the data that wasn't
+    /// sealed yet, along with the kind to seal each as.
+    pub fn end(&self, tail: Vec<u8>) -> PaddedEnd {
+        let padded_len = self.padding.padded_len(self.len + LENGTH_SIZE as u64);
+
+        PaddedEnd {
+            buf: [&self.len.to_le_bytes()[..], &tail].concat(),
+            zeros: padded_len - self.len - LENGTH_SIZE as u64,
+            first: true,
+        }
+    }
+
+    /// The data of a frame opened as `kind`, without the padding.
+    pub fn strip<'a>(&mut self, plaintext: &'a [u8], kind: u8) -> Result<&'a [u8], Error> {
+        if !self.is_padded() {
+            return Ok(plaintext);
+        }
+
+        let data_len = self.len;
+        self.len += plaintext.len() as u64;
+        self.index += 1;
+        self.ended = kind & LAST_FRAME != 0;
+
+        let plaintext = match kind & LENGTH_FRAME != 0 {
+            true if plaintext.len() >= LENGTH_SIZE => {
+                let (length, plaintext) = plaintext.split_at(LENGTH_SIZE);
+                let length = u64::from_le_bytes(length.try_into().unwrap());
+
+                if length < data_len {
+                    return Err(Error::new(ErrorKind::InvalidData, "Invalid padded length"));
+                }
+
+                self.data_left = Some(length - data_len);
+                self.padded_len = self.padding.padded_len(length + LENGTH_SIZE as u64);
+
+                plaintext
+            }
+            true => return Err(Error::new(ErrorKind::InvalidData, "Truncated length frame")),
+            false => plaintext,
+        };
+
+        match self.data_left.as_mut() {
+            Some(left) => {
+                let data = plaintext.len().min(*left as usize);
+                *left -= data as u64;
+
+                Ok(&plaintext[..data])
+            }
+            None => Ok(plaintext),
+        }
+    }
+
+    /// Fails unless the whole padded stream was read.
+    pub fn finish(&self) -> Result<(), Error> {
+        if !self.is_padded() {
+            return Ok(());
+        }
+
+        match self.data_left {
+            Some(0) if self.ended && self.len == self.padded_len => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidData, "Padded stream is truncated")),
+        }
+    }
+}
+
+/// Frames ending a padded stream, see `Padder::end`.
+pub(crate) struct PaddedEnd {
+    buf: Vec<u8>,
+    /// Zeros left to add after `buf`.
+    zeros: u64,
+    first: bool,
+}
+
+impl Iterator for PaddedEnd {
+    type Item = (Vec<u8>, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Never more than a frame of zeros is held at once
+        if self.buf.len() < FRAME_SIZE {
+            let zeros = self.zeros.min((FRAME_SIZE - self.buf.len()) as u64);
+
+            self.buf.resize(self.buf.len() + zeros as usize, 0);
+            self.zeros -= zeros;
+        }
+
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let frame: Vec<u8> = self.buf.drain(..self.buf.len().min(FRAME_SIZE)).collect();
+
+        let mut kind = DATA_FRAME;
+
+        if std::mem::take(&mut self.first) {
+            kind |= LENGTH_FRAME;
+        }
+
+        if self.buf.is_empty() && self.zeros == 0 {
+            kind |= LAST_FRAME;
+        }
+
+        Some((frame, kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::encryption::{
+        aes_gcm_256::AesGcmAlgorithm, chachapoly::ChaChaPolyAlgorithm, xchachapoly::XChaChaPolyAlgorithm,
+        DecryptionAlgorithm, DecryptionModule, EncryptionAlgorithm, EncryptionModule,
+    };
+
+    const KEY: [u8; 32] = [7; 32];
+    const NONCE_SIZE: usize = 24;
+    const SEALED_FRAME: usize = NONCE_SIZE + FRAME_SIZE + 16;
+
+    fn seal(data: &[u8], padding: Padding) -> Vec<u8> {
+        let algorithm = XChaChaPolyAlgorithm::new().with_key(KEY.to_vec()).with_padding(padding);
+        let mut sealed = vec![];
+
+        let mut encryptor = algorithm.encryptor(&mut sealed).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finalise().unwrap();
+
+        sealed
+    }
+
+    fn open(sealed: &[u8], padding: Padding) -> Result<Vec<u8>, Error> {
+        let algorithm = XChaChaPolyAlgorithm::new().with_key(KEY.to_vec()).with_padding(padding);
+        let mut data = vec![];
+
+        let mut decryptor = algorithm.decryptor(sealed).unwrap();
+        decryptor.read_to_end(&mut data)?;
+        decryptor.finalise()?;
+
+        Ok(data)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn padme_lengths() {
+        assert_eq!(padme(0), 0);
+        assert_eq!(padme(1), 1);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(1000), 1024);
+        assert_eq!(padme(1025), 1088);
+
+        for len in [100, 5_000, 1 << 20, 123_456_789] {
+            assert!(padme(len) >= len);
+            assert!(padme(len) - len <= len / 8);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in [0, 1, 100, FRAME_SIZE - 8, FRAME_SIZE, FRAME_SIZE + 1, 3 * FRAME_SIZE + 17, 70_000] {
+            let data = data(len);
+
+            let sealed = seal(&data, Padding::Padme);
+            assert_eq!(open(&sealed, Padding::Padme).unwrap(), data, "length {}", len);
+
+            let unpadded = seal(&data, Padding::None);
+            assert_eq!(open(&unpadded, Padding::None).unwrap(), data, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn round_trip_every_algorithm() {
+        let data = data(3 * FRAME_SIZE + 5);
+
+        let mut sealed = vec![];
+        let algorithm = ChaChaPolyAlgorithm::new().with_key(KEY.to_vec()).with_padding(Padding::Padme);
+        let mut encryptor = algorithm.encryptor(&mut sealed).unwrap();
+        encryptor.write_all(&data).unwrap();
+        encryptor.finalise().unwrap();
+
+        let mut opened = vec![];
+        let mut decryptor = algorithm.decryptor(&sealed[..]).unwrap();
+        decryptor.read_to_end(&mut opened).unwrap();
+        decryptor.finalise().unwrap();
+        assert_eq!(opened, data);
+
+        let mut sealed = vec![];
+        let algorithm = AesGcmAlgorithm::new().with_key(KEY.to_vec()).with_padding(Padding::Padme);
+        let mut encryptor = algorithm.encryptor(&mut sealed).unwrap();
+        encryptor.write_all(&data).unwrap();
+        encryptor.finalise().unwrap();
+
+        let mut opened = vec![];
+        let mut decryptor = algorithm.decryptor(&sealed[..]).unwrap();
+        decryptor.read_to_end(&mut opened).unwrap();
+        decryptor.finalise().unwrap();
+        assert_eq!(opened, data);
+    }
+
+    #[test]
+    fn padded_size_hides_length() {
+        // Both pad to the same length, so they are sealed to the same size
+        let short = seal(&data(1000), Padding::Padme);
+        let long = seal(&data(1010), Padding::Padme);
+
+        assert_eq!(short.len(), long.len());
+        assert!(seal(&data(1000), Padding::None).len() < short.len());
+    }
+
+    #[test]
+    fn truncation_fails() {
+        let sealed = seal(&data(3 * FRAME_SIZE + 100), Padding::Padme);
+
+        // Without the last frame, the length frame or the id
+        assert!(open(&sealed[..ID_SIZE + 3 * SEALED_FRAME], Padding::Padme).is_err());
+        assert!(open(&sealed[..ID_SIZE + 2 * SEALED_FRAME], Padding::Padme).is_err());
+        assert!(open(&sealed[..sealed.len() - 1], Padding::Padme).is_err());
+        assert!(open(&sealed[..ID_SIZE], Padding::Padme).is_err());
+        assert!(open(&sealed[..ID_SIZE - 1], Padding::Padme).is_err());
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let sealed = seal(&data(3 * FRAME_SIZE + 100), Padding::Padme);
+
+        let mut flipped = sealed.clone();
+        flipped[ID_SIZE + SEALED_FRAME + 100] ^= 1;
+        assert!(open(&flipped, Padding::Padme).is_err());
+
+        let mut id = sealed.clone();
+        id[0] ^= 1;
+        assert!(open(&id, Padding::Padme).is_err());
+
+        // Frames swapped within the stream
+        let frames = |sealed: &[u8]| -> Vec<Vec<u8>> { sealed[ID_SIZE..].chunks(SEALED_FRAME).map(|c| c.to_vec()).collect() };
+        let mut swapped = frames(&sealed);
+        swapped.swap(0, 1);
+        let swapped = [&sealed[..ID_SIZE], &swapped.concat()].concat();
+        assert!(open(&swapped, Padding::Padme).is_err());
+
+        // A frame taken from another stream under the same key
+        let other = seal(&data(3 * FRAME_SIZE + 100), Padding::Padme);
+        let mut mixed = frames(&sealed);
+        mixed[1] = frames(&other)[1].clone();
+        let mixed = [&sealed[..ID_SIZE], &mixed.concat()].concat();
+        assert!(open(&mixed, Padding::Padme).is_err());
+
+        // A frame added after the last one
+        let appended = [&sealed[..], &frames(&sealed)[0]].concat();
+        assert!(open(&appended, Padding::Padme).is_err());
+    }
+
+    #[test]
+    fn padding_must_match() {
+        let data = data(5000);
+
+        assert!(open(&seal(&data, Padding::None), Padding::Padme).is_err());
+        assert!(open(&seal(&data, Padding::Padme), Padding::None).is_err());
+    }
+}
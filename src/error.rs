@@ -22,6 +22,12 @@ pub enum ZapError {
     EncryptionSecretError(EncryptionSecretError),
     #[error(transparent)]
     FailedToInitialiseLogger(log::SetLoggerError),
+    #[error(transparent)]
+    ArchiveError(ArchiveError),
+    #[error(transparent)]
+    RepositoryError(RepositoryError),
+    #[error("{0} entries failed")]
+    EntriesFailed(usize),
 }
 
 impl From<EncryptionSecretError> for ZapError {
@@ -30,6 +36,12 @@ impl From<EncryptionSecretError> for ZapError {
     }
 }
 
+impl From<EncryptionKeyError> for ZapError {
+    fn from(value: EncryptionKeyError) -> Self {
+        ZapError::EncryptionSecretError(value.into())
+    }
+}
+
 impl From<EncryptionError> for ZapError {
     fn from(value: EncryptionError) -> Self {
         ZapError::EncryptionError(value)
@@ -49,6 +61,18 @@ impl From<DecompressionError> for ZapError {
 }
 
 
+impl From<ArchiveError> for ZapError {
+    fn from(value: ArchiveError) -> Self {
+        ZapError::ArchiveError(value)
+    }
+}
+
+impl From<RepositoryError> for ZapError {
+    fn from(value: RepositoryError) -> Self {
+        ZapError::RepositoryError(value)
+    }
+}
+
 impl From<std::io::Error> for ZapError {
     fn from(value: std::io::Error) -> Self {
         ZapError::IOError(value)
@@ -115,7 +139,21 @@ pub enum EncryptionKeyError
     #[error("Keyfile not provided")]
     KeyfileNotProvided,
     #[error("Keyfile not found: {0}")]
-    FailedToFindKeyfile(String)
+    FailedToFindKeyfile(String),
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+    #[error("None of the key slots of the archive open with this password or key")]
+    NoMatchingSlot,
+    #[error("The archive is encrypted for public keys, pass a key file")]
+    KeyRequired,
+    #[error("The archive is only encrypted with a password")]
+    PasswordRequired,
+    #[error("The archive has no key slots, it was encrypted by an older version")]
+    NoKeySlots,
+    #[error("The last key slot can't be removed")]
+    LastKeySlot,
+    #[error("Encrypting metadata needs the archive to be encrypted with key slots")]
+    MissingFileKey,
 }
 
 
@@ -169,6 +207,10 @@ pub enum CompressionError {
     FailedToBuildThreadPool(ThreadPoolBuildError),
     #[error("Failed to walk directory: {0}")]
     FailedToWalkDirectory(walkdir::Error),
+    #[error("Failed to write solid index: {0}")]
+    FailedToWriteSolidIndex(Box<PipelineCompressionError>),
+    #[error("Cancelled")]
+    Cancelled,
     #[error(transparent)]
     IOError(std::io::Error)
 }
@@ -197,6 +239,10 @@ pub enum DecompressionError {
     FailedToBuildThreadPool(ThreadPoolBuildError),
     #[error("Failed to walk directory: {0}")]
     FailedToWalkDirectory(walkdir::Error),
+    #[error("Failed to read solid index: {0}")]
+    FailedToReadSolidIndex(Box<PipelineDecompressionError>),
+    #[error("Cancelled")]
+    Cancelled,
     #[error(transparent)]
     IOError(std::io::Error)
 }
@@ -223,6 +269,10 @@ impl From<walkdir::Error> for DecompressionError {
 pub enum PipelineCompressionError {
     #[error("Generic Error: {0}")]
     Generic(String),
+    #[error("Panicked: {0}")]
+    Panicked(String),
+    #[error("Cancelled")]
+    Cancelled,
     #[error(transparent)]
     HashingError(HashingError),
     #[error(transparent)]
@@ -239,6 +289,14 @@ pub enum PipelineCompressionError {
     EncryptorInitError(EncryptorInitError),
     #[error(transparent)]
     CompressorInitError(CompressorInitError),
+    #[error(transparent)]
+    BuildError(PipelineBuildError),
+}
+
+impl From<PipelineBuildError> for PipelineCompressionError {
+    fn from(value: PipelineBuildError) -> Self {
+        PipelineCompressionError::BuildError(value)
+    }
 }
 
 impl From<CompressorInitError> for PipelineCompressionError {
@@ -299,6 +357,10 @@ impl From<ThreadPoolBuildError> for PipelineCompressionError {
 pub enum PipelineDecompressionError {
     #[error("Generic Error: {0}")]
     Generic(String),
+    #[error("Panicked: {0}")]
+    Panicked(String),
+    #[error("Cancelled")]
+    Cancelled,
     #[error(transparent)]
     HashingError(HashingError),
     #[error(transparent)]
@@ -315,6 +377,14 @@ pub enum PipelineDecompressionError {
     DecryptorInitError(EncryptorInitError),
     #[error(transparent)]
     CompressionInitError(CompressorInitError),
+    #[error(transparent)]
+    BuildError(PipelineBuildError),
+}
+
+impl From<PipelineBuildError> for PipelineDecompressionError {
+    fn from(value: PipelineBuildError) -> Self {
+        PipelineDecompressionError::BuildError(value)
+    }
 }
 
 impl From<CompressorInitError> for PipelineDecompressionError {
@@ -371,6 +441,136 @@ impl From<ThreadPoolBuildError> for PipelineDecompressionError {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("Not a zap archive")]
+    NotAnArchive,
+    #[error("Unsupported archive version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Corrupt archive: {0}")]
+    Corrupt(String),
+    #[error("No such entry: {0}")]
+    EntryNotFound(String),
+    #[error("Invalid entry name: {0}")]
+    InvalidName(String),
+    #[error("Duplicate entry: {0}")]
+    DuplicateEntry(String),
+    #[error("Entry isn't stored with random access: {0}")]
+    NotSeekable(String),
+    #[error("Broken archive chain: {0}")]
+    BrokenChain(String),
+    #[error("Missing volume: {0}")]
+    MissingVolume(String),
+    #[error("Split archives can't be changed in place: {0}")]
+    SplitArchive(String),
+    #[error("No recovery record: {0}")]
+    NoRecoveryRecord(String),
+    #[error("No room left for key slots in the header: {0}")]
+    HeaderFull(String),
+    #[error("The metadata of the archive is encrypted, a password or key is needed: {0}")]
+    Sealed(String),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    KeyError(EncryptionKeyError),
+    #[error(transparent)]
+    IOError(std::io::Error),
+    #[error(transparent)]
+    CompressionError(PipelineCompressionError),
+    #[error(transparent)]
+    DecompressionError(PipelineDecompressionError),
+}
+
+impl From<EncryptionKeyError> for ArchiveError {
+    fn from(value: EncryptionKeyError) -> Self {
+        ArchiveError::KeyError(value)
+    }
+}
+
+impl From<PipelineCompressionError> for ArchiveError {
+    fn from(value: PipelineCompressionError) -> Self {
+        match value {
+            PipelineCompressionError::Cancelled => ArchiveError::Cancelled,
+            value => ArchiveError::CompressionError(value),
+        }
+    }
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(value: std::io::Error) -> Self {
+        ArchiveError::IOError(value)
+    }
+}
+
+impl From<PipelineDecompressionError> for ArchiveError {
+    fn from(value: PipelineDecompressionError) -> Self {
+        match value {
+            PipelineDecompressionError::Cancelled => ArchiveError::Cancelled,
+            value => ArchiveError::DecompressionError(value),
+        }
+    }
+}
+
+impl From<PipelineBuildError> for ArchiveError {
+    fn from(value: PipelineBuildError) -> Self {
+        ArchiveError::DecompressionError(value.into())
+    }
+}
+
+impl From<serde_json::Error> for ArchiveError {
+    fn from(value: serde_json::Error) -> Self {
+        ArchiveError::Corrupt(value.to_string())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RepositoryError {
+    #[error("Not a zap repository: {0}")]
+    NotARepository(String),
+    #[error("Repository already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Unsupported repository version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Corrupt repository: {0}")]
+    Corrupt(String),
+    #[error("No such snapshot: {0}")]
+    SnapshotNotFound(String),
+    #[error("More than one snapshot starts with {0}")]
+    AmbiguousSnapshot(String),
+    #[error("Repository is in use by another process: {0}")]
+    Locked(String),
+    #[error(transparent)]
+    IOError(std::io::Error),
+    #[error(transparent)]
+    CompressionError(PipelineCompressionError),
+    #[error(transparent)]
+    DecompressionError(PipelineDecompressionError),
+}
+
+impl From<std::io::Error> for RepositoryError {
+    fn from(value: std::io::Error) -> Self {
+        RepositoryError::IOError(value)
+    }
+}
+
+impl From<PipelineCompressionError> for RepositoryError {
+    fn from(value: PipelineCompressionError) -> Self {
+        RepositoryError::CompressionError(value)
+    }
+}
+
+impl From<PipelineDecompressionError> for RepositoryError {
+    fn from(value: PipelineDecompressionError) -> Self {
+        RepositoryError::DecompressionError(value)
+    }
+}
+
+impl From<serde_json::Error> for RepositoryError {
+    fn from(value: serde_json::Error) -> Self {
+        RepositoryError::Corrupt(value.to_string())
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PipelineBuildError {
     #[error(transparent)]
@@ -378,7 +578,11 @@ pub enum PipelineBuildError {
     #[error(transparent)]
     SignerInit(SignerInitError),
     #[error(transparent)]
-    EncryptorInit(EncryptorInitError)
+    EncryptorInit(EncryptorInitError),
+    #[error("Unknown {0}")]
+    UnknownStage(String),
+    #[error("Unsupported encryption secret: {0}")]
+    UnsupportedSecret(String),
 }
 
 impl From<CompressorInitError> for PipelineBuildError {
@@ -0,0 +1,226 @@
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use log::debug;
+use rayon::{ThreadPool, ThreadPoolBuildError, ThreadPoolBuilder, Yield};
+
+use crate::options::Parallelism;
+
+// Entries allowed to be pending for every worker thread.
+const QUEUE_DEPTH_PER_THREAD: usize = 4;
+// How long a worker with no pending job to run waits for a permit
+// before looking for pending jobs again.
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+
+/// Runs the per-entry tasks of a directory job.
+pub(crate) enum Executor {
+    Pool(Arc<ThreadPool>),
+    Inline,
+}
+
+impl Executor {
+    pub(crate) fn new(parallelism: &Parallelism) -> Result<Executor, ThreadPoolBuildError> {
+        let threads = match parallelism {
+            Parallelism::Available => std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            Parallelism::Threads(threads) => threads.get(),
+            Parallelism::Pool(pool) => return Ok(Executor::Pool(pool.clone())),
+            Parallelism::SingleThreaded => return Ok(Executor::Inline),
+        };
+
+        debug!("Building thread pool with {} threads", threads);
+
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+
+        Ok(Executor::Pool(Arc::new(pool)))
+    }
+
+    /// Runs every job as a task of its own on the pool, at most a few
+    /// per thread at a time.
+    pub(crate) fn work_queue(&self) -> WorkQueue {
+        let pool = match self {
+            Executor::Pool(pool) => pool,
+            Executor::Inline => {
+                return WorkQueue {
+                    pool: None,
+                    permits: Arc::new(Semaphore::new(0)),
+                    capacity: 0,
+                }
+            }
+        };
+
+        let capacity = pool.current_num_threads() * QUEUE_DEPTH_PER_THREAD;
+
+        WorkQueue {
+            pool: Some(pool.clone()),
+            permits: Arc::new(Semaphore::new(capacity)),
+            capacity,
+        }
+    }
+}
+
+/// Producer side of the work queue. Submitting blocks while too many
+/// jobs are pending, so whatever produces the jobs can't get ahead of
+/// the workers.
+///
+/// When the producer is itself a worker of the pool, it runs pending
+/// jobs while it waits instead. Blocking would hold up the jobs that
+/// give the permits back, for good with a single thread.
+pub(crate) struct WorkQueue {
+    // Jobs run straight away on the calling thread when there is none
+    pool: Option<Arc<ThreadPool>>,
+    permits: Arc<Semaphore>,
+    // Free permits once every job is done
+    capacity: usize,
+}
+
+impl WorkQueue {
+    pub(crate) fn submit<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return job(),
+        };
+
+        let permit = Semaphore::acquire(&self.permits, pool);
+
+        pool.spawn(move || {
+            job();
+
+            // Also released when the job unwinds
+            drop(permit);
+        });
+    }
+
+    /// Waits for every submitted job to be done.
+    pub(crate) fn finish(self) {
+        if let Some(pool) = &self.pool {
+            drop(self.permits.wait(pool, |permits| permits == self.capacity));
+        }
+    }
+}
+
+/// Counts the jobs that may still be submitted before one is done.
+struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits for a permit, given back when the returned guard is dropped.
+    fn acquire(semaphore: &Arc<Semaphore>, pool: &ThreadPool) -> Permit {
+        *semaphore.wait(pool, |permits| permits > 0) -= 1;
+
+        Permit(semaphore.clone())
+    }
+
+    /// Waits until `ready` holds for the number of free permits. Workers
+    /// of `pool` run its pending jobs meanwhile.
+    fn wait<F>(&self, pool: &ThreadPool, ready: F) -> MutexGuard<'_, usize>
+    where
+        F: Fn(usize) -> bool,
+    {
+        let mut permits = self.permits.lock().unwrap();
+
+        while !ready(*permits) {
+            drop(permits);
+
+            match pool.yield_now() {
+                Some(Yield::Executed) => permits = self.permits.lock().unwrap(),
+                // Every job in flight runs on another thread, there is
+                // nothing to run until one is done or more are spawned
+                Some(Yield::Idle) => {
+                    permits = self.permits.lock().unwrap();
+
+                    if !ready(*permits) {
+                        permits = self.released.wait_timeout(permits, IDLE_WAIT).unwrap().0;
+                    }
+                }
+                None => {
+                    permits = self.permits.lock().unwrap();
+
+                    if !ready(*permits) {
+                        permits = self.released.wait(permits).unwrap();
+                    }
+                }
+            }
+        }
+
+        permits
+    }
+}
+
+struct Permit(Arc<Semaphore>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap() += 1;
+        self.0.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn finish_waits_for_every_job() {
+        let executor = Executor::new(&Parallelism::Threads(NonZeroUsize::new(2).unwrap())).unwrap();
+        let queue = executor.work_queue();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..50 {
+            let done = done.clone();
+
+            queue.submit(move || {
+                thread::sleep(Duration::from_millis(1));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        queue.finish();
+
+        assert_eq!(done.load(Ordering::SeqCst), 50);
+    }
+
+    #[test]
+    fn submitting_blocks_while_the_queue_is_full() {
+        let executor = Executor::new(&Parallelism::Threads(NonZeroUsize::new(1).unwrap())).unwrap();
+        let queue = executor.work_queue();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..20 {
+            most.fetch_max(pending.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            let pending = pending.clone();
+
+            queue.submit(move || {
+                thread::sleep(Duration::from_millis(1));
+                pending.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        queue.finish();
+
+        assert!(most.load(Ordering::SeqCst) <= QUEUE_DEPTH_PER_THREAD + 1);
+    }
+}
@@ -1,5 +1,9 @@
+pub(crate) mod executor;
 
-use std::io::Error;
+use std::{
+    io::Error,
+    path::{Component, Path},
+};
 
 // Cleanup is a function that signals for all nested
 // writers/readers that no more will be read/written
@@ -31,3 +35,10 @@ pub fn bind_io_constructors<T, U, V, W>(
     move | x | c(b(a(x)))
 }
 
+// Paths read back from an archive, index or manifest are only ever
+// written relative to the folder they came from. Anything else, like
+// an absolute path, a prefix or `..`, would let a crafted file reach
+// outside of the output folder.
+pub(crate) fn is_relative_path(path: &Path) -> bool {
+    path.components().next().is_some() && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
@@ -1,25 +1,37 @@
+pub mod archive;
+pub mod cancel;
 pub mod compression;
+pub mod dedup;
 pub mod encryption;
 pub mod error;
 pub mod internal;
+pub mod options;
 pub mod pipeline;
 pub mod prelude;
+pub mod progress;
+pub mod report;
+pub mod repository;
 pub mod signing;
+pub mod solid;
 
 use std::{
-    backtrace,
+    any::Any,
+    collections::BTreeMap,
+    io, mem,
+    panic::{self, AssertUnwindSafe},
     path::{self, Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
-use crate::pipeline::ProcessingPipeline;
-use compression::CompressionType;
-use crossbeam::sync::WaitGroup;
-use encryption::{EncryptionSecret, EncryptionType};
-use error::{CompressionError, DecompressionError};
+use crate::pipeline::{seekable::SEEKABLE_EXT, ProcessingPipeline};
+use archive::EntryMetadata;
+use dedup::{ChunkStore, DedupMode, FileIndex, CHUNK_DIR, MANIFEST_EXT, REFERENCE_EXT};
+use error::{CompressionError, DecompressionError, PipelineCompressionError, PipelineDecompressionError};
+use internal::executor::{Executor, WorkQueue};
 use log::{debug, error};
-use rayon::ThreadPoolBuilder;
-use signing::SigningType;
+use options::{ArchiveOptions, ErrorPolicy};
+use report::{CompressionReport, DecompressionReport, ReportCollector, SkipReason};
+use solid::{SolidGroup, SolidStore, SOLID_DIR};
 use walkdir::WalkDir;
 
 pub struct Processor {}
@@ -27,28 +39,71 @@ pub struct Processor {}
 pub fn compress_directory(
     input_folder_path: &str,
     output_folder_path: &str,
-    encryption: EncryptionType,
-    encryption_secret: EncryptionSecret,
-    compression: CompressionType,
-    compression_level: flate2::Compression,
-    signing: SigningType,
-) -> Result<(), CompressionError> {
-    let avail_thread: usize = std::thread::available_parallelism()?.into();
-
-    debug!("Building thread pool with {} threads", avail_thread);
-
-    let thread_pool = ThreadPoolBuilder::new().num_threads(avail_thread).build()?;
-
-    let wg = WaitGroup::new();
-
-    let encryption_ref = Arc::new(encryption);
-    let compression_ref = Arc::new(compression);
-    let compression_level_ref = Arc::new(compression_level);
-    let signing_ref = Arc::new(signing);
-    let encryption_secret_ref = Arc::new(encryption_secret);
+    options: ArchiveOptions,
+) -> Result<CompressionReport, CompressionError> {
+    let work_queue = Executor::new(&options.parallelism)?.work_queue();
+
+    let collector = Arc::new(ReportCollector::new(options.error_policy));
+    let metadata = Arc::new(Mutex::new(BTreeMap::new()));
+
+    let chunk_store = match options.dedup {
+        DedupMode::Chunks => Some(Arc::new(
+            ChunkStore::new(Path::new(output_folder_path).join(CHUNK_DIR)).with_key(options.chunk_key()),
+        )),
+        _ => None,
+    };
+
+    let file_index = match options.dedup {
+        DedupMode::Files => Some(Arc::new(FileIndex::new(PathBuf::from(output_folder_path)))),
+        _ => None,
+    };
+
+    let content_key = options.content_key();
+
+    let pipeline_template = ProcessingPipeline::new()
+        .with_compression(Arc::new(options.compression))
+        .with_compression_level(Arc::new(options.compression_level))
+        .with_encryption(Arc::new(options.encryption))
+        .with_encryption_secret(Arc::new(options.encryption_secret))
+        .with_padding(options.padding)
+        .with_signing(Arc::new(options.signing))
+        .with_registry(options.registry)
+        .with_stages(options.stages)
+        .with_block_size(options.block_size)
+        .with_chunk_store(chunk_store.clone())
+        .with_file_index(file_index.clone())
+        .with_content_key(content_key)
+        .with_progress(options.progress)
+        .with_cancellation(options.cancellation);
+
+    let solid_store = options.solid_block_size.map(|_| {
+        Arc::new(SolidStore::new(Path::new(output_folder_path).join(SOLID_DIR)))
+    });
+    let solid_block_size = options.solid_block_size.unwrap_or_default() as u64;
+    let mut solid_group = SolidGroup::default();
+    let mut solid_blocks = 0;
+
+    // Entries already submitted still have to be waited for when the
+    // walk fails, so the error is only returned once they are done
+    let mut walk_error = None;
 
     for entry in WalkDir::new(input_folder_path) {
-        let entry = entry?;
+        if pipeline_template.is_cancelled() {
+            break;
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if options.error_policy == ErrorPolicy::Continue => {
+                error!("Error while walking '{}': {}", input_folder_path, e);
+                collector.failed(walk_error_path(&e, input_folder_path), io::Error::from(e).into(), vec![]);
+                continue;
+            }
+            Err(e) => {
+                walk_error = Some(e.into());
+                break;
+            }
+        };
 
         let entry_path = entry.into_path();
 
@@ -57,17 +112,64 @@ pub fn compress_directory(
             continue;
         }
 
+        let parent_path = match entry_path.strip_prefix(input_folder_path) {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => entry_path.clone(),
+        };
+
         // Ignore the keyfile TODO
         if entry_path.as_os_str() == "keyfile.zk" {
+            collector.skipped(vec![parent_path], SkipReason::Keyfile);
             continue;
         }
 
-        let parent_path = match entry_path.strip_prefix(input_folder_path) {
-            Ok(p) => p,
-            Err(e) => panic!("Error: {:?}", e), // TODO: Graceful cleanup
-        };
+        if collector.is_aborted() {
+            collector.skipped(vec![parent_path], SkipReason::Aborted);
+            continue;
+        }
+
+        // Small files are packed together instead of getting an entry of their own
+        if let Some(solid_store) = &solid_store {
+            let size = match entry_path.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(e) if options.error_policy == ErrorPolicy::Continue => {
+                    error!("Error while reading the metadata of '{}': {}", entry_path.display(), e);
+                    collector.failed(parent_path, e.into(), vec![]);
+                    continue;
+                }
+                Err(e) => {
+                    walk_error = Some(e.into());
+                    break;
+                }
+            };
+
+            if size < solid_block_size {
+                if solid_group.size() + size > solid_block_size {
+                    spawn_solid_block(
+                        &work_queue,
+                        &collector,
+                        &metadata,
+                        solid_store,
+                        &pipeline_template,
+                        solid_blocks,
+                        solid_group.take(),
+                    );
+                    solid_blocks += 1;
+                }
 
-        let output_path = path::Path::new(output_folder_path).join(rewrite_ext(parent_path));
+                solid_group.push(entry_path, parent_path, size);
+                continue;
+            }
+        }
+
+        let mut output_path = path::Path::new(output_folder_path).join(rewrite_ext(&parent_path));
+
+        // Deduplicated files are stored as a list of chunks, seekable
+        // ones under their own extension
+        match chunk_store.is_some() {
+            true => output_path.set_extension(MANIFEST_EXT),
+            false => output_path.set_extension(pipeline_template.stream_ext()),
+        };
 
         debug!(
             "Compressing: {:?} -> {:?}",
@@ -75,70 +177,109 @@ pub fn compress_directory(
             output_path.display()
         );
 
-        let current_dir = output_path.parent().unwrap();
+        let pipeline = pipeline_template
+            .clone()
+            .with_source(entry_path.clone())
+            .with_destination(output_path.clone());
 
-        std::fs::create_dir_all(current_dir)
-            .expect("Failed to create all the required directories/subdirectories");
+        let collector = collector.clone();
+        let metadata = metadata.clone();
 
-        let encryption_secret_ref = encryption_secret_ref.clone();
-        let encryption_ref = encryption_ref.clone();
-        let compression_ref = compression_ref.clone();
-        let compression_level_ref = compression_level_ref.clone();
-        let signing_ref = signing_ref.clone();
+        work_queue.submit(move || {
+            if pipeline.is_cancelled() {
+                return;
+            }
 
-        let wg_ref = wg.clone();
+            if collector.is_aborted() {
+                collector.skipped(vec![parent_path], SkipReason::Aborted);
+                return;
+            }
 
-        thread_pool.spawn(move || {
-            let result = std::panic::catch_unwind(|| {
-                let pipeline = ProcessingPipeline::new()
-                    .with_source(entry_path.clone())
-                    .with_destination(output_path.clone())
-                    .with_compression(compression_ref)
-                    .with_compression_level(compression_level_ref)
-                    .with_encryption(encryption_ref)
-                    .with_encryption_secret(encryption_secret_ref)
-                    .with_signing(signing_ref);
+            pipeline.file_started(&parent_path);
 
-                match pipeline.compress_dir() {
-                    Ok(_) => debug!(
-                        "Finished compressing '{:?}' successfully",
-                        entry_path.display()
-                    ),
-                    Err(e) => {
-                        let bt = backtrace::Backtrace::capture();
-                        error!(
-                            "Error while compressing '{}': {:?}",
-                            entry_path.display(),
-                            e
-                        );
-                        log::trace!(
-                            "Error while compressing '{}': {:?}",
-                            entry_path.display(),
-                            bt
-                        );
-                        panic!();
+            let result = catch_panic(
+                || {
+                    match output_path.parent() {
+                        Some(current_dir) => std::fs::create_dir_all(current_dir).map_err(|e| e.into()),
+                        None => Ok(()),
                     }
-                };
+                    .and_then(|_| pipeline.clone().compress_dir())
+                },
+                PipelineCompressionError::Panicked,
+            );
 
-                drop(wg_ref);
-            });
+            pipeline.file_finished(&parent_path);
+
+            // Whatever was written so far is unusable
+            if result.is_err() && pipeline.is_cancelled() {
+                let _ = std::fs::remove_file(&output_path);
+                return;
+            }
 
             match result {
-                Ok(_) => {}
+                Ok(file_metadata) => {
+                    debug!(
+                        "Finished compressing '{:?}' successfully",
+                        entry_path.display()
+                    );
+                    metadata.lock().unwrap().insert(parent_path.clone(), file_metadata);
+                    collector.succeeded(vec![parent_path]);
+                }
                 Err(e) => {
-                    log::trace!(
-                        "Thread panicked while handling file: {:?}\nMessage: {:?}",
+                    error!(
+                        "Error while compressing '{}': {:?}",
                         entry_path.display(),
                         e
                     );
+                    collector.failed(parent_path, e, vec![]);
                 }
             }
         });
     }
 
-    wg.wait();
+    if let (Some(solid_store), None) = (&solid_store, &walk_error) {
+        if !solid_group.is_empty() {
+            spawn_solid_block(
+                &work_queue,
+                &collector,
+                &metadata,
+                solid_store,
+                &pipeline_template,
+                solid_blocks,
+                solid_group,
+            );
+        }
+    }
+
+    work_queue.finish();
 
-    Ok(())
+    if let Some(e) = walk_error {
+        return Err(e);
+    }
+
+    if pipeline_template.is_cancelled() {
+        return Err(CompressionError::Cancelled);
+    }
+
+    if let Some(solid_store) = solid_store {
+        if let Err(e) = solid_store.finish(&pipeline_template) {
+            return Err(CompressionError::FailedToWriteSolidIndex(Box::new(e)));
+        }
+    }
+
+    let dedup = match (chunk_store, file_index) {
+        (Some(store), _) => Some(store.stats()),
+        (_, Some(index)) => Some(index.stats()),
+        _ => None,
+    };
+
+    let metadata = mem::take(&mut *metadata.lock().unwrap());
+
+    Ok(CompressionReport {
+        entries: collector.take(),
+        dedup,
+        metadata,
+    })
 }
 
 // todo: This function will alter the filename of binary files eg:
@@ -146,111 +287,304 @@ pub fn compress_directory(
 pub fn decompress_directory(
     input_folder_path: &str,
     output_folder_path: &str,
-    encryption: EncryptionType,
-    encryption_secret: EncryptionSecret,
-    compression: CompressionType,
-    signing: SigningType,
-) -> Result<(), DecompressionError> {
-    let avail_thread: usize = std::thread::available_parallelism()?.into();
+    options: ArchiveOptions,
+) -> Result<DecompressionReport, DecompressionError> {
+    let work_queue = Executor::new(&options.parallelism)?.work_queue();
+
+    let collector = Arc::new(ReportCollector::new(options.error_policy));
+    let chunk_key = options.chunk_key();
+
+    let pipeline_template = ProcessingPipeline::new()
+        .with_compression(Arc::new(options.compression))
+        .with_encryption(Arc::new(options.encryption))
+        .with_encryption_secret(Arc::new(options.encryption_secret))
+        .with_padding(options.padding)
+        .with_signing(Arc::new(options.signing))
+        .with_registry(options.registry)
+        .with_stages(options.stages)
+        .with_progress(options.progress)
+        .with_cancellation(options.cancellation);
+
+    // Archives written with chunk deduplication keep their chunks in
+    // a separate directory, which is only read through the chunk lists.
+    let chunk_dir = Path::new(input_folder_path).join(CHUNK_DIR);
+
+    let pipeline_template = match chunk_dir.is_dir() {
+        true => pipeline_template.with_chunk_store(Some(Arc::new(ChunkStore::new(chunk_dir).with_key(chunk_key)))),
+        false => pipeline_template,
+    };
+
+    let pipeline_template = pipeline_template
+        .with_file_index(Some(Arc::new(FileIndex::new(PathBuf::from(input_folder_path)))));
+
+    // Files packed into solid blocks are extracted block by block
+    let solid_dir = Path::new(input_folder_path).join(SOLID_DIR);
+
+    if solid_dir.is_dir() {
+        let solid_store = match SolidStore::open(&pipeline_template, solid_dir) {
+            Ok(s) => Arc::new(s),
+            Err(e) => return Err(DecompressionError::FailedToReadSolidIndex(Box::new(e))),
+        };
 
-    debug!("Building thread pool with {} threads", avail_thread);
+        let entries = solid_store.entries();
 
-    let thread_pool = ThreadPoolBuilder::new().num_threads(avail_thread).build()?;
+        for block in solid_store.blocks() {
+            let solid_store = solid_store.clone();
+            let pipeline = pipeline_template.clone();
+            let output_root = PathBuf::from(output_folder_path);
 
-    let wg = WaitGroup::new();
+            let paths: Vec<PathBuf> = entries
+                .iter()
+                .filter(|e| e.block == block)
+                .map(|e| e.path.clone())
+                .collect();
 
-    let encryption_ref = Arc::new(encryption);
-    let compression_ref = Arc::new(compression);
-    let signing_ref = Arc::new(signing);
-    let encryption_secret_ref = Arc::new(encryption_secret);
+            let collector = collector.clone();
 
-    for entry in WalkDir::new(input_folder_path) {
-        let entry = entry.unwrap();
+            work_queue.submit(move || {
+                if pipeline.is_cancelled() {
+                    return;
+                }
+
+                if collector.is_aborted() {
+                    collector.skipped(paths, SkipReason::Aborted);
+                    return;
+                }
+
+                paths.iter().for_each(|p| pipeline.file_started(p));
+
+                let result = catch_panic(
+                    || solid_store.extract_block(&pipeline, block, &output_root),
+                    PipelineDecompressionError::Panicked,
+                );
+
+                paths.iter().for_each(|p| pipeline.file_finished(p));
+
+                match result {
+                    Ok(_) => {
+                        debug!("Finished decompressing solid block {} successfully", block);
+                        collector.succeeded(paths);
+                    }
+                    Err(e) => {
+                        error!("Error while decompressing solid block {}: {:?}", block, e);
+                        collector.failed(solid::block_name(block), e, paths);
+                    }
+                }
+            });
+        }
+    }
+
+    let walker = WalkDir::new(input_folder_path)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != CHUNK_DIR && e.file_name() != SOLID_DIR);
+
+    let mut walk_error = None;
+
+    for entry in walker {
+        if pipeline_template.is_cancelled() {
+            break;
+        }
+
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if options.error_policy == ErrorPolicy::Continue => {
+                error!("Error while walking '{}': {}", input_folder_path, e);
+                collector.failed(walk_error_path(&e, input_folder_path), io::Error::from(e).into(), vec![]);
+                continue;
+            }
+            Err(e) => {
+                walk_error = Some(e.into());
+                break;
+            }
+        };
         let entry_path = entry.into_path();
 
         if path::Path::new(&entry_path).is_dir() {
             continue;
         }
 
+        let parent_path = match entry_path.strip_prefix(input_folder_path) {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => entry_path.clone(),
+        };
+
         if entry_path == path::Path::new("keyfile.zk") {
+            collector.skipped(vec![parent_path], SkipReason::Keyfile);
             continue;
         }
 
-        if entry_path.extension().unwrap_or_default() == "lz4" {
-            let parent_path = entry_path.strip_prefix(input_folder_path).unwrap();
+        let extension = entry_path.extension().unwrap_or_default();
 
-            let output_path =
-                path::Path::new(output_folder_path).join(parent_path.with_extension(""));
+        if extension != "lz4" && extension != SEEKABLE_EXT && extension != MANIFEST_EXT && extension != REFERENCE_EXT {
+            collector.skipped(vec![parent_path], SkipReason::UnknownEntry);
+            continue;
+        }
 
-            debug!(
-                "Decompressing: {:?} -> {:?}",
-                entry_path.display(),
-                output_path.display()
-            );
+        let relative_path = parent_path.with_extension("");
 
-            let current_dir = output_path.parent().unwrap();
-
-            std::fs::create_dir_all(current_dir)
-                .expect("Failed to create all the required directories/subdirectories");
-
-            let encryption_secret_ref = encryption_secret_ref.clone();
-            let encryption_ref = encryption_ref.clone();
-            let compression_ref = compression_ref.clone();
-            let signing_ref = signing_ref.clone();
-
-            let wg_ref = wg.clone();
-
-            thread_pool.spawn(move || {
-                let result = std::panic::catch_unwind(|| {
-                    let pipeline = ProcessingPipeline::new()
-                        .with_source(entry_path.clone())
-                        .with_destination(output_path.clone())
-                        .with_compression(compression_ref)
-                        .with_encryption(encryption_ref)
-                        .with_encryption_secret(encryption_secret_ref)
-                        .with_signing(signing_ref);
-
-                    match pipeline.decompress_dir() {
-                        Ok(_) => debug!(
-                            "Finished decompressing '{:?}' successfully",
-                            entry_path.display()
-                        ),
-                        Err(e) => {
-                            let bt = backtrace::Backtrace::capture();
-                            error!(
-                                "Error while decompressing '{}': {:?}",
-                                entry_path.display(),
-                                e
-                            );
-                            log::trace!(
-                                "Error while decompressing '{}': {:?}",
-                                entry_path.display(),
-                                bt
-                            );
-                            panic!();
-                        }
-                    };
-
-                    drop(wg_ref)
-                });
+        if collector.is_aborted() {
+            collector.skipped(vec![relative_path], SkipReason::Aborted);
+            continue;
+        }
 
-                match result {
-                    Ok(_) => {}
-                    Err(e) => {
-                        log::trace!(
-                            "Thread panicked while handling file: {:?}\nMessage: {:?}",
-                            entry_path.display(),
-                            e
-                        );
+        let output_path = path::Path::new(output_folder_path).join(&relative_path);
+
+        debug!(
+            "Decompressing: {:?} -> {:?}",
+            entry_path.display(),
+            output_path.display()
+        );
+
+        let pipeline = pipeline_template
+            .clone()
+            .with_source(entry_path.clone())
+            .with_destination(output_path.clone());
+
+        let collector = collector.clone();
+
+        work_queue.submit(move || {
+            if pipeline.is_cancelled() {
+                return;
+            }
+
+            if collector.is_aborted() {
+                collector.skipped(vec![relative_path], SkipReason::Aborted);
+                return;
+            }
+
+            pipeline.file_started(&relative_path);
+
+            let result = catch_panic(
+                || {
+                    match output_path.parent() {
+                        Some(current_dir) => std::fs::create_dir_all(current_dir).map_err(|e| e.into()),
+                        None => Ok(()),
                     }
+                    .and_then(|_| pipeline.clone().decompress_dir())
+                },
+                PipelineDecompressionError::Panicked,
+            );
+
+            pipeline.file_finished(&relative_path);
+
+            if result.is_err() && pipeline.is_cancelled() {
+                let _ = std::fs::remove_file(&output_path);
+                return;
+            }
+
+            match result {
+                Ok(_) => {
+                    debug!(
+                        "Finished decompressing '{:?}' successfully",
+                        entry_path.display()
+                    );
+                    collector.succeeded(vec![relative_path]);
                 }
-            });
-        }
+                Err(e) => {
+                    error!(
+                        "Error while decompressing '{}': {:?}",
+                        entry_path.display(),
+                        e
+                    );
+                    collector.failed(relative_path, e, vec![]);
+                }
+            }
+        });
+    }
+
+    work_queue.finish();
+
+    if let Some(e) = walk_error {
+        return Err(e);
     }
 
-    wg.wait();
+    if pipeline_template.is_cancelled() {
+        return Err(DecompressionError::Cancelled);
+    }
 
-    Ok(())
+    Ok(DecompressionReport {
+        entries: collector.take(),
+    })
+}
+
+fn spawn_solid_block(
+    work_queue: &WorkQueue,
+    collector: &Arc<ReportCollector<PipelineCompressionError>>,
+    metadata: &Arc<Mutex<BTreeMap<PathBuf, EntryMetadata>>>,
+    solid_store: &Arc<SolidStore>,
+    pipeline: &ProcessingPipeline,
+    block: usize,
+    group: SolidGroup,
+) {
+    debug!("Compressing solid block {}", block);
+
+    let solid_store = solid_store.clone();
+    let pipeline = pipeline.clone();
+    let paths = group.paths();
+
+    let collector = collector.clone();
+    let metadata = metadata.clone();
+
+    work_queue.submit(move || {
+        if pipeline.is_cancelled() {
+            return;
+        }
+
+        if collector.is_aborted() {
+            collector.skipped(paths, SkipReason::Aborted);
+            return;
+        }
+
+        paths.iter().for_each(|p| pipeline.file_started(p));
+
+        let result = catch_panic(
+            || solid_store.write_block(&pipeline, block, group),
+            PipelineCompressionError::Panicked,
+        );
+
+        paths.iter().for_each(|p| pipeline.file_finished(p));
+
+        match result {
+            Ok(files) => {
+                debug!("Finished compressing solid block {} successfully", block);
+                metadata.lock().unwrap().extend(files);
+                collector.succeeded(paths);
+            }
+            Err(e) => {
+                error!("Error while compressing solid block {}: {:?}", block, e);
+                collector.failed(solid::block_name(block), e, paths);
+            }
+        }
+    });
+}
+
+/// Runs the task of a single entry, a panic only failing that entry
+/// instead of taking the worker thread down with it.
+/// Path a walk error is reported under, relative to the walked folder.
+fn walk_error_path(e: &walkdir::Error, folder: &str) -> PathBuf {
+    let path = e.path().unwrap_or(Path::new(folder));
+
+    path.strip_prefix(folder).unwrap_or(path).to_path_buf()
+}
+
+pub(crate) fn catch_panic<T, E, F>(task: F, panicked: fn(String) -> E) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E>,
+{
+    match panic::catch_unwind(AssertUnwindSafe(task)) {
+        Ok(result) => result,
+        Err(payload) => Err(panicked(panic_message(payload))),
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
 }
 
 fn rewrite_ext(path: &Path) -> PathBuf {
@@ -259,3 +593,173 @@ fn rewrite_ext(path: &Path) -> PathBuf {
         None => path.with_extension("lz4"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        cancel::CancellationToken,
+        encryption::{padding::Padding, EncryptionSecret, EncryptionType},
+        options::Parallelism,
+        pipeline::stage::{StageDescriptor, StageKind, StageRegistry},
+        progress::ProgressObserver,
+    };
+
+    #[test]
+    fn panicking_entries_are_reported_as_failed() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+
+        fs::create_dir_all(&input).unwrap();
+        fs::write(input.join("a"), b"a").unwrap();
+        fs::write(input.join("b"), b"b").unwrap();
+
+        let descriptor = StageDescriptor::new(StageKind::Compression, "panics");
+        let mut registry = StageRegistry::default();
+        registry.register(
+            descriptor.clone(),
+            |_, _| panic!("stage failed to build"),
+            |_, _| panic!("stage failed to build"),
+        );
+
+        let options = ArchiveOptions::new()
+            .with_registry(Arc::new(registry))
+            .with_stages(vec![descriptor]);
+
+        let report = compress_directory(input.to_str().unwrap(), output.to_str().unwrap(), options).unwrap();
+
+        assert!(report.entries.succeeded.is_empty());
+        assert_eq!(report.entries.failed.len(), 2);
+
+        for failed in report.entries.failed {
+            assert!(matches!(
+                failed.error,
+                PipelineCompressionError::Panicked(message) if message == "stage failed to build"
+            ));
+        }
+    }
+
+    #[test]
+    fn padded_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input");
+        let packed = dir.path().join("packed");
+        let output = dir.path().join("output");
+
+        fs::create_dir_all(input.join("sub")).unwrap();
+        fs::write(input.join("a"), b"aye").unwrap();
+        fs::write(input.join("sub/b"), vec![7; 100_000]).unwrap();
+
+        let options = || {
+            ArchiveOptions::new()
+                .with_encryption(EncryptionType::XChaCha)
+                .with_encryption_secret(EncryptionSecret::Password(vec![1; 32]))
+                .with_padding(Padding::Padme)
+        };
+
+        let report = compress_directory(input.to_str().unwrap(), packed.to_str().unwrap(), options()).unwrap();
+        assert_eq!(report.entries.succeeded.len(), 2);
+
+        let report = decompress_directory(packed.to_str().unwrap(), output.to_str().unwrap(), options()).unwrap();
+        assert!(report.entries.failed.is_empty());
+
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"aye");
+        assert_eq!(fs::read(output.join("sub/b")).unwrap(), vec![7; 100_000]);
+    }
+
+    #[test]
+    fn compress_from_inside_the_pool() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+
+        fs::create_dir_all(&input).unwrap();
+
+        for i in 0..20 {
+            fs::write(input.join(i.to_string()), vec![i as u8; 1000]).unwrap();
+        }
+
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let options = ArchiveOptions::new().with_parallelism(Parallelism::Pool(pool.clone()));
+
+        let report = pool
+            .install(|| compress_directory(input.to_str().unwrap(), output.to_str().unwrap(), options))
+            .unwrap();
+
+        assert_eq!(report.entries.succeeded.len(), 20);
+    }
+
+    #[test]
+    fn cancelled_compress_leaves_no_partial_output() {
+        struct CancelOnFirstBytes(CancellationToken);
+
+        impl ProgressObserver for CancelOnFirstBytes {
+            fn bytes_processed(&self, _bytes: u64) {
+                self.0.cancel();
+            }
+        }
+
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+
+        fs::create_dir_all(input.join("sub")).unwrap();
+
+        for i in 0..4 {
+            fs::write(input.join(i.to_string()), vec![i as u8; 1_000_000]).unwrap();
+            fs::write(input.join("sub").join(i.to_string()), vec![i as u8; 1_000_000]).unwrap();
+        }
+
+        let token = CancellationToken::new();
+        let options = ArchiveOptions::new()
+            .with_parallelism(Parallelism::SingleThreaded)
+            .with_cancellation(token.clone())
+            .with_progress(Arc::new(CancelOnFirstBytes(token.clone())));
+
+        let result = compress_directory(input.to_str().unwrap(), output.to_str().unwrap(), options);
+
+        assert!(matches!(result, Err(CompressionError::Cancelled)));
+        assert!(token.is_cancelled());
+
+        let leftovers: Vec<_> = WalkDir::new(&output)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
+    }
+
+    #[test]
+    fn unreadable_files_are_reported_and_the_walk_goes_on() {
+        let dir = TempDir::new().unwrap();
+        let input = dir.path().join("input");
+
+        fs::create_dir_all(&input).unwrap();
+        fs::write(input.join("a"), b"aye").unwrap();
+        fs::write(input.join("c"), b"sea").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("missing"), input.join("b")).unwrap();
+
+        let options = |policy| {
+            ArchiveOptions::new()
+                .with_solid_block_size(Some(64 * 1024))
+                .with_parallelism(Parallelism::SingleThreaded)
+                .with_error_policy(policy)
+        };
+
+        let input = input.to_str().unwrap();
+        let output = dir.path().join("continue");
+        let report = compress_directory(input, output.to_str().unwrap(), options(ErrorPolicy::Continue)).unwrap();
+
+        assert_eq!(report.entries.failed.len(), 1);
+        assert_eq!(report.entries.failed[0].path, Path::new("b"));
+        assert_eq!(report.entries.succeeded.len(), 2);
+
+        let output = dir.path().join("fail_fast");
+        assert!(compress_directory(input, output.to_str().unwrap(), options(ErrorPolicy::FailFast)).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::{num::NonZeroUsize, sync::Arc};
+
+use rayon::ThreadPool;
+
+use crate::{
+    cancel::CancellationToken,
+    compression::CompressionType,
+    dedup::DedupMode,
+    encryption::{
+        keys::{self, FileKey, KeySlot, MetadataKey},
+        padding::Padding,
+        EncryptionSecret, EncryptionType,
+    },
+    error::EncryptionKeyError,
+    pipeline::stage::{self, StageDescriptor, StageKind, StageRegistry},
+    progress::ProgressObserver,
+    signing::SigningType,
+};
+
+/// What to do with the remaining entries once one has failed.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Keep going and report every failure at the end.
+    #[default]
+    Continue,
+    /// Don't start any new entry after the first failure. Entries
+    /// that were not attempted are reported as skipped.
+    FailFast,
+}
+
+/// Threads the entries of a run are processed on. The output is the
+/// same whichever is used.
+#[derive(Default, Clone)]
+pub enum Parallelism {
+    /// A new pool with one thread per available core.
+    #[default]
+    Available,
+    /// A new pool with this many threads.
+    Threads(NonZeroUsize),
+    /// An existing pool. The call blocks until every entry is done. When
+    /// made from one of the pool's own threads, it runs pending jobs of
+    /// the pool while it waits.
+    Pool(Arc<ThreadPool>),
+    /// Everything runs on the calling thread.
+    SingleThreaded,
+}
+
+/// Settings shared by `compress_directory` and `decompress_directory`.
+/// Anything not relevant to the direction being run is ignored.
+#[derive(Default)]
+pub struct ArchiveOptions {
+    pub(crate) encryption: EncryptionType,
+    pub(crate) encryption_secret: EncryptionSecret,
+    pub(crate) file_key: Option<FileKey>,
+    pub(crate) encrypt_metadata: bool,
+    pub(crate) padding: Padding,
+    pub(crate) compression: CompressionType,
+    pub(crate) compression_level: flate2::Compression,
+    pub(crate) signing: SigningType,
+    pub(crate) registry: Option<Arc<StageRegistry>>,
+    pub(crate) stages: Option<Arc<Vec<StageDescriptor>>>,
+    pub(crate) block_size: Option<usize>,
+    pub(crate) dedup: DedupMode,
+    pub(crate) solid_block_size: Option<usize>,
+    pub(crate) error_policy: ErrorPolicy,
+    pub(crate) progress: Option<Arc<dyn ProgressObserver>>,
+    pub(crate) cancellation: Option<CancellationToken>,
+    pub(crate) parallelism: Parallelism,
+    pub(crate) volume_size: Option<u64>,
+    pub(crate) parity: Option<u8>,
+}
+
+impl ArchiveOptions {
+    pub fn new() -> ArchiveOptions {
+        ArchiveOptions::default()
+    }
+
+    pub fn with_encryption(mut self, encryption: EncryptionType) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    pub fn with_encryption_secret(mut self, encryption_secret: EncryptionSecret) -> Self {
+        self.encryption_secret = encryption_secret;
+        self
+    }
+
+    /// Encrypt with `file_key` instead of a password, storing it in the
+    /// archive header for each of its recipients. Replaces the
+    /// encryption secret, `compress_directory` and `pack_directory` have
+    /// to be given the same key.
+    pub fn with_file_key(mut self, file_key: FileKey) -> Self {
+        self.encryption_secret = file_key.secret();
+        self.file_key = Some(file_key);
+        self
+    }
+
+    /// Encrypt the header and index too, so that listing the archive
+    /// needs the password or key. Needs a file key, see `with_file_key`.
+    pub fn with_metadata_encryption(mut self, encrypt_metadata: bool) -> Self {
+        self.encrypt_metadata = encrypt_metadata;
+        self
+    }
+
+    /// Pad encrypted entries with `padding`, so that their stored size
+    /// gives less of their true size away.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_compression_level(mut self, compression_level: flate2::Compression) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    pub fn with_signing(mut self, signing: SigningType) -> Self {
+        self.signing = signing;
+        self
+    }
+
+    /// Look stages up in `registry` instead of the built-in one, to use
+    /// stages registered by other crates.
+    pub fn with_registry(mut self, registry: Arc<StageRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Run entries through `stages`, in the order data goes through
+    /// them when compressing. Replaces the stages picked by
+    /// `with_encryption`, `with_compression` and `with_signing`.
+    pub fn with_stages(mut self, stages: Vec<StageDescriptor>) -> Self {
+        self.stages = Some(Arc::new(stages));
+        self
+    }
+
+    /// Store entries as independently decodable blocks of `block_size`
+    /// bytes so they can be read back with `SeekableReader`.
+    pub fn with_block_size(mut self, block_size: Option<usize>) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Deduplicate file contents across the whole directory.
+    /// Takes precedence over `with_block_size` for the files it handles.
+    pub fn with_dedup(mut self, dedup: DedupMode) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Pack files smaller than `solid_block_size` together into shared
+    /// blocks of about that size before compression and encryption.
+    /// Packed files bypass deduplication and the seekable block size.
+    pub fn with_solid_block_size(mut self, solid_block_size: Option<usize>) -> Self {
+        self.solid_block_size = solid_block_size;
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Report progress of the run to `progress`.
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Stop the run once `cancellation` is cancelled. Entries being
+    /// written at that point are removed.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    pub fn with_parallelism(mut self, parallelism: Parallelism) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Size of the volumes the archive is split into, the archive has
+    /// to be written to a `VolumeWriter` of the same size.
+    pub fn with_volume_size(mut self, volume_size: Option<u64>) -> Self {
+        self.volume_size = volume_size;
+        self
+    }
+
+    /// Follow the archive with a recovery record of `parity` percent of
+    /// its size, from which `recovery::repair` rebuilds damaged bytes.
+    pub fn with_parity(mut self, parity: Option<u8>) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    pub fn volume_size(&self) -> Option<u64> {
+        self.volume_size
+    }
+
+    pub fn parity(&self) -> Option<u8> {
+        self.parity
+    }
+
+    pub fn metadata_encryption(&self) -> bool {
+        self.encrypt_metadata
+    }
+
+    /// Key slots to store in the header of a new archive.
+    pub(crate) fn key_slots(&self) -> Vec<KeySlot> {
+        match &self.file_key {
+            Some(file_key) => file_key.slots().to_vec(),
+            None => vec![],
+        }
+    }
+
+    /// Key the header and index of a new archive are encrypted with.
+    pub(crate) fn metadata_key(&self) -> Result<Option<MetadataKey>, EncryptionKeyError> {
+        match (&self.file_key, self.encrypt_metadata) {
+            (Some(file_key), true) => Ok(MetadataKey::new(&file_key.secret())),
+            (None, true) => Err(EncryptionKeyError::MissingFileKey),
+            (_, false) => Ok(None),
+        }
+    }
+
+    /// Key deduplicated chunks are named with, when entries are
+    /// encrypted.
+    pub(crate) fn chunk_key(&self) -> Option<[u8; 32]> {
+        match self.encrypts_entries() {
+            true => keys::chunk_key(&self.encryption_secret),
+            false => None,
+        }
+    }
+
+    /// Key the content hashes in entry metadata are made with, when
+    /// entries are encrypted.
+    pub(crate) fn content_key(&self) -> Option<[u8; 32]> {
+        match self.encrypts_entries() {
+            true => keys::content_key(&self.encryption_secret),
+            false => None,
+        }
+    }
+
+    fn encrypts_entries(&self) -> bool {
+        self.stages()
+            .iter()
+            .any(|s| s.kind == StageKind::Encryption && s.name != "passthrough")
+    }
+
+    /// Stages entries go through, in the order data goes through them
+    /// when compressing.
+    pub fn stages(&self) -> Vec<StageDescriptor> {
+        match &self.stages {
+            Some(stages) => stages.to_vec(),
+            None => stage::default_stages(
+                &self.signing,
+                &self.compression,
+                &self.encryption,
+                &self.padding,
+                &self.encryption_secret,
+            ),
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    io::{Error, ErrorKind, Read, Write},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    compression::{AsyncCompress, AsyncDecompress, Compress, Decompress},
+    encryption::{AsyncDecryptionModule, AsyncEncryptionModule, DecryptionModule, EncryptionModule},
+    error::{PipelineCompressionError, PipelineDecompressionError},
+    signing::{
+        passthrough::{AsyncSignerPassthrough, AsyncVerifierPassthrough},
+        AsyncSign, AsyncVerify, SigningType,
+    },
+};
+
+use super::{stage::StageDescriptor, ProcessingPipeline};
+
+// Input buffered ahead of a synchronous decoding stage, more than any
+// built-in stage reads to decode a single frame or block.
+const LOOKAHEAD: usize = 1024 * 1024;
+// Size of the reads taken from the async input.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Async version of `ProcessingPipeline::compress_stream` and
+/// `ProcessingPipeline::decompress_stream`, over `AsyncRead`/`AsyncWrite`.
+///
+/// Neither side blocks on IO: encoding stages encode the data they are
+/// given in memory, which is then handed on to the output through
+/// `AsyncWrite`, and decoding stages only run once enough input has been
+/// read through `AsyncRead` to decode their next frame from memory.
+///
+/// Compression and encryption still run on the task driving the future.
+/// Every poll encodes the buffer it was given, or decodes up to a 1 MiB
+/// lookahead of input, on the executor thread. Run large streams inside
+/// `tokio::task::spawn_blocking` or `block_in_place` where that would
+/// hold up other tasks for too long.
+#[derive(Default, Clone)]
+pub struct AsyncProcessingPipeline {
+    inner: ProcessingPipeline,
+}
+
+impl From<ProcessingPipeline> for AsyncProcessingPipeline {
+    fn from(inner: ProcessingPipeline) -> Self {
+        AsyncProcessingPipeline { inner }
+    }
+}
+
+impl AsyncProcessingPipeline {
+    pub fn new(inner: ProcessingPipeline) -> AsyncProcessingPipeline {
+        AsyncProcessingPipeline { inner }
+    }
+
+    /// Compresses, encrypts and signs everything read from `reader` into
+    /// `writer`. Output is always a plain stream, the block size of the
+    /// inner pipeline is ignored.
+    pub async fn compress_stream<R, W>(&self, mut reader: R, writer: W) -> Result<(), PipelineCompressionError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin + Send,
+    {
+        let (signing, stages) = self.split_signing();
+
+        let buffer = SharedBuffer::default();
+        let stages = self
+            .inner
+            .registry()
+            .build_writer(buffer.clone(), &stages, &self.inner.stage_context())?;
+
+        self.build_signer(signing, AsyncBridge::new(stages, buffer, writer), &mut reader)
+            .await
+    }
+
+    /// Verifies, decrypts and decompresses everything read from `reader`
+    /// into `writer`. The input must be a plain stream.
+    pub async fn decompress_stream<R, W>(&self, reader: R, mut writer: W) -> Result<(), PipelineDecompressionError>
+    where
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin,
+    {
+        let (signing, stages) = self.split_signing();
+
+        let input = SharedInput::default();
+        let stages = self
+            .inner
+            .registry()
+            .build_reader(input.clone(), &stages, &self.inner.stage_context())?;
+
+        self.build_verifier(signing, AsyncReadBridge::new(stages, input, reader), &mut writer)
+            .await?;
+
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    /// Splits the signing stage off the stages of the inner pipeline,
+    /// when it has an async counterpart to run on top of the others.
+    /// Any other signing stage runs synchronously with the rest.
+    fn split_signing(&self) -> (Option<SigningType>, Vec<StageDescriptor>) {
+        let mut stages = self.inner.stages();
+
+        let signing = match stages.first() {
+            Some(stage) if *stage == StageDescriptor::from(&SigningType::Passthrough) => {
+                Some(SigningType::Passthrough)
+            }
+            _ => None,
+        };
+
+        if signing.is_some() {
+            stages.remove(0);
+        }
+
+        (signing, stages)
+    }
+
+    async fn build_signer<C, R>(
+        &self,
+        signing: Option<SigningType>,
+        io: C,
+        source: &mut R,
+    ) -> Result<(), PipelineCompressionError>
+    where
+        C: AsyncCompress,
+        R: AsyncRead + Unpin,
+    {
+        match signing {
+            Some(SigningType::Passthrough) => {
+                let mut signer = AsyncSignerPassthrough::from(io);
+                tokio::io::copy(source, &mut signer).await?;
+                signer.finalise().await?;
+            }
+            None => {
+                let mut io = io;
+                tokio::io::copy(source, &mut io).await?;
+                io.finalise().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn build_verifier<D, W>(
+        &self,
+        signing: Option<SigningType>,
+        io: D,
+        destination: &mut W,
+    ) -> Result<(), PipelineDecompressionError>
+    where
+        D: AsyncDecompress,
+        W: AsyncWrite + Unpin,
+    {
+        match signing {
+            Some(SigningType::Passthrough) => {
+                let mut verifier = AsyncVerifierPassthrough::from(io);
+                tokio::io::copy(&mut verifier, destination).await?;
+                verifier.finalise().await?;
+            }
+            None => {
+                let mut io = io;
+                tokio::io::copy(&mut io, destination).await?;
+                io.finalise().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// In-memory sink a synchronous stage writes its output into.
+#[derive(Default, Clone)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a synchronous encryption or compression stage as an async one.
+/// Data written to the bridge is encoded straight away into a shared
+/// buffer, which is drained into `io` before more input is accepted.
+///
+/// Encoding happens inside `poll_write`, on the thread polling it.
+pub struct AsyncBridge<M, W> {
+    module: Option<M>,
+    buffer: SharedBuffer,
+    io: W,
+}
+
+impl<M, W> AsyncBridge<M, W>
+where
+    M: Write + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn new(module: M, buffer: SharedBuffer, io: W) -> Self {
+        AsyncBridge {
+            module: Some(module),
+            buffer,
+            io,
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut buffer = self.buffer.0.lock().unwrap();
+
+        while !buffer.is_empty() {
+            match ready!(Pin::new(&mut self.io).poll_write(cx, &buffer))? {
+                0 => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                len => {
+                    buffer.drain(..len);
+                }
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    async fn drain(&mut self) -> std::io::Result<()> {
+        poll_fn(|cx| self.poll_drain(cx)).await
+    }
+}
+
+impl<M, W> AsyncWrite for AsyncBridge<M, W>
+where
+    M: Write + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+
+        match this.module.as_mut() {
+            Some(module) => Poll::Ready(module.write(buf)),
+            None => Poll::Ready(Err(Error::other("Write after finalise"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    // Shutting down doesn't finalise the stage, use `finalise` for that.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+impl<M, W> AsyncEncryptionModule for AsyncBridge<M, W>
+where
+    M: EncryptionModule + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn finalise(mut self) -> Result<(), Error> {
+        if let Some(module) = self.module.take() {
+            module.finalise()?;
+        }
+
+        self.drain().await?;
+        self.io.flush().await
+    }
+}
+
+// A compression stage finishes the stages below it, including `io`.
+impl<M, W> AsyncCompress for AsyncBridge<M, W>
+where
+    M: Compress + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn finalise(mut self) -> Result<(), Error> {
+        if let Some(module) = self.module.take() {
+            module.finalise()?;
+        }
+
+        self.drain().await?;
+        self.io.flush().await
+    }
+}
+
+/// In-memory source a synchronous stage reads its input from. Running
+/// dry before the end of the input is reported as `WouldBlock`.
+#[derive(Default, Clone)]
+struct SharedInput(Arc<Mutex<InputBuffer>>);
+
+#[derive(Default)]
+struct InputBuffer {
+    data: VecDeque<u8>,
+    ended: bool,
+}
+
+impl SharedInput {
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().data.len()
+    }
+
+    fn is_ended(&self) -> bool {
+        self.0.lock().unwrap().ended
+    }
+}
+
+impl Read for SharedInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut input = self.0.lock().unwrap();
+
+        if input.data.is_empty() && !input.ended && !buf.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        input.data.read(buf)
+    }
+}
+
+/// Runs a synchronous decryption or decompression stage as an async one.
+/// Input is read from `io` into a shared buffer until `LOOKAHEAD` bytes
+/// or the end of the input are there, then the stage decodes from it
+/// without blocking.
+///
+/// Decoding happens inside `poll_read`, on the thread polling it, and
+/// may go through up to `LOOKAHEAD` (1 MiB) of input at once.
+pub struct AsyncReadBridge<M, R> {
+    module: Option<M>,
+    input: SharedInput,
+    io: R,
+    chunk: Vec<u8>,
+}
+
+impl<M, R> AsyncReadBridge<M, R>
+where
+    M: Read + Unpin,
+    R: AsyncRead + Unpin,
+{
+    fn new(module: M, input: SharedInput, io: R) -> Self {
+        AsyncReadBridge {
+            module: Some(module),
+            input,
+            io,
+            chunk: vec![0; READ_SIZE],
+        }
+    }
+
+    /// Reads from `io` until `want` bytes are buffered or the input ends.
+    fn poll_fill(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<std::io::Result<()>> {
+        loop {
+            let buffered = self.input.len();
+
+            if buffered >= want || self.input.is_ended() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let len = (want - buffered).min(READ_SIZE);
+            let mut buf = ReadBuf::new(&mut self.chunk[..len]);
+            ready!(Pin::new(&mut self.io).poll_read(cx, &mut buf))?;
+
+            let mut input = self.input.0.lock().unwrap();
+
+            match buf.filled() {
+                [] => input.ended = true,
+                filled => input.data.extend(filled),
+            }
+        }
+    }
+
+    async fn fill(&mut self, want: usize) -> std::io::Result<()> {
+        poll_fn(|cx| self.poll_fill(cx, want)).await
+    }
+
+    /// Reads from the stage, which must not run out of buffered input.
+    fn read_module(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let module = match self.module.as_mut() {
+            Some(module) => module,
+            None => return Err(Error::other("Read after finalise")),
+        };
+
+        match module.read(buf) {
+            // Whatever the stage had read of its frame is lost
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Stage read more than {} bytes at once", LOOKAHEAD),
+            )),
+            result => result,
+        }
+    }
+
+    /// Reads whatever the stage still reads after the data, such as
+    /// padding, a lookahead at a time rather than all at once.
+    async fn drain(&mut self) -> std::io::Result<()> {
+        loop {
+            self.fill(LOOKAHEAD).await?;
+
+            let buffered = self.input.len();
+
+            if self.read_module(&mut [0; 1])? != 0 {
+                return Err(Error::new(ErrorKind::InvalidData, "Data after the end of the stream"));
+            }
+
+            if self.input.len() == buffered {
+                break;
+            }
+        }
+
+        self.fill(usize::MAX).await
+    }
+}
+
+impl<M, R> AsyncRead for AsyncReadBridge<M, R>
+where
+    M: Read + Unpin,
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_fill(cx, LOOKAHEAD))?;
+
+        let len = this.read_module(buf.initialize_unfilled())?;
+        buf.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<M, R> AsyncDecryptionModule for AsyncReadBridge<M, R>
+where
+    M: DecryptionModule + Unpin + Send,
+    R: AsyncRead + Unpin + Send,
+{
+    async fn finalise(mut self) -> Result<(), Error> {
+        self.drain().await?;
+
+        match self.module.take() {
+            Some(module) => module.finalise(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<M, R> AsyncDecompress for AsyncReadBridge<M, R>
+where
+    M: Decompress + Unpin + Send,
+    R: AsyncRead + Unpin + Send,
+{
+    async fn finalise(mut self) -> Result<(), Error> {
+        self.drain().await?;
+
+        match self.module.take() {
+            Some(module) => module.finalise(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compression::CompressionType,
+        encryption::{padding::Padding, EncryptionSecret, EncryptionType},
+    };
+
+    use super::*;
+
+    /// Hands out a few bytes at a time, and only every other poll.
+    struct Trickle {
+        data: Vec<u8>,
+        pos: usize,
+        ready: bool,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            self.ready = !self.ready;
+
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let len = buf.remaining().min(7).min(self.data.len() - self.pos);
+            buf.put_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn trickle(data: Vec<u8>) -> Trickle {
+        Trickle { data, pos: 0, ready: false }
+    }
+
+    fn pipeline(padding: Padding) -> AsyncProcessingPipeline {
+        ProcessingPipeline::new()
+            .with_compression(Arc::new(CompressionType::Lz4))
+            .with_encryption(Arc::new(EncryptionType::XChaCha))
+            .with_encryption_secret(Arc::new(EncryptionSecret::Password(vec![7; 32])))
+            .with_padding(padding)
+            .into()
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip_through_a_slow_reader() {
+        for padding in [Padding::None, Padding::Padme] {
+            let pipeline = pipeline(padding);
+            let data = data(300_000);
+
+            let mut encoded = vec![];
+            pipeline.compress_stream(&data[..], &mut encoded).await.unwrap();
+
+            let mut decoded = vec![];
+            pipeline.decompress_stream(trickle(encoded), &mut decoded).await.unwrap();
+
+            assert_eq!(decoded, data);
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_what_the_blocking_pipeline_encodes() {
+        let pipeline = pipeline(Padding::Padme);
+        let data = data(100_000);
+
+        let mut encoded = vec![];
+        pipeline.inner.compress_stream(&data[..], &mut encoded).unwrap();
+
+        let mut decoded = vec![];
+        pipeline.decompress_stream(&encoded[..], &mut decoded).await.unwrap();
+
+        assert_eq!(decoded, data);
+    }
+
+    #[tokio::test]
+    async fn tampered_and_truncated_input_fails() {
+        let pipeline = pipeline(Padding::Padme);
+
+        let mut encoded = vec![];
+        pipeline.compress_stream(&data(100_000)[..], &mut encoded).await.unwrap();
+
+        let mut tampered = encoded.clone();
+        tampered[encoded.len() / 2] ^= 1;
+        assert!(pipeline.decompress_stream(trickle(tampered), &mut vec![]).await.is_err());
+
+        let truncated = encoded[..encoded.len() - 100].to_vec();
+        assert!(pipeline.decompress_stream(trickle(truncated), &mut vec![]).await.is_err());
+    }
+}
@@ -1,32 +1,60 @@
+pub mod asynchronous;
+pub mod seekable;
+pub mod stage;
+
 use std::{
     fs::File,
-    io::{copy, Read, Write},
-    path::PathBuf, sync::Arc,
+    io::{copy, Error, ErrorKind, Read, Seek, Write},
+    path::{Path, PathBuf}, sync::Arc,
 };
 
 use crate::{
-    compression::{
-        gzip::GzipAlgorithm, lz4::Lz4Algorithm, snappy::SnappyAlgorithm, Compress,
-        CompressionAlgorithm, CompressionType, DecompressionAlgorithm, passthrough::PassthroughAlgorithm, Decompress,
-    },
-    encryption::{
-        aes_gcm_256::AesGcmAlgorithm, chachapoly::ChaChaPolyAlgorithm,
-        passthrough::{EncryptorPassthrough, DecryptorPassthrough}, xchachapoly::XChaChaPolyAlgorithm, DecryptionAlgorithm,
-        EncryptionAlgorithm, EncryptionModule, EncryptionSecret, EncryptionType, DecryptionModule,
-    },
+    archive::{EntryMetadata, HashingReader},
+    cancel::CancellationToken,
+    compression::CompressionType,
+    encryption::{padding::Padding, EncryptionSecret, EncryptionType},
+    dedup::{ChunkStore, Claimed, FileIndex, MANIFEST_EXT, REFERENCE_EXT},
     error::{PipelineBuildError, PipelineCompressionError, PipelineDecompressionError},
-    signing::{
-        passthrough::{SignerPassthrough, VerifierPassthrough}, Sign, SignerMethod, SigningType, VerifierMethod, Verify,
-    },
+    progress::ProgressObserver,
+    signing::{SigningType, Sign, Verify},
+};
+
+use self::{
+    seekable::{SeekableReader, SeekableWriter, DEFAULT_BLOCK_SIZE, SEEKABLE_EXT},
+    stage::{BoxedReadStage, BoxedWriteStage, StageContext, StageDescriptor, StageRegistry},
 };
 
-#[derive(Default)]
+// Amount of data handled by a task between cancellation checks.
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Default, Clone)]
 pub struct ProcessingPipeline {
     encryption: Arc<EncryptionType>,
     encryption_secret: Arc<EncryptionSecret>,
+    padding: Padding,
     compression: Arc<CompressionType>,
     compression_level: Arc<flate2::Compression>,
     signing: Arc<SigningType>,
+    // When set, entries are written as independently decodable blocks
+    // of this many bytes so they can be read back with random access.
+    block_size: Option<usize>,
+    // When set, files are split into content-defined chunks and
+    // only a chunk list is written to the destination.
+    chunk_store: Option<Arc<ChunkStore>>,
+    // When set, files whose content was already stored are written
+    // as a reference to the earlier entry.
+    file_index: Option<Arc<FileIndex>>,
+    // Key the content hashes of compressed files are made with, when
+    // their entries are encrypted.
+    content_key: Option<[u8; 32]>,
+    // Stages to look up when assembling the pipeline, the built-in
+    // ones are used when not set.
+    registry: Option<Arc<StageRegistry>>,
+    // Overrides the stages picked from the encryption, compression
+    // and signing types above.
+    stages: Option<Arc<Vec<StageDescriptor>>>,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    cancellation: Option<CancellationToken>,
     source: PathBuf,
     destination: PathBuf,
 }
@@ -46,6 +74,11 @@ impl ProcessingPipeline {
         self
     }
 
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
     pub fn with_compression(mut self, compression: Arc<CompressionType>) -> Self {
         self.compression = compression;
         self
@@ -61,6 +94,80 @@ impl ProcessingPipeline {
         self
     }
 
+    pub fn with_block_size(mut self, block_size: Option<usize>) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_chunk_store(mut self, chunk_store: Option<Arc<ChunkStore>>) -> Self {
+        self.chunk_store = chunk_store;
+        self
+    }
+
+    pub fn with_file_index(mut self, file_index: Option<Arc<FileIndex>>) -> Self {
+        self.file_index = file_index;
+        self
+    }
+
+    pub(crate) fn with_content_key(mut self, content_key: Option<[u8; 32]>) -> Self {
+        self.content_key = content_key;
+        self
+    }
+
+    pub(crate) fn content_key(&self) -> Option<&[u8; 32]> {
+        self.content_key.as_ref()
+    }
+
+    pub fn with_registry(mut self, registry: Option<Arc<StageRegistry>>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn with_stages(mut self, stages: Option<Arc<Vec<StageDescriptor>>>) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Option<Arc<dyn ProgressObserver>>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Same pipeline, but without progress reporting. Used for archive
+    /// metadata, which doesn't count towards the processed bytes.
+    pub(crate) fn without_progress(&self) -> ProcessingPipeline {
+        self.clone().with_progress(None)
+    }
+
+    pub fn with_cancellation(mut self, cancellation: Option<CancellationToken>) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+
+    pub(crate) fn file_started(&self, path: &Path) {
+        if let Some(progress) = &self.progress {
+            progress.file_started(path);
+        }
+    }
+
+    pub(crate) fn file_finished(&self, path: &Path) {
+        if let Some(progress) = &self.progress {
+            progress.file_finished(path);
+        }
+    }
+
+    /// Counts bytes that were handled without going through the
+    /// pipeline, such as deduplicated content.
+    pub(crate) fn report_bytes(&self, bytes: u64) {
+        if let Some(progress) = &self.progress {
+            progress.bytes_processed(bytes);
+        }
+    }
+
     pub fn with_source(mut self, source: PathBuf) -> Self {
         self.source = source;
         self
@@ -71,136 +178,224 @@ impl ProcessingPipeline {
         self
     }
 
-    pub fn compress_dir(self) -> Result<(), PipelineCompressionError> {
+    /// Compresses the source file to the destination, returning the
+    /// metadata of the file as it was read.
+    pub fn compress_dir(self) -> Result<EntryMetadata, PipelineCompressionError> {
+        let source = File::open(&self.source)?;
+        let mut metadata = EntryMetadata::from_file(&source.metadata()?);
+        let mut source = HashingReader::new(source, self.content_key());
+
+        if let Some(chunk_store) = &self.chunk_store {
+            chunk_store.store(&self, &mut source, &self.destination)?;
+            source.finish(&mut metadata);
+
+            return Ok(metadata);
+        }
+
+        if let Some(file_index) = &self.file_index {
+            let claim = match file_index.claim(&mut source, &self.destination)? {
+                Claimed::First(claim) => claim,
+                Claimed::Duplicate(target) => {
+                    self.report_bytes(source.get_mut().stream_position()?);
+
+                    let reference = self.destination.with_extension(REFERENCE_EXT);
+                    file_index.store_reference(&self, &target, &reference)?;
+                    source.finish(&mut metadata);
+
+                    return Ok(metadata);
+                }
+            };
+
+            source.get_mut().rewind()?;
+            source.restart();
+
+            self.encode_entry(&mut source, File::create(&self.destination)?)?;
+            claim.written();
+            source.finish(&mut metadata);
+
+            return Ok(metadata);
+        }
+
         let io = File::create(&self.destination)?;
 
-        self.build_encryptor(io)
+        self.encode_entry(&mut source, io)?;
+        source.finish(&mut metadata);
+
+        Ok(metadata)
     }
 
     pub fn decompress_dir(self) -> Result<(), PipelineDecompressionError> {
-        let io = File::open(&self.source)?;
+        let mut destination = File::create(&self.destination)?;
+        let extension = self.source.extension().unwrap_or_default();
 
-        self.build_dencryptor(io)
+        if let Some(chunk_store) = &self.chunk_store {
+            if extension == MANIFEST_EXT {
+                return chunk_store.restore(&self, &self.source, &mut destination);
+            }
+        }
+
+        if let Some(file_index) = &self.file_index {
+            if extension == REFERENCE_EXT {
+                let target = file_index.resolve(&self, &self.source)?;
+                return self.decode_file(&target, &mut destination);
+            }
+        }
+
+        self.decode_file(&self.source, &mut destination)
     }
 
-    pub fn build_encryptor<T>(self, io: T) -> Result<(), PipelineCompressionError> 
+    fn decode_file<W>(&self, source: &Path, destination: &mut W) -> Result<(), PipelineDecompressionError>
     where
-        T: Write,
+        W: Write,
     {
-        let encryption_secret = (*self.encryption_secret).clone(); // TODO: Try to get rid of this clone...
-
-        match encryption_secret {
-            EncryptionSecret::Password(p) => match *self.encryption {
-                EncryptionType::XChaCha => self.build_compressor(XChaChaPolyAlgorithm::new().with_key(p).encryptor(io)?),
-                EncryptionType::ChaCha => self.build_compressor(ChaChaPolyAlgorithm::new().with_key(p).encryptor(io)?),
-                EncryptionType::AesGcm => self.build_compressor(AesGcmAlgorithm::new().with_key(p).encryptor(io)?),
-                EncryptionType::Passthrough => self.build_compressor(EncryptorPassthrough::from(io)),
-            },
-            EncryptionSecret::Key(_) => {
-                unimplemented!("Key encryption not yet implemented")
-            }
-            EncryptionSecret::None => self.build_compressor(EncryptorPassthrough::from(io)),
+        let io = File::open(source)?;
+
+        match source.extension().unwrap_or_default() == SEEKABLE_EXT {
+            true => self.decompress_seekable(io, destination),
+            false => self.decompress_stream(io, destination),
         }
     }
 
-    pub fn build_compressor<T>(&self, io: T) -> Result<(), PipelineCompressionError>
+    /// Encodes an entry with `compress_seekable` when a block size is
+    /// set, with `compress_stream` otherwise.
+    pub(crate) fn encode_entry<R, W>(&self, reader: R, writer: W) -> Result<(), PipelineCompressionError>
     where
-        T: EncryptionModule,
+        R: Read,
+        W: Write + Send,
     {
-        let compression_level = *self.compression_level; // TODO: Try to get rid of this copy...
-
-        match *self.compression {
-            CompressionType::Lz4 => self.build_signer(Lz4Algorithm::new().compressor(io)?),
-            CompressionType::Gzip => self.build_signer(
-                GzipAlgorithm::with_compression_level(compression_level).compressor(io)?,
-            ),
-            CompressionType::Snappy => self.build_signer(SnappyAlgorithm::new().compressor(io)?),
-            CompressionType::Passthrough => self.build_signer(PassthroughAlgorithm::new().compressor(io)?),
+        match self.block_size {
+            Some(_) => self.compress_seekable(reader, writer),
+            None => self.compress_stream(reader, writer),
+        }
+    }
+
+    /// Extension of the entries `encode_entry` writes, which tells how
+    /// they have to be read back.
+    pub(crate) fn stream_ext(&self) -> &'static str {
+        match self.block_size {
+            Some(_) => SEEKABLE_EXT,
+            None => "lz4",
         }
     }
 
-    pub fn build_signer<T>(&self, io: T) -> Result<(), PipelineCompressionError> 
+    /// Compresses, encrypts and signs everything read from `reader`
+    /// into `writer`, without touching the file system. The output is
+    /// a plain stream whatever the block size, read back with
+    /// `decompress_stream`.
+    pub fn compress_stream<R, W>(&self, mut reader: R, writer: W) -> Result<(), PipelineCompressionError>
     where
-        T: Compress,
+        R: Read,
+        W: Write + Send,
     {
-        match *self.signing {
-            SigningType::Passthrough => {
-                let pipeline = PipelineTask::from_writer(SignerPassthrough::from(io));
-                self.execute_compression_pipeline(pipeline)
-            }
-        }
+        self.build_encryptor(writer, &mut reader)
     }
 
-    fn execute_compression_pipeline<T>(&self, pipeline: T) -> Result<(), PipelineCompressionError> 
-    where 
-        T: CompressionPipeline,
+    /// Verifies, decrypts and decompresses a stream written by
+    /// `compress_stream` from `reader` into `writer`.
+    pub fn decompress_stream<R, W>(&self, reader: R, mut writer: W) -> Result<(), PipelineDecompressionError>
+    where
+        R: Read + Send,
+        W: Write,
     {
-        let mut source = File::open(&self.source)?;
+        self.build_dencryptor(reader, &mut writer)
+    }
 
-        match pipeline.compress(&mut source) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e)
-        }
+    /// Like `compress_stream`, but encodes blocks of the block size, or
+    /// of `DEFAULT_BLOCK_SIZE` when none is set, each on their own so
+    /// they can be read back with random access through `SeekableReader`.
+    pub fn compress_seekable<R, W>(&self, mut reader: R, writer: W) -> Result<(), PipelineCompressionError>
+    where
+        R: Read,
+        W: Write + Send,
+    {
+        let block_size = self.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+
+        let mut writer = SeekableWriter::new(self.clone(), writer, block_size);
+        copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+
+        Ok(())
     }
 
-    pub fn build_dencryptor<T>(self, io: T) -> Result<(), PipelineDecompressionError> 
+    /// Decodes everything written by `compress_seekable` from `reader`
+    /// into `writer`.
+    pub fn decompress_seekable<R, W>(&self, reader: R, writer: &mut W) -> Result<(), PipelineDecompressionError>
     where
-        T: Read,
+        R: Read + Seek,
+        W: Write,
     {
-        let encryption_secret = (*self.encryption_secret).clone(); // TODO: Try to get rid of this clone...
-
-        match encryption_secret {
-            EncryptionSecret::Password(p) => match *self.encryption {
-                EncryptionType::XChaCha => self.build_decompressor(XChaChaPolyAlgorithm::new().with_key(p).decryptor(io)?),
-                EncryptionType::ChaCha => self.build_decompressor(ChaChaPolyAlgorithm::new().with_key(p).decryptor(io)?),
-                EncryptionType::AesGcm => self.build_decompressor(AesGcmAlgorithm::new().with_key(p).decryptor(io)?),
-                EncryptionType::Passthrough => self.build_decompressor(DecryptorPassthrough::from(io)),
-            },
-            EncryptionSecret::Key(_) => {
-                unimplemented!("Key encryption not yet implemented")
-            }
-            EncryptionSecret::None => self.build_decompressor(DecryptorPassthrough::from(io)),
+        let mut reader = SeekableReader::new(self.clone(), reader)?;
+        copy(&mut reader, writer)?;
+
+        Ok(())
+    }
+
+    /// Stages this pipeline is made of, in the order data goes through
+    /// them when encoding.
+    pub fn stages(&self) -> Vec<StageDescriptor> {
+        if let Some(stages) = &self.stages {
+            return stages.to_vec();
+        }
+
+        stage::default_stages(
+            &self.signing,
+            &self.compression,
+            &self.encryption,
+            &self.padding,
+            &self.encryption_secret,
+        )
+    }
+
+    fn stage_context(&self) -> StageContext {
+        StageContext {
+            encryption_secret: self.encryption_secret.clone(),
+            compression_level: *self.compression_level,
         }
     }
 
-    pub fn build_decompressor<T>(&self, io: T) -> Result<(), PipelineDecompressionError>
+    fn registry(&self) -> Arc<StageRegistry> {
+        self.registry.clone().unwrap_or_else(StageRegistry::builtin)
+    }
+
+    /// Assembles the encoding stages on top of `io`. Everything written
+    /// to the returned stage ends up in `io` once it is finished.
+    pub fn build_writer<'a, W>(&self, io: W) -> Result<BoxedWriteStage<'a>, PipelineBuildError>
     where
-        T: DecryptionModule,
+        W: Write + Send + 'a,
     {
-        let compression_level = *self.compression_level; // TODO: Try to get rid of this copy...
-
-        match *self.compression {
-            CompressionType::Lz4 => self.build_verifier(Lz4Algorithm::new().decompressor(io)?),
-            CompressionType::Gzip => self.build_verifier(
-                GzipAlgorithm::with_compression_level(compression_level).decompressor(io)?,
-            ),
-            CompressionType::Snappy => self.build_verifier(SnappyAlgorithm::new().decompressor(io)?),
-            CompressionType::Passthrough => self.build_verifier(PassthroughAlgorithm::new().decompressor(io)?),
-        }
+        self.registry().build_writer(io, &self.stages(), &self.stage_context())
     }
 
-    pub fn build_verifier<T>(&self, io: T) -> Result<(), PipelineDecompressionError> 
+    /// Assembles the decoding stages on top of `io`.
+    pub fn build_reader<'a, R>(&self, io: R) -> Result<BoxedReadStage<'a>, PipelineBuildError>
     where
-        T: Decompress,
+        R: Read + Send + 'a,
     {
-        match *self.signing {
-            SigningType::Passthrough => {
-                let pipeline = PipelineTask::from_reader(VerifierPassthrough::from(io));
-                self.execute_decompression_pipeline(pipeline)
-            }
-        }
+        self.registry().build_reader(io, &self.stages(), &self.stage_context())
     }
 
-    fn execute_decompression_pipeline<T>(&self, pipeline: T) -> Result<(), PipelineDecompressionError> 
-    where 
-        T: DecompressionPipeline,
+    pub fn build_encryptor<T, R>(&self, io: T, source: &mut R) -> Result<(), PipelineCompressionError> 
+    where
+        T: Write + Send,
+        R: Read,
     {
-        let mut destination = File::create(&self.destination)?;
+        let pipeline = PipelineTask::from_writer(self.build_writer(io)?)
+            .with_progress(self.progress.clone())
+            .with_cancellation(self.cancellation.clone());
 
-        match pipeline.decompress(&mut destination) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e)
-        }
+        pipeline.compress(source).map(|_| ())
+    }
+
+    pub fn build_dencryptor<T, W>(&self, io: T, destination: &mut W) -> Result<(), PipelineDecompressionError> 
+    where
+        T: Read + Send,
+        W: Write,
+    {
+        let pipeline = PipelineTask::from_reader(self.build_reader(io)?)
+            .with_progress(self.progress.clone())
+            .with_cancellation(self.cancellation.clone());
+
+        pipeline.decompress(destination).map(|_| ())
     }
 }
 
@@ -218,28 +413,41 @@ pub trait DecompressionPipeline {
 
 pub struct PipelineTask<T> {
     inner: T,
+    progress: Option<Arc<dyn ProgressObserver>>,
+    cancellation: Option<CancellationToken>,
 }
 
 impl PipelineTask<()> {
-    pub fn builder() -> TaskPipelineBuilder<(), (), (), ()> {
-        TaskPipelineBuilder::new()
-    }
-
     pub fn from_writer<U>(io: U) -> PipelineTask<U>
     where
         U: Write,
     {
-        PipelineTask { inner: io }
+        PipelineTask { inner: io, progress: None, cancellation: None }
     }
 
     pub fn from_reader<U>(io: U) -> PipelineTask<U>
     where
         U: Read,
     {
-        PipelineTask { inner: io }
+        PipelineTask { inner: io, progress: None, cancellation: None }
     }
 }
 
+impl<T> PipelineTask<T> {
+    /// Reports the uncompressed bytes going through the task.
+    pub fn with_progress(mut self, progress: Option<Arc<dyn ProgressObserver>>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Stops the task between chunks once `cancellation` is cancelled.
+    pub fn with_cancellation(mut self, cancellation: Option<CancellationToken>) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+}
+
 impl<T> CompressionPipeline for PipelineTask<T>
 where
     T: Sign,
@@ -248,7 +456,13 @@ where
     where
         F: Read,
     {
-        copy(input, &mut self.inner)?;
+        copy_chunks(
+            input,
+            &mut self.inner,
+            self.progress.as_deref(),
+            self.cancellation.as_ref(),
+            PipelineCompressionError::Cancelled,
+        )?;
         Ok(self.inner.finalise()?)
     }
 }
@@ -264,105 +478,118 @@ where
     where
         F: Write,
     {
-        copy(&mut self.inner, output)?;
+        copy_chunks(
+            &mut self.inner,
+            output,
+            self.progress.as_deref(),
+            self.cancellation.as_ref(),
+            PipelineDecompressionError::Cancelled,
+        )?;
         Ok(self.inner.finalise()?)
     }
 }
 
-// TODO: Consider removing, this didn't end up being very helpful
+// Like `io::copy`, checking for cancellation and reporting progress
+// between chunks. Fails with `cancelled` once cancelled.
+pub(crate) fn copy_chunks<R, W, E>(
+    reader: &mut R,
+    writer: &mut W,
+    progress: Option<&dyn ProgressObserver>,
+    cancellation: Option<&CancellationToken>,
+    cancelled: E,
+) -> Result<u64, E>
+where
+    R: Read,
+    W: Write,
+    E: From<Error>,
+{
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    let mut copied = 0;
 
-pub struct TaskPipelineBuilder<T, E, C, S> {
-    io: T,
-    encryption: E,
-    compression: C,
-    signing: S,
-}
+    loop {
+        if cancellation.is_some_and(|c| c.is_cancelled()) {
+            return Err(cancelled);
+        }
+
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
 
-impl TaskPipelineBuilder<(), (), (), ()> {
-    pub fn new() -> Self {
-        TaskPipelineBuilder {
-            io: (),
-            encryption: (),
-            compression: (),
-            signing: (),
+        writer.write_all(&buf[..len])?;
+        copied += len as u64;
+
+        if let Some(progress) = progress {
+            progress.bytes_processed(len as u64);
         }
     }
 }
 
-impl Default for TaskPipelineBuilder<(), (), (), ()> {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
 
-impl<T, E, C, S> TaskPipelineBuilder<T, E, C, S> {
-    pub fn with_encryption<E2>(self, with: E2) -> TaskPipelineBuilder<T, E2, C, S> {
-        TaskPipelineBuilder {
-            io: self.io,
-            encryption: with,
-            compression: self.compression,
-            signing: self.signing,
-        }
+    use super::*;
+
+    fn pipeline() -> ProcessingPipeline {
+        ProcessingPipeline::new()
+            .with_compression(Arc::new(CompressionType::Lz4))
+            .with_block_size(Some(4096))
     }
 
-    pub fn with_compress_algorithm<C2>(self, with: C2) -> TaskPipelineBuilder<T, E, C2, S> {
-        TaskPipelineBuilder {
-            io: self.io,
-            encryption: self.encryption,
-            compression: with,
-            signing: self.signing,
-        }
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 % 251) as u8).collect()
     }
 
-    pub fn with_signing<S2>(self, with: S2) -> TaskPipelineBuilder<T, E, C, S2> {
-        TaskPipelineBuilder {
-            io: self.io,
-            encryption: self.encryption,
-            compression: self.compression,
-            signing: with,
-        }
+    #[test]
+    fn streams_ignore_the_block_size() {
+        let data = data(20_000);
+
+        let mut encoded = vec![];
+        pipeline().compress_stream(&data[..], &mut encoded).unwrap();
+
+        let mut decoded = vec![];
+        pipeline().decompress_stream(&encoded[..], &mut decoded).unwrap();
+
+        assert_eq!(decoded, data);
     }
 
-    pub fn with_io<U>(self, io: U) -> TaskPipelineBuilder<U, E, C, S> {
-        TaskPipelineBuilder {
-            io,
-            encryption: self.encryption,
-            compression: self.compression,
-            signing: self.signing,
+    #[test]
+    fn seekable_round_trip() {
+        let data = data(20_000);
+
+        for pipeline in [pipeline(), pipeline().with_block_size(None)] {
+            let mut encoded = vec![];
+            pipeline.compress_seekable(&data[..], &mut encoded).unwrap();
+
+            let mut decoded = vec![];
+            pipeline.decompress_seekable(Cursor::new(&encoded), &mut decoded).unwrap();
+
+            assert_eq!(decoded, data);
         }
     }
-}
 
-impl<T, E, C, S> TaskPipelineBuilder<T, E, C, S>
-where
-    T: Write,
-    E: EncryptionAlgorithm<T>,
-    C: CompressionAlgorithm<E::Encryptor>,
-    S: SignerMethod<C::Compressor>,
-{
-    pub fn compression_pipeline(self) -> Result<PipelineTask<S::Signer>, PipelineBuildError> {
-        Ok(PipelineTask {
-            inner: self.signing.signer(
-                self.compression
-                    .compressor(self.encryption.encryptor(self.io)?)?,
-            )?,
-        })
-    }
-}
+    #[test]
+    fn cancelled_streams() {
+        let data = data(20_000);
 
-impl<T, E, C, S> TaskPipelineBuilder<T, E, C, S>
-where
-    T: Read,
-    E: DecryptionAlgorithm<T>,
-    C: DecompressionAlgorithm<E::Decryptor>,
-    S: VerifierMethod<C::Decompressor>,
-{
-    pub fn decompression_pipeline(self) -> Result<PipelineTask<S::Verifier>, PipelineBuildError> {
-        Ok(PipelineTask {
-            inner: self.signing.verifier(
-                self.compression
-                    .decompressor(self.encryption.decryptor(self.io)?)?,
-            )?,
-        })
+        let mut encoded = vec![];
+        pipeline().compress_stream(&data[..], &mut encoded).unwrap();
+
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let pipeline = pipeline().with_cancellation(Some(cancellation));
+
+        assert!(matches!(
+            pipeline.compress_stream(&data[..], vec![]),
+            Err(PipelineCompressionError::Cancelled)
+        ));
+        assert!(matches!(
+            pipeline.decompress_stream(&encoded[..], &mut vec![]),
+            Err(PipelineDecompressionError::Cancelled)
+        ));
     }
 }
@@ -0,0 +1,621 @@
+use std::{
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    mem,
+};
+
+use crate::error::{PipelineCompressionError, PipelineDecompressionError};
+
+use super::ProcessingPipeline;
+
+/*
+    A seekable entry is written as:
+    [ block 0 ][ block 1 ] ... [ block n ][ table ][ table length ][ table crc32 ]
+    [ ...     ][ ...     ]     [ ...     ][ ...   ][ 8            ][ 4           ] (Bytes, LE)
+
+    Every block is a complete, independent run through the
+    encryption -> compression -> signing chain, so any one of them
+    can be decoded without touching the others.
+
+    The table is encoded through the same chain as the blocks, so
+    that the offsets and lengths it holds are only readable, and
+    can only be changed, with the secret the blocks need. Once
+    decoded it is made of:
+    [ block size ][ total length ][ block count ][ entries ]
+    [ 4          ][ 8            ][ 4           ][ ...     ] (Bytes, LE)
+
+    Table entry (per block):
+    [ offset ][ frame length ][ plain length ][ frame crc32 ]
+    [ 8      ][ 4            ][ 4            ][ 4           ] (Bytes, LE)
+
+    The checksums catch damage before anything is decoded.
+
+    Nothing in the entry says it is seekable, that is recorded
+    alongside it, see `SEEKABLE_EXT`.
+*/
+
+pub const DEFAULT_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Extension of the files and archive objects holding a seekable entry.
+pub const SEEKABLE_EXT: &str = "zapseek";
+
+const TABLE_HEADER_SIZE: usize = 16;
+const TABLE_ENTRY_SIZE: usize = 20;
+const TRAILER_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Copy)]
+struct BlockEntry {
+    offset: u64,
+    frame_len: u32,
+    plain_len: u32,
+    crc32: u32,
+}
+
+/// Where every block is, and how long it is once decoded.
+struct BlockTable {
+    block_size: u32,
+    total_len: u64,
+    blocks: Vec<BlockEntry>,
+}
+
+impl BlockTable {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(TABLE_HEADER_SIZE + self.blocks.len() * TABLE_ENTRY_SIZE);
+        buf.extend_from_slice(&self.block_size.to_le_bytes());
+        buf.extend_from_slice(&self.total_len.to_le_bytes());
+        buf.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+
+        for block in &self.blocks {
+            buf.extend_from_slice(&block.offset.to_le_bytes());
+            buf.extend_from_slice(&block.frame_len.to_le_bytes());
+            buf.extend_from_slice(&block.plain_len.to_le_bytes());
+            buf.extend_from_slice(&block.crc32.to_le_bytes());
+        }
+
+        buf
+    }
+
+    /// Parses a decoded table, checking that it describes blocks that
+    /// follow each other up to `blocks_end` and add up to the total
+    /// length.
+    fn from_bytes(buf: &[u8], blocks_end: u64) -> Result<BlockTable, Error> {
+        let corrupt = |reason: &str| Error::new(ErrorKind::InvalidData, format!("Corrupt seekable block table: {}", reason));
+
+        if buf.len() < TABLE_HEADER_SIZE {
+            return Err(corrupt("too short"));
+        }
+
+        let (header, entries) = buf.split_at(TABLE_HEADER_SIZE);
+
+        let block_size = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let total_len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let block_count = u32::from_le_bytes(header[12..16].try_into().unwrap()) as u64;
+
+        if block_size == 0 {
+            return Err(corrupt("block size of 0"));
+        }
+
+        if entries.len() as u64 != block_count * TABLE_ENTRY_SIZE as u64 {
+            return Err(corrupt("wrong number of entries"));
+        }
+
+        if block_count != total_len.div_ceil(block_size as u64) {
+            return Err(corrupt("block count doesn't match the length"));
+        }
+
+        let blocks: Vec<BlockEntry> = entries
+            .chunks_exact(TABLE_ENTRY_SIZE)
+            .map(|raw| BlockEntry {
+                offset: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                frame_len: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+                plain_len: u32::from_le_bytes(raw[12..16].try_into().unwrap()),
+                crc32: u32::from_le_bytes(raw[16..20].try_into().unwrap()),
+            })
+            .collect();
+
+        let mut offset = 0u64;
+        let mut plain_total = 0u64;
+
+        for (i, block) in blocks.iter().enumerate() {
+            // Every block but the last is full, so a position maps
+            // straight to its block
+            let full = i + 1 < blocks.len();
+
+            if block.offset != offset
+                || block.plain_len == 0
+                || block.plain_len > block_size
+                || (full && block.plain_len != block_size)
+            {
+                return Err(corrupt(&format!("invalid block {}", i)));
+            }
+
+            offset = offset
+                .checked_add(block.frame_len as u64)
+                .ok_or_else(|| corrupt("offset overflow"))?;
+            plain_total += block.plain_len as u64;
+        }
+
+        if offset != blocks_end {
+            return Err(corrupt("blocks don't end where the table starts"));
+        }
+
+        if plain_total != total_len {
+            return Err(corrupt("block lengths don't add up to the total length"));
+        }
+
+        Ok(BlockTable {
+            block_size,
+            total_len,
+            blocks,
+        })
+    }
+}
+
+/// Reads and decodes the block table at the end of `io`.
+fn read_table<R>(pipeline: &ProcessingPipeline, io: &mut R) -> Result<BlockTable, PipelineDecompressionError>
+where
+    R: Read + Seek,
+{
+    let corrupt = |reason: &str| Error::new(ErrorKind::InvalidData, format!("Corrupt seekable entry: {}", reason));
+
+    let len = io.seek(SeekFrom::End(0))?;
+
+    let table_end = match len.checked_sub(TRAILER_SIZE as u64) {
+        Some(end) => end,
+        None => return Err(corrupt("missing block table").into()),
+    };
+
+    let mut trailer = [0u8; TRAILER_SIZE];
+    io.seek(SeekFrom::Start(table_end))?;
+    io.read_exact(&mut trailer)?;
+
+    let table_len = u64::from_le_bytes(trailer[0..8].try_into().unwrap());
+    let crc32 = u32::from_le_bytes(trailer[8..12].try_into().unwrap());
+
+    let table_start = match table_end.checked_sub(table_len) {
+        Some(start) => start,
+        None => return Err(corrupt("block table is longer than the entry").into()),
+    };
+
+    let mut encoded = vec![];
+    io.seek(SeekFrom::Start(table_start))?;
+    io.take(table_len).read_to_end(&mut encoded)?;
+
+    if crc32fast::hash(&encoded) != crc32 {
+        return Err(corrupt("block table failed its checksum").into());
+    }
+
+    let mut table = vec![];
+    pipeline.without_progress().build_dencryptor(&encoded[..], &mut table)?;
+
+    Ok(BlockTable::from_bytes(&table, table_start)?)
+}
+
+pub struct SeekableWriter<W>
+where
+    W: Write,
+{
+    pipeline: ProcessingPipeline,
+    io: W,
+    block_size: usize,
+    buffer: Vec<u8>,
+    blocks: Vec<BlockEntry>,
+    offset: u64,
+    total_len: u64,
+}
+
+impl<W> SeekableWriter<W>
+where
+    W: Write,
+{
+    pub fn new(pipeline: ProcessingPipeline, io: W, block_size: usize) -> Self {
+        // Block lengths are stored as u32 in the table.
+        let block_size = block_size.clamp(1, u32::MAX as usize);
+
+        SeekableWriter {
+            pipeline,
+            io,
+            block_size,
+            buffer: Vec::with_capacity(block_size),
+            blocks: vec![],
+            offset: 0,
+            total_len: 0,
+        }
+    }
+
+    fn write_block(&mut self, block: &[u8]) -> Result<(), PipelineCompressionError> {
+        let mut frame = vec![];
+        self.pipeline.build_encryptor(&mut frame, &mut &block[..])?;
+
+        let frame_len = match u32::try_from(frame.len()) {
+            Ok(len) => len,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Encoded block is too large").into()),
+        };
+
+        self.io.write_all(&frame)?;
+
+        self.blocks.push(BlockEntry {
+            offset: self.offset,
+            frame_len,
+            plain_len: block.len() as u32,
+            crc32: crc32fast::hash(&frame),
+        });
+
+        self.offset += frame.len() as u64;
+        self.total_len += block.len() as u64;
+
+        Ok(())
+    }
+
+    /// Writes out any buffered data followed by the block table
+    /// and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W, PipelineCompressionError> {
+        if !self.buffer.is_empty() {
+            let block = mem::take(&mut self.buffer);
+            self.write_block(&block)?;
+        }
+
+        let table = BlockTable {
+            block_size: self.block_size as u32,
+            total_len: self.total_len,
+            blocks: mem::take(&mut self.blocks),
+        };
+
+        let mut encoded = vec![];
+        self.pipeline
+            .without_progress()
+            .build_encryptor(&mut encoded, &mut &table.to_bytes()[..])?;
+
+        self.io.write_all(&encoded)?;
+        self.io.write_all(&(encoded.len() as u64).to_le_bytes())?;
+        self.io.write_all(&crc32fast::hash(&encoded).to_le_bytes())?;
+        self.io.flush()?;
+
+        Ok(self.io)
+    }
+}
+
+impl<W> Write for SeekableWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = std::cmp::min(buf.len(), self.block_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == self.block_size {
+            let block = mem::replace(&mut self.buffer, Vec::with_capacity(self.block_size));
+
+            if let Err(e) = self.write_block(&block) {
+                return Err(match e {
+                    PipelineCompressionError::IOError(e) => e,
+                    e => Error::other(e.to_string()),
+                });
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // Partial blocks are only written by finish, flushing them
+        // early would break the fixed block size the table relies on.
+        self.io.flush()
+    }
+}
+
+/// Random access reader over an entry written by [`SeekableWriter`].
+/// Only the blocks covering the requested range are decoded.
+pub struct SeekableReader<R>
+where
+    R: Read + Seek,
+{
+    pipeline: ProcessingPipeline,
+    io: R,
+    blocks: Vec<BlockEntry>,
+    block_size: u64,
+    total_len: u64,
+    position: u64,
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl<R> SeekableReader<R>
+where
+    R: Read + Seek,
+{
+    pub fn new(pipeline: ProcessingPipeline, mut io: R) -> Result<Self, PipelineDecompressionError> {
+        let table = read_table(&pipeline, &mut io)?;
+
+        Ok(SeekableReader {
+            pipeline,
+            io,
+            blocks: table.blocks,
+            block_size: table.block_size as u64,
+            total_len: table.total_len,
+            position: 0,
+            cache: None,
+        })
+    }
+
+    /// Length of the decoded entry.
+    pub fn len(&self) -> u64 {
+        self.total_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total_len == 0
+    }
+
+    fn load_block(&mut self, block: usize) -> Result<(), Error> {
+        if let Some((cached, _)) = self.cache {
+            if cached == block {
+                return Ok(());
+            }
+        }
+
+        let entry = match self.blocks.get(block) {
+            Some(entry) => *entry,
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("Missing block {}", block))),
+        };
+
+        let mut frame = vec![0u8; entry.frame_len as usize];
+        self.io.seek(SeekFrom::Start(entry.offset))?;
+        self.io.read_exact(&mut frame)?;
+
+        if crc32fast::hash(&frame) != entry.crc32 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Block {} failed its checksum", block),
+            ));
+        }
+
+        let mut plain = Vec::with_capacity(entry.plain_len as usize);
+
+        if let Err(e) = self.pipeline.build_dencryptor(&frame[..], &mut plain) {
+            return Err(match e {
+                PipelineDecompressionError::IOError(e) => e,
+                e => Error::other(e.to_string()),
+            });
+        }
+
+        if plain.len() != entry.plain_len as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Block {} decoded to an unexpected length", block),
+            ));
+        }
+
+        self.cache = Some((block, plain));
+
+        Ok(())
+    }
+}
+
+impl<R> Read for SeekableReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.position >= self.total_len {
+            return Ok(0);
+        }
+
+        let block = (self.position / self.block_size) as usize;
+        let block_offset = (self.position % self.block_size) as usize;
+
+        self.load_block(block)?;
+
+        let plain = match &self.cache {
+            Some((_, plain)) => plain,
+            None => unreachable!(),
+        };
+
+        let plain = match plain.get(block_offset..) {
+            Some(plain) => plain,
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("Block {} is too short", block))),
+        };
+
+        let cpy_len = std::cmp::min(buf.len(), plain.len());
+        buf[..cpy_len].copy_from_slice(&plain[..cpy_len]);
+
+        self.position += cpy_len as u64;
+
+        Ok(cpy_len)
+    }
+}
+
+impl<R> Seek for SeekableReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => self.total_len.checked_add_signed(p),
+            SeekFrom::Current(p) => self.position.checked_add_signed(p),
+        };
+
+        match position {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use super::*;
+    use crate::{
+        compression::CompressionType,
+        encryption::{padding::Padding, EncryptionSecret, EncryptionType},
+    };
+
+    const BLOCK_SIZE: usize = 1000;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 17 % 251) as u8).collect()
+    }
+
+    fn pipeline(encrypted: bool) -> ProcessingPipeline {
+        let pipeline = ProcessingPipeline::new().with_compression(Arc::new(CompressionType::Lz4));
+
+        match encrypted {
+            true => pipeline
+                .with_encryption(Arc::new(EncryptionType::XChaCha))
+                .with_encryption_secret(Arc::new(EncryptionSecret::Password(vec![7; 32]))),
+            false => pipeline,
+        }
+    }
+
+    fn write(pipeline: &ProcessingPipeline, data: &[u8]) -> Vec<u8> {
+        let mut writer = SeekableWriter::new(pipeline.clone(), vec![], BLOCK_SIZE);
+        writer.write_all(data).unwrap();
+
+        writer.finish().unwrap()
+    }
+
+    fn read_all(pipeline: &ProcessingPipeline, entry: Vec<u8>) -> Result<Vec<u8>, PipelineDecompressionError> {
+        let mut reader = SeekableReader::new(pipeline.clone(), Cursor::new(entry))?;
+        let mut content = vec![];
+        reader.read_to_end(&mut content)?;
+
+        Ok(content)
+    }
+
+    #[test]
+    fn round_trip() {
+        for encrypted in [false, true] {
+            let pipeline = pipeline(encrypted);
+
+            for len in [0, 1, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1, 10 * BLOCK_SIZE + 7] {
+                let entry = write(&pipeline, &data(len));
+
+                assert_eq!(read_all(&pipeline, entry).unwrap(), data(len), "length {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn padded_blocks_hide_length() {
+        let pipeline = ProcessingPipeline::new()
+            .with_encryption(Arc::new(EncryptionType::XChaCha))
+            .with_encryption_secret(Arc::new(EncryptionSecret::Password(vec![7; 32])))
+            .with_padding(Padding::Padme);
+
+        let short = write(&pipeline, &data(10 * BLOCK_SIZE + 900));
+        let long = write(&pipeline, &data(10 * BLOCK_SIZE + 901));
+
+        assert_eq!(short.len(), long.len());
+        assert_eq!(read_all(&pipeline, short).unwrap(), data(10 * BLOCK_SIZE + 900));
+        assert_eq!(read_all(&pipeline, long).unwrap(), data(10 * BLOCK_SIZE + 901));
+    }
+
+    #[test]
+    fn random_access() {
+        let pipeline = pipeline(true);
+        let data = data(10 * BLOCK_SIZE + 7);
+
+        let mut reader = SeekableReader::new(pipeline.clone(), Cursor::new(write(&pipeline, &data))).unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+
+        for start in [0, 999, 1000, 4321, data.len() - 3] {
+            let mut buf = [0u8; 1500];
+            reader.seek(SeekFrom::Start(start as u64)).unwrap();
+
+            let read = reader.read(&mut buf).unwrap();
+            assert!(read > 0);
+            assert_eq!(&buf[..read], &data[start..start + read]);
+        }
+
+        reader.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(reader.read(&mut [0u8; 10]).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-(data.len() as i64) - 1)).is_err());
+    }
+
+    #[test]
+    fn table_is_encrypted() {
+        let pipeline = pipeline(true);
+        let data = data(10 * BLOCK_SIZE + 7);
+        let entry = write(&pipeline, &data);
+
+        // Neither the total length nor the block size is readable
+        let total_len = (data.len() as u64).to_le_bytes();
+        assert!(!entry.windows(8).any(|w| w == total_len));
+        assert!(!entry.windows(4).any(|w| w == (BLOCK_SIZE as u32).to_le_bytes()));
+
+        // and the table can't be read without the secret
+        let wrong = pipeline.with_encryption_secret(Arc::new(EncryptionSecret::Password(vec![8; 32])));
+        assert!(SeekableReader::new(wrong, Cursor::new(entry)).is_err());
+    }
+
+    #[test]
+    fn corrupt_table_is_rejected() {
+        for encrypted in [false, true] {
+            let pipeline = pipeline(encrypted);
+            let entry = write(&pipeline, &data(5 * BLOCK_SIZE));
+            let table_end = entry.len() - TRAILER_SIZE;
+            let table_len = u64::from_le_bytes(entry[table_end..table_end + 8].try_into().unwrap()) as usize;
+
+            // Every byte of the table and the blocks is covered
+            for i in (0..table_end).step_by(table_len / 3 + 1).chain(table_end - table_len..table_end) {
+                let mut corrupt = entry.clone();
+                corrupt[i] ^= 0x55;
+
+                assert!(read_all(&pipeline, corrupt).is_err(), "byte {} of {}", i, entry.len());
+            }
+
+            // Lengths pointing outside of the entry
+            let mut corrupt = entry.clone();
+            corrupt[table_end..table_end + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+            assert!(read_all(&pipeline, corrupt).is_err());
+
+            assert!(read_all(&pipeline, entry[..entry.len() - 1].to_vec()).is_err());
+            assert!(read_all(&pipeline, entry[..4].to_vec()).is_err());
+        }
+    }
+
+    /// Table of blocks of 10 bytes, each encoded to 5.
+    fn table(total_len: u64, plain_lens: &[u32]) -> BlockTable {
+        BlockTable {
+            block_size: 10,
+            total_len,
+            blocks: plain_lens
+                .iter()
+                .enumerate()
+                .map(|(i, plain_len)| BlockEntry {
+                    offset: 5 * i as u64,
+                    frame_len: 5,
+                    plain_len: *plain_len,
+                    crc32: 0,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn inconsistent_table_is_rejected() {
+        let parse = |table: BlockTable, blocks_end| BlockTable::from_bytes(&table.to_bytes(), blocks_end);
+
+        assert!(parse(table(25, &[10, 10, 5]), 15).is_ok());
+        assert!(parse(table(0, &[]), 0).is_ok());
+
+        // Blocks not ending where the table starts
+        assert!(parse(table(25, &[10, 10, 5]), 16).is_err());
+        // Total length not matching the blocks
+        assert!(parse(table(26, &[10, 10, 5]), 15).is_err());
+        // Short block before the last
+        assert!(parse(table(25, &[5, 10, 10]), 15).is_err());
+        // Block count not matching the total length
+        assert!(parse(table(20, &[10, 10, 0]), 15).is_err());
+        assert!(parse(table(10, &[10, 0]), 10).is_err());
+        // Block longer than the block size
+        assert!(parse(table(11, &[11]), 5).is_err());
+    }
+}
@@ -0,0 +1,639 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{Error, Read, Write},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::{
+        gzip::GzipAlgorithm, lz4::Lz4Algorithm, passthrough::PassthroughAlgorithm,
+        snappy::SnappyAlgorithm, Compress, CompressionAlgorithm, CompressionType, Decompress,
+        DecompressionAlgorithm,
+    },
+    encryption::{
+        aes_gcm_256::AesGcmAlgorithm, chachapoly::ChaChaPolyAlgorithm, passthrough::EncryptionPassthrough,
+        padding::Padding, xchachapoly::XChaChaPolyAlgorithm, DecryptionAlgorithm, DecryptionModule,
+        EncryptionAlgorithm, EncryptionModule, EncryptionSecret, EncryptionType,
+    },
+    error::PipelineBuildError,
+    signing::{
+        passthrough::{SignerPassthrough, VerifierPassthrough},
+        Sign, SigningType, Verify,
+    },
+};
+
+/// Appended to the name of encryption stages that pad their plaintext.
+const PADME_SUFFIX: &str = "-padme";
+
+/// One step of an encoding pipeline. Stages are stacked on top of each
+/// other, every stage writing into the one below it.
+pub trait WriteStage: Write + Send {
+    /// Flushes the stage and finishes every stage below it. Signing
+    /// stages return their signature.
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// One step of a decoding pipeline, reading from the stage below it.
+pub trait ReadStage: Read + Send {
+    /// Finishes the stage and every stage below it. Signing stages
+    /// return the signature they verified.
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error>;
+}
+
+pub type BoxedWriteStage<'a> = Box<dyn WriteStage + 'a>;
+pub type BoxedReadStage<'a> = Box<dyn ReadStage + 'a>;
+
+/// Builds a stage on top of `inner`.
+pub type WriteStageFactory = Arc<
+    dyn for<'a> Fn(BoxedWriteStage<'a>, &StageContext) -> Result<BoxedWriteStage<'a>, PipelineBuildError>
+        + Send
+        + Sync,
+>;
+
+/// Builds a stage reading from `inner`.
+pub type ReadStageFactory = Arc<
+    dyn for<'a> Fn(BoxedReadStage<'a>, &StageContext) -> Result<BoxedReadStage<'a>, PipelineBuildError>
+        + Send
+        + Sync,
+>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StageKind {
+    Signing,
+    Compression,
+    Encryption,
+}
+
+/// Names a registered stage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StageDescriptor {
+    pub kind: StageKind,
+    pub name: String,
+}
+
+impl StageDescriptor {
+    pub fn new(kind: StageKind, name: impl Into<String>) -> StageDescriptor {
+        StageDescriptor {
+            kind,
+            name: name.into(),
+        }
+    }
+
+    pub fn signing(name: impl Into<String>) -> StageDescriptor {
+        StageDescriptor::new(StageKind::Signing, name)
+    }
+
+    pub fn compression(name: impl Into<String>) -> StageDescriptor {
+        StageDescriptor::new(StageKind::Compression, name)
+    }
+
+    pub fn encryption(name: impl Into<String>) -> StageDescriptor {
+        StageDescriptor::new(StageKind::Encryption, name)
+    }
+
+    /// The encryption stage `self`, padding its plaintext with `padding`.
+    pub fn with_padding(self, padding: Padding) -> StageDescriptor {
+        match padding {
+            Padding::None => self,
+            Padding::Padme => StageDescriptor {
+                name: format!("{}{}", self.name, PADME_SUFFIX),
+                ..self
+            },
+        }
+    }
+
+    /// True for an encryption stage that pads its plaintext.
+    pub fn is_padded(&self) -> bool {
+        self.kind == StageKind::Encryption && self.name.ends_with(PADME_SUFFIX)
+    }
+}
+
+impl fmt::Display for StageDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} stage '{}'", self.kind, self.name)
+    }
+}
+
+impl From<&EncryptionType> for StageDescriptor {
+    fn from(value: &EncryptionType) -> Self {
+        StageDescriptor::encryption(match value {
+            EncryptionType::Passthrough => "passthrough",
+            EncryptionType::XChaCha => "xchacha",
+            EncryptionType::AesGcm => "aesgcm",
+            EncryptionType::ChaCha => "chacha",
+        })
+    }
+}
+
+impl From<&CompressionType> for StageDescriptor {
+    fn from(value: &CompressionType) -> Self {
+        StageDescriptor::compression(match value {
+            CompressionType::Passthrough => "passthrough",
+            CompressionType::Lz4 => "lz4",
+            CompressionType::Gzip => "gzip",
+            CompressionType::Snappy => "snappy",
+        })
+    }
+}
+
+impl From<&SigningType> for StageDescriptor {
+    fn from(value: &SigningType) -> Self {
+        StageDescriptor::signing(match value {
+            SigningType::Passthrough => "passthrough",
+        })
+    }
+}
+
+/// Stages picked for the given types when no stages are set explicitly,
+/// in the order data goes through them when encoding.
+pub(crate) fn default_stages(
+    signing: &SigningType,
+    compression: &CompressionType,
+    encryption: &EncryptionType,
+    padding: &Padding,
+    encryption_secret: &EncryptionSecret,
+) -> Vec<StageDescriptor> {
+    let encryption = match (encryption_secret, encryption) {
+        (EncryptionSecret::None, _) | (_, EncryptionType::Passthrough) => {
+            StageDescriptor::from(&EncryptionType::Passthrough)
+        }
+        _ => StageDescriptor::from(encryption).with_padding(*padding),
+    };
+
+    vec![
+        StageDescriptor::from(signing),
+        StageDescriptor::from(compression),
+        encryption,
+    ]
+}
+
+/// Settings handed to every stage factory.
+#[derive(Default, Clone)]
+pub struct StageContext {
+    pub encryption_secret: Arc<EncryptionSecret>,
+    pub compression_level: flate2::Compression,
+}
+
+impl StageContext {
+    /// The password for encryption stages.
+    pub fn key(&self) -> Result<Vec<u8>, PipelineBuildError> {
+        match &*self.encryption_secret {
+            EncryptionSecret::Password(p) => Ok(p.clone()),
+            EncryptionSecret::Key(_) => Err(PipelineBuildError::UnsupportedSecret(
+                "Key files only open archives encrypted for their public key".into(),
+            )),
+            EncryptionSecret::None => Err(PipelineBuildError::UnsupportedSecret(
+                "No encryption secret given".into(),
+            )),
+        }
+    }
+}
+
+/// Stages available to pipelines, looked up by descriptor.
+///
+/// `StageRegistry::default()` holds every built-in stage, more can be
+/// added with `register` before handing the registry to a pipeline.
+#[derive(Clone)]
+pub struct StageRegistry {
+    stages: HashMap<StageDescriptor, (WriteStageFactory, ReadStageFactory)>,
+}
+
+impl Default for StageRegistry {
+    fn default() -> Self {
+        let mut registry = StageRegistry::empty();
+        register_builtin(&mut registry);
+        registry
+    }
+}
+
+impl StageRegistry {
+    pub fn empty() -> StageRegistry {
+        StageRegistry {
+            stages: HashMap::new(),
+        }
+    }
+
+    /// Shared registry with the built-in stages, used by pipelines
+    /// that weren't given one.
+    pub fn builtin() -> Arc<StageRegistry> {
+        static BUILTIN: OnceLock<Arc<StageRegistry>> = OnceLock::new();
+
+        BUILTIN.get_or_init(|| Arc::new(StageRegistry::default())).clone()
+    }
+
+    /// Adds a stage, replacing any stage with the same descriptor.
+    pub fn register<W, R>(&mut self, descriptor: StageDescriptor, writer: W, reader: R)
+    where
+        W: for<'a> Fn(BoxedWriteStage<'a>, &StageContext) -> Result<BoxedWriteStage<'a>, PipelineBuildError>
+            + Send
+            + Sync
+            + 'static,
+        R: for<'a> Fn(BoxedReadStage<'a>, &StageContext) -> Result<BoxedReadStage<'a>, PipelineBuildError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.stages.insert(descriptor, (Arc::new(writer), Arc::new(reader)));
+    }
+
+    pub fn contains(&self, descriptor: &StageDescriptor) -> bool {
+        self.stages.contains_key(descriptor)
+    }
+
+    /// Stacks `stages` on top of `io`. Stages are listed in the order
+    /// data goes through them when encoding, so the last one writes
+    /// to `io`.
+    pub fn build_writer<'a, W>(
+        &self,
+        io: W,
+        stages: &[StageDescriptor],
+        context: &StageContext,
+    ) -> Result<BoxedWriteStage<'a>, PipelineBuildError>
+    where
+        W: Write + Send + 'a,
+    {
+        let mut stage: BoxedWriteStage<'a> = Box::new(IoWriter(io));
+
+        for descriptor in stages.iter().rev() {
+            let (writer, _) = self.lookup(descriptor)?;
+            stage = writer(stage, context)?;
+        }
+
+        Ok(stage)
+    }
+
+    /// Decoding twin of `build_writer`, taking the same list of stages.
+    pub fn build_reader<'a, R>(
+        &self,
+        io: R,
+        stages: &[StageDescriptor],
+        context: &StageContext,
+    ) -> Result<BoxedReadStage<'a>, PipelineBuildError>
+    where
+        R: Read + Send + 'a,
+    {
+        let mut stage: BoxedReadStage<'a> = Box::new(IoReader(io));
+
+        for descriptor in stages.iter().rev() {
+            let (_, reader) = self.lookup(descriptor)?;
+            stage = reader(stage, context)?;
+        }
+
+        Ok(stage)
+    }
+
+    fn lookup(&self, descriptor: &StageDescriptor) -> Result<&(WriteStageFactory, ReadStageFactory), PipelineBuildError> {
+        self.stages
+            .get(descriptor)
+            .ok_or_else(|| PipelineBuildError::UnknownStage(descriptor.to_string()))
+    }
+}
+
+// Boxed stages can be used wherever the built-in modules expect the
+// layer below them.
+
+impl EncryptionModule for BoxedWriteStage<'_> {
+    fn finalise(self) -> Result<(), Error> {
+        self.finish().map(|_| ())
+    }
+}
+
+impl Compress for BoxedWriteStage<'_> {
+    fn finalise(self) -> Result<(), Error> {
+        self.finish().map(|_| ())
+    }
+}
+
+impl Sign for BoxedWriteStage<'_> {
+    fn finalise(self) -> Result<Option<Vec<u8>>, Error> {
+        self.finish()
+    }
+}
+
+impl DecryptionModule for BoxedReadStage<'_> {
+    fn finalise(self) -> Result<(), Error> {
+        self.finish().map(|_| ())
+    }
+}
+
+impl Decompress for BoxedReadStage<'_> {
+    fn finalise(self) -> Result<(), Error> {
+        self.finish().map(|_| ())
+    }
+}
+
+impl Verify for BoxedReadStage<'_> {
+    fn finalise(self) -> Result<Option<Vec<u8>>, Error> {
+        self.finish()
+    }
+}
+
+/// Bottom of a write stack.
+struct IoWriter<W>(W);
+
+impl<W: Write + Send> Write for IoWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write + Send> WriteStage for IoWriter<W> {
+    fn finish(mut self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        self.0.flush()?;
+        Ok(None)
+    }
+}
+
+/// Bottom of a read stack.
+struct IoReader<R>(R);
+
+impl<R: Read + Send> Read for IoReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: Read + Send> ReadStage for IoReader<R> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+}
+
+/*
+    Encryption modules only flush their writer when finalised, they
+    don't know about the stage below them. They write through a handle
+    instead, so that stage can still be finished afterwards.
+*/
+
+type SharedSlot<T> = Arc<Mutex<Option<T>>>;
+
+struct WriteHandle<'a>(SharedSlot<BoxedWriteStage<'a>>);
+
+impl Write for WriteHandle<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(inner) => inner.write(buf),
+            None => Err(Error::other("Stage already finished")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+struct ReadHandle<'a>(SharedSlot<BoxedReadStage<'a>>);
+
+impl Read for ReadHandle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(inner) => inner.read(buf),
+            None => Err(Error::other("Stage already finished")),
+        }
+    }
+}
+
+struct EncryptStage<'a, M> {
+    module: M,
+    inner: SharedSlot<BoxedWriteStage<'a>>,
+}
+
+impl<'a, M> EncryptStage<'a, M>
+where
+    M: EncryptionModule + Send + 'a,
+{
+    fn build<F, E>(inner: BoxedWriteStage<'a>, constructor: F) -> Result<BoxedWriteStage<'a>, PipelineBuildError>
+    where
+        F: FnOnce(WriteHandle<'a>) -> Result<M, E>,
+        PipelineBuildError: From<E>,
+    {
+        let inner = Arc::new(Mutex::new(Some(inner)));
+        let module = constructor(WriteHandle(inner.clone()))?;
+
+        Ok(Box::new(EncryptStage { module, inner }))
+    }
+}
+
+impl<M: EncryptionModule + Send> Write for EncryptStage<'_, M> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.module.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.module.flush()
+    }
+}
+
+impl<M: EncryptionModule + Send> WriteStage for EncryptStage<'_, M> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        let EncryptStage { module, inner } = *self;
+
+        module.finalise()?;
+        let inner = inner.lock().unwrap().take();
+        match inner {
+            Some(stage) => stage.finish(),
+            None => Ok(None),
+        }
+    }
+}
+
+struct DecryptStage<'a, M> {
+    module: M,
+    inner: SharedSlot<BoxedReadStage<'a>>,
+}
+
+impl<'a, M> DecryptStage<'a, M>
+where
+    M: DecryptionModule + Send + 'a,
+{
+    fn build<F, E>(inner: BoxedReadStage<'a>, constructor: F) -> Result<BoxedReadStage<'a>, PipelineBuildError>
+    where
+        F: FnOnce(ReadHandle<'a>) -> Result<M, E>,
+        PipelineBuildError: From<E>,
+    {
+        let inner = Arc::new(Mutex::new(Some(inner)));
+        let module = constructor(ReadHandle(inner.clone()))?;
+
+        Ok(Box::new(DecryptStage { module, inner }))
+    }
+}
+
+impl<M: DecryptionModule + Send> Read for DecryptStage<'_, M> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.module.read(buf)
+    }
+}
+
+impl<M: DecryptionModule + Send> ReadStage for DecryptStage<'_, M> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        let DecryptStage { module, inner } = *self;
+
+        module.finalise()?;
+        let inner = inner.lock().unwrap().take();
+        match inner {
+            Some(stage) => stage.finish(),
+            None => Ok(None),
+        }
+    }
+}
+
+// Compressors and signers finalise the layer below them themselves.
+
+struct CompressStage<M>(M);
+
+impl<M: Compress + Send> Write for CompressStage<M> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<M: Compress + Send> WriteStage for CompressStage<M> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        self.0.finalise()?;
+        Ok(None)
+    }
+}
+
+struct DecompressStage<M>(M);
+
+impl<M: Decompress + Send> Read for DecompressStage<M> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<M: Decompress + Send> ReadStage for DecompressStage<M> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        self.0.finalise()?;
+        Ok(None)
+    }
+}
+
+struct SignStage<M>(M);
+
+impl<M: Sign + Send> Write for SignStage<M> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<M: Sign + Send> WriteStage for SignStage<M> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        self.0.finalise()
+    }
+}
+
+struct VerifyStage<M>(M);
+
+impl<M: Verify + Send> Read for VerifyStage<M> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<M: Verify + Send> ReadStage for VerifyStage<M> {
+    fn finish(self: Box<Self>) -> Result<Option<Vec<u8>>, Error> {
+        self.0.finalise()
+    }
+}
+
+fn register_builtin(registry: &mut StageRegistry) {
+    // Encryption
+    registry.register(
+        StageDescriptor::encryption("passthrough"),
+        |inner, _| EncryptStage::build(inner, |io| EncryptionPassthrough::new().encryptor(io)),
+        |inner, _| DecryptStage::build(inner, |io| EncryptionPassthrough::new().decryptor(io)),
+    );
+    for padding in [Padding::None, Padding::Padme] {
+        registry.register(
+            StageDescriptor::encryption("xchacha").with_padding(padding),
+            move |inner, ctx| {
+                let key = ctx.key()?;
+                let algorithm = XChaChaPolyAlgorithm::new().with_padding(padding).with_key(key);
+                EncryptStage::build(inner, |io| algorithm.encryptor(io))
+            },
+            move |inner, ctx| {
+                let key = ctx.key()?;
+                let algorithm = XChaChaPolyAlgorithm::new().with_padding(padding).with_key(key);
+                DecryptStage::build(inner, |io| algorithm.decryptor(io))
+            },
+        );
+        registry.register(
+            StageDescriptor::encryption("chacha").with_padding(padding),
+            move |inner, ctx| {
+                let key = ctx.key()?;
+                let algorithm = ChaChaPolyAlgorithm::new().with_padding(padding).with_key(key);
+                EncryptStage::build(inner, |io| algorithm.encryptor(io))
+            },
+            move |inner, ctx| {
+                let key = ctx.key()?;
+                let algorithm = ChaChaPolyAlgorithm::new().with_padding(padding).with_key(key);
+                DecryptStage::build(inner, |io| algorithm.decryptor(io))
+            },
+        );
+        registry.register(
+            StageDescriptor::encryption("aesgcm").with_padding(padding),
+            move |inner, ctx| {
+                let key = ctx.key()?;
+                let algorithm = AesGcmAlgorithm::new().with_padding(padding).with_key(key);
+                EncryptStage::build(inner, |io| algorithm.encryptor(io))
+            },
+            move |inner, ctx| {
+                let key = ctx.key()?;
+                let algorithm = AesGcmAlgorithm::new().with_padding(padding).with_key(key);
+                DecryptStage::build(inner, |io| algorithm.decryptor(io))
+            },
+        );
+    }
+
+    // Compression
+    registry.register(
+        StageDescriptor::compression("passthrough"),
+        |inner, _| Ok(Box::new(CompressStage(PassthroughAlgorithm::new().compressor(inner)?))),
+        |inner, _| Ok(Box::new(DecompressStage(PassthroughAlgorithm::new().decompressor(inner)?))),
+    );
+    registry.register(
+        StageDescriptor::compression("lz4"),
+        |inner, _| Ok(Box::new(CompressStage(Lz4Algorithm::new().compressor(inner)?))),
+        |inner, _| Ok(Box::new(DecompressStage(Lz4Algorithm::new().decompressor(inner)?))),
+    );
+    registry.register(
+        StageDescriptor::compression("gzip"),
+        |inner, ctx| {
+            let algorithm = GzipAlgorithm::with_compression_level(ctx.compression_level);
+            Ok(Box::new(CompressStage(algorithm.compressor(inner)?)))
+        },
+        |inner, _| Ok(Box::new(DecompressStage(GzipAlgorithm::new().decompressor(inner)?))),
+    );
+    registry.register(
+        StageDescriptor::compression("snappy"),
+        |inner, _| Ok(Box::new(CompressStage(SnappyAlgorithm::new().compressor(inner)?))),
+        |inner, _| Ok(Box::new(DecompressStage(SnappyAlgorithm::new().decompressor(inner)?))),
+    );
+
+    // Signing
+    registry.register(
+        StageDescriptor::signing("passthrough"),
+        |inner, _| Ok(Box::new(SignStage(SignerPassthrough::from(inner)))),
+        |inner, _| Ok(Box::new(VerifyStage(VerifierPassthrough::from(inner)))),
+    );
+}
@@ -0,0 +1,19 @@
+use std::path::Path;
+
+/// Receives progress events while a directory is compressed or
+/// decompressed. Events come from the worker threads, in no particular
+/// order across files.
+///
+/// Bytes are counted on the uncompressed side, so the total for a run
+/// matches the size of the files being archived or extracted.
+pub trait ProgressObserver: Send + Sync {
+    /// A file is about to be processed. Paths are relative to the
+    /// archived folder.
+    fn file_started(&self, _path: &Path) {}
+
+    /// A file is done with, whether it succeeded or not.
+    fn file_finished(&self, _path: &Path) {}
+
+    /// `bytes` more bytes went through the pipeline.
+    fn bytes_processed(&self, _bytes: u64) {}
+}
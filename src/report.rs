@@ -0,0 +1,136 @@
+use std::{
+    collections::BTreeMap,
+    mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    archive::EntryMetadata,
+    dedup::DedupStats,
+    error::{PipelineCompressionError, PipelineDecompressionError},
+    options::ErrorPolicy,
+};
+
+/// Summary of a `compress_directory` run.
+#[derive(Default, Debug)]
+pub struct CompressionReport {
+    pub entries: EntryReport<PipelineCompressionError>,
+    /// Chunk deduplication figures, when deduplication was enabled.
+    pub dedup: Option<DedupStats>,
+    /// Metadata of every file that was stored, taken while it was read
+    /// to be compressed.
+    pub metadata: BTreeMap<PathBuf, EntryMetadata>,
+}
+
+/// Summary of a `decompress_directory` run.
+#[derive(Default, Debug)]
+pub struct DecompressionReport {
+    pub entries: EntryReport<PipelineDecompressionError>,
+}
+
+/// Outcome of every entry in a run. Paths are relative to the archived
+/// folder.
+#[derive(Debug)]
+pub struct EntryReport<E> {
+    pub succeeded: Vec<PathBuf>,
+    pub skipped: Vec<SkippedEntry>,
+    pub failed: Vec<FailedEntry<E>>,
+}
+
+impl<E> Default for EntryReport<E> {
+    fn default() -> Self {
+        EntryReport {
+            succeeded: vec![],
+            skipped: vec![],
+            failed: vec![],
+        }
+    }
+}
+
+impl<E> EntryReport<E> {
+    /// True when no entry failed.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct SkippedEntry {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The keyfile is never archived.
+    Keyfile,
+    /// Not an entry written by `compress_directory`.
+    UnknownEntry,
+    /// Not attempted because an earlier entry failed under
+    /// `ErrorPolicy::FailFast`.
+    Aborted,
+}
+
+#[derive(Debug)]
+pub struct FailedEntry<E> {
+    pub path: PathBuf,
+    pub error: E,
+    /// Files lost along with a failed solid block, empty otherwise.
+    pub affected: Vec<PathBuf>,
+}
+
+/// Collects entry outcomes from the worker threads.
+pub(crate) struct ReportCollector<E> {
+    report: Mutex<EntryReport<E>>,
+    policy: ErrorPolicy,
+    aborted: AtomicBool,
+}
+
+impl<E> ReportCollector<E> {
+    pub(crate) fn new(policy: ErrorPolicy) -> Self {
+        ReportCollector {
+            report: Mutex::new(EntryReport::default()),
+            policy,
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    /// Set once an entry failed under `ErrorPolicy::FailFast`, no new
+    /// entries should be started from then on.
+    pub(crate) fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn succeeded(&self, paths: Vec<PathBuf>) {
+        self.report.lock().unwrap().succeeded.extend(paths);
+    }
+
+    pub(crate) fn skipped(&self, paths: Vec<PathBuf>, reason: SkipReason) {
+        self.report
+            .lock()
+            .unwrap()
+            .skipped
+            .extend(paths.into_iter().map(|path| SkippedEntry { path, reason }));
+    }
+
+    pub(crate) fn failed(&self, path: PathBuf, error: E, affected: Vec<PathBuf>) {
+        if self.policy == ErrorPolicy::FailFast {
+            self.aborted.store(true, Ordering::Relaxed);
+        }
+
+        self.report.lock().unwrap().failed.push(FailedEntry {
+            path,
+            error,
+            affected,
+        });
+    }
+
+    /// Takes the collected outcomes, once every worker is done.
+    pub(crate) fn take(&self) -> EntryReport<E> {
+        mem::take(&mut *self.report.lock().unwrap())
+    }
+}
@@ -0,0 +1,767 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    archive::EntryMetadata,
+    dedup::{self, ChunkStore},
+    encryption::EncryptionSecret,
+    error::RepositoryError,
+    internal,
+    options::ArchiveOptions,
+    pipeline::{
+        stage::{StageDescriptor, StageKind},
+        ProcessingPipeline,
+    },
+};
+
+/*
+    A repository is a folder holding:
+
+    config                  Version, stages and chunk key, as JSON
+    check.lz4               Known text, encoded to check the secret
+    lock                    Locked while the repository is in use
+    chunks/<id>.lz4         Encoded chunks, shared by every snapshot
+    snapshots/<id>.lz4      Encoded snapshot manifests, as JSON
+
+    Chunks are only added by snapshots and only removed by prune, so a
+    snapshot can be restored for as long as its manifest is there.
+    Snapshots and restores share the lock while forget and prune take
+    it alone, so prune never removes a chunk a running snapshot is
+    about to point at.
+
+    In encrypted repositories chunks are named by their HMAC-SHA256
+    under a random key, stored encoded like the check in the config,
+    so that a name doesn't tell whether a known file is stored.
+    Repositories created before have no key and use SHA-256.
+*/
+
+const VERSION: u16 = 1;
+const CONFIG_NAME: &str = "config";
+const CHECK_NAME: &str = "check.lz4";
+const CHECK_TEXT: &[u8] = b"zap repository";
+const LOCK_NAME: &str = "lock";
+const CHUNK_DIR: &str = "chunks";
+const SNAPSHOT_DIR: &str = "snapshots";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct Config {
+    version: u16,
+    /// Stages every chunk and manifest went through, in the order data
+    /// goes through them when encoding.
+    stages: Vec<StageDescriptor>,
+    /// Hex encoded, encoded key chunks are named with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    time: SystemTime,
+    /// Folder the snapshot was taken of.
+    source: PathBuf,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    /// Relative to the snapshot folder.
+    path: String,
+    metadata: EntryMetadata,
+    /// Hex encoded id and length of every chunk, in file order.
+    chunks: Vec<(String, usize)>,
+}
+
+/// Summary of a snapshot.
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub time: SystemTime,
+    pub source: PathBuf,
+    pub files: usize,
+    /// Total size of the files.
+    pub size: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PruneStats {
+    pub removed_chunks: usize,
+    /// Encoded size of the removed chunks.
+    pub freed_bytes: u64,
+}
+
+/// Snapshots of folders sharing one content addressed chunk store, so
+/// every snapshot only stores the chunks no earlier snapshot did.
+pub struct Repository {
+    path: PathBuf,
+    config: Config,
+    pipeline: ProcessingPipeline,
+    /// Decoded from the config, only known when opened with the secret.
+    chunk_key: Option<[u8; 32]>,
+}
+
+impl Repository {
+    /// Creates an empty repository at `path`. Chunks and manifests go
+    /// through the stages picked by `options`.
+    pub fn init<P>(path: P, options: ArchiveOptions) -> Result<Repository, RepositoryError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+
+        if path.join(CONFIG_NAME).exists() {
+            return Err(RepositoryError::AlreadyExists(path.display().to_string()));
+        }
+
+        // Folders to remove again if the repository can't be set up,
+        // they are only removed while still empty
+        let created: Vec<PathBuf> = [path.clone(), path.join(CHUNK_DIR), path.join(SNAPSHOT_DIR)]
+            .into_iter()
+            .filter(|dir| !dir.exists())
+            .collect();
+
+        let repository = Repository::create(path.clone(), options);
+
+        if repository.is_err() {
+            let _ = fs::remove_file(path.join(CHECK_NAME));
+
+            for dir in created.iter().rev() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+
+        repository
+    }
+
+    fn create(path: PathBuf, options: ArchiveOptions) -> Result<Repository, RepositoryError> {
+        fs::create_dir_all(path.join(CHUNK_DIR))?;
+        fs::create_dir_all(path.join(SNAPSHOT_DIR))?;
+
+        let config = Config {
+            version: VERSION,
+            stages: options.stages(),
+            chunk_key: None,
+        };
+
+        let mut repository = Repository::with_config(path, config, options);
+
+        repository
+            .pipeline
+            .build_encryptor(File::create(repository.path.join(CHECK_NAME))?, &mut &CHECK_TEXT[..])?;
+
+        if repository.is_encrypted() {
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key);
+
+            let mut encoded = vec![];
+            repository
+                .pipeline
+                .without_progress()
+                .build_encryptor(&mut encoded, &mut &key[..])?;
+
+            repository.config.chunk_key = Some(dedup::to_hex(&encoded));
+            repository.chunk_key = Some(key);
+        }
+
+        // Written last, the repository only counts as one once complete
+        fs::write(
+            repository.path.join(CONFIG_NAME),
+            serde_json::to_vec_pretty(&repository.config)?,
+        )?;
+
+        Ok(repository)
+    }
+
+    /// Opens the repository at `path`. Only the encryption secret,
+    /// stage registry, progress and cancellation are taken from
+    /// `options`, the stages are read from the repository.
+    pub fn open<P>(path: P, options: ArchiveOptions) -> Result<Repository, RepositoryError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+
+        let config: Config = match fs::read(path.join(CONFIG_NAME)) {
+            Ok(config) => serde_json::from_slice(&config)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(RepositoryError::NotARepository(path.display().to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if config.version != VERSION {
+            return Err(RepositoryError::UnsupportedVersion(config.version));
+        }
+
+        // Without a secret only what isn't encoded can be used, like
+        // checking whether one is needed.
+        let has_secret = !matches!(options.encryption_secret, EncryptionSecret::None);
+
+        let mut repository = Repository::with_config(path, config, options);
+
+        if has_secret {
+            repository.check_secret()?;
+            repository.chunk_key = repository.decode_chunk_key()?;
+        }
+
+        Ok(repository)
+    }
+
+    fn with_config(path: PathBuf, config: Config, options: ArchiveOptions) -> Repository {
+        let pipeline = ProcessingPipeline::new()
+            .with_compression_level(Arc::new(options.compression_level))
+            .with_encryption_secret(Arc::new(options.encryption_secret))
+            .with_registry(options.registry)
+            .with_stages(Some(Arc::new(config.stages.clone())))
+            .with_progress(options.progress)
+            .with_cancellation(options.cancellation);
+
+        Repository {
+            path,
+            config,
+            pipeline,
+            chunk_key: None,
+        }
+    }
+
+    /// True when snapshots need an encryption secret to be read.
+    pub fn is_encrypted(&self) -> bool {
+        self.config
+            .stages
+            .iter()
+            .any(|s| s.kind == StageKind::Encryption && s.name != "passthrough")
+    }
+
+    /// Takes a snapshot of `source`. Files whose size and modification
+    /// time match the latest snapshot of the same folder are not read
+    /// again, and only chunks not already in the repository are stored.
+    pub fn snapshot<P>(&self, source: P) -> Result<SnapshotInfo, RepositoryError>
+    where
+        P: AsRef<Path>,
+    {
+        let _lock = self.lock(false)?;
+
+        let source = fs::canonicalize(source)?;
+        let chunks = ChunkStore::open(self.path.join(CHUNK_DIR))?.with_key(self.chunk_key);
+
+        let parent: HashMap<String, ManifestFile> = match self.latest_of(&source)? {
+            Some(id) => self
+                .manifest(&id)?
+                .files
+                .into_iter()
+                .map(|file| (file.path.clone(), file))
+                .collect(),
+            None => HashMap::new(),
+        };
+
+        let mut files = vec![];
+
+        for entry in WalkDir::new(&source).sort_by_file_name() {
+            let entry = entry.map_err(Error::from)?;
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let relative = match entry.path().strip_prefix(&source) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+
+            let path = relative.to_string_lossy().into_owned();
+            let metadata = EntryMetadata::from_file(&entry.metadata().map_err(Error::from)?);
+
+            let chunk_list = match parent.get(&path) {
+                Some(previous)
+                    if previous.metadata.size == metadata.size
+                        && previous.metadata.modified.is_some()
+                        && previous.metadata.modified == metadata.modified =>
+                {
+                    previous.chunks.clone()
+                }
+                _ => {
+                    self.pipeline.file_started(relative);
+
+                    let result = chunks.store_chunks(&self.pipeline, File::open(entry.path())?);
+
+                    self.pipeline.file_finished(relative);
+
+                    result?
+                        .iter()
+                        .map(|(hash, len)| (dedup::to_hex(hash), *len))
+                        .collect()
+                }
+            };
+
+            files.push(ManifestFile {
+                path,
+                metadata,
+                chunks: chunk_list,
+            });
+        }
+
+        let manifest = Manifest {
+            time: SystemTime::now(),
+            source,
+            files,
+        };
+
+        let mut id = [0u8; 8];
+        OsRng.fill_bytes(&mut id);
+        let id = dedup::to_hex(&id);
+
+        let json = serde_json::to_vec(&manifest)?;
+        let manifest_path = self.snapshot_path(&id);
+        let partial_path = manifest_path.with_extension("partial");
+
+        self.pipeline
+            .without_progress()
+            .build_encryptor(File::create(&partial_path)?, &mut &json[..])?;
+
+        fs::rename(&partial_path, &manifest_path)?;
+
+        Ok(info_of(id, &manifest))
+    }
+
+    /// Every snapshot, oldest first.
+    pub fn snapshots(&self) -> Result<Vec<SnapshotInfo>, RepositoryError> {
+        let mut snapshots = vec![];
+
+        for id in self.snapshot_ids()? {
+            let manifest = self.manifest(&id)?;
+            snapshots.push(info_of(id, &manifest));
+        }
+
+        snapshots.sort_by_key(|s| s.time);
+
+        Ok(snapshots)
+    }
+
+    /// Restores the snapshot whose id starts with `id` below `output`,
+    /// returning the number of files restored.
+    pub fn restore<P>(&self, id: &str, output: P) -> Result<usize, RepositoryError>
+    where
+        P: AsRef<Path>,
+    {
+        let _lock = self.lock(false)?;
+
+        let manifest = self.manifest(&self.find(id)?)?;
+        let chunks = ChunkStore::new(self.path.join(CHUNK_DIR)).with_key(self.chunk_key);
+
+        for file in manifest.files.iter() {
+            let path = Path::new(&file.path);
+
+            // Anything but a plain relative path would let a crafted
+            // manifest write outside of the output folder.
+            if !internal::is_relative_path(path) {
+                return Err(RepositoryError::Corrupt(format!("Invalid path: {}", file.path)));
+            }
+
+            let output_path = output.as_ref().join(path);
+
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let mut destination = File::create(&output_path)?;
+
+            self.pipeline.file_started(path);
+
+            for (hash, len) in file.chunks.iter() {
+                let hash = match dedup::from_hex(hash) {
+                    Some(hash) => hash,
+                    None => return Err(RepositoryError::Corrupt(format!("Invalid chunk: {}", hash))),
+                };
+
+                destination.write_all(&chunks.read_chunk(&self.pipeline, &hash, *len)?)?;
+            }
+
+            self.pipeline.file_finished(path);
+        }
+
+        Ok(manifest.files.len())
+    }
+
+    /// Removes snapshots, keeping the latest snapshot of each of the
+    /// last `keep_daily` days with snapshots, for every folder. Days
+    /// are in UTC. Returns the snapshots removed, their chunks stay
+    /// until `prune` is run.
+    pub fn forget(&self, keep_daily: usize) -> Result<Vec<SnapshotInfo>, RepositoryError> {
+        let _lock = self.lock(true)?;
+
+        let mut snapshots = self.snapshots()?;
+        snapshots.reverse();
+
+        let mut kept: HashMap<PathBuf, Vec<u64>> = HashMap::new();
+        let mut removed = vec![];
+
+        for snapshot in snapshots {
+            let day = snapshot.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / SECONDS_PER_DAY;
+            let days = kept.entry(snapshot.source.clone()).or_default();
+
+            // Newest first, so an earlier snapshot of a kept day is older
+            if days.last() != Some(&day) && days.len() < keep_daily {
+                days.push(day);
+                continue;
+            }
+
+            fs::remove_file(self.snapshot_path(&snapshot.id))?;
+            removed.push(snapshot);
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes the chunks no snapshot uses anymore, along with chunks
+    /// and manifests left partially written by interrupted runs.
+    pub fn prune(&self) -> Result<PruneStats, RepositoryError> {
+        let _lock = self.lock(true)?;
+
+        let mut used = HashSet::new();
+
+        for id in self.snapshot_ids()? {
+            for file in self.manifest(&id)?.files {
+                used.extend(file.chunks.into_iter().map(|(hash, _)| hash));
+            }
+        }
+
+        let mut stats = PruneStats::default();
+
+        for entry in fs::read_dir(self.path.join(CHUNK_DIR))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            let unused = match dedup::parse_chunk_file_name(&name) {
+                Some(hash) => !used.contains(&dedup::to_hex(&hash)),
+                None => name.ends_with(".partial"),
+            };
+
+            if unused {
+                stats.freed_bytes += entry.metadata()?.len();
+                stats.removed_chunks += 1;
+
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        for entry in fs::read_dir(self.path.join(SNAPSHOT_DIR))? {
+            let entry = entry?;
+
+            if entry.file_name().to_string_lossy().ends_with(".partial") {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    fn check_secret(&self) -> Result<(), RepositoryError> {
+        let mut check = vec![];
+
+        self.pipeline
+            .without_progress()
+            .build_dencryptor(File::open(self.path.join(CHECK_NAME))?, &mut check)?;
+
+        if check != CHECK_TEXT {
+            return Err(RepositoryError::Corrupt("Secret check failed".into()));
+        }
+
+        Ok(())
+    }
+
+    fn decode_chunk_key(&self) -> Result<Option<[u8; 32]>, RepositoryError> {
+        let encoded = match &self.config.chunk_key {
+            Some(encoded) => encoded,
+            None => return Ok(None),
+        };
+
+        let corrupt = || RepositoryError::Corrupt("Invalid chunk key".into());
+
+        let mut key = vec![];
+
+        self.pipeline
+            .without_progress()
+            .build_dencryptor(&dedup::decode_hex(encoded).ok_or_else(corrupt)?[..], &mut key)?;
+
+        Ok(Some(key.try_into().map_err(|_| corrupt())?))
+    }
+
+    /// Locks the repository until the returned file is dropped, failing
+    /// right away when another process holds a conflicting lock.
+    fn lock(&self, exclusive: bool) -> Result<File, RepositoryError> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.join(LOCK_NAME))?;
+
+        let result = match exclusive {
+            true => lock.try_lock(),
+            false => lock.try_lock_shared(),
+        };
+
+        match result {
+            Ok(()) => Ok(lock),
+            Err(TryLockError::WouldBlock) => Err(RepositoryError::Locked(self.path.display().to_string())),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    fn snapshot_path(&self, id: &str) -> PathBuf {
+        self.path.join(SNAPSHOT_DIR).join(format!("{}.lz4", id))
+    }
+
+    fn snapshot_ids(&self) -> Result<Vec<String>, RepositoryError> {
+        let mut ids = vec![];
+
+        for entry in fs::read_dir(self.path.join(SNAPSHOT_DIR))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+
+            if let Some(id) = name.strip_suffix(".lz4") {
+                ids.push(id.to_string());
+            }
+        }
+
+        Ok(ids)
+    }
+
+    /// Full id of the only snapshot starting with `prefix`.
+    fn find(&self, prefix: &str) -> Result<String, RepositoryError> {
+        let mut matches = self.snapshot_ids()?.into_iter().filter(|id| id.starts_with(prefix));
+
+        match (matches.next(), matches.next()) {
+            (Some(id), None) if !prefix.is_empty() => Ok(id),
+            (Some(_), _) => Err(RepositoryError::AmbiguousSnapshot(prefix.to_string())),
+            (None, _) => Err(RepositoryError::SnapshotNotFound(prefix.to_string())),
+        }
+    }
+
+    fn latest_of(&self, source: &Path) -> Result<Option<String>, RepositoryError> {
+        Ok(self
+            .snapshots()?
+            .into_iter()
+            .rfind(|s| s.source == source)
+            .map(|s| s.id))
+    }
+
+    fn manifest(&self, id: &str) -> Result<Manifest, RepositoryError> {
+        let mut json = vec![];
+
+        self.pipeline
+            .without_progress()
+            .build_dencryptor(File::open(self.snapshot_path(id))?, &mut json)?;
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+fn info_of(id: String, manifest: &Manifest) -> SnapshotInfo {
+    SnapshotInfo {
+        id,
+        time: manifest.time,
+        source: manifest.source.clone(),
+        files: manifest.files.len(),
+        size: manifest.files.iter().filter_map(|f| f.metadata.size).sum(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::encryption::EncryptionType;
+
+    fn options() -> ArchiveOptions {
+        ArchiveOptions::new()
+            .with_encryption(EncryptionType::XChaCha)
+            .with_encryption_secret(EncryptionSecret::Password(vec![7; 32]))
+    }
+
+    fn source(dir: &TempDir) -> PathBuf {
+        let source = dir.path().join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a"), b"known content").unwrap();
+        source
+    }
+
+    fn chunk_names(path: &Path) -> Vec<String> {
+        fs::read_dir(path.join(CHUNK_DIR))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn plain_name() -> String {
+        format!("{}.lz4", dedup::to_hex(&Sha256::digest(b"known content")))
+    }
+
+    #[test]
+    fn failed_init_removes_what_it_created() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo");
+
+        let options = options().with_encryption_secret(EncryptionSecret::Key("key".into()));
+
+        assert!(Repository::init(&path, options).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn encrypted_chunks_are_named_with_the_repository_key() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo");
+        let source = source(&dir);
+
+        let repository = Repository::init(&path, options()).unwrap();
+        let id = repository.snapshot(&source).unwrap().id;
+
+        assert_eq!(chunk_names(&path).len(), 1);
+        assert_ne!(chunk_names(&path), [plain_name()]);
+
+        // The key is read back from the config
+        let repository = Repository::open(&path, options()).unwrap();
+        repository.restore(&id, dir.path().join("out")).unwrap();
+        assert_eq!(fs::read(dir.path().join("out/a")).unwrap(), b"known content");
+
+        // and the same content is stored once
+        repository.snapshot(&source).unwrap();
+        fs::write(source.join("b"), b"known content").unwrap();
+        repository.snapshot(&source).unwrap();
+        assert_eq!(chunk_names(&path).len(), 1);
+    }
+
+    #[test]
+    fn plain_repositories_have_no_chunk_key() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo");
+
+        let repository = Repository::init(&path, ArchiveOptions::new()).unwrap();
+        repository.snapshot(source(&dir)).unwrap();
+
+        assert_eq!(chunk_names(&path), [plain_name()]);
+        assert!(!String::from_utf8(fs::read(path.join(CONFIG_NAME)).unwrap())
+            .unwrap()
+            .contains("chunk_key"));
+    }
+
+    #[test]
+    fn prune_waits_for_running_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo");
+
+        let repository = Repository::init(&path, options()).unwrap();
+        let other = Repository::open(&path, options()).unwrap();
+
+        let snapshot = repository.lock(false).unwrap();
+
+        assert!(matches!(other.prune(), Err(RepositoryError::Locked(_))));
+        assert!(matches!(other.forget(1), Err(RepositoryError::Locked(_))));
+        other.snapshot(source(&dir)).unwrap();
+
+        drop(snapshot);
+
+        let prune = other.lock(true).unwrap();
+        assert!(matches!(repository.snapshot(source(&dir)), Err(RepositoryError::Locked(_))));
+        drop(prune);
+
+        assert_eq!(other.prune().unwrap().removed_chunks, 0);
+    }
+
+    /// Moves a snapshot to `days` days and `seconds` seconds after the
+    /// epoch, for tests that need snapshots from several days.
+    fn set_time(repository: &Repository, id: &str, days: u64, seconds: u64) {
+        let mut manifest = repository.manifest(id).unwrap();
+        manifest.time = UNIX_EPOCH + std::time::Duration::from_secs(days * SECONDS_PER_DAY + seconds);
+
+        let json = serde_json::to_vec(&manifest).unwrap();
+
+        repository
+            .pipeline
+            .without_progress()
+            .build_encryptor(File::create(repository.snapshot_path(id)).unwrap(), &mut &json[..])
+            .unwrap();
+    }
+
+    fn ids(snapshots: &[SnapshotInfo]) -> Vec<String> {
+        snapshots.iter().map(|s| s.id.clone()).collect()
+    }
+
+    #[test]
+    fn forget_keeps_the_newest_snapshot_of_each_day_per_folder() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo");
+        let first = source(&dir);
+        let second = dir.path().join("other");
+
+        fs::create_dir_all(&second).unwrap();
+        fs::write(second.join("b"), b"other content").unwrap();
+
+        let repository = Repository::init(&path, options()).unwrap();
+
+        let take = |source: &Path, days, seconds| {
+            let id = repository.snapshot(source).unwrap().id;
+            set_time(&repository, &id, days, seconds);
+            id
+        };
+
+        let morning = take(&first, 10, 100);
+        let evening = take(&first, 10, 200);
+        let next_day = take(&first, 11, 100);
+        let last_day = take(&first, 12, 100);
+        let other = take(&second, 10, 100);
+
+        // The older snapshot of a kept day goes first
+        assert_eq!(ids(&repository.forget(3).unwrap()), [morning]);
+
+        // and every folder keeps its own days
+        assert_eq!(ids(&repository.forget(1).unwrap()), [next_day, evening]);
+        assert_eq!(ids(&repository.snapshots().unwrap()), [other, last_day]);
+    }
+
+    #[test]
+    fn prune_removes_unused_chunks_and_leftovers() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("repo");
+        let source = source(&dir);
+
+        let repository = Repository::init(&path, options()).unwrap();
+
+        fs::write(source.join("b"), b"first version").unwrap();
+        repository.snapshot(&source).unwrap();
+
+        fs::write(source.join("b"), b"second, longer version").unwrap();
+        let kept = repository.snapshot(&source).unwrap().id;
+
+        assert_eq!(chunk_names(&path).len(), 3);
+
+        fs::write(path.join(CHUNK_DIR).join("00.lz4.partial"), b"cut short").unwrap();
+        fs::write(path.join(SNAPSHOT_DIR).join("00.partial"), b"cut short").unwrap();
+
+        assert_eq!(repository.forget(1).unwrap().len(), 1);
+
+        let stats = repository.prune().unwrap();
+        assert_eq!(stats.removed_chunks, 2);
+        assert!(stats.freed_bytes > 0);
+
+        assert_eq!(chunk_names(&path).len(), 2);
+        assert!(!path.join(SNAPSHOT_DIR).join("00.partial").exists());
+
+        // What is left still restores
+        let output = dir.path().join("out");
+        assert_eq!(repository.restore(&kept, &output).unwrap(), 2);
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"known content");
+        assert_eq!(fs::read(output.join("b")).unwrap(), b"second, longer version");
+    }
+}
@@ -1,10 +1,13 @@
 pub mod passthrough;
 
 // External
-use std::io::{
-    Error, Read, Write
+use std::{
+    future::Future,
+    io::{Error, Read, Write},
 };
 
+use tokio::io::{AsyncRead, AsyncWrite};
+
 use crate::{error::SignerInitError, compression::{Compress, Decompress}};
 
 pub trait Signer<U> {
@@ -38,6 +41,16 @@ pub trait Verify: Read {
     fn finalise(self) -> Result<Option<Vec<u8>>, Error>;
 }
 
+/// Async counterpart of [`Sign`].
+pub trait AsyncSign: AsyncWrite + Unpin + Send {
+    fn finalise(self) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send;
+}
+
+/// Async counterpart of [`Verify`].
+pub trait AsyncVerify: AsyncRead + Unpin + Send {
+    fn finalise(self) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send;
+}
+
 pub trait SignerMethod<T>
 where T: Compress
 {
@@ -1,9 +1,15 @@
 // External
-use std::io::{Error, Read, Write};
+use std::{
+    io::{Error, Read, Write},
+    pin::Pin,
+    task::{Context, Poll},
+};
 
-use crate::compression::{Compress, Decompress};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use super::{Sign, Verify};
+use crate::compression::{AsyncCompress, AsyncDecompress, Compress, Decompress};
+
+use super::{AsyncSign, AsyncVerify, Sign, Verify};
 
 pub struct SignerPassthrough<T> {
     inner: T,
@@ -90,3 +96,101 @@ where
         self.inner.read(buf)
     }
 }
+
+pub struct AsyncSignerPassthrough<T> {
+    inner: T,
+}
+
+impl<T> AsyncSignerPassthrough<T>
+where
+    T: AsyncCompress,
+{
+    pub fn new(writer: T) -> Self {
+        AsyncSignerPassthrough { inner: writer }
+    }
+}
+
+impl<T> From<T> for AsyncSignerPassthrough<T>
+where
+    T: AsyncCompress,
+{
+    fn from(writer: T) -> Self {
+        AsyncSignerPassthrough::new(writer)
+    }
+}
+
+impl<T> AsyncSign for AsyncSignerPassthrough<T>
+where
+    T: AsyncCompress,
+{
+    async fn finalise(self) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.finalise().await?;
+        Ok(None)
+    }
+}
+
+impl<T> AsyncWrite for AsyncSignerPassthrough<T>
+where
+    T: AsyncCompress,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub struct AsyncVerifierPassthrough<T> {
+    inner: T,
+}
+
+impl<T> AsyncVerifierPassthrough<T>
+where
+    T: AsyncDecompress,
+{
+    pub fn new(reader: T) -> Self {
+        AsyncVerifierPassthrough { inner: reader }
+    }
+}
+
+impl<T> From<T> for AsyncVerifierPassthrough<T>
+where
+    T: AsyncDecompress,
+{
+    fn from(reader: T) -> Self {
+        AsyncVerifierPassthrough::new(reader)
+    }
+}
+
+impl<T> AsyncVerify for AsyncVerifierPassthrough<T>
+where
+    T: AsyncDecompress,
+{
+    async fn finalise(self) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.finalise().await?;
+        Ok(None)
+    }
+}
+
+impl<T> AsyncRead for AsyncVerifierPassthrough<T>
+where
+    T: AsyncDecompress,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
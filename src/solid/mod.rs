@@ -0,0 +1,564 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File},
+    io::{copy, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    archive::{EntryMetadata, HashingReader},
+    error::{PipelineCompressionError, PipelineDecompressionError},
+    internal,
+    pipeline::{
+        seekable::{SeekableReader, SeekableWriter, DEFAULT_BLOCK_SIZE},
+        ProcessingPipeline,
+    },
+};
+
+/// Directory (relative to the output folder) holding the solid blocks.
+pub const SOLID_DIR: &str = ".zap-solid";
+
+const INDEX_NAME: &str = "index.lz4";
+const INDEX_MAGIC: &[u8; 8] = b"ZAPSOLI1";
+
+/// Where a file packed into a solid block lives.
+#[derive(Debug, Clone)]
+pub struct SolidEntry {
+    /// Path of the file relative to the archived folder.
+    pub path: PathBuf,
+    pub block: usize,
+    /// Offset of the file within the decoded block.
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Files collected for the next solid block.
+#[derive(Default)]
+pub struct SolidGroup {
+    files: Vec<(PathBuf, PathBuf)>,
+    size: u64,
+}
+
+impl SolidGroup {
+    /// Adds `source`, to be stored as `relative` in the index.
+    pub fn push(&mut self, source: PathBuf, relative: PathBuf, size: u64) {
+        self.files.push((source, relative));
+        self.size += size;
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Paths the files will be stored as.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|(_, relative)| relative.clone()).collect()
+    }
+
+    pub fn take(&mut self) -> SolidGroup {
+        mem::take(self)
+    }
+}
+
+/// Concatenates small files into shared blocks, so the compressor can
+/// make use of redundancy between files. Blocks are written with the
+/// seekable framing so single files can still be extracted cheaply.
+pub struct SolidStore {
+    dir: PathBuf,
+    entries: Mutex<Vec<SolidEntry>>,
+}
+
+impl SolidStore {
+    pub fn new(dir: PathBuf) -> SolidStore {
+        SolidStore {
+            dir,
+            entries: Mutex::new(vec![]),
+        }
+    }
+
+    /// Reads the index of the solid store in `dir`.
+    pub fn open(pipeline: &ProcessingPipeline, dir: PathBuf) -> Result<SolidStore, PipelineDecompressionError> {
+        let mut index = vec![];
+        pipeline
+            .without_progress()
+            .build_dencryptor(File::open(dir.join(INDEX_NAME))?, &mut index)?;
+
+        let entries = parse_index(index)?;
+
+        if let Some(entry) = entries.iter().find(|e| !dir.join(block_file_name(e.block)).is_file()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Solid index refers to missing block {}", entry.block),
+            )
+            .into());
+        }
+
+        Ok(SolidStore {
+            dir,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Entries of all blocks, sorted by path once the store is finished.
+    pub fn entries(&self) -> Vec<SolidEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Numbers of the blocks referenced by the index, in order.
+    pub fn blocks(&self) -> Vec<usize> {
+        let blocks: BTreeSet<usize> = self.entries.lock().unwrap().iter().map(|e| e.block).collect();
+        blocks.into_iter().collect()
+    }
+
+    fn block_path(&self, block: usize) -> PathBuf {
+        self.dir.join(block_file_name(block))
+    }
+
+    /// Writes `group` as `block`, returning the metadata of each file
+    /// as it was read. A block that fails to be written is removed
+    /// again, so no partial block is left behind.
+    pub fn write_block(
+        &self,
+        pipeline: &ProcessingPipeline,
+        block: usize,
+        group: SolidGroup,
+    ) -> Result<Vec<(PathBuf, EntryMetadata)>, PipelineCompressionError> {
+        let result = self.try_write_block(pipeline, block, group);
+
+        if result.is_err() {
+            let _ = fs::remove_file(self.block_path(block));
+        }
+
+        result
+    }
+
+    fn try_write_block(
+        &self,
+        pipeline: &ProcessingPipeline,
+        block: usize,
+        group: SolidGroup,
+    ) -> Result<Vec<(PathBuf, EntryMetadata)>, PipelineCompressionError> {
+        fs::create_dir_all(&self.dir)?;
+
+        let io = File::create(self.block_path(block))?;
+        let mut writer = SeekableWriter::new(pipeline.clone(), io, DEFAULT_BLOCK_SIZE);
+
+        let mut entries = Vec::with_capacity(group.files.len());
+        let mut files = Vec::with_capacity(group.files.len());
+        let mut offset = 0;
+
+        for (source, relative) in group.files {
+            let source = File::open(&source)?;
+            let mut metadata = EntryMetadata::from_file(&source.metadata()?);
+
+            // Use the copied length rather than the size seen while walking,
+            // in case the file changed in between.
+            let mut source = HashingReader::new(source, pipeline.content_key());
+            let length = copy(&mut source, &mut writer)?;
+
+            source.finish(&mut metadata);
+            files.push((relative.clone(), metadata));
+
+            entries.push(SolidEntry {
+                path: relative,
+                block,
+                offset,
+                length,
+            });
+
+            offset += length;
+        }
+
+        writer.finish()?;
+
+        self.entries.lock().unwrap().extend(entries);
+
+        Ok(files)
+    }
+
+    /// Writes the encoded index, once every block has been written.
+    ///
+    /// Blocks that failed leave a gap in the numbering, so the blocks
+    /// left are numbered from 0 again first, as extracting expects.
+    pub fn finish(&self, pipeline: &ProcessingPipeline) -> Result<(), PipelineCompressionError> {
+        let blocks = self.blocks();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        // Every block only ever moves down to a number that is free by
+        // then, either because its block failed or was already moved
+        for (new, old) in blocks.iter().enumerate() {
+            if new != *old {
+                fs::rename(self.block_path(*old), self.block_path(new))?;
+            }
+        }
+
+        let renumbered: HashMap<usize, usize> = blocks.iter().enumerate().map(|(new, old)| (*old, new)).collect();
+
+        for entry in entries.iter_mut() {
+            entry.block = renumbered[&entry.block];
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let index = format_index(&entries);
+
+        let io = File::create(self.dir.join(INDEX_NAME))?;
+        pipeline
+            .without_progress()
+            .build_encryptor(io, &mut &index[..])
+    }
+
+    /// Opens a random access reader over a decoded block.
+    pub fn block_reader(
+        &self,
+        pipeline: &ProcessingPipeline,
+        block: usize,
+    ) -> Result<SeekableReader<File>, PipelineDecompressionError> {
+        SeekableReader::new(pipeline.clone(), File::open(self.block_path(block))?)
+    }
+
+    /// Extracts a single entry, only decoding the parts of its block
+    /// that cover it.
+    pub fn extract<W>(
+        &self,
+        pipeline: &ProcessingPipeline,
+        entry: &SolidEntry,
+        destination: &mut W,
+    ) -> Result<(), PipelineDecompressionError>
+    where
+        W: Write,
+    {
+        let mut reader = self.block_reader(pipeline, entry.block)?;
+        extract_from(&mut reader, entry, destination)
+    }
+
+    /// Extracts every entry of `block` below `output_root`. An entry
+    /// that fails part way is removed again.
+    pub fn extract_block(
+        &self,
+        pipeline: &ProcessingPipeline,
+        block: usize,
+        output_root: &Path,
+    ) -> Result<(), PipelineDecompressionError> {
+        let mut entries: Vec<SolidEntry> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.block == block)
+            .cloned()
+            .collect();
+
+        // Reading in block order keeps every decoded sub-block cached
+        // for as long as it is needed.
+        entries.sort_by_key(|e| e.offset);
+
+        let mut reader = self.block_reader(pipeline, block)?;
+
+        for entry in entries {
+            let output_path = output_root.join(&entry.path);
+
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let result = extract_from(&mut reader, &entry, &mut File::create(&output_path)?);
+
+            if result.is_err() {
+                let _ = fs::remove_file(&output_path);
+                return result;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Path of a block relative to the folder holding the solid directory.
+pub fn block_name(block: usize) -> PathBuf {
+    Path::new(SOLID_DIR).join(block_file_name(block))
+}
+
+fn block_file_name(block: usize) -> String {
+    format!("block-{:06}.lz4", block)
+}
+
+/// Path of the index relative to the folder holding the solid directory.
+pub fn index_name() -> PathBuf {
+    Path::new(SOLID_DIR).join(INDEX_NAME)
+}
+
+/// Formats `entries` as an index, ready to be encoded.
+pub(crate) fn format_index(entries: &[SolidEntry]) -> Vec<u8> {
+    /*
+        The index starts with its magic, followed by one record per file:
+        [ block ][ offset ][ length ][ path length ][ path ]
+        [ 8     ][ 8      ][ 8      ][ 4           ][ ...  ] (Bytes, LE)
+
+        Paths are stored as their raw bytes on unix, so that any name,
+        even one that isn't UTF-8, comes back the same.
+    */
+    let mut index = INDEX_MAGIC.to_vec();
+
+    for entry in entries.iter() {
+        let path = path_to_bytes(&entry.path);
+
+        index.extend_from_slice(&(entry.block as u64).to_le_bytes());
+        index.extend_from_slice(&entry.offset.to_le_bytes());
+        index.extend_from_slice(&entry.length.to_le_bytes());
+        index.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        index.extend_from_slice(&path);
+    }
+
+    index
+}
+
+/// Parses a decoded index into the entries of every block.
+pub(crate) fn parse_index(index: Vec<u8>) -> Result<Vec<SolidEntry>, Error> {
+    let mut records = match index.strip_prefix(INDEX_MAGIC) {
+        Some(records) => records,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Corrupt solid index")),
+    };
+
+    let mut entries = vec![];
+
+    while !records.is_empty() {
+        let entry = parse_index_record(&mut records).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Corrupt solid index entry {}", entries.len()),
+            )
+        })?;
+
+        entries.push(entry);
+    }
+
+    // Blocks are numbered from 0 and none is empty, so none can be
+    // numbered past the number of entries
+    if let Some(entry) = entries.iter().find(|e| e.block >= entries.len()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Solid index refers to block {} of at most {}", entry.block, entries.len()),
+        ));
+    }
+
+    Ok(entries)
+}
+
+fn extract_from<R, W>(
+    reader: &mut R,
+    entry: &SolidEntry,
+    destination: &mut W,
+) -> Result<(), PipelineDecompressionError>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    reader.seek(SeekFrom::Start(entry.offset))?;
+
+    let copied = copy(&mut reader.take(entry.length), destination)?;
+
+    if copied != entry.length {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("Solid block {} is shorter than its index", entry.block),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn parse_index_record(records: &mut &[u8]) -> Option<SolidEntry> {
+    let block = u64::from_le_bytes(take(records, 8)?.try_into().ok()?).try_into().ok()?;
+    let offset = u64::from_le_bytes(take(records, 8)?.try_into().ok()?);
+    let length = u64::from_le_bytes(take(records, 8)?.try_into().ok()?);
+    let path_len = u32::from_le_bytes(take(records, 4)?.try_into().ok()?);
+    let path = path_from_bytes(take(records, path_len as usize)?)?;
+
+    if !internal::is_relative_path(&path) {
+        return None;
+    }
+
+    Some(SolidEntry {
+        path,
+        block,
+        offset,
+        length,
+    })
+}
+
+/// Splits the first `len` bytes off `records`.
+fn take<'a>(records: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if records.len() < len {
+        return None;
+    }
+
+    let (head, tail) = records.split_at(len);
+    *records = tail;
+
+    Some(head)
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    String::from_utf8(bytes.to_vec()).ok().map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, block: usize) -> SolidEntry {
+        SolidEntry {
+            path: PathBuf::from(path),
+            block,
+            offset: 10 * block as u64,
+            length: 3,
+        }
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let entries = [entry("a", 0), entry("tab\there", 1), entry("new\nline/file", 2), entry("ünï", 3)];
+        let parsed = parse_index(format_index(&entries)).unwrap();
+
+        assert_eq!(parsed.len(), entries.len());
+
+        for (parsed, entry) in parsed.iter().zip(entries.iter()) {
+            assert_eq!(parsed.path, entry.path);
+            assert_eq!((parsed.block, parsed.offset, parsed.length), (entry.block, entry.offset, entry.length));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn index_keeps_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = PathBuf::from(OsStr::from_bytes(b"not\xffutf8"));
+        let entries = [SolidEntry {
+            path: path.clone(),
+            ..entry("", 0)
+        }];
+
+        assert_eq!(parse_index(format_index(&entries)).unwrap()[0].path, path);
+    }
+
+    #[test]
+    fn corrupt_index_is_rejected() {
+        let index = format_index(&[entry("a", 0), entry("b", 1)]);
+
+        assert!(parse_index(index[..index.len() - 1].to_vec()).is_err());
+        assert!(parse_index(index[INDEX_MAGIC.len()..].to_vec()).is_err());
+        assert!(parse_index(format_index(&[entry("../escape", 0)])).is_err());
+        assert!(parse_index(format_index(&[entry("/absolute", 0)])).is_err());
+        assert!(parse_index(format_index(&[entry("./dot", 0)])).is_err());
+        assert!(parse_index(INDEX_MAGIC.to_vec()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn block_numbers_are_bounded_by_the_entries() {
+        assert!(parse_index(format_index(&[entry("a", 0), entry("b", 2)])).is_err());
+
+        let last = SolidEntry {
+            block: usize::MAX,
+            ..entry("a", 0)
+        };
+
+        assert!(parse_index(format_index(&[last])).is_err());
+    }
+
+    #[test]
+    fn store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = dir.path().join("sources");
+        let output = dir.path().join("output");
+        fs::create_dir_all(&sources).unwrap();
+
+        let pipeline = ProcessingPipeline::new();
+        let store = SolidStore::new(dir.path().join(SOLID_DIR));
+        let mut group = SolidGroup::default();
+
+        for (name, content) in [("a", &b"first"[..]), ("line\nbreak", b"second"), ("c", b"")] {
+            fs::write(sources.join(name), content).unwrap();
+            group.push(sources.join(name), PathBuf::from(name), content.len() as u64);
+        }
+
+        store.write_block(&pipeline, 0, group).unwrap();
+        store.finish(&pipeline).unwrap();
+
+        let store = SolidStore::open(&pipeline, dir.path().join(SOLID_DIR)).unwrap();
+        assert_eq!(store.blocks(), [0]);
+
+        store.extract_block(&pipeline, 0, &output).unwrap();
+
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"first");
+        assert_eq!(fs::read(output.join("line\nbreak")).unwrap(), b"second");
+        assert_eq!(fs::read(output.join("c")).unwrap(), b"");
+    }
+
+    #[test]
+    fn failed_blocks_leave_no_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let sources = dir.path().join("sources");
+        let output = dir.path().join("output");
+        fs::create_dir_all(&sources).unwrap();
+
+        let pipeline = ProcessingPipeline::new();
+        let store = SolidStore::new(dir.path().join(SOLID_DIR));
+
+        for (block, name) in ["missing", "b", "c"].iter().enumerate() {
+            if *name != "missing" {
+                fs::write(sources.join(name), name).unwrap();
+            }
+
+            let mut group = SolidGroup::default();
+            group.push(sources.join(name), PathBuf::from(name), 1);
+
+            assert_eq!(store.write_block(&pipeline, block, group).is_ok(), *name != "missing");
+        }
+
+        store.finish(&pipeline).unwrap();
+
+        let store = SolidStore::open(&pipeline, dir.path().join(SOLID_DIR)).unwrap();
+        assert_eq!(store.blocks(), [0, 1]);
+
+        for block in store.blocks() {
+            store.extract_block(&pipeline, block, &output).unwrap();
+        }
+
+        assert_eq!(fs::read(output.join("b")).unwrap(), b"b");
+        assert_eq!(fs::read(output.join("c")).unwrap(), b"c");
+        assert!(!output.join("missing").exists());
+    }
+}